@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use watchdir::tree::Head;
+
+/// Gives a tree of `width` directories on each of `depth` levels under
+/// `/top`, and the path of the deepest directory.
+fn setup(width: i32, depth: i32) -> (Head<i32>, PathBuf) {
+    let top = PathBuf::from("/top");
+    let mut tree = Head::new(top.to_owned());
+    tree.insert(&top, 0).unwrap();
+    let mut value = 1;
+    let mut dir = top;
+    for _ in 0..depth {
+        for i in 0..width {
+            tree.insert(&dir.join(i.to_string()), value).unwrap();
+            value += 1;
+        }
+        dir.push("0");
+    }
+    (tree, dir)
+}
+
+pub fn bench_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Path tree insert");
+    for width in [10, 100] {
+        group.bench_function(BenchmarkId::from_parameter(width), |b| {
+            b.iter(|| setup(width, 10))
+        });
+    }
+    group.finish()
+}
+
+pub fn bench_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Path tree path of deepest value");
+    for depth in [10, 50] {
+        let (tree, dir) = setup(10, depth);
+        let value = tree.get(&dir).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(depth), |b| {
+            b.iter(|| tree.path(value))
+        });
+    }
+    group.finish()
+}
+
+pub fn bench_rename(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Path tree rename");
+    for depth in [10, 50] {
+        let (mut tree, _) = setup(10, depth);
+        let from = Path::new("/top/0");
+        let to = Path::new("/top/moved");
+        let value = tree.get(from).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(depth), |b| {
+            b.iter(|| {
+                tree.rename(value, to).unwrap();
+                tree.rename(value, from).unwrap();
+            })
+        });
+    }
+    group.finish()
+}
+
+pub fn bench_delete(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Path tree delete");
+    for width in [10, 100] {
+        group.bench_function(BenchmarkId::from_parameter(width), |b| {
+            b.iter_batched(
+                || setup(width, 10).0,
+                |mut tree| tree.delete(1).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(
+    benches,
+    bench_insert,
+    bench_path,
+    bench_rename,
+    bench_delete
+);
+criterion_main!(benches);
@@ -5,6 +5,8 @@ fn main() {
         println!("cargo:rustc-env=GIT_SHA={}", git_sha);
     }
 
+    println!("cargo:rustc-check-cfg=cfg(unsound_local_offset)");
+
     println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
     let time = match env::var("SOURCE_DATE_EPOCH") {
         Ok(t) => t.parse().unwrap(),
@@ -37,7 +39,7 @@ fn get_git_sha() -> Option<String> {
         }
 
         if let Some(ref_file) = head_content.strip_prefix("ref: ") {
-            let ref_file = gitdir.join(&ref_file);
+            let ref_file = gitdir.join(ref_file);
             if !ref_file.is_file() {
                 return None;
             }
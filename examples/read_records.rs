@@ -0,0 +1,40 @@
+//! Reads the binary output of watchdir from stdin and prints the events.
+//!
+//! ```sh
+//! watchdir --format cbor DIR | cargo run --example read_records -- cbor
+//! ```
+
+use std::io::{self, Read};
+
+use watchdir::record::Record;
+
+fn main() -> io::Result<()> {
+    let msgpack = match std::env::args().nth(1).as_deref() {
+        Some("cbor") | None => false,
+        Some("msgpack") => true,
+        Some(v) => panic!("unknown format: {}", v),
+    };
+    let mut stdin = io::stdin().lock();
+    let mut len = [0; 4];
+    loop {
+        // Each record is prefixed with its length in a big-endian u32.
+        match stdin.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            v => v?,
+        }
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        stdin.read_exact(&mut data)?;
+        let record: Record = if msgpack {
+            rmp_serde::from_slice(&data).unwrap()
+        } else {
+            serde_cbor::from_slice(&data).unwrap()
+        };
+        let Record { kind, time, path, from, to, .. } = record;
+        let path = path.or(from).unwrap_or_default();
+        match to {
+            Some(to) => println!("{} {} {} -> {}", time, kind, path, to),
+            None => println!("{} {} {}", time, kind, path),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use watchdir::parser;
+
+fuzz_target!(|data: &[u8]| {
+    let mut data = data;
+    while let Some((_, size)) = parser::parse(data) {
+        assert!(size > 0 && size <= data.len());
+        data = &data[size..];
+    }
+});
@@ -0,0 +1,261 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use time::{format_description::FormatItem, macros::format_description};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+use watchdir::{Event, FileType};
+
+use crate::{exec, TimedEvent};
+
+/// Names of snapshots in UTC, which sort by their times.
+const NAME_FORMAT: &[FormatItem] = format_description!(
+    "[year][month][day]T[hour][minute][second].[subsecond digits:3]Z"
+);
+const TAR_ZST: &str = ".tar.zst";
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// A directory with copies of the files.
+    Dir,
+    /// A tar archive compressed with zstd.
+    TarZst,
+}
+
+pub struct ArchiveOpts {
+    /// Directory of the snapshots.
+    pub dest: PathBuf,
+    pub format: Format,
+    /// Remove the oldest snapshots beyond this many.
+    pub keep: Option<usize>,
+    /// Take a snapshot once no event comes within this time.
+    pub debounce: Duration,
+}
+
+/// Takes a snapshot of the files changed in each batch of events, with
+/// their paths relative to the top directory, named after the time of the
+/// batch. Files are only taken again when their sizes or modification
+/// times change, so reading them does not count.
+pub struct Archiver {
+    opts: ArchiveOpts,
+    top_dir: PathBuf,
+    /// Sizes and modification times of the files by the last snapshots.
+    seen: Arc<Mutex<HashMap<PathBuf, Stamp>>>,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+type Stamp = (u64, Option<SystemTime>);
+
+impl Archiver {
+    /// Files already there are not taken until they change.
+    pub fn new(
+        opts: ArchiveOpts,
+        top_dir: PathBuf,
+        rx: broadcast::Receiver<TimedEvent>,
+    ) -> Self {
+        let seen = WalkDir::new(&top_dir)
+            .into_iter()
+            .flatten()
+            .filter(|v| v.file_type().is_file())
+            .filter_map(|v| {
+                Some((v.path().to_owned(), stamp(&v.metadata().ok()?)))
+            })
+            .collect();
+        Self { opts, top_dir, seen: Arc::new(Mutex::new(seen)), rx }
+    }
+
+    pub async fn run(mut self) {
+        while let Some((event, _)) = exec::recv(&mut self.rx).await {
+            let mut paths = BTreeSet::new();
+            self.follow(&event, &mut paths);
+            self.forget(&event);
+            paths.extend(changed_paths(&event));
+            let mut t = time::OffsetDateTime::now_utc();
+            while let Ok(Some((event, _))) = tokio::time::timeout(
+                self.opts.debounce,
+                exec::recv(&mut self.rx),
+            )
+            .await
+            {
+                self.follow(&event, &mut paths);
+                self.forget(&event);
+                paths.extend(changed_paths(&event));
+                t = time::OffsetDateTime::now_utc();
+            }
+            if paths.is_empty() {
+                continue;
+            }
+            let name = t.format(NAME_FORMAT).unwrap();
+            let (top_dir, dest) =
+                (self.top_dir.clone(), self.opts.dest.clone());
+            let (format, keep) = (self.opts.format, self.opts.keep);
+            let seen = self.seen.clone();
+            tokio::task::spawn_blocking(move || {
+                let files =
+                    changed_files(&top_dir, &paths, &mut seen.lock().unwrap());
+                if files.is_empty() {
+                    return;
+                }
+                debug!("Archiving {} files to {}", files.len(), name);
+                if let Err(e) =
+                    snapshot(&top_dir, &dest, &name, format, &files)
+                {
+                    warn!("Failed to archive to {}: {}", dest.display(), e);
+                    return;
+                }
+                if let Some(keep) = keep {
+                    if let Err(e) = prune(&dest, keep) {
+                        warn!("Failed to remove old snapshots: {}", e);
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    /// Moves the stamps and the paths of the batch along with the top
+    /// directory.
+    fn follow(&mut self, event: &Event, paths: &mut BTreeSet<PathBuf>) {
+        let (from, to) = match event {
+            Event::MoveTop(from, Some(to)) => (from, to),
+            _ => return,
+        };
+        let rebase = |v: &Path| Some(to.join(v.strip_prefix(from).ok()?));
+        let mut seen = self.seen.lock().unwrap();
+        *seen = std::mem::take(&mut *seen)
+            .into_iter()
+            .filter_map(|(k, v)| Some((rebase(&k)?, v)))
+            .collect();
+        *paths = paths.iter().filter_map(|v| rebase(v)).collect();
+        self.top_dir = to.to_owned();
+    }
+
+    /// Drops the stamps of the files gone by the event.
+    fn forget(&self, event: &Event) {
+        let path = match event {
+            Event::Delete(path, _)
+            | Event::MoveAway(path, ..)
+            | Event::Move(path, ..) => path,
+            _ => return,
+        };
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|v, _| !v.starts_with(path));
+    }
+}
+
+/// Gives the paths which may have new content after the event.
+fn changed_paths(event: &Event) -> Option<PathBuf> {
+    match event {
+        Event::Create(path, _)
+        | Event::MoveInto(path, ..)
+        | Event::Move(_, path, _)
+        | Event::Modify(path, FileType::File, _)
+        | Event::Close(path, FileType::File) => Some(path.to_owned()),
+        _ => None,
+    }
+}
+
+fn stamp(metadata: &fs::Metadata) -> Stamp {
+    (metadata.len(), metadata.modified().ok())
+}
+
+/// Gives the changed files still there under the paths, relative to the
+/// top directory, including those in new directories.
+fn changed_files(
+    top_dir: &Path,
+    paths: &BTreeSet<PathBuf>,
+    seen: &mut HashMap<PathBuf, Stamp>,
+) -> BTreeSet<PathBuf> {
+    let mut files = BTreeSet::new();
+    for entry in paths.iter().flat_map(WalkDir::new).flatten() {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let (path, metadata) =
+            match (entry.path().strip_prefix(top_dir), entry.metadata()) {
+                (Ok(path), Ok(metadata)) => (path, metadata),
+                _ => continue,
+            };
+        let stamp = stamp(&metadata);
+        if seen.insert(entry.path().to_owned(), stamp) != Some(stamp) {
+            files.insert(path.to_owned());
+        }
+    }
+    files
+}
+
+/// Written under a temporary name and renamed, so a snapshot is either
+/// whole or missing.
+fn snapshot(
+    top_dir: &Path,
+    dest: &Path,
+    name: &str,
+    format: Format,
+    files: &BTreeSet<PathBuf>,
+) -> io::Result<()> {
+    let (tmp, path) = match format {
+        Format::Dir => (dest.join(format!(".{}", name)), dest.join(name)),
+        Format::TarZst => (
+            dest.join(format!(".{}{}", name, TAR_ZST)),
+            dest.join(format!("{}{}", name, TAR_ZST)),
+        ),
+    };
+    match format {
+        Format::Dir => {
+            fs::create_dir_all(&tmp)?;
+            for file in files {
+                let target = tmp.join(file);
+                fs::create_dir_all(target.parent().unwrap())?;
+                match fs::copy(top_dir.join(file), target) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => drop(result?),
+                }
+            }
+        }
+        Format::TarZst => {
+            let encoder =
+                zstd::Encoder::new(fs::File::create(&tmp)?, ZSTD_LEVEL)?;
+            let mut builder = tar::Builder::new(encoder);
+            for file in files {
+                match builder.append_path_with_name(top_dir.join(file), file) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+            builder.into_inner()?.finish()?;
+        }
+    }
+    fs::rename(tmp, path)
+}
+
+/// Removes the oldest snapshots beyond `keep`. Other entries of the
+/// directory are left alone.
+fn prune(dest: &Path, keep: usize) -> io::Result<()> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dest)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let time = name.strip_suffix(TAR_ZST).unwrap_or(&name);
+        if time::PrimitiveDateTime::parse(time, NAME_FORMAT).is_ok() {
+            snapshots.push((name, entry.path()));
+        }
+    }
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    for (_, path) in snapshots.into_iter().take(excess) {
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::{fs, path::Path};
+
+pub use watchdir::record::Process;
+use watchdir::{Event, FileType};
+
+/// Finds the processes which have the files of events open, by the links
+/// in `/proc/*/fd`. It is best effort: a process may close the file before
+/// the scan, and those of other users are only seen as root.
+///
+/// A scan reads the descriptors of every process, so at most `per_second`
+/// are done in a second, and later events in it are not audited.
+pub struct Audit {
+    per_second: u32,
+    /// Second of the last scan.
+    second: i64,
+    scans: u32,
+}
+
+impl Audit {
+    pub fn new(per_second: u32) -> Self {
+        Self { per_second, second: 0, scans: 0 }
+    }
+
+    /// Gives the processes with the file of a `Create`, `Open`, `Close` or
+    /// `Modify` event open, or `None` for other events or past the limit.
+    /// The scan runs on a blocking task.
+    pub async fn processes(
+        &mut self,
+        event: &Event,
+        t: time::OffsetDateTime,
+    ) -> Option<Vec<Process>> {
+        let path = match event {
+            Event::Create(path, FileType::File)
+            | Event::Open(path, FileType::File)
+            | Event::Close(path, FileType::File)
+            | Event::Modify(path, FileType::File, _) => path.to_owned(),
+            _ => return None,
+        };
+        if t.unix_timestamp() != self.second {
+            self.second = t.unix_timestamp();
+            self.scans = 0;
+        }
+        if self.scans >= self.per_second {
+            return None;
+        }
+        self.scans += 1;
+        tokio::task::spawn_blocking(move || scan(&path)).await.ok()
+    }
+}
+
+fn scan(path: &Path) -> Vec<Process> {
+    let own = std::process::id();
+    let mut processes = Vec::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(v) => v,
+        Err(_) => return processes,
+    };
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_str().and_then(|v| v.parse().ok())
+        {
+            Some(v) if v != own => v,
+            _ => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(v) => v,
+            // Gone, or of another user.
+            Err(_) => continue,
+        };
+        let has_open = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|v| v == path));
+        if has_open {
+            let comm = fs::read_to_string(entry.path().join("comm"))
+                .map(|v| v.trim_end().to_owned())
+                .unwrap_or_default();
+            processes.push(Process { pid, comm });
+        }
+    }
+    processes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_processes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        fs::write(&path, "").unwrap();
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .stdin(fs::File::open(&path).unwrap())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let mut audit = Audit::new(1);
+        let t = time::OffsetDateTime::now_utc();
+        let event = Event::Open(path.to_owned(), FileType::File);
+        let processes = audit.processes(&event, t).await.unwrap();
+        assert_eq!(processes, [Process {
+            pid: child.id(),
+            comm: "sleep".to_owned()
+        }]);
+        // Past the limit in the same second.
+        assert!(audit.processes(&event, t).await.is_none());
+        let event = Event::Delete(path, FileType::File);
+        let t = t + time::Duration::seconds(1);
+        assert!(audit.processes(&event, t).await.is_none());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+use watchdir::{Skip, WatcherOpts};
+
+/// Prints the limits of inotify, the usage by the current user, and the
+/// watches DIR needs, walking it as the watcher would with the options.
+/// Gives the exit status, which is 1 if DIR needs more watches than are
+/// left.
+pub fn run(dir: Option<&Path>, opts: &WatcherOpts) -> i32 {
+    let max_watches = read_limit("max_user_watches");
+    let (instances, watches) = usage();
+    print_row("max_user_watches", max_watches);
+    print_row("max_user_instances", read_limit("max_user_instances"));
+    print_row("max_queued_events", read_limit("max_queued_events"));
+    print_row("instances in use", Some(instances));
+    print_row("watches in use", Some(watches));
+
+    let plan = match dir {
+        Some(v) => watchdir::plan(v, opts),
+        None => return 0,
+    };
+    let needed = plan.iter().filter(|(_, skip)| skip.is_none()).count();
+    print_row("watches needed", Some(needed));
+    // Their subdirectories are not counted.
+    for (path, skip) in &plan {
+        if let Some(Skip::Unreadable(kind)) = skip {
+            println!("Failed to read {}: {}", path.display(), kind);
+        }
+    }
+    match max_watches {
+        Some(max) if watches + needed > max => {
+            println!(
+                "DIR needs {} more watches than are left",
+                watches + needed - max
+            );
+            1
+        }
+        _ => 0,
+    }
+}
+
+fn print_row(name: &str, value: Option<usize>) {
+    match value {
+        Some(v) => println!("{:<20}{}", name, v),
+        None => println!("{:<20}unknown", name),
+    }
+}
+
+fn read_limit(name: &str) -> Option<usize> {
+    let path = Path::new("/proc/sys/fs/inotify").join(name);
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Counts inotify instances and their watches in the processes of the
+/// current user.
+fn usage() -> (usize, usize) {
+    let uid = unsafe { libc::getuid() };
+    let (mut instances, mut watches) = (0, 0);
+    let procs = fs::read_dir("/proc").into_iter().flatten().flatten();
+    for proc in procs {
+        let is_pid =
+            proc.file_name().as_bytes().iter().all(u8::is_ascii_digit);
+        if !is_pid || proc.metadata().map_or(true, |v| v.uid() != uid) {
+            continue;
+        }
+        let fds = fs::read_dir(proc.path().join("fd")).into_iter().flatten();
+        for fd in fds.flatten() {
+            if !fs::read_link(fd.path())
+                .is_ok_and(|v| v.as_os_str() == "anon_inode:inotify")
+            {
+                continue;
+            }
+            instances += 1;
+            let info = proc.path().join("fdinfo").join(fd.file_name());
+            watches += fs::read_to_string(info).map_or(0, |v| {
+                v.lines().filter(|v| v.starts_with("inotify wd:")).count()
+            });
+        }
+    }
+    (instances, watches)
+}
@@ -1,20 +1,31 @@
 use std::{
+    ffi::OsString,
     fs,
+    net::SocketAddr,
     ops::Deref,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
-use clap::{IntoApp, Parser, ValueHint};
+use clap::{ArgEnum, ErrorKind, IntoApp, Parser, ValueHint};
 use clap_complete::{generate, shells};
 use clap_derive::{ArgEnum, Parser};
 use lazy_static::lazy_static;
 use snafu::{ResultExt, Snafu};
+use time::format_description::OwnedFormatItem;
+use watchdir::Regex;
+
+use crate::{config::Config, rule::Rule};
 
 lazy_static! {
     pub static ref VERSION: String =
         [env!("CARGO_PKG_VERSION"), env!("BUILD_DATE"), &env!("GIT_SHA")[..5]]
             .join(" ");
+    pub static ref CONFIG_DIR: PathBuf =
+        directories::ProjectDirs::from("", "", env!("CARGO_BIN_NAME"))
+            .unwrap()
+            .config_dir()
+            .to_owned();
 }
 
 #[derive(Parser)]
@@ -26,9 +37,36 @@ pub struct Opts {
     #[clap(short = 'H', long)]
     pub include_hidden: bool,
 
+    /// Ignore events of files which are already deleted but still open
+    #[clap(long)]
+    pub excl_unlink: bool,
+
+    /// How to treat symbolic links to directories
+    #[clap(
+        value_name = "POLICY",
+        long,
+        arg_enum,
+        default_value = "follow-top"
+    )]
+    pub symlinks: Symlink,
+
+    /// Hide events of hidden files
+    #[clap(long)]
+    pub exclude_hidden_files: bool,
+
+    /// Treat names with the prefix as hidden instead of names with a dot
+    #[clap(
+        value_name = "PREFIX",
+        long,
+        multiple_occurrences = true,
+        allow_invalid_utf8 = true,
+        parse(from_os_str)
+    )]
+    pub hidden_prefix: Vec<OsString>,
+
     /// The directory to be watched
     #[clap(name = "DIR", value_hint = ValueHint::DirPath,
-        required_unless_present_any = ["completion"])]
+        required_unless_present_any = ["completion", "check", "connect"])]
     pub dir: Option<Dir>,
 
     /// Show debug messages
@@ -43,14 +81,118 @@ pub struct Opts {
     #[clap(value_name = "EVENT_TYPE", long, arg_enum, use_delimiter = true)]
     pub exclude_events: Vec<Event>,
 
+    /// Only show events of files, not directories
+    #[clap(long, conflicts_with = "only-dirs")]
+    pub only_files: bool,
+
+    /// Only show events of directories
+    #[clap(long)]
+    pub only_dirs: bool,
+
+    /// Only show events with a path matching the regex
+    #[clap(value_name = "REGEX", long, multiple_occurrences = true)]
+    pub include_regex: Vec<Regex>,
+
+    /// Hide events with a path matching the regex
+    #[clap(value_name = "REGEX", long, multiple_occurrences = true)]
+    pub exclude_regex: Vec<Regex>,
+
+    /// Allow or deny events by the first matching rule, e.g.
+    /// 'deny glob:target/**'. It matches glob: paths relative to DIR,
+    /// regex: full paths, event: types or type: file or dir
+    #[clap(
+        value_name = "allow|deny MATCHER:PATTERN",
+        long,
+        multiple_occurrences = true
+    )]
+    pub rule: Vec<Rule>,
+
+    /// Include digests of files in modify events, which are given once the
+    /// files are closed after writing
+    #[clap(value_name = "ALGO", long, arg_enum)]
+    pub hash_on_modify: Option<HashAlgo>,
+
+    /// Keep watching DIR after it is deleted, moved or unmounted, once it
+    /// appears again
+    #[clap(long)]
+    pub wait_for_dir: bool,
+
+    /// Watch renames in the parent of DIR too, to follow DIR when it is
+    /// renamed, and to tell when it is replaced by another directory
+    #[clap(long)]
+    pub watch_parent: bool,
+
+    /// Retry watching unreadable directories every some milliseconds
+    #[clap(value_name = "TIME", long)]
+    pub retry_failed: Option<u64>,
+
+    /// Check every some milliseconds whether unmounted directories are
+    /// mounted again, and watch them again if so
+    #[clap(value_name = "TIME", long)]
+    pub rewatch_unmounted: Option<u64>,
+
+    /// Scan directories again for missed changes after the system was
+    /// suspended for at least some milliseconds
+    #[clap(value_name = "TIME", long)]
+    pub rescan_after_suspend: Option<u64>,
+
+    /// Only watch some levels of subdirectories at first, and deeper ones
+    /// once their parents have events
+    #[clap(value_name = "LEVELS", long)]
+    pub lazy_depth: Option<usize>,
+
+    /// Keep at most some directories watched, unwatching the least recently
+    /// active ones until their parents have events
+    #[clap(value_name = "COUNT", long)]
+    pub max_watches: Option<usize>,
+
+    /// Do not watch directories on other file systems than DIR
+    #[clap(long)]
+    pub one_file_system: bool,
+
+    /// Scan directories again when reached through another path, e.g. a
+    /// bind mount, instead of once by device and inode
+    #[clap(long = "no-dedupe-dirs", parse(from_flag = std::ops::Not::not))]
+    pub dedupe_dirs: bool,
+
+    /// Repeat events in directories reached by several paths, e.g. through
+    /// bind mounts, under each path
+    #[clap(long)]
+    pub alias_events: bool,
+
     /// Canonicalize paths
     #[clap(long)]
     canonicalize: bool,
 
-    /// List events per line
+    /// Print events in a condensed format, one line each
     #[clap(long)]
     pub oneline: bool,
 
+    /// Write events to stdout and the socket output as length-prefixed
+    /// records, or print them as text
+    #[clap(
+        value_name = "FORMAT",
+        long,
+        arg_enum,
+        default_value = "text",
+        conflicts_with_all = &["porcelain", "summary"]
+    )]
+    pub format: Format,
+
+    /// Give records the group of related events: those sharing a cookie,
+    /// or under the same directory within some milliseconds
+    #[clap(value_name = "TIME", long)]
+    pub group_events: Option<u64>,
+
+    /// Print events in the stable format for scripts
+    #[clap(long, conflicts_with_all = &["oneline", "group-by-dir", "summary"])]
+    pub porcelain: bool,
+
+    /// Print the directory once for consecutive events in it, and the names
+    /// of files under it
+    #[clap(long)]
+    pub group_by_dir: bool,
+
     /// Strip watched directory path
     #[clap(long = "no-prefix", parse(from_flag = std::ops::Not::not))]
     pub prefix: bool,
@@ -59,17 +201,290 @@ pub struct Opts {
     #[clap(short, long)]
     pub time: bool,
 
+    /// Print time in a strftime-like format, e.g. "%H:%M:%S"
+    #[clap(value_name = "FORMAT", long, conflicts_with = "time-style")]
+    pub timefmt: Option<TimeFormat>,
+
+    /// Print time as a Unix timestamp, RFC 3339, or time since the previous
+    /// event
+    #[clap(value_name = "STYLE", long, arg_enum)]
+    pub time_style: Option<TimeStyle>,
+
+    /// Print time in UTC
+    #[clap(long, conflicts_with = "local")]
+    pub utc: bool,
+
+    /// Print time in the local timezone
+    #[clap(long)]
+    pub local: bool,
+
     /// When to use colors
     #[clap(value_name = "WHEN", long, arg_enum, default_value = "auto")]
     pub color: ColorWhen,
 
+    /// Color paths by LS_COLORS
+    #[clap(long)]
+    pub ls_colors: bool,
+
     /// Generate completions for shell
     #[clap(value_name = "SHELL", long, arg_enum)]
     pub completion: Option<Shell>,
 
+    /// Use the options of a profile in the config file
+    #[clap(value_name = "NAME", long)]
+    pub profile: Option<String>,
+
+    /// Print the events served by another watchdir over SSE or WebSocket
+    #[clap(value_name = "URL", long, value_hint = ValueHint::Url,
+        conflicts_with = "DIR")]
+    pub connect: Option<reqwest::Url>,
+
+    /// Report limits and usage of inotify, and the watches DIR needs
+    #[clap(long)]
+    pub check: bool,
+
+    /// List the directories which would be watched, and those skipped,
+    /// without watching them
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Print counts of events per directory every some milliseconds instead
+    /// of each event
+    #[clap(value_name = "TIME", long)]
+    pub summary: Option<u64>,
+
     /// Throttle modify event for some milliseconds
     #[clap(value_name = "TIME", long, default_value = "1000")]
     pub throttle_modify: u64,
+
+    /// Print the bytes appended to files matching the glob, relative to
+    /// DIR, under a header of the file like `tail -F`. Their modify events
+    /// are not printed unless requested
+    #[clap(value_name = "GLOB", long, multiple_occurrences = true)]
+    pub tail: Vec<globset::Glob>,
+
+    /// Print the diff of modified text files below their events
+    #[clap(long)]
+    pub diff: bool,
+
+    /// Only diff files up to some bytes
+    #[clap(value_name = "BYTES", long, default_value = "65536")]
+    pub diff_size: u64,
+
+    /// Print the processes which have the files of create, open, close and
+    /// modify events open, found in /proc. Processes may be missed. The
+    /// extra events are only audited if requested
+    #[clap(long)]
+    pub audit: bool,
+
+    /// Look for processes of --audit at most some times a second
+    #[clap(value_name = "COUNT", long, default_value = "10")]
+    pub audit_rate: u32,
+
+    /// Throttle events of a type for some milliseconds, e.g. access=5000
+    #[clap(value_name = "EVENT_TYPE=TIME", long, multiple_occurrences = true)]
+    pub throttle: Vec<Throttle>,
+
+    /// Report files saved by renaming a new file over them as modified,
+    /// waiting some milliseconds for the rename
+    #[clap(value_name = "TIME", long)]
+    pub atomic_saves: Option<u64>,
+
+    /// Warn when the total size of the files in DIR crosses some bytes
+    #[clap(value_name = "BYTES", long, multiple_occurrences = true)]
+    pub size_threshold: Vec<u64>,
+
+    /// Warn when events in a directory suddenly come at some per second,
+    /// ten times its average rate or more
+    #[clap(value_name = "RATE", long)]
+    pub burst_rate: Option<u64>,
+
+    /// Exit after printing some events
+    #[clap(
+        value_name = "COUNT",
+        long,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub exit_after_events: Option<u64>,
+
+    /// Exit with status 2 if no event is printed for some milliseconds, or
+    /// with --wait-for, if the event does not occur in the time
+    #[clap(value_name = "TIME", long)]
+    pub exit_timeout: Option<u64>,
+
+    /// Exit after printing an event of a path matching the glob, relative
+    /// to DIR
+    #[clap(value_name = "GLOB", long)]
+    pub wait_for: Option<globset::Glob>,
+
+    /// Only wait for events of the types
+    #[clap(
+        value_name = "EVENT_TYPE",
+        long,
+        arg_enum,
+        use_delimiter = true,
+        requires = "wait-for"
+    )]
+    pub for_event: Vec<EventType>,
+
+    /// Log statistics every some milliseconds
+    #[clap(value_name = "TIME", long)]
+    pub stats_interval: Option<u64>,
+
+    /// List the watched directories to stderr on SIGQUIT
+    #[clap(long)]
+    pub list_watches: bool,
+
+    /// Serve Prometheus metrics over HTTP
+    #[clap(value_name = "ADDR", long)]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Stream events as Server-Sent Events over HTTP
+    #[clap(value_name = "ADDR", long)]
+    pub serve_sse: Option<SocketAddr>,
+
+    /// Push events to WebSocket clients
+    #[clap(value_name = "ADDR", long)]
+    pub serve_ws: Option<SocketAddr>,
+
+    /// Write length-prefixed JSON events to a Unix socket
+    #[clap(value_name = "PATH", long, value_hint = ValueHint::FilePath)]
+    pub output_socket: Option<PathBuf>,
+
+    /// POST events as JSON to a URL
+    #[clap(value_name = "URL", long, value_hint = ValueHint::Url)]
+    pub webhook: Option<reqwest::Url>,
+
+    /// Sign webhook bodies with HMAC-SHA256 in X-Watchdir-Signature
+    #[clap(value_name = "SECRET", long, requires = "webhook")]
+    pub webhook_secret: Option<String>,
+
+    /// POST webhook events in batches after some milliseconds of quiet
+    #[clap(value_name = "TIME", long, requires = "webhook")]
+    pub webhook_batch: Option<u64>,
+
+    /// Run a shell command for each event, given in WATCHDIR_EVENT,
+    /// WATCHDIR_PATH, WATCHDIR_OLD_PATH, WATCHDIR_FILE_TYPE and WATCHDIR_TIME
+    #[clap(value_name = "COMMAND", long)]
+    pub exec: Option<String>,
+
+    /// Run a shell command once for each batch of events. The quoted paths
+    /// replace {}, or are written to its stdin if there is no {}
+    #[clap(value_name = "COMMAND", long)]
+    pub exec_batch: Option<String>,
+
+    /// Ignore events of the paths which commands of watchdir report to
+    /// change, one per line, in the file named by $WATCHDIR_SELF_PATHS, so
+    /// that their own changes do not trigger them again
+    #[clap(long)]
+    pub ignore_self: bool,
+
+    /// Run a shell command for each event of a type, of paths matching the
+    /// glob relative to DIR if given, e.g. --hook 'create:*.rs' 'cargo check'
+    #[clap(
+        value_names = &["EVENT_TYPE[:GLOB]", "COMMAND"],
+        long,
+        number_of_values = 2,
+        multiple_occurrences = true,
+        allow_hyphen_values = true
+    )]
+    hook: Vec<String>,
+
+    /// Hooks of `--hook`, parsed by `try_parse`.
+    #[clap(skip)]
+    pub hooks: Vec<Hook>,
+
+    /// Run at most some hook commands at once
+    #[clap(value_name = "COUNT", long, default_value = "4")]
+    pub hook_jobs: usize,
+
+    /// Mirror changes of DIR into another directory, copying created and
+    /// modified files, removing deleted ones and renaming moved ones. Files
+    /// of the same size are compared by their checksums
+    #[clap(value_name = "DEST", long)]
+    pub sync_to: Option<PathBuf>,
+
+    /// Sync at most some paths at once for --sync-to
+    #[clap(value_name = "COUNT", long, default_value = "4")]
+    pub sync_jobs: usize,
+
+    /// Copy the files changed in each batch of events into a new snapshot
+    /// in a directory, named after the time
+    #[clap(value_name = "DIR", long)]
+    pub archive_to: Option<PathBuf>,
+
+    /// Format of the snapshots of --archive-to
+    #[clap(
+        value_name = "FORMAT",
+        long,
+        arg_enum,
+        ignore_case = true,
+        default_value = "dir"
+    )]
+    pub archive_format: ArchiveFormat,
+
+    /// Keep only some latest snapshots of --archive-to
+    #[clap(value_name = "COUNT", long, requires = "archive-to")]
+    pub archive_keep: Option<usize>,
+
+    /// Wait some milliseconds after the command of --exec-batch exits.
+    /// Events while it runs or waits cause one more run
+    #[clap(value_name = "TIME", long, requires = "exec-batch")]
+    pub cooldown: Option<u64>,
+
+    /// Clear the screen before each run of --exec-batch, or else before
+    /// each burst of events after --debounce of quiet
+    #[clap(long)]
+    pub clear: bool,
+
+    /// Stop the command of --exec-batch if it still runs when the next
+    /// batch comes, and run it again
+    #[clap(long, requires = "exec-batch")]
+    pub restart: bool,
+
+    /// Signal to stop the command with when restarting
+    #[clap(
+        value_name = "SIGNAL",
+        long,
+        arg_enum,
+        ignore_case = true,
+        default_value = "term"
+    )]
+    pub signal: Signal,
+
+    /// Kill the command if it has not exited some milliseconds after the
+    /// signal
+    #[clap(value_name = "TIME", long, default_value = "5000")]
+    pub kill_timeout: u64,
+
+    /// Batch events until none comes for some milliseconds
+    #[clap(value_name = "TIME", long, default_value = "200")]
+    pub debounce: u64,
+
+    /// Emit events as org.watchdir.Event signals on the session bus
+    #[clap(long)]
+    pub dbus: bool,
+
+    /// Publish events as JSON to an MQTT broker at HOST[:PORT]
+    #[clap(value_name = "ADDR", long, value_hint = ValueHint::Hostname)]
+    pub mqtt_broker: Option<String>,
+
+    /// MQTT topic to publish events to
+    #[clap(value_name = "TOPIC", long, default_value = "watchdir/events")]
+    pub mqtt_topic: String,
+
+    /// MQTT quality of service
+    #[clap(
+        value_name = "QOS",
+        long,
+        arg_enum,
+        default_value = "at-least-once"
+    )]
+    pub mqtt_qos: Qos,
+
+    /// Where to emit events
+    #[clap(value_name = "TARGET", long, arg_enum, default_value = "stdout")]
+    pub log_target: LogTarget,
 }
 
 #[derive(ArgEnum, Clone)]
@@ -89,6 +504,52 @@ pub enum ExtraEvent {
     Close,
 }
 
+#[derive(ArgEnum, Clone)]
+pub enum HashAlgo {
+    Blake3,
+    Sha256,
+}
+
+#[derive(ArgEnum, Clone)]
+pub enum Symlink {
+    NoFollow,
+    FollowTop,
+    Follow,
+}
+
+#[derive(ArgEnum, Clone)]
+pub enum LogTarget {
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+#[derive(ArgEnum, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum Qos {
+    #[clap(alias = "0")]
+    AtMostOnce,
+    #[clap(alias = "1")]
+    AtLeastOnce,
+    #[clap(alias = "2")]
+    ExactlyOnce,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum Format {
+    Text,
+    Cbor,
+    Msgpack,
+}
+
+#[derive(ArgEnum, Clone)]
+pub enum TimeStyle {
+    Epoch,
+    EpochMs,
+    Rfc3339,
+    Relative,
+}
+
 #[derive(ArgEnum, Clone)]
 pub enum ColorWhen {
     Auto,
@@ -122,6 +583,95 @@ impl FromStr for Dir {
     }
 }
 
+#[derive(ArgEnum, Clone)]
+pub enum EventType {
+    Create,
+    Delete,
+    Move,
+    Modify,
+    Access,
+    Attrib,
+    Open,
+    Close,
+    Unmount,
+}
+
+#[derive(Clone)]
+pub struct Throttle {
+    pub event: EventType,
+    pub time: u64,
+}
+
+impl FromStr for Throttle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (event, time) = s.split_once('=').ok_or(Error::InvalidThrottle)?;
+        Ok(Self {
+            event: EventType::from_str(event, true)
+                .map_err(|_| Error::InvalidThrottle)?,
+            time: time.parse().map_err(|_| Error::InvalidThrottle)?,
+        })
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum ArchiveFormat {
+    Dir,
+    TarZst,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum Signal {
+    Term,
+    Int,
+    Kill,
+    Hup,
+}
+
+#[derive(Clone)]
+pub struct Hook {
+    pub event: EventType,
+    pub glob: Option<globset::Glob>,
+    pub command: String,
+}
+
+impl Hook {
+    /// Parses the event type and glob of `on`, which are split at the first
+    /// ':', so the glob and the command may contain any character.
+    fn new(on: &str, command: &str) -> Result<Self> {
+        let (event, glob) = match on.split_once(':') {
+            Some((event, glob)) => (
+                event,
+                Some(
+                    globset::Glob::new(glob)
+                        .map_err(|_| Error::InvalidHook)?,
+                ),
+            ),
+            None => (on, None),
+        };
+        Ok(Self {
+            event: EventType::from_str(event, true)
+                .map_err(|_| Error::InvalidHook)?,
+            glob,
+            command: command.to_owned(),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct TimeFormat(pub OwnedFormatItem);
+
+impl FromStr for TimeFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        time::format_description::parse_strftime_owned(s)
+            .map(Self)
+            .context(InvalidTimeFormat {})
+    }
+}
+
 #[derive(Parser, ArgEnum, Clone, PartialEq)]
 pub enum Shell {
     Bash,
@@ -140,25 +690,82 @@ pub enum Error {
     #[snafu(display("Permission denied"))]
     PermRead,
 
-    #[snafu(display("Valid values are auto', 'always', 'ansi' or 'never'"))]
-    OptionColor,
+    #[snafu(display("Expect EVENT_TYPE=TIME, e.g. access=5000"))]
+    InvalidThrottle,
+
+    #[snafu(display(
+        "Expect EVENT_TYPE[:GLOB] COMMAND, e.g. 'create:*.rs' 'cargo check'"
+    ))]
+    InvalidHook,
+
+    #[snafu(display(
+        "Expect allow|deny MATCHER:PATTERN, e.g. deny glob:*.o"
+    ))]
+    InvalidRule,
+
+    #[snafu(display("{}", source))]
+    InvalidTimeFormat { source: time::error::InvalidFormatDescription },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Parses the command line, with the defaults from the config file.
 pub fn parse() -> Opts {
-    let mut opts = Opts::parse();
+    let opts = try_parse().unwrap_or_else(|e| e.exit());
 
     if let Some(shell) = opts.completion {
         print_completions(shell);
         std::process::exit(0);
     }
+    opts
+}
+
+/// Like `parse`, but gives the error instead of exiting. The config file
+/// is read again each time.
+pub fn try_parse() -> Result<Opts, clap::Error> {
+    let config = Config::load(&CONFIG_DIR.join("config.yaml"))
+        .map_err(|e| Opts::into_app().error(ErrorKind::Io, e))?;
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let given = Opts::into_app().try_get_matches_from(&args)?;
+    let defaults = config
+        .args(given.value_of("profile"))
+        .map_err(|e| Opts::into_app().error(ErrorKind::InvalidValue, e))?
+        .into_iter()
+        .filter(|(name, _)| given.occurrences_of(name) == 0)
+        .map(|(_, v)| v);
+    args.splice(1..1, defaults.collect::<Vec<_>>());
+    let mut opts = Opts::try_parse_from(args)?;
+    opts.hooks = hooks(&opts.hook)
+        .map_err(|e| Opts::into_app().error(ErrorKind::InvalidValue, e))?;
 
     if opts.canonicalize {
-        opts.dir =
-            Some(Dir(opts.dir.unwrap().canonicalize().unwrap().join("")));
+        opts.dir = opts.dir.map(|v| Dir(v.canonicalize().unwrap().join("")));
     }
-    opts
+    Ok(opts)
+}
+
+/// Gives the extra events to print, and all those to watch, which include
+/// the ones needed by outputs.
+pub fn extra_events(
+    opts: &Opts,
+) -> (Vec<watchdir::ExtraEvent>, Vec<watchdir::ExtraEvent>) {
+    let requested: Vec<watchdir::ExtraEvent> =
+        opts.extra_events.iter().map(|e| e.clone().into()).collect();
+    let mut extra_events = requested.clone();
+    if (!opts.tail.is_empty() || !opts.size_threshold.is_empty())
+        && !extra_events.contains(&watchdir::ExtraEvent::Modify)
+    {
+        extra_events.push(watchdir::ExtraEvent::Modify);
+    }
+    if opts.sync_to.is_some() || opts.archive_to.is_some() {
+        extra_events.push(watchdir::ExtraEvent::CloseWrite);
+    }
+    (requested, extra_events)
+}
+
+/// Parses the pairs of values of `--hook`.
+fn hooks(values: &[String]) -> Result<Vec<Hook>> {
+    values.chunks(2).map(|v| Hook::new(&v[0], &v[1])).collect()
 }
 
 pub fn print_completions(shell: Shell) {
@@ -171,3 +778,85 @@ pub fn print_completions(shell: Shell) {
         Shell::Zsh => generate(shells::Zsh, &mut app, name, &mut buf),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Opts, clap::Error> {
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        Opts::try_parse_from(["watchdir", dir].iter().chain(args))
+    }
+
+    #[test]
+    fn test_format_conflicts() {
+        assert!(parse(&["--format", "cbor"]).is_ok());
+        for other in [&["--porcelain"][..], &["--summary", "1000"]] {
+            let args = [&["--format", "cbor"][..], other].concat();
+            let e = parse(&args).err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        }
+        assert!(parse(&["--porcelain"]).is_ok());
+    }
+
+    #[test]
+    fn test_extra_events() {
+        use watchdir::ExtraEvent as E;
+
+        let opts = parse(&["--tail", "*.log", "--size-threshold", "1"]);
+        let (requested, watched) = extra_events(&opts.unwrap());
+        assert!(requested.is_empty());
+        assert_eq!(watched, [E::Modify]);
+
+        let (requested, watched) = extra_events(&parse(&["--audit"]).unwrap());
+        assert!(requested.is_empty() && watched.is_empty());
+
+        let opts = parse(&["--extra-events", "modify", "--tail", "*.log"]);
+        let (requested, watched) = extra_events(&opts.unwrap());
+        assert_eq!(requested, [E::Modify]);
+        assert_eq!(watched, [E::Modify]);
+    }
+
+    #[test]
+    fn test_hook() {
+        let args = ["--hook", "create:a=b:*.rs", "FOO=1 echo a:b"];
+        let hooks = hooks(&parse(&args).unwrap().hook).unwrap();
+        assert_eq!(hooks.len(), 1);
+        assert!(matches!(hooks[0].event, EventType::Create));
+        assert_eq!(hooks[0].glob.as_ref().unwrap().glob(), "a=b:*.rs");
+        assert_eq!(hooks[0].command, "FOO=1 echo a:b");
+
+        assert!(Hook::new("nothing", "true").is_err());
+        assert!(parse(&["--hook", "create"]).is_err());
+    }
+
+    #[test]
+    fn test_hook_from_config() {
+        let config: Config = serde_yaml::from_str(
+            "On:\n  modify:\n    - Glob: 'a=b:*.rs'\n      Run: echo a=b:c\n",
+        )
+        .unwrap();
+        let args: Vec<_> =
+            config.args(None).unwrap().into_iter().map(|(_, v)| v).collect();
+        let dir = std::env::temp_dir();
+        let opts = Opts::try_parse_from(
+            vec![OsString::from("watchdir"), dir.into()]
+                .into_iter()
+                .chain(args),
+        )
+        .unwrap();
+        let hooks = hooks(&opts.hook).unwrap();
+        assert!(matches!(hooks[0].event, EventType::Modify));
+        assert_eq!(hooks[0].glob.as_ref().unwrap().glob(), "a=b:*.rs");
+        assert_eq!(hooks[0].command, "echo a=b:c");
+    }
+
+    #[test]
+    fn test_exit_after_events() {
+        let opts = parse(&["--exit-after-events", "1"]).unwrap();
+        assert_eq!(opts.exit_after_events, Some(1));
+        let e = parse(&["--exit-after-events", "0"]).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::ValueValidation);
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, ffi::OsString, path::Path};
+
+use serde::Deserialize;
+
+/// Defaults of the command line options. Options given on the command line
+/// replace them, as do the options of a chosen profile.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "PascalCase")]
+pub struct Config {
+    include_hidden: bool,
+    extra_events: Vec<String>,
+    exclude_events: Vec<String>,
+    include_regex: Vec<String>,
+    exclude_regex: Vec<String>,
+    rules: Vec<String>,
+    oneline: bool,
+    group_by_dir: bool,
+    time: bool,
+    time_format: Option<String>,
+    time_style: Option<String>,
+    utc: bool,
+    local: bool,
+    throttle_modify: Option<u64>,
+    throttle: BTreeMap<String, u64>,
+    color: Option<String>,
+    ls_colors: bool,
+    exec: Option<String>,
+    exec_batch: Option<String>,
+    debounce: Option<u64>,
+    cooldown: Option<u64>,
+    /// Hooks by event type.
+    on: BTreeMap<String, Hooks>,
+    hook_jobs: Option<u64>,
+    ignore_self: bool,
+    list_watches: bool,
+    restart: bool,
+    clear: bool,
+    signal: Option<String>,
+    kill_timeout: Option<u64>,
+    /// Named sets of options, chosen by `--profile`.
+    profiles: BTreeMap<String, Config>,
+}
+
+impl Config {
+    /// Reads the config file. A missing file gives the default.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let config: Self = match std::fs::File::open(path) {
+            Ok(f) => serde_yaml::from_reader(f)
+                .map_err(|e| format!("Failed to parse config: {}", e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(format!("Failed to read config: {}", e)),
+        };
+        if config.profiles.values().any(|v| !v.profiles.is_empty()) {
+            return Err("Profiles in config cannot be nested".to_owned());
+        }
+        Ok(config)
+    }
+
+    /// Gives the options as arguments, each with the name of the option.
+    /// Those of the profile replace the others of the same name.
+    pub fn args(
+        &self,
+        profile: Option<&str>,
+    ) -> Result<Vec<(&'static str, OsString)>, String> {
+        let mut args = self.options();
+        if let Some(name) = profile {
+            let overrides = self
+                .profiles
+                .get(name)
+                .ok_or_else(|| format!("No profile named {} in config", name))?
+                .options();
+            args.retain(|(v, _)| !overrides.iter().any(|(name, _)| v == name));
+            args.extend(overrides);
+        }
+        Ok(args)
+    }
+
+    fn options(&self) -> Vec<(&'static str, OsString)> {
+        let mut args = Vec::new();
+        let mut push = |name: &'static str, value: Option<String>| {
+            args.push((name, match value {
+                Some(v) => format!("--{}={}", name, v).into(),
+                None => format!("--{}", name).into(),
+            }))
+        };
+        let flags = [
+            ("include-hidden", self.include_hidden),
+            ("oneline", self.oneline),
+            ("group-by-dir", self.group_by_dir),
+            ("time", self.time),
+            ("utc", self.utc),
+            ("local", self.local),
+            ("ls-colors", self.ls_colors),
+            ("ignore-self", self.ignore_self),
+            ("restart", self.restart),
+            ("clear", self.clear),
+            ("list-watches", self.list_watches),
+        ];
+        for (name, _) in flags.iter().filter(|(_, v)| *v) {
+            push(name, None);
+        }
+        if !self.extra_events.is_empty() {
+            push("extra-events", Some(self.extra_events.join(",")));
+        }
+        if !self.exclude_events.is_empty() {
+            push("exclude-events", Some(self.exclude_events.join(",")));
+        }
+        for v in &self.include_regex {
+            push("include-regex", Some(v.to_owned()));
+        }
+        for v in &self.exclude_regex {
+            push("exclude-regex", Some(v.to_owned()));
+        }
+        for v in &self.rules {
+            push("rule", Some(v.to_owned()));
+        }
+        let options = [
+            ("timefmt", &self.time_format),
+            ("time-style", &self.time_style),
+            ("color", &self.color),
+            ("exec", &self.exec),
+            ("exec-batch", &self.exec_batch),
+            ("signal", &self.signal),
+        ];
+        for (name, v) in options {
+            if let Some(v) = v {
+                push(name, Some(v.to_owned()));
+            }
+        }
+        let times = [
+            ("throttle-modify", self.throttle_modify),
+            ("debounce", self.debounce),
+            ("cooldown", self.cooldown),
+            ("kill-timeout", self.kill_timeout),
+            ("hook-jobs", self.hook_jobs),
+        ];
+        for (name, v) in times {
+            if let Some(v) = v {
+                push(name, Some(v.to_string()));
+            }
+        }
+        for (event, time) in &self.throttle {
+            push("throttle", Some(format!("{}={}", event, time)));
+        }
+        for (event, hooks) in &self.on {
+            let hooks = match hooks {
+                Hooks::One(v) => std::slice::from_ref(v),
+                Hooks::Many(v) => v,
+            };
+            for hook in hooks {
+                let (on, run) = match hook {
+                    Hook::Command(command) => (event.to_owned(), command),
+                    Hook::Spec { glob: Some(glob), run } => {
+                        (format!("{}:{}", event, glob), run)
+                    }
+                    Hook::Spec { glob: None, run } => (event.to_owned(), run),
+                };
+                // Given as separate arguments, so that nothing in them is
+                // taken as a separator.
+                args.push(("hook", "--hook".into()));
+                args.push(("hook", on.into()));
+                args.push(("hook", run.into()));
+            }
+        }
+        args
+    }
+}
+
+/// One hook or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Hooks {
+    One(Hook),
+    Many(Vec<Hook>),
+}
+
+/// Command of a hook, alone or with a glob of the paths.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Hook {
+    Command(String),
+    #[serde(rename_all = "PascalCase")]
+    Spec {
+        glob: Option<String>,
+        run: String,
+    },
+}
@@ -0,0 +1,72 @@
+use futures::StreamExt;
+use reqwest::Url;
+use snafu::{ResultExt, Snafu};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::warn;
+
+use crate::print::Printer;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    Http { source: reqwest::Error },
+
+    #[snafu(display("{}", source))]
+    WebSocket { source: tungstenite::Error },
+
+    #[snafu(display("Unsupported URL scheme: {}", scheme))]
+    Scheme { scheme: String },
+}
+
+/// Prints the events served by another watchdir, with `--serve-sse` for
+/// URLs of HTTP, or with `--serve-ws` for those of WebSocket. Returns when
+/// the server closes the connection.
+pub async fn run(url: Url, printer: &mut Printer) -> Result<(), Error> {
+    match url.scheme() {
+        "http" | "https" => read_sse(url, printer).await,
+        "ws" | "wss" => read_ws(url, printer).await,
+        scheme => Err(Error::Scheme { scheme: scheme.to_owned() }),
+    }
+}
+
+async fn read_sse(url: Url, printer: &mut Printer) -> Result<(), Error> {
+    let mut response = reqwest::get(url)
+        .await
+        .and_then(|v| v.error_for_status())
+        .context(Http {})?;
+    let mut buf = Vec::new();
+    while let Some(chunk) = response.chunk().await.context(Http {})? {
+        buf.extend_from_slice(&chunk);
+        while let Some(end) = buf.iter().position(|&c| c == b'\n') {
+            let line: Vec<_> = buf.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(data) = line.trim_end().strip_prefix("data: ") {
+                print(printer, data);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn read_ws(url: Url, printer: &mut Printer) -> Result<(), Error> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .context(WebSocket {})?;
+    while let Some(message) = ws.next().await {
+        match message.context(WebSocket {})? {
+            Message::Text(data) => print(printer, &data),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn print(printer: &mut Printer, record: &str) {
+    match watchdir::replay::parse(record) {
+        Ok((event, t)) => {
+            printer.print(&event, t).unwrap();
+        }
+        Err(e) => warn!("Invalid record from the server: {}", e),
+    }
+}
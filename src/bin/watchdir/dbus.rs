@@ -0,0 +1,66 @@
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+use zbus::{names::BusName, Connection};
+
+use crate::{record::Record, TimedEvent};
+
+const OBJECT_PATH: &str = "/org/watchdir";
+const INTERFACE: &str = "org.watchdir";
+const SIGNAL: &str = "Event";
+
+/// Emits events as `org.watchdir.Event` signals on the session bus, with
+/// the kind, path, destination of moves, file type and time as string
+/// arguments. Absent values are empty.
+pub struct DbusOutput {
+    connection: Connection,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl DbusOutput {
+    pub async fn connect(
+        rx: broadcast::Receiver<TimedEvent>,
+    ) -> zbus::Result<Self> {
+        let connection = Connection::session().await?;
+        Ok(Self { connection, rx })
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            let (event, t) = match self.rx.recv().await {
+                Ok(v) => v,
+                Err(RecvError::Lagged(n)) => {
+                    warn!(
+                        "D-Bus output is too slow, {} events are skipped",
+                        n
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let record = match Record::new(&event, t) {
+                Some(v) => v,
+                None => continue,
+            };
+            let body = (
+                record.kind,
+                record.path.or(record.from).unwrap_or_default(),
+                record.to.unwrap_or_default(),
+                record.file_type.unwrap_or_default(),
+                record.time,
+            );
+            if let Err(e) = self
+                .connection
+                .emit_signal(
+                    None::<BusName>,
+                    OBJECT_PATH,
+                    INTERFACE,
+                    SIGNAL,
+                    &body,
+                )
+                .await
+            {
+                warn!("Failed to emit D-Bus signal: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, warn};
+use watchdir::Event;
+
+use crate::{print, record::Record, TimedEvent};
+
+/// Placeholder of the paths in the command.
+const PLACEHOLDER: &str = "{}";
+
+/// Variable with the file in which commands report the paths they change.
+const SELF_PATHS_VAR: &str = "WATCHDIR_SELF_PATHS";
+
+lazy_static! {
+    /// File of `SelfPaths`, given to the commands if any.
+    static ref SELF_PATHS_FILE: Mutex<Option<PathBuf>> = Mutex::default();
+}
+
+/// Paths which the commands of watchdir report to change, so that their
+/// events can be skipped. Commands append them to the file named by
+/// `WATCHDIR_SELF_PATHS`, one per line, absolute or relative to DIR.
+///
+/// An event is caused by the commands if all of its paths are reported
+/// paths or under them.
+pub struct SelfPaths {
+    file: PathBuf,
+    top_dir: PathBuf,
+    /// Bytes of the file read so far.
+    offset: u64,
+    paths: HashSet<PathBuf>,
+}
+
+impl SelfPaths {
+    /// Creates the file, which is given to the commands started after.
+    pub fn new(file: PathBuf, top_dir: PathBuf) -> io::Result<Self> {
+        fs::File::create(&file)?;
+        *SELF_PATHS_FILE.lock().unwrap() = Some(file.to_owned());
+        Ok(Self { file, top_dir, offset: 0, paths: HashSet::new() })
+    }
+
+    pub fn is_caused(&mut self, event: &Event) -> bool {
+        let paths = affected_paths(event);
+        if paths.is_empty() {
+            return false;
+        }
+        if let Err(e) = self.read() {
+            warn!("Failed to read {}: {}", self.file.display(), e);
+        }
+        paths.iter().all(|v| v.ancestors().any(|v| self.paths.contains(v)))
+    }
+
+    /// Reads the complete lines appended since the last read.
+    fn read(&mut self) -> io::Result<()> {
+        let mut file = fs::File::open(&self.file)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut appended = Vec::new();
+        file.read_to_end(&mut appended)?;
+        let len = match appended.iter().rposition(|c| *c == b'\n') {
+            Some(v) => v + 1,
+            None => return Ok(()),
+        };
+        for line in appended[..len].split(|c| *c == b'\n') {
+            if !line.is_empty() {
+                let path = self.top_dir.join(OsStr::from_bytes(line));
+                debug!("Commands change {}", path.display());
+                self.paths.insert(path);
+            }
+        }
+        self.offset += len as u64;
+        Ok(())
+    }
+}
+
+impl Drop for SelfPaths {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.file);
+    }
+}
+
+/// Gives the shell command, with the file of `SelfPaths` if any.
+fn shell(command: &OsStr) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    if let Some(file) = SELF_PATHS_FILE.lock().unwrap().as_ref() {
+        shell.env(SELF_PATHS_VAR, file);
+    }
+    shell
+}
+
+/// Runs a shell command for each event, one at a time, with the event in
+/// its environment:
+///
+/// - `WATCHDIR_EVENT`, the kind, e.g. `create` or `move_away`
+/// - `WATCHDIR_PATH`, the path, or the new one of a move
+/// - `WATCHDIR_OLD_PATH`, the old path of a move
+/// - `WATCHDIR_FILE_TYPE`, `file` or `dir`
+/// - `WATCHDIR_TIME`, the time in RFC 3339
+///
+/// Variables which do not apply to the event are unset.
+pub struct EventExec {
+    command: String,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl EventExec {
+    pub fn new(command: String, rx: broadcast::Receiver<TimedEvent>) -> Self {
+        Self { command, rx }
+    }
+
+    pub async fn run(mut self) {
+        while let Some((event, t)) = recv(&mut self.rx).await {
+            let record = match Record::new(&event, t) {
+                Some(v) => v,
+                None => continue,
+            };
+            match event_command(&self.command, &event, record).spawn() {
+                Ok(child) => wait(child, &self.command).await,
+                Err(e) => warn!("Failed to run {}: {}", self.command, e),
+            }
+        }
+    }
+}
+
+pub struct ExecOpts {
+    /// Shell command.
+    pub command: String,
+    /// Run the command once no event comes within this time.
+    pub debounce: Duration,
+    /// Wait this long after the command exits before the next batch.
+    /// Events while it runs or waits make one batch.
+    pub cooldown: Option<Duration>,
+    /// Clear the screen before each run.
+    pub clear: bool,
+    /// Stop the command if it still runs when the next batch comes, and
+    /// run it again.
+    pub restart: Option<Restart>,
+}
+
+pub struct Restart {
+    /// Signal sent to the process group of the command.
+    pub signal: libc::c_int,
+    /// Kill the process group if the command has not exited in this time.
+    pub kill_timeout: Duration,
+}
+
+/// Runs a shell command once for each batch of events. The paths of the
+/// batch replace `{}` in the command, quoted, or are written to its stdin
+/// one per line if there is no `{}`.
+pub struct BatchExec {
+    opts: ExecOpts,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl BatchExec {
+    pub fn new(opts: ExecOpts, rx: broadcast::Receiver<TimedEvent>) -> Self {
+        Self { opts, rx }
+    }
+
+    pub async fn run(mut self) {
+        // The command of the last batch, if restarting.
+        let mut running: Option<Child> = None;
+        loop {
+            let first = match running.as_mut() {
+                Some(child) => tokio::select! {
+                    v = recv(&mut self.rx) => Ok(v),
+                    v = child.wait() => Err(v),
+                },
+                None => Ok(recv(&mut self.rx).await),
+            };
+            let event = match first {
+                Ok(Some((event, _))) => event,
+                Ok(None) => break,
+                Err(v) => {
+                    report(v, &self.opts.command);
+                    running = None;
+                    continue;
+                }
+            };
+            let mut paths = BTreeSet::new();
+            paths.extend(affected_paths(&event));
+            while let Ok(Some((event, _))) =
+                tokio::time::timeout(self.opts.debounce, recv(&mut self.rx))
+                    .await
+            {
+                paths.extend(affected_paths(&event));
+            }
+            if paths.is_empty() {
+                continue;
+            }
+            if let Some(restart) = &self.opts.restart {
+                if let Some(child) = running.take() {
+                    debug!("Restarting {}", self.opts.command);
+                    stop(child, restart, &self.opts.command).await;
+                }
+                running = self.start(&paths).await;
+                continue;
+            }
+            if let Some(child) = self.start(&paths).await {
+                wait(child, &self.opts.command).await;
+            }
+            if let Some(cooldown) = self.opts.cooldown {
+                tokio::time::sleep(cooldown).await;
+            }
+        }
+    }
+
+    async fn start(&self, paths: &BTreeSet<PathBuf>) -> Option<Child> {
+        let substituted = self.opts.command.contains(PLACEHOLDER);
+        let command = if substituted {
+            let quoted: Vec<_> = paths.iter().map(|v| quote(v)).collect();
+            let mut command = Vec::new();
+            for (i, part) in self.opts.command.split(PLACEHOLDER).enumerate() {
+                if i > 0 {
+                    command.extend_from_slice(&quoted.join(&b' '));
+                }
+                command.extend_from_slice(part.as_bytes());
+            }
+            OsString::from_vec(command)
+        } else {
+            OsString::from(&self.opts.command)
+        };
+
+        debug!("Running {} with {} paths", self.opts.command, paths.len());
+        if self.opts.clear {
+            clear_screen();
+        }
+        let mut shell = shell(&command);
+        shell.stdin(if substituted { Stdio::null() } else { Stdio::piped() });
+        if self.opts.restart.is_some() {
+            // Signals reach the processes it starts too.
+            shell.process_group(0);
+        }
+        let mut child = match shell.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to run {}: {}", self.opts.command, e);
+                return None;
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            let mut input = Vec::new();
+            for path in paths {
+                input.extend_from_slice(path.as_os_str().as_bytes());
+                input.push(b'\n');
+            }
+            // The command may not read all of them.
+            let _ = stdin.write_all(&input).await;
+        }
+        Some(child)
+    }
+}
+
+/// Signals the process group of the command, and kills it if the command
+/// does not exit in time.
+async fn stop(mut child: Child, restart: &Restart, command: &str) {
+    if let Some(pid) = child.id() {
+        let group = -(pid as libc::pid_t);
+        unsafe { libc::kill(group, restart.signal) };
+        if tokio::time::timeout(restart.kill_timeout, child.wait())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        warn!(
+            "{} did not exit in {:?}, killing it",
+            command, restart.kill_timeout
+        );
+        unsafe { libc::kill(group, libc::SIGKILL) };
+    }
+    let _ = child.wait().await;
+}
+
+/// Gives the shell command with the event in its environment. Paths are
+/// passed as they are, without lossy conversion.
+pub fn event_command(command: &str, event: &Event, record: Record) -> Command {
+    let (path, old_path) = match event {
+        Event::Move(from, to, _) | Event::MoveTop(from, Some(to)) => {
+            (Some(to.as_path()), Some(from.as_path()))
+        }
+        _ => (event.path(), None),
+    };
+    let vars = [
+        ("WATCHDIR_EVENT", Some(OsStr::new(&record.kind))),
+        ("WATCHDIR_PATH", path.map(Path::as_os_str)),
+        ("WATCHDIR_OLD_PATH", old_path.map(Path::as_os_str)),
+        ("WATCHDIR_FILE_TYPE", record.file_type.as_deref().map(OsStr::new)),
+        ("WATCHDIR_TIME", Some(OsStr::new(&record.time))),
+    ];
+    let mut shell = shell(OsStr::new(command));
+    shell.stdin(Stdio::null());
+    for (name, value) in vars {
+        match value {
+            Some(v) => shell.env(name, v),
+            None => shell.env_remove(name),
+        };
+    }
+    shell
+}
+
+/// Gives `None` if no more events will come.
+pub async fn recv(
+    rx: &mut broadcast::Receiver<TimedEvent>,
+) -> Option<TimedEvent> {
+    loop {
+        match rx.recv().await {
+            Ok(v) => return Some(v),
+            Err(RecvError::Lagged(n)) => {
+                warn!("Command is too slow, {} events are skipped", n)
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+pub async fn wait(mut child: Child, command: &str) {
+    report(child.wait().await, command);
+}
+
+fn report(status: std::io::Result<ExitStatus>, command: &str) {
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("{} exited with {}", command, status),
+        Err(e) => warn!("Failed to wait for {}: {}", command, e),
+    }
+}
+
+fn clear_screen() {
+    use std::io::Write;
+
+    let mut stdout = std::io::stdout().lock();
+    // Nothing to clear if stdout is gone.
+    let _ = stdout.write_all(print::CLEAR).and_then(|_| stdout.flush());
+}
+
+/// Gives the paths of files changed by the event.
+pub fn affected_paths(event: &Event) -> Vec<PathBuf> {
+    match event {
+        Event::Move(from, to, _) => vec![from.to_owned(), to.to_owned()],
+        _ => event.path().map(|v| v.to_owned()).into_iter().collect(),
+    }
+}
+
+/// Quotes the path for the shell.
+fn quote(path: &std::path::Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &c in path.as_os_str().as_bytes() {
+        match c {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+    use watchdir::FileType;
+
+    use super::*;
+
+    #[test]
+    fn test_event_command_env() {
+        let path = PathBuf::from(OsStr::from_bytes(b"/tmp/\xff.txt"));
+        let event = Event::Create(path.to_owned(), FileType::File);
+        let record = Record::new(&event, OffsetDateTime::now_utc()).unwrap();
+        let command = event_command("true", &event, record);
+        let envs: Vec<_> = command.as_std().get_envs().collect();
+        assert!(envs
+            .contains(&(OsStr::new("WATCHDIR_PATH"), Some(path.as_os_str()))));
+        assert!(envs.contains(&(OsStr::new("WATCHDIR_OLD_PATH"), None)));
+    }
+
+    #[tokio::test]
+    async fn test_self_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let top_dir = dir.path().join("top");
+        let mut self_paths =
+            SelfPaths::new(dir.path().join("self"), top_dir.to_owned())
+                .unwrap();
+        let create = |path| Event::Create(top_dir.join(path), FileType::File);
+        assert!(!self_paths.is_caused(&create("target/a")));
+
+        let event = create("src/a");
+        let record = Record::new(&event, OffsetDateTime::now_utc()).unwrap();
+        let command = r#"echo target >> "$WATCHDIR_SELF_PATHS""#;
+        let status = event_command(command, &event, record).status();
+        assert!(status.await.unwrap().success());
+
+        assert!(self_paths.is_caused(&create("target")));
+        assert!(self_paths.is_caused(&create("target/debug/a")));
+        assert!(!self_paths.is_caused(&create("src/a")));
+        assert!(!self_paths.is_caused(&create("targets")));
+        // Moves are caused by commands only if both paths are reported.
+        let moves = |from, to| {
+            Event::Move(top_dir.join(from), top_dir.join(to), FileType::File)
+        };
+        assert!(self_paths.is_caused(&moves("target/a", "target/b")));
+        assert!(!self_paths.is_caused(&moves("src/a", "target/a")));
+    }
+}
@@ -0,0 +1,109 @@
+use std::{path::PathBuf, sync::Arc};
+
+use globset::GlobMatcher;
+use tokio::sync::{broadcast, Semaphore};
+use tracing::{debug, warn};
+use watchdir::Event;
+
+use crate::{exec, print::EventKind, record::Record, TimedEvent};
+
+/// Command to run on events of a kind.
+pub struct Hook {
+    pub kind: EventKind,
+    /// Glob of paths relative to the top directory. Any path if `None`.
+    pub glob: Option<GlobMatcher>,
+    pub command: String,
+}
+
+/// Runs the commands of hooks matching each event, with the event in their
+/// environment as with `--exec`. At most `jobs` commands run at once, and
+/// events wait for a free slot.
+pub struct HookRunner {
+    hooks: Vec<Hook>,
+    top_dir: PathBuf,
+    jobs: Arc<Semaphore>,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl HookRunner {
+    pub fn new(
+        hooks: Vec<Hook>,
+        top_dir: PathBuf,
+        jobs: usize,
+        rx: broadcast::Receiver<TimedEvent>,
+    ) -> Self {
+        Self { hooks, top_dir, jobs: Arc::new(Semaphore::new(jobs)), rx }
+    }
+
+    pub async fn run(mut self) {
+        while let Some((event, t)) = exec::recv(&mut self.rx).await {
+            if let Some(to) = event.moved_top() {
+                self.top_dir = to.to_owned();
+            }
+            for hook in &self.hooks {
+                if !self.matches(hook, &event) {
+                    continue;
+                }
+                let record = match Record::new(&event, t) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let permit = self.jobs.clone().acquire_owned().await.unwrap();
+                let command = hook.command.to_owned();
+                let target = affected(&event);
+                debug!("Running hook {} for {}", command, record.kind);
+                let mut child = match exec::event_command(
+                    &command, &event, record,
+                )
+                .spawn()
+                {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("Failed to run hook {}: {}", command, e);
+                        continue;
+                    }
+                };
+                tokio::spawn(async move {
+                    match child.wait().await {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => warn!(
+                            "Hook {} for {} exited with {}",
+                            command, target, status
+                        ),
+                        Err(e) => {
+                            warn!("Failed to wait for hook {}: {}", command, e)
+                        }
+                    }
+                    drop(permit);
+                });
+            }
+        }
+    }
+
+    fn matches(&self, hook: &Hook, event: &Event) -> bool {
+        if EventKind::of(event) != hook.kind {
+            return false;
+        }
+        let glob = match &hook.glob {
+            Some(v) => v,
+            None => return true,
+        };
+        let paths = match event {
+            Event::Move(from, to, _) => vec![from.as_path(), to],
+            _ => event.path().into_iter().collect(),
+        };
+        paths.iter().any(|v| {
+            v.strip_prefix(&self.top_dir).is_ok_and(|v| glob.is_match(v))
+        })
+    }
+}
+
+/// Describes the paths of the event for logs.
+fn affected(event: &Event) -> String {
+    match event {
+        Event::Move(from, to, _) => {
+            format!("{} -> {}", from.display(), to.display())
+        }
+        _ => event.path().map(|v| v.display().to_string()).unwrap_or_default(),
+    }
+}
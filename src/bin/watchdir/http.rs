@@ -0,0 +1,112 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const MAX_HEAD_SIZE: usize = 8192;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+/// Reads the head of a request, ignoring its headers.
+pub async fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request head is too large",
+            ));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => {
+            Ok(Request { method: method.to_owned(), path: path.to_owned() })
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid request line",
+        )),
+    }
+}
+
+/// Splits the query of a request target into decoded key-value pairs.
+pub fn query(target: &str) -> Vec<(String, String)> {
+    let query = match target.split_once('?') {
+        Some((_, query)) => query,
+        None => return Vec::new(),
+    };
+    query
+        .split('&')
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            let (key, value) = v.split_once('=').unwrap_or((v, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Strips the query from a request target.
+pub fn path(target: &str) -> &str {
+    target.split_once('?').map_or(target, |(path, _)| path)
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = iter.clone().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|v| u8::from_str_radix(v, 16).ok())
+                {
+                    Some(v) if hex.len() == 2 => {
+                        bytes.push(v);
+                        iter.nth(1);
+                    }
+                    _ => bytes.push(b),
+                }
+            }
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn respond_not_found(stream: &mut TcpStream) -> io::Result<()> {
+    respond(stream, "404 Not Found", "text/plain", "Not Found\n").await
+}
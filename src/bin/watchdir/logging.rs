@@ -0,0 +1,132 @@
+use std::{io, os::unix::net::UnixDatagram};
+
+use watchdir::Event;
+
+use crate::record::Record;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Facility of user-level messages.
+const LOG_USER: u8 = 1 << 3;
+const LOG_WARNING: u8 = 4;
+const LOG_INFO: u8 = 6;
+
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    Syslog,
+    Journald,
+}
+
+/// Emits events as log entries to the local syslog daemon or to the
+/// systemd journal, instead of printing them.
+pub struct LogOutput {
+    protocol: Protocol,
+    socket: UnixDatagram,
+    identifier: String,
+    pid: u32,
+}
+
+impl LogOutput {
+    pub fn connect(protocol: Protocol) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(match protocol {
+            Protocol::Syslog => SYSLOG_SOCKET,
+            Protocol::Journald => JOURNALD_SOCKET,
+        })?;
+        Ok(Self {
+            protocol,
+            socket,
+            identifier: env!("CARGO_BIN_NAME").to_owned(),
+            pid: std::process::id(),
+        })
+    }
+
+    /// Gives `Ok(false)` for events which are never printed.
+    pub fn log(
+        &self,
+        event: &Event,
+        t: time::OffsetDateTime,
+    ) -> io::Result<bool> {
+        let record = match Record::new(event, t) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let priority = priority(event);
+        let message = match (&record.from, &record.to) {
+            (Some(from), Some(to)) => {
+                format!("{} {} -> {}", record.kind, from, to)
+            }
+            _ => format!(
+                "{} {}",
+                record.kind,
+                record.path.as_deref().unwrap_or_default()
+            ),
+        };
+
+        let packet = match self.protocol {
+            Protocol::Syslog => format!(
+                "<{}>{} {}[{}]: {}",
+                LOG_USER | priority,
+                t.format(time::macros::format_description!(
+                    "[month repr:short] [day padding:space] \
+                     [hour]:[minute]:[second]"
+                ))
+                .unwrap(),
+                self.identifier,
+                self.pid,
+                message
+            )
+            .into_bytes(),
+            Protocol::Journald => {
+                let mut packet = Vec::new();
+                let mut fields = vec![
+                    ("PRIORITY", priority.to_string()),
+                    ("SYSLOG_IDENTIFIER", self.identifier.to_owned()),
+                    ("MESSAGE", message),
+                    ("WATCHDIR_EVENT", record.kind),
+                    ("WATCHDIR_TIME", record.time),
+                ];
+                if let Some(path) = record.path.or(record.from) {
+                    fields.push(("WATCHDIR_PATH", path));
+                }
+                if let Some(to) = record.to {
+                    fields.push(("WATCHDIR_TO", to));
+                }
+                if let Some(file_type) = record.file_type {
+                    fields.push(("WATCHDIR_FILE_TYPE", file_type));
+                }
+                for (name, value) in fields {
+                    write_field(&mut packet, name, &value);
+                }
+                packet
+            }
+        };
+        self.socket.send(&packet)?;
+        Ok(true)
+    }
+}
+
+fn priority(event: &Event) -> u8 {
+    match event {
+        Event::MoveTop(..)
+        | Event::DeleteTop(_)
+        | Event::ReplaceTop(_)
+        | Event::UnmountTop(_) => LOG_WARNING,
+        _ => LOG_INFO,
+    }
+}
+
+/// Serializes a field in the native journal protocol. Values with newlines,
+/// such as some paths, are length-prefixed.
+fn write_field(packet: &mut Vec<u8>, name: &str, value: &str) {
+    packet.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        packet.push(b'\n');
+        packet.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        packet.push(b'=');
+    }
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(b'\n');
+}
@@ -0,0 +1,56 @@
+use std::path::Path;
+
+use lscolors::{Indicator, LsColors};
+use termcolor::{Color, ColorSpec};
+use watchdir::FileType;
+
+/// Gives the style of the path by `LS_COLORS`. Paths which are gone, e.g.
+/// deleted ones, are styled by their file type and extension only.
+pub fn style(
+    ls_colors: &LsColors,
+    path: &Path,
+    file_type: &FileType,
+) -> Option<ColorSpec> {
+    let style = match (path.symlink_metadata(), file_type) {
+        (Ok(metadata), _) => {
+            ls_colors.style_for_path_with_metadata(path, Some(&metadata))
+        }
+        (Err(_), FileType::Dir) => {
+            ls_colors.style_for_indicator(Indicator::Directory)
+        }
+        (Err(_), FileType::File) => ls_colors.style_for_path(path),
+    }?;
+
+    let mut spec = ColorSpec::new();
+    spec.set_fg(style.foreground.map(color))
+        .set_bg(style.background.map(color))
+        .set_bold(style.font_style.bold)
+        .set_dimmed(style.font_style.dimmed)
+        .set_italic(style.font_style.italic)
+        .set_underline(style.font_style.underline)
+        .set_strikethrough(style.font_style.strikethrough);
+    Some(spec)
+}
+
+fn color(v: lscolors::Color) -> Color {
+    match v {
+        lscolors::Color::Black => Color::Black,
+        lscolors::Color::Red => Color::Red,
+        lscolors::Color::Green => Color::Green,
+        lscolors::Color::Yellow => Color::Yellow,
+        lscolors::Color::Blue => Color::Blue,
+        lscolors::Color::Magenta => Color::Magenta,
+        lscolors::Color::Cyan => Color::Cyan,
+        lscolors::Color::White => Color::White,
+        lscolors::Color::BrightBlack => Color::Ansi256(8),
+        lscolors::Color::BrightRed => Color::Ansi256(9),
+        lscolors::Color::BrightGreen => Color::Ansi256(10),
+        lscolors::Color::BrightYellow => Color::Ansi256(11),
+        lscolors::Color::BrightBlue => Color::Ansi256(12),
+        lscolors::Color::BrightMagenta => Color::Ansi256(13),
+        lscolors::Color::BrightCyan => Color::Ansi256(14),
+        lscolors::Color::BrightWhite => Color::Ansi256(15),
+        lscolors::Color::Fixed(v) => Color::Ansi256(v),
+        lscolors::Color::RGB(r, g, b) => Color::Rgb(r, g, b),
+    }
+}
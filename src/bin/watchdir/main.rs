@@ -1,16 +1,67 @@
 #[cfg(not(target_os = "linux"))]
 compile_error!("This program only works on Linux.");
 
+mod archive;
+mod audit;
+mod check;
 mod cli;
+mod config;
+mod connect;
+mod dbus;
+mod exec;
+mod hook;
+mod http;
+mod logging;
+mod ls_colors;
+mod metrics;
+mod mqtt;
+mod pause;
+mod porcelain;
 mod print;
+mod record;
+mod reload;
+mod rule;
+mod socket;
+mod sse;
+mod stats;
+mod sync;
+mod tail;
 mod theme;
+mod webhook;
+mod ws;
 
-use futures::{pin_mut, StreamExt};
+use std::{
+    collections::VecDeque,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures::{future, pin_mut, StreamExt};
 use termcolor::ColorChoice;
-use tokio::sync::mpsc;
-use tracing::{error, info, warn, Level};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc},
+    time::{sleep_until, Duration, Instant},
+};
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::EnvFilter;
-use watchdir::{Event, Watcher, WatcherOpts};
+use watchdir::{Event, FileType, Watcher, WatcherOpts};
+
+/// Exit status when no event is printed within `--exit-timeout`.
+const EXIT_TIMEOUT: i32 = 2;
+
+/// Exit status when interrupted by Ctrl-C.
+const EXIT_INTERRUPT: i32 = 130;
+
+/// Times the average rate of a directory for `--burst-rate`.
+const BURST_FACTOR: f64 = 10.0;
+
+type TimedEvent = (Event, time::OffsetDateTime);
+
+/// Broadcasts printed events to other outputs.
+type Events = broadcast::Sender<TimedEvent>;
 
 #[tokio::main]
 async fn main() {
@@ -22,85 +73,621 @@ async fn main() {
         cli::ColorWhen::Never => false,
     });
 
-    let dirs = directories::ProjectDirs::from("", "", env!("CARGO_BIN_NAME"))
-        .unwrap();
-    let file_theme = dirs.config_dir().join("theme.yaml");
-    let printer_theme = std::fs::File::open(file_theme)
-        .map(|f| {
-            let res: Result<theme::Theme, serde_yaml::Error> =
-                serde_yaml::from_reader(f);
-            match res {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Failed to parse theme config: {}", e);
-                    std::process::exit(1);
-                }
-            }
-        })
-        .unwrap_or_else(|e| {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                error!("Failed to read theme config: {}", e);
-                std::process::exit(1);
-            }
-            theme::Theme::default()
-        });
-
-    info!("version: {}", *cli::VERSION);
-    info!("Initializing...");
-    let now = std::time::Instant::now();
-    let mut watcher = match Watcher::new(
-        opts.dir.as_ref().unwrap(),
-        WatcherOpts::new(
+    if opts.check {
+        let mut watcher_opts = WatcherOpts::new(
             if opts.include_hidden {
                 watchdir::Dotdir::Include
             } else {
                 watchdir::Dotdir::Exclude
             },
-            opts.extra_events.into_iter().map(|e| e.into()).collect(),
-        ),
-    ) {
+            Vec::new(),
+        )
+        .dedupe_dirs(opts.dedupe_dirs)
+        .one_file_system(opts.one_file_system)
+        .symlink(opts.symlinks.into());
+        if !opts.hidden_prefix.is_empty() {
+            watcher_opts = watcher_opts.hidden_prefixes(opts.hidden_prefix);
+        }
+        std::process::exit(check::run(opts.dir.as_deref(), &watcher_opts));
+    }
+
+    let file_theme = cli::CONFIG_DIR.join("theme.yaml");
+    let printer_theme = theme::Theme::load(&file_theme).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    let mut printer = print::Printer::new(printer_opts(&opts, printer_theme));
+
+    if let Some(url) = opts.connect {
+        info!("Connecting to {}", url);
+        if let Err(e) = connect::run(url, &mut printer).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        info!("Connection closed.");
+        return;
+    }
+
+    info!("version: {}", *cli::VERSION);
+    info!("Initializing...");
+    let now = std::time::Instant::now();
+    let need_progress = !opts.debug && isatty_stderr();
+    // Extra events to print. The others are only for outputs.
+    let (requested, extra_events) = cli::extra_events(&opts);
+    let mut watcher_opts = WatcherOpts::new(
+        if opts.include_hidden {
+            watchdir::Dotdir::Include
+        } else {
+            watchdir::Dotdir::Exclude
+        },
+        extra_events,
+    )
+    .reattach_top(opts.wait_for_dir)
+    .watch_parent(opts.watch_parent)
+    .exclude_hidden_files(opts.exclude_hidden_files)
+    .excl_unlink(opts.excl_unlink)
+    .alias_events(opts.alias_events)
+    .dedupe_dirs(opts.dedupe_dirs)
+    .one_file_system(opts.one_file_system)
+    .symlink(opts.symlinks.into());
+    if let Some(interval) = opts.retry_failed {
+        watcher_opts =
+            watcher_opts.retry_failed(Duration::from_millis(interval));
+    }
+    if let Some(interval) = opts.rewatch_unmounted {
+        watcher_opts =
+            watcher_opts.rewatch_unmounted(Duration::from_millis(interval));
+    }
+    if let Some(min) = opts.rescan_after_suspend {
+        watcher_opts =
+            watcher_opts.rescan_after_suspend(Duration::from_millis(min));
+    }
+    if let Some(depth) = opts.lazy_depth {
+        watcher_opts = watcher_opts.lazy_depth(depth);
+    }
+    if let Some(max) = opts.max_watches {
+        watcher_opts = watcher_opts.max_watches(max);
+    }
+    if !opts.hidden_prefix.is_empty() {
+        watcher_opts = watcher_opts.hidden_prefixes(opts.hidden_prefix);
+    }
+    if opts.only_files {
+        watcher_opts = watcher_opts.file_types(vec![FileType::File]);
+    } else if opts.only_dirs {
+        watcher_opts = watcher_opts.file_types(vec![FileType::Dir]);
+    }
+    for regex in opts.include_regex {
+        watcher_opts = watcher_opts.include_regex(regex);
+    }
+    for regex in opts.exclude_regex {
+        watcher_opts = watcher_opts.exclude_regex(regex);
+    }
+    if let Some(algo) = opts.hash_on_modify {
+        watcher_opts = watcher_opts.hash_on_modify(algo.into());
+    }
+    if opts.dry_run {
+        let dir = opts.dir.as_ref().unwrap();
+        for (path, skip) in watchdir::plan(dir, &watcher_opts) {
+            match skip {
+                Some(v) => println!("skip  {} ({})", path.display(), v),
+                None => println!("watch {}", path.display()),
+            }
+        }
+        return;
+    }
+    let watcher = Watcher::new_async(
+        opts.dir.as_ref().unwrap(),
+        watcher_opts,
+        progress_reporter(need_progress),
+    )
+    .await;
+    if need_progress {
+        eprint!("\r\x1b[K");
+    }
+    let mut watcher = match watcher {
         Ok(watcher) => watcher,
         Err(e) => {
             error!("{}", e);
+            if e.is_watch_limit() {
+                info!(
+                    "Raise the limit with `sysctl \
+                     fs.inotify.max_user_watches=<NUMBER>`, or exclude some \
+                     directories"
+                );
+            }
             std::process::exit(1);
         }
     };
-    info!("Initialized successfully! Elapsed time: {:?}", now.elapsed());
+    let init_duration = now.elapsed();
+    info!("Initialized successfully! Elapsed time: {:?}", init_duration);
+
+    if let Some(addr) = opts.metrics_listen {
+        match metrics::MetricsServer::bind(
+            addr,
+            watcher.stats_reader(),
+            init_duration,
+        )
+        .await
+        {
+            Ok(server) => {
+                info!("Serving metrics on http://{}/metrics", addr);
+                tokio::spawn(server.serve());
+            }
+            Err(e) => {
+                error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let started = std::time::Instant::now();
+    let stats_reader = watcher.stats_reader();
+    let controller = watcher.controller();
+    let activity = Arc::new(Mutex::new(stats::Activity::default()));
+    if let Some(stats_interval) = opts.stats_interval {
+        let stats = watcher.stats_reader();
+        let activity = Arc::clone(&activity);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_millis(stats_interval));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let activity = activity.lock().unwrap();
+                info!("{}", stats::format(&stats.read(), &activity));
+            }
+        });
+    }
+    if opts.list_watches {
+        let watches = watcher.watches_reader();
+        tokio::spawn(async move {
+            let mut quits = signal(SignalKind::quit()).unwrap();
+            while quits.recv().await.is_some() {
+                let paths = match watches.read().await {
+                    Some(v) => v,
+                    None => break,
+                };
+                info!("Watching {} directories:", paths.len());
+                let mut lines = Vec::new();
+                for path in paths {
+                    lines.extend_from_slice(path.as_os_str().as_bytes());
+                    lines.push(b'\n');
+                }
+                let _ = std::io::stderr().write_all(&lines);
+            }
+        });
+    }
+
+    let (events, _) = broadcast::channel(1024);
+    // All events before printing, for outputs which copy the tree.
+    let (all_events, _) = broadcast::channel(1024);
+
+    if let Some(addr) = opts.serve_sse {
+        match sse::SseServer::bind(addr, events.clone()).await {
+            Ok(server) => {
+                info!("Serving events on http://{}/events", addr);
+                tokio::spawn(server.serve());
+            }
+            Err(e) => {
+                error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(addr) = opts.serve_ws {
+        let top_dir = opts.dir.as_ref().unwrap().to_path_buf();
+        match ws::WsServer::bind(addr, events.clone(), top_dir).await {
+            Ok(server) => {
+                info!("Serving events on ws://{}/events", addr);
+                tokio::spawn(server.serve());
+            }
+            Err(e) => {
+                error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = opts.output_socket {
+        tokio::spawn(
+            socket::SocketOutput::new(
+                path,
+                encoding(opts.format).unwrap_or(record::Encoding::Json),
+                events.subscribe(),
+                opts.group_events.map(Duration::from_millis),
+            )
+            .run(),
+        );
+    }
+
+    if let Some(url) = opts.webhook {
+        let webhook = webhook::Webhook::new(
+            webhook::WebhookOpts {
+                url,
+                secret: opts.webhook_secret,
+                batch: opts.webhook_batch.map(Duration::from_millis),
+            },
+            events.subscribe(),
+        );
+        tokio::spawn(webhook.run());
+    }
+
+    // Created before the commands start, so that they get its file.
+    let top_dir = opts.dir.as_deref().unwrap();
+    let mut self_paths = opts.ignore_self.then(|| {
+        let file = std::env::temp_dir()
+            .join(format!("watchdir-self-{}", std::process::id()));
+        exec::SelfPaths::new(file, top_dir.to_owned()).unwrap_or_else(|e| {
+            error!("Failed to create the file of self paths: {}", e);
+            std::process::exit(1);
+        })
+    });
+    // Events skipped since the last one which is not.
+    let mut skipped_self = 0;
+
+    if let Some(command) = opts.exec {
+        tokio::spawn(exec::EventExec::new(command, events.subscribe()).run());
+    }
+
+    if !opts.hooks.is_empty() {
+        let hooks = opts
+            .hooks
+            .iter()
+            .map(|v| hook::Hook {
+                kind: v.event.clone().into(),
+                glob: v.glob.as_ref().map(|v| v.compile_matcher()),
+                command: v.command.to_owned(),
+            })
+            .collect();
+        let runner = hook::HookRunner::new(
+            hooks,
+            opts.dir.as_deref().unwrap().to_owned(),
+            opts.hook_jobs.max(1),
+            events.subscribe(),
+        );
+        tokio::spawn(runner.run());
+    }
+
+    if !opts.tail.is_empty() {
+        let mut globs = globset::GlobSetBuilder::new();
+        for glob in &opts.tail {
+            globs.add(glob.clone());
+        }
+        match globs.build() {
+            Ok(globs) => {
+                let output = tail::TailOutput::new(
+                    opts.dir.as_deref().unwrap().to_owned(),
+                    globs,
+                    all_events.subscribe(),
+                );
+                tokio::spawn(output.run());
+            }
+            Err(e) => {
+                error!("Invalid globs of tail: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(dest) = opts.sync_to {
+        let top_dir = opts.dir.as_deref().unwrap();
+        create_outside(&dest, top_dir);
+        let mirror = sync::Mirror::new(
+            top_dir.to_owned(),
+            dest,
+            opts.sync_jobs,
+            all_events.subscribe(),
+        );
+        tokio::spawn(mirror.run());
+    }
+
+    if let Some(dest) = opts.archive_to {
+        let top_dir = opts.dir.as_deref().unwrap();
+        create_outside(&dest, top_dir);
+        let archiver = archive::Archiver::new(
+            archive::ArchiveOpts {
+                dest,
+                format: match opts.archive_format {
+                    cli::ArchiveFormat::Dir => archive::Format::Dir,
+                    cli::ArchiveFormat::TarZst => archive::Format::TarZst,
+                },
+                keep: opts.archive_keep,
+                debounce: Duration::from_millis(opts.debounce),
+            },
+            top_dir.to_owned(),
+            all_events.subscribe(),
+        );
+        tokio::spawn(archiver.run());
+    }
+
+    if let Some(command) = opts.exec_batch {
+        let (signal, kill_timeout) = (opts.signal, opts.kill_timeout);
+        let exec = exec::BatchExec::new(
+            exec::ExecOpts {
+                command,
+                debounce: Duration::from_millis(opts.debounce),
+                cooldown: opts.cooldown.map(Duration::from_millis),
+                clear: opts.clear && isatty_stdout(),
+                restart: opts.restart.then(|| exec::Restart {
+                    signal: match signal {
+                        cli::Signal::Term => libc::SIGTERM,
+                        cli::Signal::Int => libc::SIGINT,
+                        cli::Signal::Kill => libc::SIGKILL,
+                        cli::Signal::Hup => libc::SIGHUP,
+                    },
+                    kill_timeout: Duration::from_millis(kill_timeout),
+                }),
+            },
+            events.subscribe(),
+        );
+        tokio::spawn(exec.run());
+    }
+
+    if opts.dbus {
+        match dbus::DbusOutput::connect(events.subscribe()).await {
+            Ok(output) => {
+                tokio::spawn(output.run());
+            }
+            Err(e) => {
+                error!("Failed to connect to the session bus: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(broker) = opts.mqtt_broker {
+        let output = mqtt::MqttOutput::new(
+            mqtt::MqttOpts {
+                broker,
+                topic: opts.mqtt_topic,
+                qos: opts.mqtt_qos.into(),
+            },
+            events.subscribe(),
+        );
+        tokio::spawn(output.run());
+    }
+
+    let log_output = match opts.log_target {
+        cli::LogTarget::Stdout => None,
+        cli::LogTarget::Syslog => Some(logging::Protocol::Syslog),
+        cli::LogTarget::Journald => Some(logging::Protocol::Journald),
+    }
+    .map(|protocol| match logging::LogOutput::connect(protocol) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to connect to the log daemon: {}", e);
+            std::process::exit(1);
+        }
+    });
 
     let (tx, mut rx) = mpsc::channel(32);
+    let atomic_saves = opts.atomic_saves.map(Duration::from_millis);
+    let size_thresholds = opts.size_threshold;
+    let top_dir = opts.dir.as_deref().unwrap().to_owned();
+    let burst_rate = opts.burst_rate;
     tokio::spawn(async move {
-        let event_stream = watcher.stream();
+        // The tree is scanned on a blocking task, while events are queued
+        // in the kernel.
+        let size_monitor = match size_thresholds {
+            v if v.is_empty() => None,
+            v => Some(
+                tokio::task::spawn_blocking(move || {
+                    watchdir::size::SizeMonitor::new(&top_dir, v)
+                })
+                .await
+                .unwrap(),
+            ),
+        };
+        let event_stream = match atomic_saves {
+            // A save is given as a modify of the file it replaced.
+            Some(window) => watchdir::save::AtomicSaves::new(window)
+                .apply(watcher.stream())
+                .map(|(item, t)| match item {
+                    watchdir::save::Item::Event(event) => (event, t),
+                    watchdir::save::Item::Replaced(path, file_type) => {
+                        (Event::Modify(path, file_type, None), t)
+                    }
+                })
+                .left_stream(),
+            None => watcher.stream().right_stream(),
+        };
+        let event_stream = match size_monitor {
+            Some(monitor) => monitor
+                .apply(event_stream)
+                .filter_map(|(item, t)| {
+                    future::ready(match item {
+                        watchdir::size::Item::Event(event) => Some((event, t)),
+                        watchdir::size::Item::SizeThreshold(total) => {
+                            warn!(
+                                "Size of watched dir crossed a threshold: {} \
+                                 bytes.",
+                                total
+                            );
+                            None
+                        }
+                    })
+                })
+                .left_stream(),
+            None => event_stream.right_stream(),
+        };
+        let event_stream = match burst_rate {
+            Some(rate) => {
+                watchdir::burst::BurstDetector::new(rate as f64, BURST_FACTOR)
+                    .apply(event_stream)
+                    .filter_map(|(item, t)| {
+                        future::ready(match item {
+                            watchdir::burst::Item::Event(event) => {
+                                Some((event, t))
+                            }
+                            watchdir::burst::Item::Burst(path, rate) => {
+                                warn!(
+                                    "Burst of events in {}: {:.0} per second.",
+                                    path.display(),
+                                    rate
+                                );
+                                None
+                            }
+                        })
+                    })
+                    .left_stream()
+            }
+            None => event_stream.right_stream(),
+        };
         pin_mut!(event_stream);
         while let Some(event) = event_stream.next().await {
-            tx.send(event).await.unwrap();
+            let _ = all_events.send(event.clone());
+            if watchdir::ExtraEvent::allows(&requested, &event.0) {
+                tx.send(event).await.unwrap();
+            }
         }
     });
 
-    let mut printer = print::Printer::new(print::PrinterOpts {
-        need_ansi: match opts.color {
-            cli::ColorWhen::Always => true,
-            cli::ColorWhen::Auto => isatty_stdout(),
-            cli::ColorWhen::Never => false,
-        },
-        color_choice: (&opts.color).into(),
-        theme: printer_theme,
-        top_dir: opts.dir.unwrap().to_owned(),
-        need_time: opts.time,
-        need_prefix: opts.prefix,
-        oneline: opts.oneline,
-        timeout_modify: std::time::Duration::from_millis(opts.throttle_modify),
-        event_filter: opts
-            .exclude_events
-            .into_iter()
-            .map(|v| v.into())
-            .collect(),
+    // Options of watching are not reloaded, only those of printing and the
+    // regexes of paths.
+    let mut reloads = reload::watch(&cli::CONFIG_DIR, || {
+        let opts = cli::try_parse().map_err(|e| {
+            // The message only, without the usage.
+            let e = e.to_string();
+            let line = e.lines().next().unwrap_or_default();
+            line.trim_start_matches("error: ").to_owned()
+        })?;
+        let theme = theme::Theme::load(&cli::CONFIG_DIR.join("theme.yaml"))?;
+        let printer_opts = printer_opts(&opts, theme);
+        Ok((printer_opts, opts.include_regex, opts.exclude_regex))
     });
 
+    let (for_event, dir) = (&opts.for_event, &opts.dir);
+    let awaited = opts.wait_for.as_ref().map(|glob| Awaited {
+        glob: glob.compile_matcher(),
+        kinds: for_event.iter().map(|v| v.clone().into()).collect(),
+        top_dir: dir.as_deref().unwrap().to_owned(),
+    });
+    let exit_timeout = opts.exit_timeout.map(Duration::from_millis);
+    let mut deadline = exit_timeout.map(|v| Instant::now() + v);
+    let mut printed = 0;
+    let mut pauses = pause::listen();
+    let mut interrupts = signal(SignalKind::interrupt()).unwrap();
+    let mut paused = false;
+    // Events received while paused, printed when resumed.
+    let mut held = VecDeque::new();
+    let mut audit = opts.audit.then_some(audit::Audit::new(opts.audit_rate));
+    let mut summary = opts.summary.map(|v| {
+        let period = Duration::from_millis(v);
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
     loop {
-        let (event, t) = rx.recv().await.unwrap();
-        printer.print(&event, t).unwrap();
+        let next = if paused { None } else { held.pop_front() };
+        let (event, t) = match next {
+            Some(v) => v,
+            None => tokio::select! {
+                v = rx.recv() => {
+                    let v = v.unwrap();
+                    activity.lock().unwrap().add(&v.0, v.1);
+                    if paused {
+                        held.push_back(v);
+                        continue;
+                    }
+                    v
+                }
+                Some(_) = interrupts.recv() => {
+                    let stats = stats_reader.read();
+                    let activity = activity.lock().unwrap();
+                    let runtime = started.elapsed();
+                    for line in stats::report(&stats, &activity, runtime) {
+                        info!("{}", line);
+                    }
+                    std::process::exit(EXIT_INTERRUPT);
+                }
+                Some(command) = pauses.recv() => {
+                    paused = match command {
+                        pause::Command::Pause => true,
+                        pause::Command::Resume => false,
+                        pause::Command::Toggle => !paused,
+                    };
+                    if paused {
+                        info!("Paused. Events are held until resumed.");
+                    } else {
+                        info!("Resumed with {} held events.", held.len());
+                    }
+                    continue;
+                }
+                _ = async { summary.as_mut().unwrap().tick().await },
+                    if summary.is_some() && !paused =>
+                {
+                    printer.print_summary().unwrap();
+                    continue;
+                }
+                Some((mut v, include, exclude)) = reloads.recv() => {
+                    v.top_dir = printer.top_dir().to_owned();
+                    printer = print::Printer::new(v);
+                    controller.set_regex(include, exclude);
+                    info!("Reloaded the config and theme.");
+                    continue;
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() && !paused =>
+                {
+                    info!("No events for {:?}.", exit_timeout.unwrap());
+                    std::process::exit(EXIT_TIMEOUT);
+                }
+            },
+        };
+        if let Some(self_paths) = self_paths.as_mut() {
+            if self_paths.is_caused(&event) {
+                skipped_self += 1;
+                continue;
+            }
+            if skipped_self > 0 {
+                info!("Skipped {} events of commands.", skipped_self);
+                skipped_self = 0;
+            }
+        }
+        let shown = match &log_output {
+            Some(output) => {
+                printer.accept(&event, t)
+                    && output.log(&event, t).unwrap_or_else(|e| {
+                        warn!("Failed to emit log entry: {}", e);
+                        false
+                    })
+            }
+            None if printer.accept(&event, t) => {
+                let processes = match audit.as_mut() {
+                    Some(v) => v.processes(&event, t).await,
+                    None => None,
+                };
+                printer
+                    .print_accepted(&event, t, processes.as_deref())
+                    .unwrap();
+                true
+            }
+            None => false,
+        };
+        if shown {
+            let _ = events.send((event.clone(), t));
+            printed += 1;
+            match &awaited {
+                None => deadline = exit_timeout.map(|v| Instant::now() + v),
+                Some(v) if is_awaited(&event, v) => std::process::exit(0),
+                Some(_) => {}
+            }
+        }
         match event {
-            Event::MoveTop(_) => {
+            Event::MoveTop(..)
+            | Event::DeleteTop(_)
+            | Event::ReplaceTop(_)
+            | Event::UnmountTop(_)
+                if opts.wait_for_dir =>
+            {
+                warn!("Watched dir is gone. Waiting for it to appear again.");
+            }
+            Event::Reattached(_) => {
+                info!("Watched dir appeared again.");
+            }
+            Event::MoveTop(_, Some(ref to)) => {
+                info!("Watched dir was moved to {}", to.display());
+            }
+            Event::MoveTop(_, None) => {
                 warn!(
                     "Watched dir was moved. The prefix of path can no longer \
                      be trusted!"
@@ -110,10 +697,29 @@ async fn main() {
                 warn!("Watched dir was deleted.");
                 std::process::exit(0);
             }
+            Event::ReplaceTop(_) => {
+                warn!("Watched dir was replaced.");
+                std::process::exit(0);
+            }
             Event::UnmountTop(_) => {
                 warn!("Watched dir was unmounted.");
                 std::process::exit(0);
             }
+            Event::WatchError(ref path, kind) => {
+                warn!("Failed to watch {}: {}", path.display(), kind);
+            }
+            Event::Watching(ref path) => {
+                info!("Watching {} now.", path.display());
+            }
+            Event::Remounted(ref path) => {
+                info!("Watching {} again after remounting.", path.display());
+            }
+            Event::Unwatched(ref path) => {
+                debug!("Unwatched {} for now.", path.display());
+            }
+            Event::WatchRemoved(ref path) => {
+                warn!("No longer watching {}.", path.display());
+            }
             Event::Unknown => {
                 error!("Unknown event occurs.");
             }
@@ -122,9 +728,93 @@ async fn main() {
             }
             _ => {}
         }
+        if opts.exit_after_events == Some(printed) {
+            std::process::exit(0);
+        }
     }
 }
 
+fn printer_opts(opts: &cli::Opts, theme: theme::Theme) -> print::PrinterOpts {
+    print::PrinterOpts {
+        need_ansi: match opts.color {
+            cli::ColorWhen::Always => true,
+            cli::ColorWhen::Auto => isatty_stdout(),
+            cli::ColorWhen::Never => false,
+        },
+        color_choice: (&opts.color).into(),
+        theme,
+        ls_colors: if opts.ls_colors {
+            Some(lscolors::LsColors::from_env().unwrap_or_default())
+        } else {
+            None
+        },
+        // Paths from another watchdir are printed in full.
+        top_dir: opts
+            .dir
+            .as_deref()
+            .map_or_else(PathBuf::new, Path::to_path_buf),
+        need_time: opts.time
+            || opts.timefmt.is_some()
+            || opts.time_style.is_some(),
+        time_style: match (&opts.timefmt, &opts.time_style) {
+            (Some(format), _) => print::TimeStyle::Format(format.0.clone()),
+            (None, Some(style)) => style.clone().into(),
+            (None, None) => print::TimeStyle::Default,
+        },
+        time_offset: time_offset(opts.utc, opts.local),
+        need_prefix: opts.prefix,
+        oneline: opts.oneline,
+        throttle: std::iter::once((
+            print::EventKind::Modify,
+            Duration::from_millis(opts.throttle_modify),
+        ))
+        .chain(
+            opts.throttle.iter().map(|v| {
+                (v.event.clone().into(), Duration::from_millis(v.time))
+            }),
+        )
+        .collect(),
+        event_filter: opts
+            .exclude_events
+            .iter()
+            .map(|v| v.clone().into())
+            .collect(),
+        rules: rule::Rules::new(opts.rule.clone()),
+        summary: opts.summary.is_some(),
+        group_by_dir: opts.group_by_dir,
+        clear: (opts.clear && opts.exec_batch.is_none() && isatty_stdout())
+            .then(|| Duration::from_millis(opts.debounce)),
+        porcelain: opts.porcelain,
+        binary: encoding(opts.format),
+        group_events: opts.group_events.map(Duration::from_millis),
+        diff_size: opts.diff.then_some(opts.diff_size),
+    }
+}
+
+/// Event of `--wait-for`.
+struct Awaited {
+    glob: globset::GlobMatcher,
+    /// Any kind if empty.
+    kinds: Vec<print::EventKind>,
+    top_dir: PathBuf,
+}
+
+fn is_awaited(event: &Event, awaited: &Awaited) -> bool {
+    let paths = match event {
+        Event::Move(from, to, _) => vec![from.as_path(), to],
+        _ => match event.file_type().and(event.path()) {
+            Some(v) => vec![v],
+            None => return false,
+        },
+    };
+    (awaited.kinds.is_empty()
+        || awaited.kinds.contains(&print::EventKind::of(event)))
+        && paths.iter().any(|v| {
+            v.strip_prefix(&awaited.top_dir)
+                .is_ok_and(|v| awaited.glob.is_match(v))
+        })
+}
+
 fn init_logger(debug: bool, color: bool) {
     let time_format = time::macros::format_description!(
         "[year]-[month]-[day]T[hour]:[minute]:\
@@ -153,6 +843,64 @@ fn init_logger(debug: bool, color: bool) {
     };
 }
 
+/// Gives the offset of the local timezone, unless UTC is asked for. The
+/// offset is only available in this multi-threaded program when built
+/// with `--cfg unsound_local_offset`.
+fn time_offset(utc: bool, local: bool) -> Option<time::UtcOffset> {
+    if utc || !(local || cfg!(unsound_local_offset)) {
+        return None;
+    }
+    match time::UtcOffset::current_local_offset() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            if local {
+                warn!("Failed to get the local timezone, using UTC: {}", e);
+            }
+            None
+        }
+    }
+}
+
+fn progress_reporter(enabled: bool) -> impl FnMut(watchdir::Progress) {
+    const INTERVAL: std::time::Duration =
+        std::time::Duration::from_millis(100);
+
+    let mut last = std::time::Instant::now();
+    move |progress| {
+        if enabled && last.elapsed() >= INTERVAL {
+            last = std::time::Instant::now();
+            eprint!(
+                "\r\x1b[KScanned {} directories, watched {}",
+                progress.scanned, progress.watched
+            );
+        }
+    }
+}
+
+/// Gives the encoding of records in the format, or `None` for text.
+fn encoding(format: cli::Format) -> Option<record::Encoding> {
+    match format {
+        cli::Format::Text => None,
+        cli::Format::Cbor => Some(record::Encoding::Cbor),
+        cli::Format::Msgpack => Some(record::Encoding::MessagePack),
+    }
+}
+
+/// Creates the directory to write copies of files in, and exits if it is
+/// within the top directory, where writing them would cause more events.
+fn create_outside(dest: &Path, top_dir: &Path) {
+    if let Err(e) = std::fs::create_dir_all(dest) {
+        error!("Failed to create {}: {}", dest.display(), e);
+        std::process::exit(1);
+    }
+    let within = std::fs::canonicalize(dest)
+        .and_then(|v| Ok(v.starts_with(std::fs::canonicalize(top_dir)?)));
+    if within.unwrap_or(true) {
+        error!("{} must be outside the watched directory", dest.display());
+        std::process::exit(1);
+    }
+}
+
 fn isatty_stdout() -> bool {
     unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
 }
@@ -173,6 +921,25 @@ impl From<cli::ExtraEvent> for watchdir::ExtraEvent {
     }
 }
 
+impl From<cli::Symlink> for watchdir::Symlink {
+    fn from(v: cli::Symlink) -> Self {
+        match v {
+            cli::Symlink::NoFollow => Self::NoFollow,
+            cli::Symlink::FollowTop => Self::FollowTop,
+            cli::Symlink::Follow => Self::Follow,
+        }
+    }
+}
+
+impl From<cli::HashAlgo> for watchdir::HashAlgo {
+    fn from(v: cli::HashAlgo) -> Self {
+        match v {
+            cli::HashAlgo::Blake3 => Self::Blake3,
+            cli::HashAlgo::Sha256 => Self::Sha256,
+        }
+    }
+}
+
 impl From<cli::Event> for print::EventGroup {
     fn from(v: cli::Event) -> Self {
         match v {
@@ -184,6 +951,43 @@ impl From<cli::Event> for print::EventGroup {
     }
 }
 
+impl From<cli::EventType> for print::EventKind {
+    fn from(v: cli::EventType) -> Self {
+        match v {
+            cli::EventType::Create => Self::Create,
+            cli::EventType::Delete => Self::Delete,
+            cli::EventType::Move => Self::Move,
+            cli::EventType::Modify => Self::Modify,
+            cli::EventType::Access => Self::Access,
+            cli::EventType::Attrib => Self::Attrib,
+            cli::EventType::Open => Self::Open,
+            cli::EventType::Close => Self::Close,
+            cli::EventType::Unmount => Self::Unmount,
+        }
+    }
+}
+
+impl From<cli::TimeStyle> for print::TimeStyle {
+    fn from(v: cli::TimeStyle) -> Self {
+        match v {
+            cli::TimeStyle::Epoch => Self::Epoch,
+            cli::TimeStyle::EpochMs => Self::EpochMs,
+            cli::TimeStyle::Rfc3339 => Self::Rfc3339,
+            cli::TimeStyle::Relative => Self::Relative,
+        }
+    }
+}
+
+impl From<cli::Qos> for rumqttc::QoS {
+    fn from(v: cli::Qos) -> Self {
+        match v {
+            cli::Qos::AtMostOnce => Self::AtMostOnce,
+            cli::Qos::AtLeastOnce => Self::AtLeastOnce,
+            cli::Qos::ExactlyOnce => Self::ExactlyOnce,
+        }
+    }
+}
+
 impl From<&cli::ColorWhen> for ColorChoice {
     fn from(v: &cli::ColorWhen) -> Self {
         match v {
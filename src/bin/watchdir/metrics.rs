@@ -0,0 +1,117 @@
+use std::{fmt::Write, io, net::SocketAddr, time::Duration};
+
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+use watchdir::{Stats, StatsReader};
+
+use crate::http;
+
+/// Serves the statistics of the watcher in Prometheus text format at
+/// `/metrics`.
+pub struct MetricsServer {
+    listener: TcpListener,
+    stats: StatsReader,
+    init_duration: Duration,
+}
+
+impl MetricsServer {
+    pub async fn bind(
+        addr: SocketAddr,
+        stats: StatsReader,
+        init_duration: Duration,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, stats, init_duration })
+    }
+
+    pub async fn serve(self) {
+        loop {
+            let (mut stream, peer) = match self.listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            debug!("Metrics connection from {}", peer);
+            let body = render(&self.stats.read(), self.init_duration);
+            tokio::spawn(async move {
+                if let Err(e) = handle(&mut stream, &body).await {
+                    debug!("Metrics connection from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(stream: &mut TcpStream, body: &str) -> io::Result<()> {
+    let request = http::read_request(stream).await?;
+    if request.method == "GET" && request.path == "/metrics" {
+        http::respond(
+            stream,
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            body,
+        )
+        .await
+    } else {
+        http::respond_not_found(stream).await
+    }
+}
+
+fn render(stats: &Stats, init_duration: Duration) -> String {
+    let mut out = String::new();
+    write_head(
+        &mut out,
+        "events_total",
+        "counter",
+        "Number of events by kind.",
+    );
+    for (kind, count) in &stats.events {
+        writeln!(out, "watchdir_events_total{{kind=\"{}\"}} {}", kind, count)
+            .unwrap();
+    }
+
+    let metrics: [(&str, &str, &str, f64); 5] = [
+        (
+            "watches",
+            "gauge",
+            "Number of watched directories.",
+            stats.watches as f64,
+        ),
+        (
+            "depth",
+            "gauge",
+            "Depth of the deepest watched directory.",
+            stats.depth as f64,
+        ),
+        (
+            "queue_overflows_total",
+            "counter",
+            "Times the kernel event queue overflowed.",
+            stats.overflows as f64,
+        ),
+        (
+            "dropped_records_total",
+            "counter",
+            "Unrecognized records from the kernel.",
+            stats.dropped as f64,
+        ),
+        (
+            "init_duration_seconds",
+            "gauge",
+            "Time spent watching the directory tree at startup.",
+            init_duration.as_secs_f64(),
+        ),
+    ];
+    for (name, kind, help, value) in metrics {
+        write_head(&mut out, name, kind, help);
+        writeln!(out, "watchdir_{} {}", name, value).unwrap();
+    }
+    out
+}
+
+fn write_head(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP watchdir_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE watchdir_{} {}", name, kind).unwrap();
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::{record::Record, TimedEvent};
+
+const DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Requests buffered by the client while the broker is unreachable.
+const CAPACITY: usize = 1024;
+
+pub struct MqttOpts {
+    /// `HOST` or `HOST:PORT` of the broker.
+    pub broker: String,
+    pub topic: String,
+    pub qos: QoS,
+}
+
+/// Publishes events as JSON to an MQTT broker.
+pub struct MqttOutput {
+    client: AsyncClient,
+    eventloop: EventLoop,
+    topic: String,
+    qos: QoS,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl MqttOutput {
+    pub fn new(opts: MqttOpts, rx: broadcast::Receiver<TimedEvent>) -> Self {
+        let (host, port) = match opts.broker.rsplit_once(':') {
+            Some((host, port)) => match port.parse() {
+                Ok(port) => (host.to_owned(), port),
+                Err(_) => (opts.broker, DEFAULT_PORT),
+            },
+            None => (opts.broker, DEFAULT_PORT),
+        };
+        let client_id = format!("watchdir-{}", std::process::id());
+        let mut mqtt_opts = MqttOptions::new(client_id, host, port);
+        mqtt_opts.set_keep_alive(KEEP_ALIVE);
+        let (client, eventloop) = AsyncClient::new(mqtt_opts, CAPACITY);
+
+        Self { client, eventloop, topic: opts.topic, qos: opts.qos, rx }
+    }
+
+    pub async fn run(self) {
+        let Self { client, mut eventloop, topic, qos, mut rx } = self;
+
+        // The event loop drives the connection and has to be polled all the
+        // time, reconnecting on errors.
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(notification) => debug!("MQTT: {:?}", notification),
+                    Err(e) => {
+                        warn!("MQTT connection failed: {}", e);
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                    }
+                }
+            }
+        });
+
+        loop {
+            let (event, t) = match rx.recv().await {
+                Ok(v) => v,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("MQTT output is too slow, {} events are skipped", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if let Some(record) = Record::new(&event, t) {
+                if let Err(e) =
+                    client.publish(&topic, qos, false, record.to_json()).await
+                {
+                    warn!("Failed to publish to MQTT broker: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::BufRead;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tracing::warn;
+
+#[derive(Clone, Copy)]
+pub enum Command {
+    Pause,
+    Resume,
+    Toggle,
+}
+
+/// Gives `Pause` on SIGUSR1 and `Resume` on SIGUSR2. If stdin is a
+/// terminal, pressing Enter gives `Toggle`.
+pub fn listen() -> mpsc::Receiver<Command> {
+    let (tx, rx) = mpsc::channel(4);
+    for (kind, command) in [
+        (SignalKind::user_defined1(), Command::Pause),
+        (SignalKind::user_defined2(), Command::Resume),
+    ] {
+        let mut signals = match signal(kind) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to listen for signals: {}", e);
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                if tx.send(command).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if unsafe { libc::isatty(libc::STDIN_FILENO) != 0 } {
+        std::thread::spawn(move || {
+            for _ in std::io::stdin().lock().lines() {
+                if tx.blocking_send(Command::Toggle).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    rx
+}
@@ -0,0 +1,99 @@
+//! Output for scripts, which stays the same across releases. Each event is
+//! a line of fields separated by tabs:
+//!
+//! ```text
+//! v1  KIND  TYPE  PATH  [TO]
+//! ```
+//!
+//! `TYPE` is `F` for files, `D` for directories and `-` for events of the
+//! top directory. `TO` is only given for moves. Backslashes, tabs and
+//! newlines in paths are escaped as `\\`, `\t` and `\n`. A change of the
+//! format comes with a new version in the first field.
+
+use std::{os::unix::ffi::OsStrExt, path::Path};
+
+use watchdir::{Event, FileType};
+
+const VERSION: &[u8] = b"v1";
+
+/// Gives the line of the event, or `None` for events which are never
+/// printed.
+pub fn line(event: &Event) -> Option<Vec<u8>> {
+    let (kind, file_type, path, to) = match event {
+        Event::Create(path, file_type) => {
+            ("CREATE", Some(file_type), path, None)
+        }
+        Event::Delete(path, file_type) => {
+            ("DELETE", Some(file_type), path, None)
+        }
+        Event::Move(from, to, file_type) => {
+            ("MOVE", Some(file_type), from, Some(to))
+        }
+        Event::MoveAway(path, file_type, _) => {
+            ("MOVE_AWAY", Some(file_type), path, None)
+        }
+        Event::MoveInto(path, file_type, _) => {
+            ("MOVE_INTO", Some(file_type), path, None)
+        }
+        Event::Modify(path, file_type, _) => {
+            ("MODIFY", Some(file_type), path, None)
+        }
+        Event::Open(path, file_type) => ("OPEN", Some(file_type), path, None),
+        Event::Close(path, file_type) => {
+            ("CLOSE", Some(file_type), path, None)
+        }
+        Event::Access(path, file_type) => {
+            ("ACCESS", Some(file_type), path, None)
+        }
+        Event::Attrib(path, file_type) => {
+            ("ATTRIB", Some(file_type), path, None)
+        }
+        Event::Unmount(path, file_type) => {
+            ("UNMOUNT", Some(file_type), path, None)
+        }
+        Event::MoveTop(path, to) => ("MOVE_TOP", None, path, to.as_ref()),
+        Event::DeleteTop(path) => ("DELETE_TOP", None, path, None),
+        Event::ReplaceTop(path) => ("REPLACE_TOP", None, path, None),
+        Event::UnmountTop(path) => ("UNMOUNT_TOP", None, path, None),
+        Event::AccessTop(path) => ("ACCESS_TOP", None, path, None),
+        Event::AttribTop(path) => ("ATTRIB_TOP", None, path, None),
+        Event::OpenTop(path) => ("OPEN_TOP", None, path, None),
+        Event::CloseTop(path) => ("CLOSE_TOP", None, path, None),
+        Event::Reattached(path) => ("REATTACHED", None, path, None),
+        Event::Noise
+        | Event::Ignored
+        | Event::Unknown
+        | Event::WatchError(..)
+        | Event::Watching(_)
+        | Event::Remounted(_)
+        | Event::Unwatched(_)
+        | Event::WatchRemoved(_) => return None,
+    };
+
+    let mut line = VERSION.to_vec();
+    line.push(b'\t');
+    line.extend_from_slice(kind.as_bytes());
+    line.push(b'\t');
+    line.push(match file_type {
+        Some(FileType::File) => b'F',
+        Some(FileType::Dir) => b'D',
+        None => b'-',
+    });
+    for path in std::iter::once(path).chain(to) {
+        line.push(b'\t');
+        escape(path, &mut line);
+    }
+    line.push(b'\n');
+    Some(line)
+}
+
+fn escape(path: &Path, buf: &mut Vec<u8>) {
+    for &c in path.as_os_str().as_bytes() {
+        match c {
+            b'\\' => buf.extend_from_slice(b"\\\\"),
+            b'\t' => buf.extend_from_slice(b"\\t"),
+            b'\n' => buf.extend_from_slice(b"\\n"),
+            _ => buf.push(c),
+        }
+    }
+}
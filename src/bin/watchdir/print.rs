@@ -1,15 +1,30 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap},
     io::Write,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
     time::Duration,
 };
 
 use termcolor::{ColorChoice, ColorSpec, StandardStream, WriteColor};
-use watchdir::{Event, FileType};
+use time::format_description::{well_known::Rfc3339, OwnedFormatItem};
+use watchdir::{
+    diff::Diffs, group::Grouping, throttle::Throttle, Event, FileType,
+};
+
+use crate::{
+    audit::Process,
+    ls_colors, porcelain,
+    record::{to_frame, Encoding, Record},
+    rule::Rules,
+    theme::Theme,
+};
+
+/// Number of files whose contents are kept for diffs.
+const DIFF_CACHE: usize = 64;
 
-use crate::theme::Theme;
+/// Clears the screen and the scrollback, and moves the cursor home.
+pub const CLEAR: &[u8] = b"\x1b[H\x1b[2J\x1b[3J";
 
 macro_rules! write_color {
     (
@@ -35,116 +50,173 @@ macro_rules! write_color {
 pub struct Printer {
     opts: PrinterOpts,
     stdout: StandardStream,
-    counter: Arc<Mutex<HashSet<PathBuf>>>,
-    time_offset: Option<time::UtcOffset>,
+    throttle: Throttle,
+    started: time::OffsetDateTime,
+    last_printed: Option<time::OffsetDateTime>,
+    /// Counts of events per directory and kind, in the summary mode.
+    counts: BTreeMap<PathBuf, BTreeMap<EventKind, u64>>,
+    /// Directory of the heading of the current group.
+    group: Option<PathBuf>,
+    /// Groups of related events for records.
+    grouping: Option<Grouping>,
+    diffs: Option<Diffs>,
 }
 
 pub struct PrinterOpts {
     pub need_ansi: bool,
     pub color_choice: ColorChoice,
     pub theme: Theme,
+    pub ls_colors: Option<lscolors::LsColors>,
     pub top_dir: PathBuf,
     pub need_time: bool,
+    pub time_style: TimeStyle,
+    /// Time is printed in UTC if there is no offset.
+    pub time_offset: Option<time::UtcOffset>,
     pub need_prefix: bool,
     pub oneline: bool,
-    pub timeout_modify: Duration,
+    /// Events of these kinds are printed at most once in the time for
+    /// the same path.
+    pub throttle: HashMap<EventKind, Duration>,
     pub event_filter: Vec<EventGroup>,
+    pub rules: Rules,
+    /// Count events instead of printing them, until `print_summary`.
+    pub summary: bool,
+    /// Print the directory once for consecutive events in it.
+    pub group_by_dir: bool,
+    /// Clear the screen before each summary, and before an event which
+    /// comes after this much quiet.
+    pub clear: Option<Duration>,
+    /// Print lines of the porcelain format instead.
+    pub porcelain: bool,
+    /// Write length-prefixed records instead.
+    pub binary: Option<Encoding>,
+    /// Give records the group of related events, for events within this
+    /// time.
+    pub group_events: Option<Duration>,
+    /// Print diffs of modified text files up to this size.
+    pub diff_size: Option<u64>,
 }
 
 impl<'a> Printer {
     pub fn new(opts: PrinterOpts) -> Self {
         let color_choice = opts.color_choice.to_owned();
+        let windows = opts.throttle.to_owned();
+        let grouping = opts.group_events.map(Grouping::new);
+        let diffs = opts
+            .diff_size
+            .map(|v| Diffs::new(NonZeroUsize::new(DIFF_CACHE).unwrap(), v));
         Self {
             opts,
             stdout: StandardStream::stdout(color_choice),
-            counter: Arc::new(Mutex::new(HashSet::new())),
-            time_offset: if cfg!(unsound_local_offset) {
-                time::UtcOffset::current_local_offset().ok()
-            } else {
-                None
-            },
+            throttle: Throttle::with(move |event| {
+                windows.get(&EventKind::of(event)).copied()
+            }),
+            started: time::OffsetDateTime::now_utc(),
+            last_printed: None,
+            counts: BTreeMap::new(),
+            group: None,
+            grouping,
+            diffs,
         }
     }
 
     pub fn print(
+        &mut self,
+        event: &Event,
+        t: time::OffsetDateTime,
+    ) -> Result<bool, std::io::Error> {
+        if !self.accept(event, t) {
+            return Ok(false);
+        }
+        self.print_accepted(event, t, None)?;
+        Ok(true)
+    }
+
+    /// Prints an event which `accept` let through, with the processes
+    /// which had its file open.
+    pub fn print_accepted(
         &mut self,
         event: &Event,
         mut t: time::OffsetDateTime,
+        processes: Option<&[Process]>,
     ) -> Result<(), std::io::Error> {
-        match event {
-            Event::Unknown | Event::Noise | Event::Ignored => return Ok(()),
-            Event::Modify(path, _) => {
-                if !self.should(path) {
-                    return Ok(());
-                }
+        if self.opts.summary {
+            self.count(event);
+            return Ok(());
+        }
+        if let Some(encoding) = self.opts.binary {
+            if let Some(mut record) = Record::new(event, t) {
+                record.group =
+                    self.grouping.as_mut().map(|v| v.assign(event, t));
+                record.processes = processes.map(<[_]>::to_vec);
+                self.stdout.write_all(&to_frame(&record, encoding))?;
+                // Stdout is flushed by lines, which records do not have.
+                self.stdout.flush()?;
             }
-            _ => {}
+            return Ok(());
         }
-        for e in &self.opts.event_filter {
-            if e.contains(event) {
-                return Ok(());
+        if self.opts.porcelain {
+            if let Some(line) = porcelain::line(event) {
+                self.stdout.write_all(&line)?;
             }
+            return Ok(());
         }
 
-        let (head, color) = self.opts.theme.head_and_color(event);
-
         if self.opts.need_ansi {
             self.stdout.write_all(b"\x1b[1000D")?;
         }
 
-        if self.opts.need_time {
-            if let Some(offset) = self.time_offset {
-                t = t.to_offset(offset);
+        if let Some(quiet) = self.opts.clear {
+            if self.last_printed.is_none_or(|v| t - v > quiet) {
+                self.clear()?;
             }
-            write_color!(self.stdout, [set_dimmed])?;
-            write!(
-                self.stdout,
-                "{}",
-                t.format(&time::macros::format_description!(
-                    "[year]-[month]-[day]T"
-                ))
-                .unwrap(),
-            )?;
-            write_color!(self.stdout, [set_bold])?;
-            write!(
-                self.stdout,
-                "{}",
-                t.format(&time::macros::format_description!(
-                    "[hour]:[minute]:[second]"
-                ))
-                .unwrap(),
-            )?;
-            write_color!(self.stdout, [set_dimmed])?;
-            write!(
-                self.stdout,
-                "{}",
-                t.format(&time::macros::format_description!(
-                    "+[offset_hour][offset_minute]  "
-                ))
-                .unwrap(),
-            )?;
         }
 
-        write_color!(self.stdout, (color)[])?;
-        write!(self.stdout, "{:<12}", head)?;
+        if self.opts.group_by_dir {
+            self.write_group(event)?;
+        }
+        let indent = if self.group.is_some() { 2 } else { 0 };
+        write!(self.stdout, "{:1$}", "", indent)?;
+
+        let time_width = indent
+            + if self.opts.need_time {
+                if let Some(offset) = self.opts.time_offset {
+                    t = t.to_offset(offset);
+                }
+                self.write_time(t)?
+            } else {
+                0
+            };
+        self.last_printed = Some(t);
+
+        let (head, style) = self.opts.theme.head_and_style(event);
+        self.stdout.set_color(style)?;
+        if self.opts.oneline {
+            write!(self.stdout, "{:<6}", abbreviate(event))?;
+        } else {
+            write!(self.stdout, "{:<12}", head)?;
+        }
 
         match event {
             Event::Create(path, file_type)
             | Event::Delete(path, file_type)
-            | Event::MoveAway(path, file_type)
-            | Event::MoveInto(path, file_type)
-            | Event::Modify(path, file_type)
+            | Event::MoveAway(path, file_type, _)
+            | Event::MoveInto(path, file_type, _)
+            | Event::Modify(path, file_type, _)
             | Event::Open(path, file_type)
             | Event::Close(path, file_type)
             | Event::Access(path, file_type)
             | Event::Attrib(path, file_type)
             | Event::Unmount(path, file_type) => {
-                let mut stripped_path = self.strip(path).to_owned();
+                let mut stripped_path = self.shorten(path);
                 if *file_type == FileType::Dir {
                     stripped_path = stripped_path.join("");
                 }
 
-                if self.opts.need_prefix {
+                if self.opts.need_prefix
+                    && !self.opts.oneline
+                    && self.group.is_none()
+                {
                     write_color!(self.stdout, [set_dimmed])?;
                     write!(
                         self.stdout,
@@ -153,18 +225,22 @@ impl<'a> Printer {
                     )?;
                 }
 
-                write_color!(self.stdout, (color)[])?;
+                self.stdout
+                    .set_color(&self.path_style(path, file_type, style))?;
                 write!(self.stdout, "{}", stripped_path.to_string_lossy())?;
             }
             Event::Move(from_path, to_path, file_type) => {
-                let mut stripped_from_path = self.strip(from_path).to_owned();
-                let mut stripped_to_path = self.strip(to_path).to_owned();
+                let mut stripped_from_path = self.shorten(from_path);
+                let mut stripped_to_path = self.shorten(to_path);
                 if *file_type == FileType::Dir {
                     stripped_from_path = stripped_from_path.join("");
                     stripped_to_path = stripped_to_path.join("");
                 }
 
-                if self.opts.need_prefix {
+                if self.opts.need_prefix
+                    && !self.opts.oneline
+                    && self.group.is_none()
+                {
                     write_color!(self.stdout, [set_dimmed])?;
                     write!(
                         self.stdout,
@@ -173,7 +249,9 @@ impl<'a> Printer {
                     )?;
                 }
 
-                write_color!(self.stdout, (color)[])?;
+                self.stdout.set_color(
+                    &self.path_style(from_path, file_type, style),
+                )?;
                 write!(
                     self.stdout,
                     "{}",
@@ -185,13 +263,14 @@ impl<'a> Printer {
                     write!(self.stdout, " → ")?;
                 } else {
                     writeln!(self.stdout)?;
-                    if self.opts.need_time {
-                        write!(self.stdout, "{:26}", "")?;
-                    }
+                    write!(self.stdout, "{:1$}", "", time_width)?;
                     write!(self.stdout, "{:<12}", "→")?;
                 }
 
-                if self.opts.need_prefix {
+                if self.opts.need_prefix
+                    && !self.opts.oneline
+                    && self.group.is_none()
+                {
                     write_color!(self.stdout, [set_dimmed])?;
                     write!(
                         self.stdout,
@@ -200,16 +279,28 @@ impl<'a> Printer {
                     )?;
                 }
 
-                write_color!(self.stdout, (color)[])?;
+                self.stdout
+                    .set_color(&self.path_style(to_path, file_type, style))?;
                 write!(self.stdout, "{}", stripped_to_path.to_string_lossy())?;
             }
-            Event::MoveTop(path)
+            Event::MoveTop(from, Some(to)) => {
+                write_color!(self.stdout, [set_dimmed])?;
+                write!(
+                    self.stdout,
+                    "{} → {}",
+                    from.to_string_lossy(),
+                    to.to_string_lossy()
+                )?;
+            }
+            Event::MoveTop(path, None)
             | Event::DeleteTop(path)
+            | Event::ReplaceTop(path)
             | Event::UnmountTop(path)
             | Event::AccessTop(path)
             | Event::AttribTop(path)
             | Event::OpenTop(path)
-            | Event::CloseTop(path) => {
+            | Event::CloseTop(path)
+            | Event::Reattached(path) => {
                 write_color!(self.stdout, [set_dimmed])?;
                 write!(self.stdout, "{}", path.to_string_lossy())?;
             }
@@ -218,33 +309,363 @@ impl<'a> Printer {
 
         write_color!(self.stdout, reset)?;
         writeln!(self.stdout)?;
+
+        if let Some(diff) = self.diffs.as_mut().and_then(|v| v.diff(event)) {
+            self.write_diff(&diff, time_width)?;
+        }
+        if let Some(processes) = processes {
+            for process in processes {
+                write!(self.stdout, "{:1$}", "", time_width)?;
+                write_color!(self.stdout, [set_dimmed])?;
+                write!(self.stdout, "pid {} ({})", process.pid, process.comm)?;
+                write_color!(self.stdout, reset)?;
+                writeln!(self.stdout)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn should(&mut self, path: &Path) -> bool {
-        if self.opts.timeout_modify.is_zero() {
-            true
-        } else if self.counter.lock().unwrap().contains(path) {
-            false
-        } else {
-            let timeout = self.opts.timeout_modify;
-            let path = path.to_owned();
-            let counter = Arc::clone(&self.counter);
-
-            counter.lock().unwrap().insert(path.to_owned());
-            tokio::spawn(async move {
-                tokio::time::sleep(timeout).await;
-                counter.lock().unwrap().remove(&path);
-            });
-            true
+    /// Writes the lines of the diff below the event, indented to its head.
+    fn write_diff(
+        &mut self,
+        diff: &str,
+        indent: usize,
+    ) -> Result<(), std::io::Error> {
+        let (added, removed) = self.opts.theme.diff_styles();
+        let (added, removed) = (added.clone(), removed.clone());
+        for line in diff.lines() {
+            write!(self.stdout, "{:1$}", "", indent)?;
+            if line.starts_with("+++")
+                || line.starts_with("---")
+                || line.starts_with("@@")
+            {
+                write_color!(self.stdout, [set_dimmed])?;
+            } else if line.starts_with('+') {
+                self.stdout.set_color(&added)?;
+            } else if line.starts_with('-') {
+                self.stdout.set_color(&removed)?;
+            }
+            write!(self.stdout, "{}", line)?;
+            write_color!(self.stdout, reset)?;
+            writeln!(self.stdout)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the directory of the event as a heading, unless the previous
+    /// event was in it. Events without a file end the group.
+    fn write_group(&mut self, event: &Event) -> Result<(), std::io::Error> {
+        let path = match event {
+            Event::Move(from, ..) => Some(from.as_path()),
+            _ => event.file_type().and(event.path()),
+        };
+        let dir = path.and_then(Path::parent).map(Path::to_owned);
+        if let Some(dir) =
+            dir.as_ref().filter(|v| self.group.as_ref() != Some(v))
+        {
+            let dir = self.strip(dir).join("");
+            if self.opts.need_prefix {
+                write_color!(self.stdout, [set_dimmed])?;
+                write!(
+                    self.stdout,
+                    "{}",
+                    self.opts.top_dir.to_string_lossy()
+                )?;
+            }
+            write_color!(self.stdout, [set_bold])?;
+            match dir.as_os_str().is_empty() {
+                true if !self.opts.need_prefix => write!(self.stdout, "./")?,
+                _ => write!(self.stdout, "{}", dir.to_string_lossy())?,
+            }
+            write_color!(self.stdout, reset)?;
+            writeln!(self.stdout)?;
+        }
+        self.group = dir;
+        Ok(())
+    }
+
+    /// Gives the path relative to the directory of the group, or else to
+    /// the top directory.
+    fn shorten(&self, path: &Path) -> PathBuf {
+        match &self.group {
+            Some(dir) if path.parent() == Some(dir) => {
+                path.file_name().unwrap().into()
+            }
+            _ => self.strip(path).to_owned(),
         }
     }
 
+    /// Counts the event for the directory which has the file.
+    fn count(&mut self, event: &Event) {
+        let path = match event {
+            Event::Move(_, to, _) => to,
+            _ => match event.path() {
+                Some(v) => v,
+                None => return,
+            },
+        };
+        let dir = match event.file_type() {
+            Some(_) => path.parent().unwrap_or(path),
+            None => path,
+        };
+        let dir = self.strip(dir).join("");
+        *self
+            .counts
+            .entry(dir)
+            .or_default()
+            .entry(EventKind::of(event))
+            .or_default() += 1;
+    }
+
+    /// Prints the counts of events since the last summary, a line for each
+    /// directory.
+    pub fn print_summary(&mut self) -> Result<(), std::io::Error> {
+        let t = match self.opts.time_offset {
+            Some(offset) => time::OffsetDateTime::now_utc().to_offset(offset),
+            None => time::OffsetDateTime::now_utc(),
+        };
+        if self.opts.clear.is_some() && !self.counts.is_empty() {
+            self.clear()?;
+        }
+        for (dir, counts) in std::mem::take(&mut self.counts) {
+            if self.opts.need_time {
+                self.write_time(t)?;
+            }
+            write_color!(self.stdout, [set_bold])?;
+            match dir.as_os_str().is_empty() {
+                true => write!(self.stdout, "./")?,
+                false => write!(self.stdout, "{}", dir.to_string_lossy())?,
+            }
+            write_color!(self.stdout, reset)?;
+            let counts: Vec<_> = counts
+                .iter()
+                .map(|(kind, count)| format!("{} {}", count, kind.name()))
+                .collect();
+            writeln!(self.stdout, ": {}", counts.join(", "))?;
+        }
+        self.last_printed = Some(t);
+        Ok(())
+    }
+
+    /// Clears the screen and the scrollback.
+    fn clear(&mut self) -> Result<(), std::io::Error> {
+        self.stdout.write_all(CLEAR)?;
+        self.group = None;
+        Ok(())
+    }
+
+    /// Writes the time and gives its width for aligning.
+    fn write_time(
+        &mut self,
+        t: time::OffsetDateTime,
+    ) -> Result<usize, std::io::Error> {
+        let time = match &self.opts.time_style {
+            TimeStyle::Format(format) => t.format(format).unwrap(),
+            TimeStyle::Epoch => t.unix_timestamp().to_string(),
+            TimeStyle::EpochMs => {
+                (t.unix_timestamp_nanos() / 1_000_000).to_string()
+            }
+            TimeStyle::Rfc3339 => t.format(&Rfc3339).unwrap(),
+            TimeStyle::Default if !self.opts.oneline => {
+                return self.write_full_time(t)
+            }
+            TimeStyle::Relative | TimeStyle::Default => {
+                let since = self.last_printed.unwrap_or(self.started);
+                format!(
+                    "{:>10}",
+                    format!("+{:.3}s", (t - since).as_seconds_f64())
+                )
+            }
+        };
+        write_color!(self.stdout, [set_dimmed])?;
+        write!(self.stdout, "{}  ", time)?;
+        Ok(time.chars().count() + 2)
+    }
+
+    fn write_full_time(
+        &mut self,
+        t: time::OffsetDateTime,
+    ) -> Result<usize, std::io::Error> {
+        write_color!(self.stdout, [set_dimmed])?;
+        write!(
+            self.stdout,
+            "{}",
+            t.format(&time::macros::format_description!(
+                "[year]-[month]-[day]T"
+            ))
+            .unwrap(),
+        )?;
+        write_color!(self.stdout, [set_bold])?;
+        write!(
+            self.stdout,
+            "{}",
+            t.format(&time::macros::format_description!(
+                "[hour]:[minute]:[second]"
+            ))
+            .unwrap(),
+        )?;
+        write_color!(self.stdout, [set_dimmed])?;
+        write!(
+            self.stdout,
+            "{}",
+            t.format(&time::macros::format_description!(
+                "+[offset_hour][offset_minute]  "
+            ))
+            .unwrap(),
+        )?;
+        Ok(26)
+    }
+
+    /// Filters out events which should not be printed.
+    pub fn accept(&mut self, event: &Event, t: time::OffsetDateTime) -> bool {
+        match event {
+            Event::Unknown
+            | Event::Noise
+            | Event::Ignored
+            | Event::WatchError(..)
+            | Event::Watching(_)
+            | Event::Remounted(_)
+            | Event::Unwatched(_)
+            | Event::WatchRemoved(_) => return false,
+            // Always shown, since the paths of later events follow it.
+            Event::MoveTop(_, Some(to)) => {
+                self.opts.top_dir = to.join("");
+                return true;
+            }
+            _ => {}
+        }
+        if self.opts.event_filter.iter().any(|e| e.contains(event)) {
+            return false;
+        }
+        if !self.opts.rules.allows(event, &self.opts.top_dir) {
+            return false;
+        }
+        self.throttle.allow(event, t)
+    }
+
+    /// Chooses the style of the path by the theme, by `LS_COLORS`, or
+    /// falls back to the style of the event.
+    fn path_style(
+        &self,
+        path: &Path,
+        file_type: &FileType,
+        event_style: &ColorSpec,
+    ) -> ColorSpec {
+        if let Some(v) = self.opts.theme.path_style(self.strip(path)) {
+            return v.to_owned();
+        }
+        self.opts
+            .ls_colors
+            .as_ref()
+            .and_then(|v| ls_colors::style(v, path, file_type))
+            .unwrap_or_else(|| event_style.to_owned())
+    }
+
+    /// Gives the top directory, which follows `Event::MoveTop`.
+    pub fn top_dir(&self) -> &Path {
+        &self.opts.top_dir
+    }
+
     pub fn strip(&self, path: &'a Path) -> &'a Path {
         path.strip_prefix(&self.opts.top_dir).unwrap()
     }
 }
 
+pub enum TimeStyle {
+    /// Full time with highlighted clock, or the time since the previous
+    /// printed event in the condensed format.
+    Default,
+    Format(OwnedFormatItem),
+    Epoch,
+    EpochMs,
+    Rfc3339,
+    /// Time since the previous printed event.
+    Relative,
+}
+
+/// Gives the short head of the condensed format.
+fn abbreviate(event: &Event) -> &'static str {
+    match event {
+        Event::Create(..) | Event::Reattached(..) => "new",
+        Event::Delete(..) | Event::DeleteTop(..) | Event::ReplaceTop(..) => {
+            "del"
+        }
+        Event::Move(..) | Event::MoveTop(..) => "mv",
+        Event::MoveAway(..) => "out",
+        Event::MoveInto(..) => "in",
+        Event::Modify(..) => "mod",
+        Event::Open(..) | Event::OpenTop(..) => "open",
+        Event::Close(..) | Event::CloseTop(..) => "close",
+        Event::Access(..) | Event::AccessTop(..) => "acc",
+        Event::Attrib(..) | Event::AttribTop(..) => "attr",
+        Event::Unmount(..) | Event::UnmountTop(..) => "umnt",
+        Event::Noise
+        | Event::Ignored
+        | Event::Unknown
+        | Event::WatchError(..)
+        | Event::Watching(_)
+        | Event::Remounted(_)
+        | Event::Unwatched(_)
+        | Event::WatchRemoved(_) => unreachable!(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EventKind {
+    Create,
+    Delete,
+    Move,
+    Modify,
+    Access,
+    Attrib,
+    Open,
+    Close,
+    Unmount,
+}
+
+impl EventKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Delete => "delete",
+            Self::Move => "move",
+            Self::Modify => "modify",
+            Self::Access => "access",
+            Self::Attrib => "attrib",
+            Self::Open => "open",
+            Self::Close => "close",
+            Self::Unmount => "unmount",
+        }
+    }
+
+    pub fn of(event: &Event) -> Self {
+        match event {
+            Event::Create(..) | Event::Reattached(..) => Self::Create,
+            Event::Delete(..)
+            | Event::DeleteTop(..)
+            | Event::ReplaceTop(..) => Self::Delete,
+            Event::Move(..)
+            | Event::MoveAway(..)
+            | Event::MoveInto(..)
+            | Event::MoveTop(..) => Self::Move,
+            Event::Modify(..) => Self::Modify,
+            Event::Access(..) | Event::AccessTop(..) => Self::Access,
+            Event::Attrib(..) | Event::AttribTop(..) => Self::Attrib,
+            Event::Open(..) | Event::OpenTop(..) => Self::Open,
+            Event::Close(..) | Event::CloseTop(..) => Self::Close,
+            Event::Unmount(..) | Event::UnmountTop(..) => Self::Unmount,
+            Event::Noise
+            | Event::Ignored
+            | Event::Unknown
+            | Event::WatchError(..)
+            | Event::Watching(_)
+            | Event::Remounted(_)
+            | Event::Unwatched(_)
+            | Event::WatchRemoved(_) => unreachable!(),
+        }
+    }
+}
+
 pub enum EventGroup {
     Create,
     Delete,
@@ -257,7 +678,12 @@ impl EventGroup {
         match self {
             Self::Create => matches!(event, Event::Create(..)),
             Self::Delete => {
-                matches!(event, Event::Delete(..) | Event::DeleteTop(..))
+                matches!(
+                    event,
+                    Event::Delete(..)
+                        | Event::DeleteTop(..)
+                        | Event::ReplaceTop(..)
+                )
             }
             Self::Move => matches!(
                 event,
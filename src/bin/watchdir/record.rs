@@ -0,0 +1,26 @@
+pub use watchdir::record::Record;
+
+/// Encoding of records.
+#[derive(Copy, Clone)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+pub fn encode(record: &Record, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => record.to_json().into_bytes(),
+        Encoding::Cbor => serde_cbor::to_vec(record).unwrap(),
+        Encoding::MessagePack => rmp_serde::to_vec_named(record).unwrap(),
+    }
+}
+
+/// Encodes the record, prefixed with its length in a big-endian u32.
+pub fn to_frame(record: &Record, encoding: Encoding) -> Vec<u8> {
+    let data = encode(record, encoding);
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&data);
+    frame
+}
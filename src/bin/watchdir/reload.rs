@@ -0,0 +1,64 @@
+use std::{path::Path, time::Duration};
+
+use futures::{pin_mut, StreamExt};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use watchdir::{Dotdir, Event, ExtraEvent, Watcher, WatcherOpts};
+
+/// Files in the config directory which are read again when changed.
+const FILES: [&str; 2] = ["config.yaml", "theme.yaml"];
+
+/// Editors write a file in several steps, so it is read again only after
+/// no change for this long.
+const QUIET: Duration = Duration::from_millis(200);
+
+/// Watches the files in the config directory, and sends what `load` gives
+/// each time they are changed. Errors of `load` are only logged.
+pub fn watch<T, F>(dir: &Path, load: F) -> mpsc::Receiver<T>
+where
+    T: Send + 'static,
+    F: Fn() -> Result<T, String> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(1);
+    let opts = WatcherOpts::new(Dotdir::Exclude, vec![ExtraEvent::Modify]);
+    let mut watcher = match Watcher::new(dir, opts) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Config is not watched: {}", e);
+            return rx;
+        }
+    };
+    tokio::spawn(async move {
+        let stream = watcher.stream();
+        pin_mut!(stream);
+        while let Some((event, _)) = stream.next().await {
+            if !is_config(&event) {
+                continue;
+            }
+            while let Ok(Some(_)) =
+                tokio::time::timeout(QUIET, stream.next()).await
+            {}
+            match load() {
+                Ok(v) => {
+                    if tx.send(v).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("Failed to reload: {}", e),
+            }
+        }
+    });
+    rx
+}
+
+/// Tells whether the event changes the content of a config file.
+fn is_config(event: &Event) -> bool {
+    let path = match event {
+        Event::Create(v, _)
+        | Event::MoveInto(v, ..)
+        | Event::Move(_, v, _)
+        | Event::Modify(v, ..) => v,
+        _ => return false,
+    };
+    path.file_name().is_some_and(|v| FILES.iter().any(|f| v == *f))
+}
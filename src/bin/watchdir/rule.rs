@@ -0,0 +1,196 @@
+use std::{os::unix::ffi::OsStrExt, path::Path, str::FromStr};
+
+use clap::ArgEnum;
+use globset::{Glob, GlobMatcher};
+use watchdir::{Event, FileType, Regex};
+
+use crate::{
+    cli::{Error, EventType},
+    print::EventKind,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    /// Paths relative to the top directory.
+    Glob(GlobMatcher),
+    /// Full paths as bytes.
+    Regex(Regex),
+    Event(EventKind),
+    Type(FileType),
+}
+
+/// Rule of `--rule`, e.g. `deny glob:target/**`.
+#[derive(Clone)]
+pub struct Rule {
+    action: Action,
+    matcher: Matcher,
+}
+
+/// Ordered rules. The first rule matching an event decides whether it
+/// passes, and events matching none pass.
+#[derive(Clone, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self(rules)
+    }
+
+    pub fn allows(&self, event: &Event, top_dir: &Path) -> bool {
+        self.0
+            .iter()
+            .find(|v| v.matches(event, top_dir))
+            .is_none_or(|v| v.action == Action::Allow)
+    }
+}
+
+impl Rule {
+    /// A move matches a rule of paths if either path does.
+    fn matches(&self, event: &Event, top_dir: &Path) -> bool {
+        let paths = match event {
+            Event::Move(from, to, _) => vec![from.as_path(), to],
+            _ => event.path().into_iter().collect(),
+        };
+        match &self.matcher {
+            Matcher::Glob(glob) => paths.iter().any(|v| {
+                v.strip_prefix(top_dir).is_ok_and(|v| glob.is_match(v))
+            }),
+            Matcher::Regex(regex) => {
+                paths.iter().any(|v| regex.is_match(v.as_os_str().as_bytes()))
+            }
+            Matcher::Event(kind) => EventKind::of(event) == *kind,
+            Matcher::Type(file_type) => event.file_type() == Some(file_type),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, matcher) =
+            s.trim().split_once(' ').ok_or(Error::InvalidRule)?;
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(Error::InvalidRule),
+        };
+        let (kind, pattern) =
+            matcher.trim().split_once(':').ok_or(Error::InvalidRule)?;
+        let matcher = match kind {
+            "glob" => Matcher::Glob(
+                Glob::new(pattern)
+                    .map_err(|_| Error::InvalidRule)?
+                    .compile_matcher(),
+            ),
+            "regex" => Matcher::Regex(
+                Regex::new(pattern).map_err(|_| Error::InvalidRule)?,
+            ),
+            "event" => Matcher::Event(
+                EventType::from_str(pattern, true)
+                    .map_err(|_| Error::InvalidRule)?
+                    .into(),
+            ),
+            "type" => Matcher::Type(match pattern {
+                "file" => FileType::File,
+                "dir" => FileType::Dir,
+                _ => return Err(Error::InvalidRule),
+            }),
+            _ => return Err(Error::InvalidRule),
+        };
+        Ok(Self { action, matcher })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Rules {
+        Rules::new(rules.iter().map(|v| v.parse().unwrap()).collect())
+    }
+
+    fn create(path: &str, file_type: FileType) -> Event {
+        Event::Create(PathBuf::from(path), file_type)
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let top_dir = Path::new("/top");
+        let event = create("/top/a.log", FileType::File);
+        let deny_first = rules(&["deny glob:*.log", "allow glob:*.log"]);
+        assert!(!deny_first.allows(&event, top_dir));
+        let allow_first = rules(&["allow glob:*.log", "deny glob:*.log"]);
+        assert!(allow_first.allows(&event, top_dir));
+        // Events matching no rule pass.
+        assert!(
+            deny_first.allows(&create("/top/a.txt", FileType::File), top_dir)
+        );
+    }
+
+    #[test]
+    fn test_deny_except() {
+        let top_dir = Path::new("/top");
+        let rules =
+            rules(&["allow glob:target/doc/**", "deny glob:target/**"]);
+        let allows =
+            |path| rules.allows(&create(path, FileType::File), top_dir);
+        assert!(allows("/top/target/doc/index.html"));
+        assert!(!allows("/top/target/debug/watchdir"));
+        assert!(allows("/top/src/main.rs"));
+        // Globs match paths relative to the top directory.
+        assert!(allows("/other/target/debug/watchdir"));
+    }
+
+    #[test]
+    fn test_move_matches_either_path() {
+        let top_dir = Path::new("/top");
+        let rules = rules(&["deny glob:target/**"]);
+        let moves = |from: &str, to: &str| {
+            let event = Event::Move(
+                PathBuf::from(from),
+                PathBuf::from(to),
+                FileType::File,
+            );
+            rules.allows(&event, top_dir)
+        };
+        assert!(!moves("/top/src/a", "/top/target/a"));
+        assert!(!moves("/top/target/a", "/top/src/a"));
+        assert!(moves("/top/src/a", "/top/src/b"));
+    }
+
+    #[test]
+    fn test_event_and_type() {
+        let top_dir = Path::new("/top");
+        let rules = rules(&["deny event:modify", "deny type:dir"]);
+        let modify =
+            Event::Modify(PathBuf::from("/top/a"), FileType::File, None);
+        assert!(!rules.allows(&modify, top_dir));
+        assert!(!rules.allows(&create("/top/b", FileType::Dir), top_dir));
+        assert!(rules.allows(&create("/top/b", FileType::File), top_dir));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for rule in [
+            "deny",
+            "deny target/**",
+            "ignore glob:target/**",
+            "deny path:target/**",
+            "deny event:rename",
+            "deny type:link",
+            "deny regex:(",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{}", rule);
+        }
+        assert!("  allow  regex:^/top/.*\\.rs$".parse::<Rule>().is_ok());
+    }
+}
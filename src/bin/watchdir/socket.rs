@@ -0,0 +1,126 @@
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::UnixStream,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, warn};
+use watchdir::group::Grouping;
+
+use crate::{
+    record::{to_frame, Encoding, Record},
+    TimedEvent,
+};
+
+/// Messages kept while the consumer is absent. The oldest ones are dropped
+/// beyond this.
+const MAX_QUEUED: usize = 4096;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes events to a Unix socket, each one encoded and prefixed with its
+/// length in a big-endian u32.
+pub struct SocketOutput {
+    path: PathBuf,
+    encoding: Encoding,
+    rx: broadcast::Receiver<TimedEvent>,
+    queue: VecDeque<Vec<u8>>,
+    grouping: Option<Grouping>,
+}
+
+impl SocketOutput {
+    pub fn new(
+        path: PathBuf,
+        encoding: Encoding,
+        rx: broadcast::Receiver<TimedEvent>,
+        group_events: Option<Duration>,
+    ) -> Self {
+        Self {
+            path,
+            encoding,
+            rx,
+            queue: VecDeque::new(),
+            grouping: group_events.map(Grouping::new),
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            match UnixStream::connect(&self.path).await {
+                Ok(stream) => {
+                    debug!("Connected to {}", self.path.display());
+                    if let Err(e) = self.deliver(stream).await {
+                        warn!(
+                            "Failed to write to {}: {}",
+                            self.path.display(),
+                            e
+                        );
+                    }
+                }
+                Err(e) => {
+                    debug!(
+                        "Failed to connect to {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+            }
+            if !self.wait_to_reconnect().await {
+                return;
+            }
+        }
+    }
+
+    /// Writes queued and new messages until the connection fails.
+    async fn deliver(
+        &mut self,
+        mut stream: UnixStream,
+    ) -> std::io::Result<()> {
+        loop {
+            while let Some(message) = self.queue.pop_front() {
+                if let Err(e) = stream.write_all(&message).await {
+                    self.queue.push_front(message);
+                    return Err(e);
+                }
+            }
+            match self.rx.recv().await {
+                Ok((event, t)) => self.enqueue(&event, t),
+                Err(RecvError::Lagged(n)) => {
+                    warn!(
+                        "Socket output is too slow, {} events are skipped",
+                        n
+                    )
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    /// Queues new messages for a while. Gives false if no more events will
+    /// come.
+    async fn wait_to_reconnect(&mut self) -> bool {
+        let sleep = tokio::time::sleep(RECONNECT_INTERVAL);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                event = self.rx.recv() => match event {
+                    Ok((event, t)) => self.enqueue(&event, t),
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return false,
+                },
+            }
+        }
+    }
+
+    fn enqueue(&mut self, event: &watchdir::Event, t: time::OffsetDateTime) {
+        if let Some(mut record) = Record::new(event, t) {
+            record.group = self.grouping.as_mut().map(|v| v.assign(event, t));
+            let message = to_frame(&record, self.encoding);
+            if self.queue.len() >= MAX_QUEUED {
+                self.queue.pop_front();
+            }
+            self.queue.push_back(message);
+        }
+    }
+}
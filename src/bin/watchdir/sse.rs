@@ -0,0 +1,78 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, warn};
+
+use crate::{http, record::Record, Events, TimedEvent};
+
+/// Streams events as Server-Sent Events at `/events`. Every client gets
+/// the events from the moment it connects.
+pub struct SseServer {
+    listener: TcpListener,
+    events: Events,
+}
+
+impl SseServer {
+    pub async fn bind(addr: SocketAddr, events: Events) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, events })
+    }
+
+    pub async fn serve(self) {
+        loop {
+            let (mut stream, peer) = match self.listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to accept SSE connection: {}", e);
+                    continue;
+                }
+            };
+            debug!("SSE connection from {}", peer);
+            let rx = self.events.subscribe();
+            tokio::spawn(async move {
+                if let Err(e) = handle(&mut stream, rx).await {
+                    debug!("SSE connection from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    stream: &mut TcpStream,
+    mut rx: broadcast::Receiver<TimedEvent>,
+) -> io::Result<()> {
+    let request = http::read_request(stream).await?;
+    if request.method != "GET" || request.path != "/events" {
+        return http::respond_not_found(stream).await;
+    }
+
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n",
+        )
+        .await?;
+    loop {
+        let (event, t) = match rx.recv().await {
+            Ok(v) => v,
+            Err(RecvError::Lagged(n)) => {
+                warn!("SSE client is too slow, {} events are skipped", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if let Some(record) = Record::new(&event, t) {
+            let message = format!(
+                "event: {}\ndata: {}\n\n",
+                record.kind,
+                record.to_json()
+            );
+            stream.write_all(message.as_bytes()).await?;
+        }
+    }
+}
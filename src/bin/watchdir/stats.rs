@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use time::OffsetDateTime;
+use watchdir::{Event, Stats};
+
+/// Paths with more events than the others are kept when there are more
+/// than this.
+const PRUNE_THRESHOLD: usize = 65536;
+
+/// Busiest paths and the peak rate of the received events.
+#[derive(Default)]
+pub struct Activity {
+    paths: HashMap<PathBuf, u64>,
+    second: i64,
+    in_second: u64,
+    peak: u64,
+}
+
+impl Activity {
+    pub fn add(&mut self, event: &Event, t: OffsetDateTime) {
+        if t.unix_timestamp() == self.second {
+            self.in_second += 1;
+        } else {
+            self.second = t.unix_timestamp();
+            self.in_second = 1;
+        }
+        self.peak = self.peak.max(self.in_second);
+
+        let path = match event {
+            Event::Move(_, to, _) => to,
+            _ => match event.path() {
+                Some(v) => v,
+                None => return,
+            },
+        };
+        *self.paths.entry(path.to_owned()).or_default() += 1;
+        if self.paths.len() > PRUNE_THRESHOLD {
+            self.paths.retain(|_, count| *count > 1);
+        }
+    }
+
+    /// Gives the paths with the most events, the busiest first.
+    pub fn busiest(&self, n: usize) -> Vec<(&Path, u64)> {
+        let mut paths: Vec<_> =
+            self.paths.iter().map(|(k, v)| (k.as_path(), *v)).collect();
+        paths.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        paths.truncate(n);
+        paths
+    }
+
+    /// Most events in a second.
+    pub fn peak(&self) -> u64 {
+        self.peak
+    }
+}
+
+/// Line of statistics logged periodically.
+pub fn format(stats: &Stats, activity: &Activity) -> String {
+    let events: Vec<String> = stats
+        .events
+        .iter()
+        .map(|(name, count)| format!("{}={}", name, count))
+        .collect();
+    format!(
+        "Watches: {}, depth: {}, overflows: {}, dropped: {}, peak: {}/s, \
+         events: [{}]",
+        stats.watches,
+        stats.depth,
+        stats.overflows,
+        stats.dropped,
+        activity.peak(),
+        events.join(", ")
+    )
+}
+
+/// Lines of the summary when exiting.
+pub fn report(
+    stats: &Stats,
+    activity: &Activity,
+    runtime: Duration,
+) -> Vec<String> {
+    let total: u64 = stats.events.values().sum();
+    let busiest: Vec<String> = activity
+        .busiest(5)
+        .iter()
+        .map(|(path, count)| format!("{} ({})", path.display(), count))
+        .collect();
+    vec![
+        format!(
+            "Ran for {:.1?}, {} events, peak {} events per second",
+            runtime,
+            total,
+            activity.peak()
+        ),
+        format(stats, activity),
+        format!("Busiest paths: {}", busiest.join(", ")),
+    ]
+}
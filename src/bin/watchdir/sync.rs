@@ -0,0 +1,451 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    os::unix::{ffi::OsStrExt, fs as unix_fs},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+use watchdir::{Event, FileType};
+
+use crate::TimedEvent;
+
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The top directory and the destination.
+type Dirs = Arc<(PathBuf, PathBuf)>;
+
+/// What to do in the destination, with paths relative to both directories.
+#[derive(Debug)]
+enum Action {
+    /// Copies a file, or a directory with its content, unless the copy
+    /// already has the same size and checksum.
+    Copy(PathBuf),
+    Remove(PathBuf),
+    /// Renames in the destination, or copies the new path if the old one
+    /// is not there.
+    Rename(PathBuf, PathBuf),
+    /// Copies the path like `Copy`, and removes what the destination has
+    /// but the top directory does not.
+    Sync(PathBuf),
+}
+
+/// Mirrors the changes of the top directory into another one, like
+/// `rsync --checksum`. Actions run on `jobs` workers with retries, and
+/// those of paths under the same entry of the top directory run in order
+/// on one worker. Events must not be filtered before, since a missed one
+/// leaves the destination behind, and the whole directory is synced again
+/// when the receiver lags.
+pub struct Mirror {
+    top_dir: PathBuf,
+    /// Sent with each action, since the top directory may move.
+    dirs: Dirs,
+    workers: Vec<mpsc::UnboundedSender<(Dirs, Action)>>,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl Mirror {
+    pub fn new(
+        top_dir: PathBuf,
+        dest: PathBuf,
+        jobs: usize,
+        rx: broadcast::Receiver<TimedEvent>,
+    ) -> Self {
+        let dirs = Arc::new((top_dir.clone(), dest));
+        let workers = (0..jobs.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(work(rx));
+                tx
+            })
+            .collect();
+        Self { top_dir, dirs, workers, rx }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            match self.rx.recv().await {
+                Ok((event, _)) => {
+                    if let Some(to) = event.moved_top() {
+                        self.top_dir = to.to_owned();
+                        self.dirs =
+                            Arc::new((to.to_owned(), self.dirs.1.clone()));
+                    }
+                    if let Some(action) = self.action(&event) {
+                        self.dispatch(action);
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Syncing is too slow, {} events are skipped", n);
+                    self.resync().await;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Syncs each entry of the top directory or of the destination.
+    async fn resync(&self) {
+        let dirs = self.dirs.clone();
+        let names = tokio::task::spawn_blocking(move || {
+            let mut names = BTreeSet::new();
+            for dir in [&dirs.0, &dirs.1] {
+                match fs::read_dir(dir) {
+                    Ok(entries) => names.extend(
+                        entries.filter_map(|v| Some(v.ok()?.file_name())),
+                    ),
+                    Err(e) => warn!("Failed to read {}: {}", dir.display(), e),
+                }
+            }
+            names
+        })
+        .await
+        .unwrap();
+        for name in names {
+            self.dispatch(Action::Sync(name.into()));
+        }
+    }
+
+    fn action(&self, event: &Event) -> Option<Action> {
+        let relative = |v: &Path| {
+            v.strip_prefix(&self.top_dir)
+                .ok()
+                .filter(|v| v.components().next().is_some())
+                .map(Path::to_path_buf)
+        };
+        Some(match event {
+            Event::Create(path, _)
+            | Event::MoveInto(path, ..)
+            | Event::Modify(path, FileType::File, _)
+            | Event::Close(path, FileType::File) => {
+                Action::Copy(relative(path)?)
+            }
+            Event::Delete(path, _) | Event::MoveAway(path, ..) => {
+                Action::Remove(relative(path)?)
+            }
+            Event::Move(from, to, _) => match (relative(from), relative(to)) {
+                (Some(from), Some(to)) => Action::Rename(from, to),
+                (None, Some(to)) => Action::Copy(to),
+                (Some(from), None) => Action::Remove(from),
+                (None, None) => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Gives actions to workers by the first component of their paths. A
+    /// rename between two workers is split into a copy and a removal.
+    fn dispatch(&self, action: Action) {
+        let send = |worker: usize, action| {
+            let _ = self.workers[worker].send((self.dirs.clone(), action));
+        };
+        match action {
+            Action::Rename(from, to) => {
+                let (a, b) = (self.worker(&from), self.worker(&to));
+                if a == b {
+                    send(a, Action::Rename(from, to));
+                } else {
+                    send(b, Action::Copy(to));
+                    send(a, Action::Remove(from));
+                }
+            }
+            Action::Copy(ref path)
+            | Action::Remove(ref path)
+            | Action::Sync(ref path) => send(self.worker(path), action),
+        }
+    }
+
+    fn worker(&self, path: &Path) -> usize {
+        let mut hasher = DefaultHasher::new();
+        path.components().next().hash(&mut hasher);
+        hasher.finish() as usize % self.workers.len()
+    }
+}
+
+async fn work(mut rx: mpsc::UnboundedReceiver<(Dirs, Action)>) {
+    while let Some((dirs, action)) = rx.recv().await {
+        let action = Arc::new(action);
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let (dirs, shared) = (dirs.clone(), action.clone());
+            let result = tokio::task::spawn_blocking(move || {
+                apply(&dirs.0, &dirs.1, &shared)
+            })
+            .await
+            .unwrap();
+            match result {
+                Ok(()) => break,
+                Err(e) if attempt == MAX_ATTEMPTS => {
+                    warn!("Failed to sync {:?}: {}", action, e)
+                }
+                Err(e) => {
+                    debug!("Retrying to sync {:?}: {}", action, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+fn apply(top_dir: &Path, dest: &Path, action: &Action) -> io::Result<()> {
+    match action {
+        Action::Copy(path) => copy(&top_dir.join(path), &dest.join(path)),
+        Action::Remove(path) => remove(&dest.join(path)),
+        Action::Rename(from, to) => {
+            match fs::rename(dest.join(from), dest.join(to)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    copy(&top_dir.join(to), &dest.join(to))
+                }
+                result => result,
+            }
+        }
+        Action::Sync(path) => sync(&top_dir.join(path), &dest.join(path)),
+    }
+}
+
+fn sync(src: &Path, dest: &Path) -> io::Result<()> {
+    if let Err(e) = fs::symlink_metadata(src) {
+        return match e.kind() {
+            io::ErrorKind::NotFound => remove(dest),
+            _ => Err(e),
+        };
+    }
+    copy(src, dest)?;
+    let mut entries = WalkDir::new(dest).min_depth(1).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let path = src.join(entry.path().strip_prefix(dest).unwrap());
+        match fs::symlink_metadata(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                remove(entry.path())?;
+                if entry.file_type().is_dir() {
+                    entries.skip_current_dir();
+                }
+            }
+            result => {
+                result?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A source gone in the meantime is left to its later event.
+fn copy(src: &Path, dest: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(src) {
+        let entry = match entry {
+            Ok(v) => v,
+            Err(e)
+                if e.io_error().map(io::Error::kind)
+                    == Some(io::ErrorKind::NotFound) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let target = match entry.path().strip_prefix(src).unwrap() {
+            v if v.as_os_str().is_empty() => dest.to_owned(),
+            v => dest.join(v),
+        };
+        if entry.file_type().is_dir() {
+            match fs::symlink_metadata(&target) {
+                Ok(v) if v.is_dir() => {}
+                Ok(_) => {
+                    remove(&target)?;
+                    fs::create_dir_all(&target)?;
+                }
+                Err(_) => fs::create_dir_all(&target)?,
+            }
+        } else {
+            match copy_file(entry.path(), &target) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn copy_file(src: &Path, dest: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    if let Ok(v) = fs::symlink_metadata(dest) {
+        if v.file_type() == metadata.file_type()
+            && v.len() == metadata.len()
+            && checksum(src)? == checksum(dest)?
+        {
+            return Ok(());
+        }
+        if v.is_dir() {
+            remove(dest)?;
+        }
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Copied beside and renamed, so the destination never has a partial
+    // file.
+    let mut name = dest.file_name().unwrap_or_default().to_owned();
+    name.push(".watchdir-tmp");
+    let tmp = dest.with_file_name(name);
+    if metadata.file_type().is_symlink() {
+        let _ = fs::remove_file(&tmp);
+        unix_fs::symlink(fs::read_link(src)?, &tmp)?;
+    } else {
+        fs::copy(src, &tmp)?;
+        fs::File::options()
+            .write(true)
+            .open(&tmp)?
+            .set_modified(metadata.modified()?)?;
+    }
+    fs::rename(&tmp, dest)
+}
+
+/// Hashes the content of a file, or the target of a symlink.
+fn checksum(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        hasher.update(fs::read_link(path)?.as_os_str().as_bytes());
+    } else {
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    }
+    Ok(hasher.finalize())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(v) if v.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let (top_dir, dest) =
+            (tmp.path().join("top"), tmp.path().join("dest"));
+        fs::create_dir_all(top_dir.join("a")).unwrap();
+        fs::create_dir(&dest).unwrap();
+        (tmp, top_dir, dest)
+    }
+
+    #[test]
+    fn test_copy_by_checksum() {
+        let (_tmp, top_dir, dest) = dirs();
+        fs::write(top_dir.join("a/file"), "new").unwrap();
+        apply(&top_dir, &dest, &Action::Copy("a".into())).unwrap();
+        assert_eq!(fs::read(dest.join("a/file")).unwrap(), b"new");
+
+        // Same size and time, but another content.
+        let modified = fs::metadata(top_dir.join("a/file")).unwrap();
+        fs::write(top_dir.join("a/file"), "old").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(top_dir.join("a/file"))
+            .unwrap()
+            .set_modified(modified.modified().unwrap())
+            .unwrap();
+        apply(&top_dir, &dest, &Action::Copy("a/file".into())).unwrap();
+        assert_eq!(fs::read(dest.join("a/file")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_sync() {
+        let (_tmp, top_dir, dest) = dirs();
+        fs::write(top_dir.join("a/kept"), "").unwrap();
+        fs::create_dir_all(dest.join("a/gone")).unwrap();
+        fs::write(dest.join("a/gone/file"), "").unwrap();
+        fs::write(dest.join("b"), "").unwrap();
+
+        apply(&top_dir, &dest, &Action::Sync("a".into())).unwrap();
+        apply(&top_dir, &dest, &Action::Sync("b".into())).unwrap();
+        assert!(dest.join("a/kept").exists());
+        assert!(!dest.join("a/gone").exists());
+        assert!(!dest.join("b").exists());
+    }
+
+    #[tokio::test]
+    async fn test_resync_after_lag() {
+        let (_tmp, top_dir, dest) = dirs();
+        fs::write(top_dir.join("a/file"), "").unwrap();
+        fs::write(dest.join("gone"), "").unwrap();
+        let (tx, rx) = broadcast::channel(1);
+        let mirror = Mirror::new(top_dir.to_owned(), dest.to_owned(), 2, rx);
+        let now = time::OffsetDateTime::now_utc();
+        for _ in 0..2 {
+            tx.send((Event::Noise, now)).unwrap();
+        }
+        drop(tx);
+        mirror.run().await;
+
+        for _ in 0..100 {
+            if dest.join("a/file").exists() && !dest.join("gone").exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Not synced after lagging");
+    }
+
+    #[tokio::test]
+    async fn test_follow_top() {
+        let (tmp, top_dir, dest) = dirs();
+        let (tx, rx) = broadcast::channel(4);
+        let mirror = Mirror::new(top_dir.to_owned(), dest.to_owned(), 1, rx);
+        let new_top_dir = tmp.path().join("new");
+        fs::rename(&top_dir, &new_top_dir).unwrap();
+        fs::write(new_top_dir.join("a/file"), "").unwrap();
+        let now = time::OffsetDateTime::now_utc();
+        for event in [
+            Event::MoveTop(top_dir, Some(new_top_dir.to_owned())),
+            Event::Create(new_top_dir.join("a/file"), FileType::File),
+        ] {
+            tx.send((event, now)).unwrap();
+        }
+        drop(tx);
+        mirror.run().await;
+
+        for _ in 0..100 {
+            if dest.join("a/file").exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Not synced after moving the top directory");
+    }
+
+    #[test]
+    fn test_action_of_close() {
+        let (_tx, rx) = broadcast::channel(1);
+        let mirror = Mirror {
+            top_dir: "/top".into(),
+            dirs: Arc::new(("/top".into(), "/dest".into())),
+            workers: Vec::new(),
+            rx,
+        };
+        let close = Event::Close("/top/a".into(), FileType::File);
+        assert!(
+            matches!(mirror.action(&close), Some(Action::Copy(v)) if v == Path::new("a"))
+        );
+        let close = Event::Close("/top/a".into(), FileType::Dir);
+        assert!(mirror.action(&close).is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use globset::GlobSet;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+use walkdir::WalkDir;
+use watchdir::{Event, FileType};
+
+use crate::TimedEvent;
+
+/// Most bytes given for one event. Older appended bytes are skipped.
+const MAX_APPENDED: u64 = 64 * 1024;
+
+/// Follows the bytes appended to files matching the globs, relative to the
+/// top directory, like `tail -F`.
+pub struct Tail {
+    top_dir: PathBuf,
+    globs: GlobSet,
+    /// Sizes of the files by the last events.
+    offsets: HashMap<PathBuf, u64>,
+}
+
+impl Tail {
+    /// Files already there are followed from their ends.
+    pub fn new(top_dir: &Path, globs: GlobSet) -> Self {
+        let mut tail = Self {
+            top_dir: top_dir.to_owned(),
+            globs,
+            offsets: HashMap::new(),
+        };
+        for entry in WalkDir::new(top_dir).into_iter().flatten() {
+            if entry.file_type().is_file() && tail.matches(entry.path()) {
+                if let Ok(metadata) = entry.metadata() {
+                    tail.offsets
+                        .insert(entry.path().to_owned(), metadata.len());
+                }
+            }
+        }
+        tail
+    }
+
+    /// Gives the bytes appended to the file of a `Modify` event since the
+    /// last one. New files are followed from their starts, and so are
+    /// truncated ones again.
+    pub fn appended(&mut self, event: &Event) -> Option<Vec<u8>> {
+        match event {
+            Event::Modify(path, FileType::File, _) if self.matches(path) => {
+                let mut file = fs::File::open(path).ok()?;
+                let len = file.metadata().ok()?.len();
+                let offset = self
+                    .offsets
+                    .insert(path.to_owned(), len)
+                    .filter(|v| *v <= len)
+                    .unwrap_or(0);
+                let start = offset.max(len.saturating_sub(MAX_APPENDED));
+                if start >= len {
+                    return None;
+                }
+                file.seek(SeekFrom::Start(start)).ok()?;
+                let mut appended = Vec::new();
+                file.take(len - start).read_to_end(&mut appended).ok()?;
+                Some(appended)
+            }
+            Event::Create(path, FileType::File)
+            | Event::MoveInto(path, FileType::File, _)
+                if self.matches(path) =>
+            {
+                self.offsets.insert(path.to_owned(), 0);
+                None
+            }
+            Event::Move(from, to, FileType::File) => {
+                let offset = self.offsets.remove(from);
+                if self.matches(to) {
+                    self.offsets.insert(to.to_owned(), offset.unwrap_or(0));
+                }
+                None
+            }
+            Event::Delete(path, _) | Event::MoveAway(path, ..) => {
+                self.offsets.remove(path);
+                None
+            }
+            Event::MoveTop(from, Some(to)) => {
+                self.offsets = std::mem::take(&mut self.offsets)
+                    .into_iter()
+                    .filter_map(|(k, v)| {
+                        Some((to.join(k.strip_prefix(from).ok()?), v))
+                    })
+                    .collect();
+                self.top_dir = to.to_owned();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.top_dir).is_ok_and(|v| self.globs.is_match(v))
+    }
+}
+
+/// Prints the bytes appended to files on stdout, with a header for each
+/// file like `tail -F`. It takes all events, so that modify events are
+/// not printed for it.
+pub struct TailOutput {
+    top_dir: PathBuf,
+    globs: GlobSet,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl TailOutput {
+    pub fn new(
+        top_dir: PathBuf,
+        globs: GlobSet,
+        rx: broadcast::Receiver<TimedEvent>,
+    ) -> Self {
+        Self { top_dir, globs, rx }
+    }
+
+    pub async fn run(mut self) {
+        let (top_dir, globs) = (self.top_dir, self.globs);
+        // Events are queued in the receiver during the scan.
+        let mut tail =
+            tokio::task::spawn_blocking(move || Tail::new(&top_dir, globs))
+                .await
+                .unwrap();
+        let mut headers = Headers::default();
+        loop {
+            let event = match self.rx.recv().await {
+                Ok((event, _)) => event,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Tailing is too slow, {} events are skipped", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if let (Some(bytes), Some(path)) =
+                (tail.appended(&event), event.path())
+            {
+                let path = path.strip_prefix(&tail.top_dir).unwrap_or(path);
+                let chunk = headers.chunk(path, &bytes);
+                let mut stdout = std::io::stdout().lock();
+                let _ = stdout.write_all(&chunk).and_then(|_| stdout.flush());
+            }
+        }
+    }
+}
+
+/// Puts a header before the bytes of a file other than the last one.
+#[derive(Default)]
+struct Headers {
+    last: Option<PathBuf>,
+    /// Whether the last bytes ended a line.
+    line_ended: bool,
+}
+
+impl Headers {
+    fn chunk(&mut self, path: &Path, bytes: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        if self.last.as_deref() != Some(path) {
+            if self.last.is_some() {
+                if !self.line_ended {
+                    chunk.push(b'\n');
+                }
+                chunk.push(b'\n');
+            }
+            chunk.extend_from_slice(
+                format!("==> {} <==\n", path.display()).as_bytes(),
+            );
+            self.last = Some(path.to_owned());
+        }
+        chunk.extend_from_slice(bytes);
+        self.line_ended = bytes.ends_with(b"\n");
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use globset::{Glob, GlobSetBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_follow_top() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (top_dir, new_top_dir) =
+            (tmp.path().join("a"), tmp.path().join("b"));
+        fs::create_dir(&top_dir).unwrap();
+        fs::write(top_dir.join("log"), "old\n").unwrap();
+        let globs = GlobSetBuilder::new()
+            .add(Glob::new("log").unwrap())
+            .build()
+            .unwrap();
+        let mut tail = Tail::new(&top_dir, globs);
+
+        fs::rename(&top_dir, &new_top_dir).unwrap();
+        let event = Event::MoveTop(top_dir, Some(new_top_dir.to_owned()));
+        assert_eq!(tail.appended(&event), None);
+        let path = new_top_dir.join("log");
+        fs::write(&path, "old\nnew\n").unwrap();
+        let event = Event::Modify(path, FileType::File, None);
+        assert_eq!(tail.appended(&event).unwrap(), b"new\n");
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut headers = Headers::default();
+        let (a, b) = (Path::new("a"), Path::new("b"));
+        assert_eq!(headers.chunk(a, b"1\n"), b"==> a <==\n1\n");
+        assert_eq!(headers.chunk(a, b"2"), b"2");
+        assert_eq!(headers.chunk(b, b"3\n"), b"\n\n==> b <==\n3\n");
+        assert_eq!(headers.chunk(a, b"4\n"), b"\n==> a <==\n4\n");
+    }
+}
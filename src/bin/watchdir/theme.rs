@@ -1,84 +1,234 @@
-use std::str::FromStr;
+use std::{convert::TryFrom, fmt, path::Path, str::FromStr};
 
+use globset::{Glob, GlobMatcher};
 use serde::{de, Deserialize, Deserializer};
+use termcolor::ColorSpec;
 
 use crate::Event;
 
+/// Terminal color from a name, an ANSI 256 color code, `R,G,B` or
+/// `#RRGGBB`.
 struct Color(termcolor::Color);
 
+/// Style from a color, or from a spec of colors and effects.
+struct Style(ColorSpec);
+
 #[derive(Deserialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "PascalCase")]
 pub struct Theme {
-    create: Color,
-    delete: Color,
-    r#move: Color,
-    move_away: Color,
-    move_into: Color,
-    modify: Color,
-    open: Color,
-    close: Color,
-    access: Color,
-    attrib: Color,
-    umount: Color,
+    create: Style,
+    delete: Style,
+    r#move: Style,
+    move_away: Style,
+    move_into: Style,
+    modify: Style,
+    open: Style,
+    close: Style,
+    access: Style,
+    attrib: Style,
+    umount: Style,
+    paths: Vec<PathStyle>,
+}
+
+/// Style of paths matching the glob. It overrides the style of the event.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "PascalCase")]
+struct PathStyle {
+    glob: Matcher,
+    style: Style,
+}
+
+struct Matcher(GlobMatcher);
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "PascalCase")]
+struct Spec {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    dimmed: bool,
 }
 
 impl Theme {
-    pub fn head_and_color(
-        &self,
-        event: &Event,
-    ) -> (&'static str, termcolor::Color) {
-        match event {
-            Event::Create(..) => ("Create", self.create.0),
-            Event::Delete(..) => ("Delete", self.delete.0),
-            Event::Move(..) => ("Move", self.r#move.0),
-            Event::MoveAway(..) => ("MoveAway", self.move_away.0),
-            Event::MoveInto(..) => ("MoveInto", self.move_into.0),
-            Event::Modify(..) => ("Modify", self.modify.0),
-            Event::Open(..) => ("Open", self.open.0),
-            Event::OpenTop(..) => ("Open", self.open.0),
-            Event::Close(..) => ("Close", self.close.0),
-            Event::CloseTop(..) => ("Close", self.close.0),
-            Event::Access(..) => ("Access", self.access.0),
-            Event::AccessTop(..) => ("Access", self.access.0),
-            Event::Attrib(..) => ("Attrib", self.attrib.0),
-            Event::AttribTop(..) => ("Attrib", self.attrib.0),
-            Event::MoveTop(..) => ("MoveTop", self.r#move.0),
-            Event::DeleteTop(..) => ("DeleteTop", self.delete.0),
-            Event::Unmount(..) => ("Unmount", self.umount.0),
-            Event::UnmountTop(..) => ("UnmountTop", self.umount.0),
-            Event::Unknown | Event::Ignored | Event::Noise => {
-                unimplemented!();
+    /// Reads the theme file. A missing file gives the default.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match std::fs::File::open(path) {
+            Ok(f) => serde_yaml::from_reader(f)
+                .map_err(|e| format!("Failed to parse theme config: {}", e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Self::default())
             }
+            Err(e) => Err(format!("Failed to read theme config: {}", e)),
         }
     }
+
+    pub fn head_and_style(&self, event: &Event) -> (&'static str, &ColorSpec) {
+        let (head, style) = match event {
+            Event::Create(..) => ("Create", &self.create),
+            Event::Delete(..) => ("Delete", &self.delete),
+            Event::Move(..) => ("Move", &self.r#move),
+            Event::MoveAway(..) => ("MoveAway", &self.move_away),
+            Event::MoveInto(..) => ("MoveInto", &self.move_into),
+            Event::Modify(..) => ("Modify", &self.modify),
+            Event::Open(..) => ("Open", &self.open),
+            Event::OpenTop(..) => ("Open", &self.open),
+            Event::Close(..) => ("Close", &self.close),
+            Event::CloseTop(..) => ("Close", &self.close),
+            Event::Access(..) => ("Access", &self.access),
+            Event::AccessTop(..) => ("Access", &self.access),
+            Event::Attrib(..) => ("Attrib", &self.attrib),
+            Event::AttribTop(..) => ("Attrib", &self.attrib),
+            Event::MoveTop(..) => ("MoveTop", &self.r#move),
+            Event::DeleteTop(..) => ("DeleteTop", &self.delete),
+            Event::ReplaceTop(..) => ("ReplaceTop", &self.delete),
+            Event::Unmount(..) => ("Unmount", &self.umount),
+            Event::UnmountTop(..) => ("UnmountTop", &self.umount),
+            Event::Reattached(..) => ("Reattached", &self.create),
+            Event::Unknown
+            | Event::Ignored
+            | Event::Noise
+            | Event::WatchError(..)
+            | Event::Watching(_)
+            | Event::Remounted(_)
+            | Event::Unwatched(_)
+            | Event::WatchRemoved(_) => {
+                unimplemented!();
+            }
+        };
+        (head, &style.0)
+    }
+
+    /// Gives the styles of added and removed lines of diffs.
+    pub fn diff_styles(&self) -> (&ColorSpec, &ColorSpec) {
+        (&self.create.0, &self.delete.0)
+    }
+
+    /// Gives the style of the first glob matching the path, if any.
+    pub fn path_style(&self, path: &Path) -> Option<&ColorSpec> {
+        self.paths.iter().find(|v| v.glob.0.is_match(path)).map(|v| &v.style.0)
+    }
 }
 
 impl Default for Theme {
     fn default() -> Self {
         Self {
-            create: Color(termcolor::Color::Green),
-            delete: Color(termcolor::Color::Magenta),
-            r#move: Color(termcolor::Color::Blue),
-            move_away: Color(termcolor::Color::Blue),
-            move_into: Color(termcolor::Color::Blue),
-            modify: Color(termcolor::Color::Yellow),
-            open: Color(termcolor::Color::Cyan),
-            close: Color(termcolor::Color::Cyan),
-            access: Color(termcolor::Color::Cyan),
-            attrib: Color(termcolor::Color::Yellow),
-            umount: Color(termcolor::Color::Magenta),
+            create: Style::fg(termcolor::Color::Green),
+            delete: Style::fg(termcolor::Color::Magenta),
+            r#move: Style::fg(termcolor::Color::Blue),
+            move_away: Style::fg(termcolor::Color::Blue),
+            move_into: Style::fg(termcolor::Color::Blue),
+            modify: Style::fg(termcolor::Color::Yellow),
+            open: Style::fg(termcolor::Color::Cyan),
+            close: Style::fg(termcolor::Color::Cyan),
+            access: Style::fg(termcolor::Color::Cyan),
+            attrib: Style::fg(termcolor::Color::Yellow),
+            umount: Style::fg(termcolor::Color::Magenta),
+            paths: Vec::new(),
         }
     }
 }
 
+impl Style {
+    fn fg(color: termcolor::Color) -> Self {
+        let mut spec = ColorSpec::new();
+        spec.set_fg(Some(color));
+        Self(spec)
+    }
+}
+
+impl From<Spec> for Style {
+    fn from(v: Spec) -> Self {
+        let mut spec = ColorSpec::new();
+        spec.set_fg(v.fg.map(|c| c.0))
+            .set_bg(v.bg.map(|c| c.0))
+            .set_bold(v.bold)
+            .set_italic(v.italic)
+            .set_underline(v.underline)
+            .set_dimmed(v.dimmed);
+        Self(spec)
+    }
+}
+
+impl<'de> Deserialize<'de> for Style {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Color(Color),
+            Spec(Spec),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Color(v) => Self::fg(v.0),
+            Repr::Spec(v) => v.into(),
+        })
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix('#') {
+            let mut rgb = [0; 3];
+            return match hex::decode_to_slice(hex, &mut rgb) {
+                Ok(_) => {
+                    Ok(Self(termcolor::Color::Rgb(rgb[0], rgb[1], rgb[2])))
+                }
+                Err(_) => Err(format!("invalid hex color: {}", s)),
+            };
+        }
+        termcolor::Color::from_str(s).map(Self).map_err(|e| e.to_string())
+    }
+}
+
 impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Color;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a color name, code, R,G,B or #RRGGBB")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Color, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Color, E> {
+                u8::try_from(v)
+                    .map(|v| Color(termcolor::Color::Ansi256(v)))
+                    .map_err(|_| E::custom("color code out of range"))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Matcher {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        FromStr::from_str(&s).map(Color).map_err(de::Error::custom)
+        Glob::new(&s)
+            .map(|v| Self(v.compile_matcher()))
+            .map_err(de::Error::custom)
     }
 }
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode, Url};
+use sha2::Sha256;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::{record::Record, TimedEvent};
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const SIGNATURE_HEADER: &str = "X-Watchdir-Signature";
+
+pub struct WebhookOpts {
+    pub url: Url,
+    /// Key to sign the body with HMAC-SHA256.
+    pub secret: Option<String>,
+    /// Send events in a JSON array once none comes within this time.
+    pub batch: Option<Duration>,
+}
+
+/// POSTs events as JSON to a URL, retrying with exponential backoff.
+pub struct Webhook {
+    opts: WebhookOpts,
+    client: Client,
+    rx: broadcast::Receiver<TimedEvent>,
+}
+
+impl Webhook {
+    pub fn new(
+        opts: WebhookOpts,
+        rx: broadcast::Receiver<TimedEvent>,
+    ) -> Self {
+        Self { opts, client: Client::new(), rx }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(record) = self.recv().await {
+            let body = match self.opts.batch {
+                None => record.to_json(),
+                Some(batch) => {
+                    let mut records = vec![record];
+                    while let Ok(Some(record)) =
+                        tokio::time::timeout(batch, self.recv()).await
+                    {
+                        records.push(record);
+                    }
+                    serde_json::to_string(&records).unwrap()
+                }
+            };
+            self.post(body).await;
+        }
+    }
+
+    /// Gives `None` if no more events will come.
+    async fn recv(&mut self) -> Option<Record> {
+        loop {
+            match self.rx.recv().await {
+                Ok((event, t)) => {
+                    if let Some(record) = Record::new(&event, t) {
+                        return Some(record);
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Webhook is too slow, {} events are skipped", n)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    async fn post(&self, body: String) {
+        let signature = self.opts.secret.as_ref().map(|secret| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC takes keys of any size");
+            mac.update(body.as_bytes());
+            format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+        });
+
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request = self
+                .client
+                .post(self.opts.url.to_owned())
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_owned());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Webhook delivered: {}", response.status());
+                    return;
+                }
+                Ok(response)
+                    if response.status().is_client_error()
+                        && response.status()
+                            != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    warn!("Webhook is rejected: {}", response.status());
+                    return;
+                }
+                Ok(response) => warn!(
+                    "Webhook failed ({}/{}): {}",
+                    attempt,
+                    MAX_ATTEMPTS,
+                    response.status()
+                ),
+                Err(e) => {
+                    warn!(
+                        "Webhook failed ({}/{}): {}",
+                        attempt, MAX_ATTEMPTS, e
+                    )
+                }
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        warn!("Webhook is given up after {} attempts", MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+use tracing::{debug, warn};
+
+use crate::{http, record::Record, Events, TimedEvent};
+
+/// Pushes events to WebSocket clients connected to `/events`. A client can
+/// receive only the events under some paths with `?path=PATH` queries,
+/// where relative paths are resolved against the watched directory.
+pub struct WsServer {
+    listener: TcpListener,
+    events: Events,
+    top_dir: PathBuf,
+}
+
+impl WsServer {
+    pub async fn bind(
+        addr: SocketAddr,
+        events: Events,
+        top_dir: PathBuf,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, events, top_dir })
+    }
+
+    pub async fn serve(mut self) {
+        // Follows the top directory for later connections.
+        let mut rx = self.events.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                v = self.listener.accept() => v,
+                Ok((event, _)) = rx.recv() => {
+                    if let Some(to) = event.moved_top() {
+                        self.top_dir = to.to_owned();
+                    }
+                    continue;
+                }
+            };
+            let (stream, peer) = match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to accept WebSocket connection: {}", e);
+                    continue;
+                }
+            };
+            debug!("WebSocket connection from {}", peer);
+            let rx = self.events.subscribe();
+            let top_dir = self.top_dir.to_owned();
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, rx, top_dir).await {
+                    debug!("WebSocket connection from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    mut rx: broadcast::Receiver<TimedEvent>,
+    mut top_dir: PathBuf,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut queries = Vec::new();
+    // The error type is imposed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        let target = request.uri().to_string();
+        if http::path(&target) != "/events" {
+            let mut response = ErrorResponse::new(None);
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Err(response);
+        }
+        queries = http::query(&target)
+            .into_iter()
+            .filter(|(key, _)| key == "path")
+            .map(|(_, value)| value)
+            .collect();
+        Ok(response)
+    };
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+    let mut filters = resolve(&queries, &top_dir);
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let (event, t) = match event {
+                    Ok(v) => v,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(
+                            "WebSocket client is too slow, {} events are \
+                             skipped",
+                            n
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return ws.close(None).await,
+                };
+                if let Some(to) = event.moved_top() {
+                    top_dir = to.to_owned();
+                    filters = resolve(&queries, &top_dir);
+                }
+                if let Some(record) = Record::new(&event, t) {
+                    if matches(&record, &filters) {
+                        ws.send(Message::Text(record.to_json())).await?;
+                    }
+                }
+            }
+            message = ws.next() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+            },
+        }
+    }
+}
+
+/// Resolves the paths of queries against the top directory.
+fn resolve(queries: &[String], top_dir: &Path) -> Vec<PathBuf> {
+    queries.iter().map(|v| top_dir.join(v)).collect()
+}
+
+fn matches(record: &Record, filters: &[PathBuf]) -> bool {
+    filters.is_empty()
+        || [&record.path, &record.from, &record.to].iter().any(|path| {
+            path.as_ref().is_some_and(|path| {
+                filters
+                    .iter()
+                    .any(|filter| Path::new(path).starts_with(filter))
+            })
+        })
+}
@@ -0,0 +1,117 @@
+//! Synchronous API, for programs without an async runtime.
+
+use std::{
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use futures::{pin_mut, StreamExt};
+use snafu::ResultExt;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+use crate::{Event, Result, StartRuntime, Stats, StatsReader, WatcherOpts};
+
+type Item = (Event, OffsetDateTime);
+
+/// Watches the directory on a thread of its own, and is iterated over
+/// the events. The thread stops when this is dropped.
+pub struct Watcher {
+    receiver: mpsc::Receiver<Item>,
+    _runner: Runner,
+    stats: StatsReader,
+}
+
+impl Watcher {
+    pub fn new(dir: &Path, opts: WatcherOpts) -> Result<Self> {
+        let watcher = crate::Watcher::new(dir, opts)?;
+        let stats = watcher.stats_reader();
+        let (sender, receiver) = mpsc::channel();
+        let runner = Runner::spawn(watcher, move |v| match v {
+            Ok(event) => sender.send(event).is_ok(),
+            // Logged by the watcher.
+            Err(_) => true,
+        })?;
+        Ok(Self { receiver, _runner: runner, stats })
+    }
+
+    /// Waits for the next event as long as the timeout at most.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Item> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Gives the next event if there is one already.
+    pub fn try_next(&mut self) -> Option<Item> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.read()
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Runs a watcher on a thread with a runtime of its own. The thread stops
+/// when this is dropped.
+pub(crate) struct Runner {
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Runner {
+    /// Gives the events and errors of the watcher to `f` until it returns
+    /// false.
+    pub(crate) fn spawn<F>(
+        mut watcher: crate::Watcher,
+        mut f: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(Result<Item>) -> bool + Send + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context(StartRuntime)?;
+        let (stop, mut stopped) = oneshot::channel();
+
+        let thread = thread::spawn(move || {
+            runtime.block_on(async {
+                let errors = watcher.errors();
+                let stream = watcher.stream();
+                pin_mut!(errors, stream);
+                loop {
+                    let next = tokio::select! {
+                        Some(event) = stream.next() => Ok(event),
+                        Some(e) = errors.next() => Err(e),
+                        _ = &mut stopped => break,
+                    };
+                    if !f(next) {
+                        break;
+                    }
+                }
+            })
+        });
+
+        Ok(Self { stop: Some(stop), thread: Some(thread) })
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
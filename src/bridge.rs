@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use futures::{pin_mut, StreamExt};
+use tokio::sync::broadcast;
+
+use crate::{Event, Watcher};
+
+impl Watcher {
+    /// Reads events on a task of its own, spawned on the current runtime
+    /// of tokio, into a broadcast channel of `capacity` events, so that
+    /// several consumers can subscribe to the returned sender. Receivers
+    /// which fall behind miss the oldest events, as `RecvError::Lagged`
+    /// tells them.
+    ///
+    /// The task stops and the watcher is dropped at the first event after
+    /// the sender and its clones are all dropped.
+    pub fn into_broadcast(
+        mut self,
+        capacity: usize,
+    ) -> broadcast::Sender<Arc<Event>> {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        let weak = tx.downgrade();
+        tokio::spawn(async move {
+            let stream = self.stream();
+            pin_mut!(stream);
+            while let Some((event, _)) = stream.next().await {
+                let tx = match weak.upgrade() {
+                    Some(v) => v,
+                    None => break,
+                };
+                // Nobody may have subscribed yet.
+                let _ = tx.send(Arc::new(event));
+            }
+        });
+        tx
+    }
+}
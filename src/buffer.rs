@@ -0,0 +1,180 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use time::OffsetDateTime;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{Event, Watcher};
+
+type Timed = (Event, OffsetDateTime);
+
+/// Item of the stream given by `BufferedStream`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Item {
+    Event(Event),
+    /// This many events were dropped since the buffer was full.
+    Dropped(u64),
+}
+
+/// What to do with a new event when the buffer is full.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Backpressure {
+    /// Stop reading until there is room, so events queue up in the kernel,
+    /// whose queue may overflow.
+    Block,
+    /// Drop the oldest event in the buffer.
+    DropOldest,
+    /// Drop the new event.
+    DropNewest,
+}
+
+/// Events read by a task of their own into a buffer, so the kernel queue
+/// keeps being drained while the consumer is slow. Dropped events are
+/// counted by an `Item::Dropped` before the next event. It ends after the
+/// events in the buffer once the events read end.
+pub struct BufferedStream {
+    inner: Pin<Box<dyn Stream<Item = (Item, OffsetDateTime)> + Send>>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when an event is pushed, or the events end.
+    pushed: Notify,
+    /// Notified when an event is taken.
+    taken: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<Timed>,
+    dropped: u64,
+    ended: bool,
+}
+
+impl Watcher {
+    /// Reads events into a buffer of `capacity` events on a task of its
+    /// own. It is spawned on the current runtime of tokio.
+    pub fn buffered(
+        mut self,
+        capacity: usize,
+        policy: Backpressure,
+    ) -> BufferedStream {
+        BufferedStream::new(
+            stream! {
+                let stream = self.stream();
+                pin_mut!(stream);
+                while let Some(event) = stream.next().await {
+                    yield event;
+                }
+            },
+            capacity,
+            policy,
+        )
+    }
+}
+
+impl BufferedStream {
+    /// Reads the events of the stream into a buffer of `capacity` events
+    /// on a task of its own, spawned on the current runtime of tokio.
+    pub fn new<S>(stream: S, capacity: usize, policy: Backpressure) -> Self
+    where
+        S: Stream<Item = Timed> + Send + 'static,
+    {
+        let capacity = capacity.max(1);
+        let shared = Arc::new(Shared::default());
+
+        let task = tokio::spawn({
+            let shared = Arc::clone(&shared);
+            async move {
+                pin_mut!(stream);
+                while let Some(event) = stream.next().await {
+                    let mut event = Some(event);
+                    while let Some(v) = event.take() {
+                        let full = {
+                            let mut state = shared.state.lock().unwrap();
+                            if state.events.len() < capacity {
+                                state.events.push_back(v);
+                                false
+                            } else {
+                                match policy {
+                                    Backpressure::Block => {
+                                        event = Some(v);
+                                        true
+                                    }
+                                    Backpressure::DropOldest => {
+                                        state.events.pop_front();
+                                        state.events.push_back(v);
+                                        state.dropped += 1;
+                                        false
+                                    }
+                                    Backpressure::DropNewest => {
+                                        state.dropped += 1;
+                                        false
+                                    }
+                                }
+                            }
+                        };
+                        if full {
+                            shared.taken.notified().await;
+                        }
+                    }
+                    shared.pushed.notify_one();
+                }
+                shared.state.lock().unwrap().ended = true;
+                shared.pushed.notify_one();
+            }
+        });
+
+        let inner = stream! {
+            loop {
+                let next = {
+                    let mut state = shared.state.lock().unwrap();
+                    if state.dropped > 0 {
+                        let dropped = std::mem::take(&mut state.dropped);
+                        Some((Item::Dropped(dropped), OffsetDateTime::now_utc()))
+                    } else if let Some((event, t)) = state.events.pop_front() {
+                        Some((Item::Event(event), t))
+                    } else if state.ended {
+                        break;
+                    } else {
+                        None
+                    }
+                };
+                match next {
+                    Some(v) => {
+                        shared.taken.notify_one();
+                        yield v
+                    }
+                    None => shared.pushed.notified().await,
+                }
+            }
+        };
+        Self { inner: Box::pin(inner), task }
+    }
+}
+
+impl Stream for BufferedStream {
+    type Item = (Item, OffsetDateTime);
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl Drop for BufferedStream {
+    /// Stops reading, and the watcher is dropped with the task.
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
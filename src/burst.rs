@@ -0,0 +1,113 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use time::OffsetDateTime;
+
+use crate::{is_about_watching, Event};
+
+type Timed = (Event, OffsetDateTime);
+
+/// Item of the stream given by `BurstDetector`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Item {
+    Event(Event),
+    /// Events in the directory suddenly came at this many per second.
+    Burst(PathBuf, f64),
+}
+
+/// Weight of the last second in the average rate of a directory.
+const SMOOTHING: f64 = 0.1;
+
+/// Directories quiet for this many seconds are forgotten, when their
+/// averages are about zero.
+const FORGET_AFTER: i64 = 300;
+
+/// Tracks the rate of events in each directory, by the times of the
+/// events, as an exponentially weighted average over seconds. An
+/// `Item::Burst` is given after an event which brings the events of its
+/// directory in the second to `min_rate` and `factor` times the average,
+/// e.g. to spot a process modifying files en masse. It is given once until
+/// a second is below that again.
+pub struct BurstDetector {
+    min_rate: f64,
+    factor: f64,
+    dirs: HashMap<PathBuf, Rate>,
+    /// Second of the last event.
+    second: i64,
+}
+
+#[derive(Default)]
+struct Rate {
+    second: i64,
+    /// Events in the second.
+    count: u64,
+    /// Average of events per second before it.
+    average: f64,
+    bursting: bool,
+}
+
+impl BurstDetector {
+    pub fn new(min_rate: f64, factor: f64) -> Self {
+        Self { min_rate, factor, dirs: HashMap::new(), second: 0 }
+    }
+
+    pub fn apply<S>(
+        mut self,
+        stream: S,
+    ) -> impl Stream<Item = (Item, OffsetDateTime)>
+    where
+        S: Stream<Item = Timed>,
+    {
+        stream! {
+            pin_mut!(stream);
+            while let Some((event, t)) = stream.next().await {
+                let burst = self.count(&event, t);
+                yield (Item::Event(event), t);
+                if let Some(burst) = burst {
+                    yield (burst, t);
+                }
+            }
+        }
+    }
+
+    fn count(&mut self, event: &Event, t: OffsetDateTime) -> Option<Item> {
+        if is_about_watching(event) {
+            return None;
+        }
+        let path = match event {
+            Event::Move(_, to, _) => to,
+            _ => event.path()?,
+        };
+        let dir = path.parent()?;
+
+        let second = t.unix_timestamp();
+        if second > self.second {
+            self.second = second;
+            self.dirs.retain(|_, v| second - v.second < FORGET_AFTER);
+        }
+        let rate = self.dirs.entry(dir.to_owned()).or_default();
+        if second > rate.second {
+            rate.average = rate.average * (1.0 - SMOOTHING)
+                + rate.count as f64 * SMOOTHING;
+            let quiet = (second - rate.second - 1).min(FORGET_AFTER) as i32;
+            rate.average *= (1.0 - SMOOTHING).powi(quiet);
+            if quiet > 0 || (rate.count as f64) < self.min_rate {
+                rate.bursting = false;
+            }
+            rate.second = second;
+            rate.count = 0;
+        }
+        rate.count += 1;
+
+        let count = rate.count as f64;
+        if rate.bursting
+            || count < self.min_rate
+            || count < self.factor * rate.average
+        {
+            return None;
+        }
+        rate.bursting = true;
+        Some(Item::Burst(dir.to_owned(), count))
+    }
+}
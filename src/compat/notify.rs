@@ -0,0 +1,348 @@
+//! Types like those of the `notify` crate, so that code written for it can
+//! move to watchdir with few changes. Watching is always done by
+//! watchdir, so moves within the tree come as one event with both paths,
+//! and only directories can be watched.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+};
+
+pub use crate::Error;
+use crate::{blocking::Runner, Dotdir, ExtraEvent, FileType, WatcherOpts};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Whether the subdirectories of a watched directory are watched too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecursiveMode {
+    Recursive,
+    NonRecursive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Any,
+    Access(AccessKind),
+    Create(CreateKind),
+    Modify(ModifyKind),
+    Remove(RemoveKind),
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    Any,
+    Read,
+    Open(AccessMode),
+    Close(AccessMode),
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccessMode {
+    Any,
+    Execute,
+    Read,
+    Write,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CreateKind {
+    Any,
+    File,
+    Folder,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModifyKind {
+    Any,
+    Data(DataChange),
+    Metadata(MetadataKind),
+    Name(RenameMode),
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DataChange {
+    Any,
+    Size,
+    Content,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MetadataKind {
+    Any,
+    AccessTime,
+    WriteTime,
+    Permissions,
+    Ownership,
+    Extended,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RenameMode {
+    Any,
+    /// The new path, moved into the tree.
+    To,
+    /// The old path, moved out of the tree.
+    From,
+    /// Both paths, the old one first.
+    Both,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemoveKind {
+    Any,
+    File,
+    Folder,
+    Other,
+}
+
+impl EventKind {
+    pub fn is_access(&self) -> bool {
+        matches!(self, Self::Access(_))
+    }
+
+    pub fn is_create(&self) -> bool {
+        matches!(self, Self::Create(_))
+    }
+
+    pub fn is_modify(&self) -> bool {
+        matches!(self, Self::Modify(_))
+    }
+
+    pub fn is_remove(&self) -> bool {
+        matches!(self, Self::Remove(_))
+    }
+
+    pub fn is_other(&self) -> bool {
+        matches!(self, Self::Other)
+    }
+}
+
+impl From<&crate::Event> for EventKind {
+    fn from(event: &crate::Event) -> Self {
+        use crate::Event as E;
+
+        let is_dir = event.file_type() == Some(&FileType::Dir);
+        match event {
+            E::Create(..) if is_dir => Self::Create(CreateKind::Folder),
+            E::Create(..) => Self::Create(CreateKind::File),
+            E::Delete(..) if is_dir => Self::Remove(RemoveKind::Folder),
+            E::Delete(..) => Self::Remove(RemoveKind::File),
+            E::DeleteTop(_) => Self::Remove(RemoveKind::Folder),
+            E::Move(..) | E::MoveTop(_, Some(_)) => {
+                Self::Modify(ModifyKind::Name(RenameMode::Both))
+            }
+            E::MoveAway(..) | E::MoveTop(_, None) => {
+                Self::Modify(ModifyKind::Name(RenameMode::From))
+            }
+            E::MoveInto(..) | E::ReplaceTop(_) => {
+                Self::Modify(ModifyKind::Name(RenameMode::To))
+            }
+            E::Modify(..) => {
+                Self::Modify(ModifyKind::Data(DataChange::Content))
+            }
+            E::Attrib(..) | E::AttribTop(_) => {
+                Self::Modify(ModifyKind::Metadata(MetadataKind::Any))
+            }
+            E::Access(..) | E::AccessTop(_) => Self::Access(AccessKind::Read),
+            E::Open(..) | E::OpenTop(_) => {
+                Self::Access(AccessKind::Open(AccessMode::Any))
+            }
+            E::Close(..) | E::CloseTop(_) => {
+                Self::Access(AccessKind::Close(AccessMode::Any))
+            }
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Event {
+    pub kind: EventKind,
+    /// Paths of the event, or the old and new paths of a rename.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Self {
+        Self { kind, paths: Vec::new() }
+    }
+
+    pub fn add_path(mut self, path: PathBuf) -> Self {
+        self.paths.push(path);
+        self
+    }
+}
+
+impl From<&crate::Event> for Event {
+    fn from(event: &crate::Event) -> Self {
+        let paths = match event {
+            crate::Event::Move(from, to, _)
+            | crate::Event::MoveTop(from, Some(to)) => {
+                vec![from.to_owned(), to.to_owned()]
+            }
+            _ => event.path().map(Path::to_path_buf).into_iter().collect(),
+        };
+        Self { kind: event.into(), paths }
+    }
+}
+
+/// Receiver of the events and errors of a watcher.
+pub trait EventHandler: Send + 'static {
+    fn handle_event(&mut self, event: Result<Event>);
+}
+
+impl<F> EventHandler for F
+where
+    F: FnMut(Result<Event>) + Send + 'static,
+{
+    fn handle_event(&mut self, event: Result<Event>) {
+        self(event)
+    }
+}
+
+impl EventHandler for mpsc::Sender<Result<Event>> {
+    fn handle_event(&mut self, event: Result<Event>) {
+        // The receiver may be gone.
+        let _ = self.send(event);
+    }
+}
+
+/// Options of the watches.
+#[derive(Clone, Debug)]
+pub struct Config {
+    hidden: bool,
+    access: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { hidden: true, access: false }
+    }
+}
+
+impl Config {
+    /// Whether hidden files and directories are watched. They are by
+    /// default.
+    pub fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Whether events of opening, reading and closing files are given.
+    /// They are not by default.
+    pub fn with_access_events(mut self, access: bool) -> Self {
+        self.access = access;
+        self
+    }
+
+    fn watcher_opts(&self, mode: RecursiveMode) -> WatcherOpts {
+        let mut extra_events = vec![ExtraEvent::Modify, ExtraEvent::Attrib];
+        if self.access {
+            extra_events.extend([
+                ExtraEvent::Access,
+                ExtraEvent::Open,
+                ExtraEvent::Close,
+            ]);
+        }
+        let opts = WatcherOpts::new(
+            if self.hidden { Dotdir::Include } else { Dotdir::Exclude },
+            extra_events,
+        )
+        .exclude_hidden_files(!self.hidden);
+        match mode {
+            RecursiveMode::Recursive => opts,
+            RecursiveMode::NonRecursive => opts.max_depth(0),
+        }
+    }
+}
+
+pub trait Watcher {
+    fn new<F: EventHandler>(event_handler: F, config: Config) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Watches the directory. Watching it again replaces the watch.
+    fn watch(
+        &mut self,
+        path: &Path,
+        recursive_mode: RecursiveMode,
+    ) -> Result<()>;
+
+    fn unwatch(&mut self, path: &Path) -> Result<()>;
+}
+
+/// Watcher of watchdir behind the `Watcher` trait. Each watched directory
+/// has a thread of its own, and events are given to the handler on it.
+pub struct RecommendedWatcher {
+    handler: Arc<Mutex<dyn EventHandler>>,
+    config: Config,
+    watches: HashMap<PathBuf, Runner>,
+}
+
+impl Watcher for RecommendedWatcher {
+    fn new<F: EventHandler>(event_handler: F, config: Config) -> Result<Self> {
+        Ok(Self {
+            handler: Arc::new(Mutex::new(event_handler)),
+            config,
+            watches: HashMap::new(),
+        })
+    }
+
+    /// Without recursion, only events of the directory and its entries
+    /// are given.
+    fn watch(
+        &mut self,
+        path: &Path,
+        recursive_mode: RecursiveMode,
+    ) -> Result<()> {
+        self.watches.remove(path);
+        let watcher = crate::Watcher::new(
+            path,
+            self.config.watcher_opts(recursive_mode),
+        )?;
+        let (handler, top_dir) = (self.handler.clone(), path.to_owned());
+        let runner = Runner::spawn(watcher, move |v| {
+            let v = match v {
+                Ok((event, _)) => {
+                    if recursive_mode == RecursiveMode::NonRecursive
+                        && !is_shallow(&event, &top_dir)
+                    {
+                        return true;
+                    }
+                    Ok(Event::from(&event))
+                }
+                Err(e) => Err(e),
+            };
+            handler.lock().unwrap().handle_event(v);
+            true
+        })?;
+        self.watches.insert(path.to_owned(), runner);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        match self.watches.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(Error::WatchNotFound { path: path.to_owned() }),
+        }
+    }
+}
+
+/// Whether the event is of the directory or its entries.
+fn is_shallow(event: &crate::Event, dir: &Path) -> bool {
+    let paths = match event {
+        crate::Event::Move(from, to, _) => vec![from.as_path(), to],
+        _ => event.path().into_iter().collect(),
+    };
+    paths.iter().any(|v| *v == dir || v.parent() == Some(dir))
+}
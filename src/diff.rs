@@ -0,0 +1,109 @@
+use std::{
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use futures::{Stream, StreamExt};
+use lru::LruCache;
+use similar::TextDiff;
+use time::OffsetDateTime;
+
+use crate::{Event, FileType};
+
+/// Lines of context around the changes.
+const CONTEXT: usize = 3;
+
+/// Gives unified diffs of text files when they are modified, from their
+/// contents at earlier events.
+///
+/// Contents are kept for as many files as the capacity, and only for text
+/// files up to `max_size` bytes: valid UTF-8 without NUL. A file is first
+/// read at any event of it, e.g. `Create`, so its first modification
+/// after that has a diff.
+pub struct Diffs {
+    known: LruCache<PathBuf, String>,
+    max_size: u64,
+}
+
+impl Diffs {
+    pub fn new(capacity: NonZeroUsize, max_size: u64) -> Self {
+        Self { known: LruCache::new(capacity), max_size }
+    }
+
+    /// Gives the diff of the file of a `Modify` or `Close` event from its
+    /// last known contents, if they changed.
+    pub fn diff(&mut self, event: &Event) -> Option<String> {
+        match event {
+            Event::Modify(path, FileType::File, _)
+            | Event::Close(path, FileType::File) => {
+                let new = match self.read(path) {
+                    Some(v) => v,
+                    None => {
+                        self.known.pop(path);
+                        return None;
+                    }
+                };
+                let old = self.known.put(path.to_owned(), new.clone())?;
+                (old != new).then(|| unified(path, &old, &new))
+            }
+            Event::Create(path, FileType::File)
+            | Event::MoveInto(path, FileType::File, _)
+            | Event::Open(path, FileType::File)
+            | Event::Access(path, FileType::File)
+            | Event::Attrib(path, FileType::File) => {
+                if !self.known.contains(path) {
+                    if let Some(v) = self.read(path) {
+                        self.known.put(path.to_owned(), v);
+                    }
+                }
+                None
+            }
+            Event::Move(from, to, FileType::File) => {
+                if let Some(v) = self.known.pop(from) {
+                    self.known.put(to.to_owned(), v);
+                }
+                None
+            }
+            Event::Delete(path, _) | Event::MoveAway(path, ..) => {
+                self.known.pop(path);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Gives the events of the stream with the diffs of `diff`.
+    pub fn apply<S>(
+        mut self,
+        stream: S,
+    ) -> impl Stream<Item = (Event, OffsetDateTime, Option<String>)>
+    where
+        S: Stream<Item = (Event, OffsetDateTime)>,
+    {
+        stream.map(move |(event, t)| {
+            let diff = self.diff(&event);
+            (event, t, diff)
+        })
+    }
+
+    /// Reads the file if it is small text.
+    fn read(&self, path: &Path) -> Option<String> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        if !metadata.is_file() || metadata.len() > self.max_size {
+            return None;
+        }
+        String::from_utf8(fs::read(path).ok()?)
+            .ok()
+            .filter(|v| !v.contains('\0'))
+    }
+}
+
+fn unified(path: &Path, old: &str, new: &str) -> String {
+    let name = path.to_string_lossy();
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(CONTEXT)
+        .header(&name, &name)
+        .to_string()
+}
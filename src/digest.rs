@@ -0,0 +1,83 @@
+use std::fmt;
+#[cfg(feature = "async")]
+use std::{fs::File, io, path::PathBuf};
+
+#[cfg(feature = "async")]
+use sha2::Digest as _;
+
+/// Files larger than this are not hashed unless another limit is given.
+pub const DEFAULT_HASH_SIZE_LIMIT: u64 = 64 << 20;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HashAlgo {
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgo {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Digest of the content of a file, displayed as `ALGO:HEX`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Digest {
+    pub algo: HashAlgo,
+    pub bytes: [u8; 32],
+}
+
+impl Digest {
+    /// Parses a digest in the form of its display.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (name, value) = s.split_once(':')?;
+        let algo = match name {
+            "blake3" => HashAlgo::Blake3,
+            "sha256" => HashAlgo::Sha256,
+            _ => return None,
+        };
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(value, &mut bytes).ok()?;
+        Some(Self { algo, bytes })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algo.name(), hex::encode(self.bytes))
+    }
+}
+
+/// Hashes the file on the blocking pool. Gives `None` if the file is
+/// larger than `size_limit` or can not be read, e.g. it is already gone.
+#[cfg(feature = "async")]
+pub(crate) async fn hash_file(
+    path: PathBuf,
+    algo: HashAlgo,
+    size_limit: u64,
+) -> Option<Digest> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = File::open(&path).ok()?;
+        if file.metadata().ok()?.len() > size_limit {
+            return None;
+        }
+        let bytes = match algo {
+            HashAlgo::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut file, &mut hasher).ok()?;
+                *hasher.finalize().as_bytes()
+            }
+            HashAlgo::Sha256 => {
+                let mut hasher = sha2::Sha256::new();
+                io::copy(&mut file, &mut hasher).ok()?;
+                hasher.finalize().into()
+            }
+        };
+        Some(Digest { algo, bytes })
+    })
+    .await
+    .unwrap()
+}
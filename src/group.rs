@@ -0,0 +1,143 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use time::OffsetDateTime;
+
+use crate::Event;
+
+/// Related events, given as one unit.
+#[derive(Debug)]
+pub struct EventGroup {
+    pub id: u64,
+    pub events: Vec<(Event, OffsetDateTime)>,
+}
+
+/// Groups related events, e.g. those of a directory being reorganized.
+/// An event belongs to the group of the previous one if it shares a
+/// cookie with the group, or if it comes within `window` and its paths
+/// are under the directory of the first event of the group.
+pub struct Grouping {
+    window: Duration,
+    next_id: u64,
+    current: Option<Current>,
+}
+
+struct Current {
+    id: u64,
+    prefix: Option<PathBuf>,
+    cookies: Vec<u32>,
+    last: OffsetDateTime,
+}
+
+impl Grouping {
+    pub fn new(window: Duration) -> Self {
+        Self { window, next_id: 0, current: None }
+    }
+
+    /// Gives the id of the group of the event, which starts a new group
+    /// unless it is related to the previous event.
+    pub fn assign(&mut self, event: &Event, t: OffsetDateTime) -> u64 {
+        let cookie = match event {
+            Event::MoveAway(.., cookie) | Event::MoveInto(.., cookie) => {
+                Some(*cookie)
+            }
+            _ => None,
+        };
+        if let Some(current) = &mut self.current {
+            if current.joins(event, cookie, t, self.window) {
+                current.last = t;
+                current.cookies.extend(cookie);
+                return current.id;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.current = Some(Current {
+            id,
+            prefix: paths(event)
+                .first()
+                .and_then(|v| v.parent())
+                .map(Path::to_owned),
+            cookies: cookie.into_iter().collect(),
+            last: t,
+        });
+        id
+    }
+
+    /// Yields groups of the events. A group is yielded once an unrelated
+    /// event comes, or no event comes within `window`.
+    pub fn apply<S>(mut self, stream: S) -> impl Stream<Item = EventGroup>
+    where
+        S: Stream<Item = (Event, OffsetDateTime)>,
+    {
+        stream! {
+            pin_mut!(stream);
+            let mut group: Option<EventGroup> = None;
+            loop {
+                let next = match group {
+                    Some(_) => {
+                        match tokio::time::timeout(self.window, stream.next())
+                            .await
+                        {
+                            Ok(v) => v,
+                            Err(_) => {
+                                self.current = None;
+                                yield group.take().unwrap();
+                                continue;
+                            }
+                        }
+                    }
+                    None => stream.next().await,
+                };
+                let (event, t) = match next {
+                    Some(v) => v,
+                    None => break,
+                };
+                let id = self.assign(&event, t);
+                if group.as_ref().is_some_and(|v| v.id != id) {
+                    yield group.take().unwrap();
+                }
+                group
+                    .get_or_insert_with(|| EventGroup { id, events: Vec::new() })
+                    .events
+                    .push((event, t));
+            }
+            if let Some(group) = group {
+                yield group;
+            }
+        }
+    }
+}
+
+impl Current {
+    fn joins(
+        &self,
+        event: &Event,
+        cookie: Option<u32>,
+        t: OffsetDateTime,
+        window: Duration,
+    ) -> bool {
+        if cookie.is_some_and(|v| self.cookies.contains(&v)) {
+            return true;
+        }
+        let paths = paths(event);
+        t - self.last <= window
+            && !paths.is_empty()
+            && self.prefix.as_ref().is_some_and(|prefix| {
+                paths.iter().all(|v| v.starts_with(prefix))
+            })
+    }
+}
+
+/// Gives the paths of files in the tree which the event is about.
+fn paths(event: &Event) -> Vec<&Path> {
+    match event {
+        Event::Move(from, to, _) => vec![from.as_path(), to.as_path()],
+        _ if event.file_type().is_some() => event.path().into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,84 @@
+use std::{fs, num::NonZeroUsize, os::unix::fs::MetadataExt, path::PathBuf};
+
+use futures::{Stream, StreamExt};
+use lru::LruCache;
+use time::OffsetDateTime;
+
+use crate::Event;
+
+/// Device and inode number of a file, which stay the same across renames.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Inode {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl From<&fs::Metadata> for Inode {
+    fn from(v: &fs::Metadata) -> Self {
+        Self { dev: v.dev(), ino: v.ino() }
+    }
+}
+
+/// Finds the inodes of the files of events, by the file at the path, or at
+/// the new path of a move. Links are not followed.
+///
+/// Files are gone by the time some events are seen, e.g. deletions. Their
+/// inodes are remembered from earlier events of the same paths, for as
+/// many paths as the capacity.
+pub struct Identify {
+    known: LruCache<PathBuf, Inode>,
+}
+
+impl Identify {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self { known: LruCache::new(capacity) }
+    }
+
+    /// Gives the inode of the file of the event, if it can be found.
+    /// Events of the top directory and others without a file have none.
+    pub fn inode(&mut self, event: &Event) -> Option<Inode> {
+        match event {
+            Event::Create(path, _)
+            | Event::MoveInto(path, ..)
+            | Event::Modify(path, ..)
+            | Event::Access(path, _)
+            | Event::Attrib(path, _)
+            | Event::Open(path, _)
+            | Event::Close(path, _) => match fs::symlink_metadata(path) {
+                Ok(v) => {
+                    let inode = Inode::from(&v);
+                    self.known.put(path.to_owned(), inode);
+                    Some(inode)
+                }
+                Err(_) => self.known.get(path).copied(),
+            },
+            Event::Move(from, to, _) => {
+                let known = self.known.pop(from);
+                let inode = match fs::symlink_metadata(to) {
+                    Ok(v) => Inode::from(&v),
+                    Err(_) => known?,
+                };
+                self.known.put(to.to_owned(), inode);
+                Some(inode)
+            }
+            Event::Delete(path, _)
+            | Event::MoveAway(path, ..)
+            | Event::Unmount(path, _) => self.known.pop(path),
+            _ => None,
+        }
+    }
+
+    /// Gives the events of the stream with their inodes.
+    pub fn apply<S>(
+        mut self,
+        stream: S,
+    ) -> impl Stream<Item = (Event, OffsetDateTime, Option<Inode>)>
+    where
+        S: Stream<Item = (Event, OffsetDateTime)>,
+    {
+        stream.map(move |(event, t)| {
+            let inode = self.inode(&event);
+            (event, t, inode)
+        })
+    }
+}
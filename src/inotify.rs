@@ -1,20 +1,30 @@
 use std::{
-    ffi::{CStr, OsStr},
-    mem::size_of,
-    os::unix::{ffi::OsStrExt, io::FromRawFd},
-    path::PathBuf,
+    ffi::OsStr, fs, mem::size_of, os::unix::ffi::OsStrExt, path::PathBuf,
+};
+#[cfg(feature = "async")]
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
 };
 
+#[cfg(feature = "async")]
 use async_stream::stream;
+#[cfg(feature = "async")]
 use futures::Stream;
 use snafu::Snafu;
-use tokio::{fs::File, io::AsyncReadExt};
-use tracing::{debug, instrument};
+#[cfg(feature = "tokio-reactor")]
+use tokio::io::unix::AsyncFd;
+#[cfg(feature = "tracing")]
+use tracing::instrument;
+
+use crate::log::debug;
 
 const MAX_FILENAME_LENGTH: usize = 255;
 const INOTIFY_EVENT_HEADER_SIZE: usize = size_of::<libc::inotify_event>();
 const MAX_INOTIFY_EVENT_SIZE: usize =
     INOTIFY_EVENT_HEADER_SIZE + MAX_FILENAME_LENGTH + 1;
+/// Large enough to read more records after an incomplete one.
+const BUFFER_SIZE: usize = 4096;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -23,154 +33,254 @@ pub enum Error {
 
     #[snafu(display("Unknown event"))]
     UnknownEvent,
+
+    #[snafu(display("Malformed record of {} bytes", size))]
+    Malformed { size: usize },
+
+    #[snafu(display("Failed to read records: {}", source))]
+    Read { source: std::io::Error },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Reads the limit of watches per user in the user namespace, which is
+/// `fs.inotify.max_user_watches` outside of containers.
+pub fn max_user_watches() -> Option<usize> {
+    [
+        "/proc/sys/user/max_inotify_watches",
+        "/proc/sys/fs/inotify/max_user_watches",
+    ]
+    .iter()
+    .find_map(|v| fs::read_to_string(v).ok()?.trim().parse().ok())
+}
+
+/// Reads events of an inotify instance. It owns the instance, which is
+/// closed when this is dropped.
+///
+/// The instance must be non-blocking. It is registered to the reactor of
+/// tokio at the first read, so this can be created outside of a runtime.
+/// Without the feature `tokio-reactor`, the reactor of watchdir is used.
+#[cfg(feature = "async")]
 pub struct EventSeq {
-    #[allow(dead_code)]
-    fd: i32,
-    file: File,
-    pollfd: libc::pollfd,
-    buffer: [u8; MAX_INOTIFY_EVENT_SIZE],
+    // Deregistered before the file descriptor is closed.
+    #[cfg(feature = "tokio-reactor")]
+    async_fd: Option<AsyncFd<RawFd>>,
+    fd: OwnedFd,
+    buffer: [u8; BUFFER_SIZE],
     len: usize,
     offset: usize,
 }
 
+#[cfg(feature = "async")]
 impl EventSeq {
+    /// Takes the ownership of the inotify file descriptor.
     pub fn new(fd: i32) -> Self {
         Self {
-            fd,
-            file: unsafe { File::from_raw_fd(fd) },
-            pollfd: libc::pollfd { fd, events: libc::POLLIN, revents: 0 },
-            buffer: [0; MAX_INOTIFY_EVENT_SIZE],
+            #[cfg(feature = "tokio-reactor")]
+            async_fd: None,
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            buffer: [0; BUFFER_SIZE],
             len: 0,
             offset: 0,
         }
     }
 
+    pub fn fd(&self) -> i32 {
+        self.fd.as_raw_fd()
+    }
+
+    /// Gives the events of the records. It ends after an error of reading,
+    /// which is given first.
     pub fn stream(&mut self) -> impl Stream<Item = Result<Event>> + '_ {
         stream! {
             loop {
-                if self.offset >= self.len {
-                    self.buffer.fill(0);
-                    self.offset = 0;
-                }
-                if self.offset == 0 {
-                        self.len = self.file.read(&mut self.buffer).await.unwrap();
+                match parse(&self.buffer[self.offset..self.len]) {
+                    Some((event, size)) => {
+                        self.offset += size;
+                        yield event
+                    }
+                    None => {
+                        if let Err(source) = self.read().await {
+                            yield Err(Error::Read { source });
+                            return;
+                        }
+                    }
                 }
-
-                let event = self.parse();
-                if let Ok(ref event) = event {
-                    self.offset += INOTIFY_EVENT_HEADER_SIZE + event.len as usize;
-                }
-                yield event
             }
         }
     }
 
-    #[instrument(skip(self), fields(len=self.len, offset=self.offset))]
-    fn parse(&self) -> Result<Event> {
-        let raw = &self.buffer[self.offset..];
-        let res: libc::inotify_event =
-            unsafe { std::ptr::read(raw.as_ptr() as *const _) };
-        let raw_event: libc::inotify_event = if res.wd > 0 {
-            res
-        } else if res.mask & libc::IN_Q_OVERFLOW > 0 {
-            return Err(Error::Overflow);
-        } else {
-            return Err(Error::UnknownEvent);
-        };
-
-        let now = time::OffsetDateTime::now_utc();
-
-        let path = if raw_event.len > 0 {
-            let raw_path = unsafe {
-                CStr::from_bytes_with_nul_unchecked(
-                    raw[INOTIFY_EVENT_HEADER_SIZE
-                        ..(INOTIFY_EVENT_HEADER_SIZE
-                            + raw_event.len as usize)]
-                        .split_inclusive(|c| *c == 0)
-                        .next()
-                        .unwrap(),
-                )
-            };
-            Some(PathBuf::from(OsStr::from_bytes(raw_path.to_bytes())))
-        } else {
-            None
-        };
-
-        let file_type = if raw_event.mask & libc::IN_ISDIR > 0 {
-            FileType::Dir
-        } else {
-            FileType::File
-        };
+    /// Moves the incomplete record to the start of the buffer.
+    fn compact(&mut self) {
+        self.buffer.copy_within(self.offset..self.len, 0);
+        self.len -= self.offset;
+        self.offset = 0;
+    }
 
-        let kind = match raw_event.mask {
-            i if i & libc::IN_MOVED_FROM > 0 => {
-                EventKind::MoveFrom(path.unwrap(), file_type)
-            }
-            i if i & libc::IN_MOVED_TO > 0 => {
-                EventKind::MoveTo(path.unwrap(), file_type)
-            }
-            i if i & libc::IN_CREATE > 0 => {
-                EventKind::Create(path.unwrap(), file_type)
-            }
-            i if i & libc::IN_MOVE_SELF > 0 => EventKind::MoveSelf,
-            i if i & libc::IN_DELETE > 0 => {
-                EventKind::Delete(path.unwrap(), file_type)
+    /// Reads records after those in the buffer when the instance is
+    /// readable.
+    #[cfg(feature = "tokio-reactor")]
+    async fn read(&mut self) -> io::Result<()> {
+        if self.async_fd.is_none() {
+            self.async_fd = Some(AsyncFd::new(self.fd.as_raw_fd())?);
+        }
+        self.compact();
+        let async_fd = self.async_fd.as_ref().unwrap();
+        let buffer = &mut self.buffer[self.len..];
+        let n = loop {
+            let mut guard = async_fd.readable().await?;
+            if let Ok(res) = guard.try_io(|fd| read(*fd.get_ref(), buffer)) {
+                break res?;
             }
-            i if i & libc::IN_DELETE_SELF > 0 => EventKind::DeleteSelf,
-            i if i & libc::IN_MODIFY > 0 => EventKind::Modify(path.unwrap()),
-            i if i & libc::IN_ATTRIB > 0 => EventKind::Attrib(path, file_type),
-            i if i & libc::IN_ACCESS > 0 => EventKind::Access(path, file_type),
-            i if i & libc::IN_OPEN > 0 => EventKind::Open(path, file_type),
-            i if i & libc::IN_CLOSE > 0 => EventKind::Close(path, file_type),
-            i if i & libc::IN_UNMOUNT > 0 => EventKind::Unmount,
-            i if i & libc::IN_IGNORED > 0 => EventKind::Ignored,
-            _ => EventKind::Unknown,
-        };
-
-        let event = Event {
-            wd: raw_event.wd,
-            cookie: raw_event.cookie,
-            len: raw_event.len,
-            kind,
-            t: now,
         };
-        debug!(?event);
+        self.len += n;
+        Ok(())
+    }
 
-        Ok(event)
+    #[cfg(not(feature = "tokio-reactor"))]
+    async fn read(&mut self) -> io::Result<()> {
+        self.compact();
+        let fd = self.fd.as_raw_fd();
+        self.len +=
+            crate::reactor::read(fd, &mut self.buffer[self.len..]).await?;
+        Ok(())
     }
 
+    /// Tells whether an event is ready to be read without waiting. Records
+    /// queued in the kernel are read into the empty buffer.
     pub fn has_next_event(&mut self) -> bool {
-        // HACK: These milliseconds represent the waiting for next event.
-        // Consider a more appropriate value.
-        const TIMEOUT: i32 = 1;
-
-        if self.offset >= self.len {
-            // XXX: ioctl is invalid
-            // let n = unsafe { libc::ioctl(self.fd, libc::FIONREAD) };
-            let n = unsafe { libc::poll(&mut self.pollfd, 1, TIMEOUT) };
-            debug!("Check if there are more events: n = {}", n);
-            n > 0
-        } else {
+        if record_size(&self.buffer[self.offset..self.len]).is_some() {
             debug!("{}", "Buffer has content");
-            true
+            return true;
+        }
+        // Readiness of tokio is not touched, since a read which fails
+        // after this clears it anyway.
+        self.compact();
+        match read(self.fd.as_raw_fd(), &mut self.buffer[self.len..]) {
+            Ok(n) => {
+                debug!("Read more events: n = {}", n);
+                self.len += n;
+                record_size(&self.buffer[..self.len]).is_some()
+            }
+            Err(_) => false,
         }
     }
 }
 
-#[derive(Debug)]
+#[cfg(feature = "async")]
+pub(crate) fn read(fd: RawFd, buffer: &mut [u8]) -> io::Result<usize> {
+    let n = unsafe {
+        libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len())
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Parses the first record of raw data read from an inotify instance,
+/// giving the event and the size of the record, or `None` if the record is
+/// incomplete. A malformed record takes up all the data, since nothing
+/// after it can be trusted.
+#[cfg_attr(feature = "tracing", instrument(skip(raw), fields(len = raw.len())))]
+pub fn parse(raw: &[u8]) -> Option<(Result<Event>, usize)> {
+    let size = record_size(raw)?;
+    if size > MAX_INOTIFY_EVENT_SIZE {
+        return Some((Err(Error::Malformed { size }), raw.len()));
+    }
+    Some((parse_record(&raw[..size]), size))
+}
+
+/// Gives the size of the first record, or `None` if it is incomplete.
+/// Malformed records are never incomplete.
+fn record_size(raw: &[u8]) -> Option<usize> {
+    if raw.len() < INOTIFY_EVENT_HEADER_SIZE {
+        return None;
+    }
+    let size = INOTIFY_EVENT_HEADER_SIZE + header(raw).len as usize;
+    if size <= raw.len() || size > MAX_INOTIFY_EVENT_SIZE {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+fn header(raw: &[u8]) -> libc::inotify_event {
+    unsafe { std::ptr::read_unaligned(raw.as_ptr() as *const _) }
+}
+
+fn parse_record(raw: &[u8]) -> Result<Event> {
+    let res = header(raw);
+    let raw_event: libc::inotify_event = if res.wd > 0 {
+        res
+    } else if res.mask & libc::IN_Q_OVERFLOW > 0 {
+        return Err(Error::Overflow);
+    } else {
+        return Err(Error::UnknownEvent);
+    };
+
+    let now = time::OffsetDateTime::now_utc();
+
+    // The name is padded with null bytes, which may be absent.
+    let path = raw[INOTIFY_EVENT_HEADER_SIZE..]
+        .split(|c| *c == 0)
+        .next()
+        .filter(|v| !v.is_empty())
+        .map(|v| PathBuf::from(OsStr::from_bytes(v)));
+
+    let file_type = if raw_event.mask & libc::IN_ISDIR > 0 {
+        FileType::Dir
+    } else {
+        FileType::File
+    };
+
+    let size = raw.len();
+    let named = |path: Option<PathBuf>| path.ok_or(Error::Malformed { size });
+    let kind = match raw_event.mask {
+        i if i & libc::IN_MOVED_FROM > 0 => {
+            EventKind::MoveFrom(named(path)?, file_type)
+        }
+        i if i & libc::IN_MOVED_TO > 0 => {
+            EventKind::MoveTo(named(path)?, file_type)
+        }
+        i if i & libc::IN_CREATE > 0 => {
+            EventKind::Create(named(path)?, file_type)
+        }
+        i if i & libc::IN_MOVE_SELF > 0 => EventKind::MoveSelf,
+        i if i & libc::IN_DELETE > 0 => {
+            EventKind::Delete(named(path)?, file_type)
+        }
+        i if i & libc::IN_DELETE_SELF > 0 => EventKind::DeleteSelf,
+        i if i & libc::IN_MODIFY > 0 => EventKind::Modify(named(path)?),
+        i if i & libc::IN_ATTRIB > 0 => EventKind::Attrib(path, file_type),
+        i if i & libc::IN_ACCESS > 0 => EventKind::Access(path, file_type),
+        i if i & libc::IN_OPEN > 0 => EventKind::Open(path, file_type),
+        i if i & libc::IN_CLOSE_WRITE > 0 => {
+            EventKind::CloseWrite(path, file_type)
+        }
+        i if i & libc::IN_CLOSE > 0 => EventKind::Close(path, file_type),
+        i if i & libc::IN_UNMOUNT > 0 => EventKind::Unmount,
+        i if i & libc::IN_IGNORED > 0 => EventKind::Ignored,
+        _ => EventKind::Unknown,
+    };
+
+    let event =
+        Event { wd: raw_event.wd, cookie: raw_event.cookie, kind, t: now };
+    debug!("{:?}", event);
+
+    Ok(event)
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Event {
     pub kind: EventKind,
     pub wd: i32,
     pub cookie: u32,
     pub t: time::OffsetDateTime,
-    len: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum EventKind {
     MoveTo(PathBuf, FileType),
     MoveFrom(PathBuf, FileType),
@@ -183,13 +293,153 @@ pub enum EventKind {
     Attrib(Option<PathBuf>, FileType),
     Open(Option<PathBuf>, FileType),
     Close(Option<PathBuf>, FileType),
+    /// Close of a file opened for writing.
+    CloseWrite(Option<PathBuf>, FileType),
     Unmount,
     Ignored,
     Unknown,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum FileType {
     Dir,
     File,
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Encodes an inotify record as the kernel does, with the name padded.
+    fn inotify_record(wd: i32, mask: u32, cookie: u32, name: &str) -> Vec<u8> {
+        let len =
+            if name.is_empty() { 0 } else { (name.len() + 16) / 16 * 16 };
+        let mut record = Vec::new();
+        record.extend_from_slice(&wd.to_ne_bytes());
+        record.extend_from_slice(&mask.to_ne_bytes());
+        record.extend_from_slice(&cookie.to_ne_bytes());
+        record.extend_from_slice(&(len as u32).to_ne_bytes());
+        record.extend_from_slice(name.as_bytes());
+        record.resize(INOTIFY_EVENT_HEADER_SIZE + len, 0);
+        record
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_arbitrary_records(
+            raw in proptest::collection::vec(any::<u8>(), 0..1024)
+        ) {
+            let mut raw = &raw[..];
+            while let Some((_, size)) = parse(raw) {
+                prop_assert!(size > 0 && size <= raw.len());
+                raw = &raw[size..];
+            }
+        }
+
+        #[test]
+        fn test_parse_records(
+            records in proptest::collection::vec(
+                (1..i32::MAX, 0..3usize, any::<u32>(), "[a-z]{1,255}"),
+                1..16,
+            )
+        ) {
+            let masks =
+                [libc::IN_CREATE, libc::IN_MOVED_FROM, libc::IN_MOVED_TO];
+            let raw: Vec<u8> = records
+                .iter()
+                .flat_map(|(wd, i, cookie, name)| {
+                    let mask = masks[*i] | libc::IN_ISDIR;
+                    inotify_record(*wd, mask, *cookie, name)
+                })
+                .collect();
+
+            let mut raw = &raw[..];
+            for (wd, i, cookie, name) in records {
+                let (event, size) = parse(raw).unwrap();
+                let event = event.unwrap();
+                let path = PathBuf::from(name);
+                let kind = match i {
+                    0 => EventKind::Create(path, FileType::Dir),
+                    1 => EventKind::MoveFrom(path, FileType::Dir),
+                    _ => EventKind::MoveTo(path, FileType::Dir),
+                };
+                prop_assert_eq!((event.wd, event.cookie), (wd, cookie));
+                prop_assert_eq!(event.kind, kind);
+                raw = &raw[size..];
+            }
+            // A record split across reads is incomplete, not malformed.
+            prop_assert!(raw.is_empty());
+            let record = inotify_record(1, libc::IN_CREATE, 0, "name");
+            prop_assert!(parse(&record[..20]).is_none());
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_end_after_read_error() {
+        use futures::{pin_mut, StreamExt};
+
+        // Not an inotify instance, so it can not be read.
+        let dir = tempfile::tempdir().unwrap();
+        let path =
+            std::ffi::CString::new(dir.path().as_os_str().as_bytes()).unwrap();
+        let fd = unsafe {
+            libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK)
+        };
+        assert!(fd >= 0);
+        let mut event_seq = EventSeq::new(fd);
+        let stream = event_seq.stream();
+        pin_mut!(stream);
+        assert!(matches!(stream.next().await, Some(Err(Error::Read { .. }))));
+        assert!(stream.next().await.is_none());
+    }
+
+    /// Moves are recognized from pairs of records by the watcher.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_recognize_moves() {
+        use futures::{pin_mut, StreamExt};
+
+        use crate::{Dotdir, Watcher, WatcherOpts};
+
+        // Shared by all the cases. Each case removes the file it moved.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let top_dir = tempfile::tempdir().unwrap();
+        let sub_dir = top_dir.path().join("sub");
+        fs::create_dir(&sub_dir).unwrap();
+
+        proptest!(|(names in proptest::collection::vec("[a-z]{1,8}", 1..8))| {
+            rt.block_on(async {
+                let mut watcher = Watcher::new(
+                    top_dir.as_ref(),
+                    WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+                )
+                .unwrap();
+                let stream = watcher.stream();
+                pin_mut!(stream);
+
+                let mut from = top_dir.path().join("file");
+                fs::File::create(&from).unwrap();
+                stream.next().await.unwrap();
+                for (i, name) in names.into_iter().enumerate() {
+                    let dir =
+                        if i % 2 == 0 { &sub_dir } else { top_dir.path() };
+                    let to = dir.join(format!("{}{}", name, i));
+                    fs::rename(&from, &to).unwrap();
+                    assert_eq!(
+                        stream.next().await.unwrap().0,
+                        crate::Event::Move(
+                            from,
+                            to.to_owned(),
+                            crate::FileType::File
+                        )
+                    );
+                    from = to;
+                }
+                fs::remove_file(&from).unwrap();
+            });
+        });
+    }
+}
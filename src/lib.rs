@@ -1,29 +1,107 @@
+// Without the watcher, much is only kept for the options of it.
+#![cfg_attr(not(feature = "async"), allow(dead_code))]
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "async")]
+mod bridge;
+#[cfg(feature = "async")]
+pub mod buffer;
+#[cfg(feature = "async")]
+pub mod burst;
+#[cfg(feature = "diff")]
+pub mod diff;
+mod digest;
+#[cfg(feature = "async")]
+pub mod group;
+#[cfg(feature = "async")]
+pub mod identity;
 mod inotify;
+mod log;
+#[cfg(feature = "async")]
+pub mod pair;
+#[cfg(feature = "async")]
 mod path_tree;
+mod plan;
+#[cfg(all(feature = "async", not(feature = "tokio-reactor")))]
+mod reactor;
+#[cfg(feature = "async")]
+pub mod record;
+#[cfg(feature = "async")]
+pub mod replay;
+#[cfg(feature = "async")]
+pub mod save;
+#[cfg(feature = "async")]
+pub mod size;
+#[cfg(feature = "async")]
+mod state;
+pub mod testing;
+pub mod throttle;
+mod walk;
+#[cfg(feature = "async")]
+mod watcher;
+
+/// Adapters to the APIs of other crates.
+#[cfg(feature = "blocking")]
+pub mod compat {
+    pub mod notify;
+}
+
+/// Tree of watched directories, only exposed for benchmarks.
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub mod tree {
+    pub use crate::path_tree::Head;
+}
+
+/// Parser of raw inotify records, only exposed to the fuzz targets.
+#[cfg(fuzzing)]
+pub mod parser {
+    pub use crate::inotify::{parse, Error, Event, EventKind, FileType};
+}
 
 use std::{
-    ffi::CString,
-    fs,
+    collections::BTreeMap,
+    ffi::OsString,
+    fs, io,
+    num::NonZeroUsize,
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use async_stream::stream;
-use futures::{pin_mut, Stream, StreamExt};
+pub use regex::bytes::Regex;
 use snafu::Snafu;
-use tracing::warn;
-use walkdir::WalkDir;
 
-#[derive(PartialEq, Debug)]
+#[cfg(feature = "async")]
+pub use crate::{
+    buffer::{Backpressure, BufferedStream},
+    watcher::Watcher,
+};
+pub use crate::{
+    digest::{Digest, HashAlgo, DEFAULT_HASH_SIZE_LIMIT},
+    plan::{plan, Skip},
+};
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum Event {
     Create(PathBuf, FileType),
     Move(PathBuf, PathBuf, FileType),
-    MoveAway(PathBuf, FileType),
-    MoveInto(PathBuf, FileType),
-    MoveTop(PathBuf),
+    /// Moved out of the watched tree. The last field is the cookie of
+    /// inotify, which is shared with the other half of the rename, e.g.
+    /// in another watcher. `pair::PairMoves` pairs them.
+    MoveAway(PathBuf, FileType, u32),
+    /// Moved into the watched tree, with the cookie like `MoveAway`.
+    MoveInto(PathBuf, FileType, u32),
+    /// The top directory was moved. The second field is where it went,
+    /// if that is known, and paths of later events are under it.
+    MoveTop(PathBuf, Option<PathBuf>),
     Delete(PathBuf, FileType),
     DeleteTop(PathBuf),
-    Modify(PathBuf, FileType),
+    /// The top directory was replaced by another one moved onto its path.
+    ReplaceTop(PathBuf),
+    Modify(PathBuf, FileType, Option<Digest>),
     Access(PathBuf, FileType),
     AccessTop(PathBuf),
     Attrib(PathBuf, FileType),
@@ -34,609 +112,884 @@ pub enum Event {
     CloseTop(PathBuf),
     Unmount(PathBuf, FileType),
     UnmountTop(PathBuf),
+    /// The top directory appeared again and is watched from scratch.
+    Reattached(PathBuf),
+    /// The directory could not be watched, so events in it are missed.
+    WatchError(PathBuf, io::ErrorKind),
+    /// The directory which could not be watched is watched now.
+    Watching(PathBuf),
+    /// A file system is mounted again on the directory after `Unmount`,
+    /// and it is watched again.
+    Remounted(PathBuf),
+    /// The directory and its subdirectories are no longer watched, to stay
+    /// within `max_watches`. They are watched again once their parent has
+    /// an event.
+    Unwatched(PathBuf),
+    /// The watch of the directory is gone and its subdirectories are
+    /// unwatched too, without another event to tell why, e.g. when that
+    /// was lost in an overflow, or the directory was replaced by a rename.
+    WatchRemoved(PathBuf),
     Noise,
     Ignored,
     Unknown,
 }
 
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Create(..) => "Create",
+            Self::Move(..) => "Move",
+            Self::MoveAway(..) => "MoveAway",
+            Self::MoveInto(..) => "MoveInto",
+            Self::MoveTop(..) => "MoveTop",
+            Self::Delete(..) => "Delete",
+            Self::DeleteTop(..) => "DeleteTop",
+            Self::ReplaceTop(..) => "ReplaceTop",
+            Self::Modify(..) => "Modify",
+            Self::Access(..) => "Access",
+            Self::AccessTop(..) => "AccessTop",
+            Self::Attrib(..) => "Attrib",
+            Self::AttribTop(..) => "AttribTop",
+            Self::Open(..) => "Open",
+            Self::OpenTop(..) => "OpenTop",
+            Self::Close(..) => "Close",
+            Self::CloseTop(..) => "CloseTop",
+            Self::Unmount(..) => "Unmount",
+            Self::UnmountTop(..) => "UnmountTop",
+            Self::Reattached(..) => "Reattached",
+            Self::WatchError(..) => "WatchError",
+            Self::Watching(..) => "Watching",
+            Self::Remounted(..) => "Remounted",
+            Self::Unwatched(..) => "Unwatched",
+            Self::WatchRemoved(..) => "WatchRemoved",
+            Self::Noise => "Noise",
+            Self::Ignored => "Ignored",
+            Self::Unknown => "Unknown",
+        }
+    }
+
+    /// Gives the file type of the event. The top directory is a directory.
+    pub fn file_type(&self) -> Option<&FileType> {
+        match self {
+            Self::Create(_, file_type)
+            | Self::Move(.., file_type)
+            | Self::MoveAway(_, file_type, _)
+            | Self::MoveInto(_, file_type, _)
+            | Self::Delete(_, file_type)
+            | Self::Modify(_, file_type, _)
+            | Self::Access(_, file_type)
+            | Self::Attrib(_, file_type)
+            | Self::Open(_, file_type)
+            | Self::Close(_, file_type)
+            | Self::Unmount(_, file_type) => Some(file_type),
+            Self::MoveTop(..)
+            | Self::DeleteTop(_)
+            | Self::ReplaceTop(_)
+            | Self::AccessTop(_)
+            | Self::AttribTop(_)
+            | Self::OpenTop(_)
+            | Self::CloseTop(_)
+            | Self::UnmountTop(_)
+            | Self::Reattached(_)
+            | Self::WatchError(..)
+            | Self::Watching(_)
+            | Self::Remounted(_)
+            | Self::Unwatched(_)
+            | Self::WatchRemoved(_) => Some(&FileType::Dir),
+            Self::Noise | Self::Ignored | Self::Unknown => None,
+        }
+    }
+
+    /// Gives the path of the event, or the source path of a move.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Create(path, _)
+            | Self::Move(path, ..)
+            | Self::MoveAway(path, ..)
+            | Self::MoveInto(path, ..)
+            | Self::MoveTop(path, _)
+            | Self::Delete(path, _)
+            | Self::DeleteTop(path)
+            | Self::ReplaceTop(path)
+            | Self::Modify(path, ..)
+            | Self::Access(path, _)
+            | Self::AccessTop(path)
+            | Self::Attrib(path, _)
+            | Self::AttribTop(path)
+            | Self::Open(path, _)
+            | Self::OpenTop(path)
+            | Self::Close(path, _)
+            | Self::CloseTop(path)
+            | Self::Unmount(path, _)
+            | Self::UnmountTop(path)
+            | Self::Reattached(path)
+            | Self::WatchError(path, _)
+            | Self::Watching(path)
+            | Self::Remounted(path)
+            | Self::Unwatched(path)
+            | Self::WatchRemoved(path) => Some(path),
+            Self::Noise | Self::Ignored | Self::Unknown => None,
+        }
+    }
+
+    /// Gives where the top directory went if the event moved it, which
+    /// the paths of later events are under.
+    pub fn moved_top(&self) -> Option<&Path> {
+        match self {
+            Self::MoveTop(_, Some(to)) => Some(to),
+            _ => None,
+        }
+    }
+
+    /// Gives the event with the paths in `from` rebased on `to`, if any
+    /// is. Events of the top directory and others are not rebased.
+    pub(crate) fn rebase(&self, from: &Path, to: &Path) -> Option<Self> {
+        let mut event = self.clone();
+        let paths = match &mut event {
+            Self::Move(from_path, to_path, _) => vec![from_path, to_path],
+            Self::Create(path, _)
+            | Self::MoveAway(path, ..)
+            | Self::MoveInto(path, ..)
+            | Self::Delete(path, _)
+            | Self::Modify(path, ..)
+            | Self::Access(path, _)
+            | Self::Attrib(path, _)
+            | Self::Open(path, _)
+            | Self::Close(path, _)
+            | Self::Unmount(path, _) => vec![path],
+            _ => return None,
+        };
+        let mut rebased = false;
+        for path in paths {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = to.join(rest);
+                rebased = true;
+            }
+        }
+        Some(event).filter(|_| rebased)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Dotdir {
     Include,
     Exclude,
 }
 
+/// How symbolic links to directories are treated.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Symlink {
+    /// Never follow links, not even the top directory.
+    NoFollow,
+    /// Follow the top directory only.
+    FollowTop,
+    /// Also follow links to directories in the tree. Loops are skipped.
+    Follow,
+}
+
 #[derive(Debug, Snafu)]
 #[allow(clippy::enum_variant_names)]
+#[non_exhaustive]
 pub enum Error {
     #[snafu(display("Failed to use inotify API"))]
     InitInotify,
 
+    /// Failed to watch the directory for other reasons than those below.
     #[snafu(display("{}: {}", source, path.display()))]
     AddWatch { source: std::io::Error, path: PathBuf },
 
+    #[snafu(display(
+        "{}: {}",
+        io::Error::from_raw_os_error(*errno),
+        path.display()
+    ))]
+    PermissionDenied { errno: i32, path: PathBuf },
+
+    /// No inotify watches are left for the directory. Each directory which
+    /// fails so is sent to `Watcher::errors`, and those of a scan are then
+    /// summed up by `WatchLimitReached`.
+    #[snafu(display(
+        "{}: {}",
+        io::Error::from_raw_os_error(*errno),
+        path.display()
+    ))]
+    WatchLimit { errno: i32, path: PathBuf },
+
+    #[snafu(display(
+        "{}: {}",
+        io::Error::from_raw_os_error(*errno),
+        path.display()
+    ))]
+    NotADirectory { errno: i32, path: PathBuf },
+
+    #[snafu(display(
+        "{}: {}",
+        io::Error::from_raw_os_error(*errno),
+        path.display()
+    ))]
+    NotFound { errno: i32, path: PathBuf },
+
     #[snafu(display("Watch the same path multiple times: {}", path.display()))]
     WatchSame { wd: i32, path: PathBuf },
+
+    /// Directories of a scan failed with `WatchLimit`. `Watcher::new` fails
+    /// with it, and later scans send it to `Watcher::errors`.
+    #[snafu(display(
+        "Reached the limit of inotify watches ({}): {} watched, {} failed",
+        limit.map_or_else(|| "unknown".to_owned(), |v| v.to_string()),
+        watched,
+        failed,
+    ))]
+    WatchLimitReached { watched: usize, failed: usize, limit: Option<usize> },
+
+    #[snafu(display("Event queue overflowed, some events are lost"))]
+    Overflow,
+
+    #[snafu(display("Failed to parse event: {}", reason))]
+    ParseEvent { reason: String },
+
+    #[snafu(display("Failed to read events: {}", source))]
+    ReadEvents { source: std::io::Error },
+
+    #[snafu(display("Failed to read event log: {}", source))]
+    ReadLog { source: std::io::Error },
+
+    #[snafu(display(
+        "Invalid record at line {} of event log: {}",
+        line,
+        reason
+    ))]
+    ParseLog { line: usize, reason: String },
+
+    #[snafu(display("Failed to save state to {}: {}", path.display(), source))]
+    SaveState { source: std::io::Error, path: PathBuf },
+
+    #[snafu(display(
+        "Failed to load state from {}: {}",
+        path.display(),
+        source
+    ))]
+    LoadState { source: std::io::Error, path: PathBuf },
+
+    #[snafu(display("Invalid record at line {} of state: {}", line, reason))]
+    ParseState { line: usize, reason: String },
+
+    #[snafu(display("Failed to start the runtime: {}", source))]
+    StartRuntime { source: std::io::Error },
+
+    #[snafu(display("Not watching {}", path.display()))]
+    WatchNotFound { path: PathBuf },
+
+    /// The option waits on timers or blocking tasks of tokio, so
+    /// `Watcher::new` fails with it without the feature `tokio-reactor`.
+    #[snafu(display("{} needs the feature tokio-reactor", option))]
+    NeedsTokio { option: &'static str },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub struct Watcher {
-    opts: WatcherOpts,
-    fd: i32,
-    top_wd: i32,
-    top_dir: PathBuf,
-    path_tree: path_tree::Head<i32>,
-    event_seq: inotify::EventSeq,
-    cached_inotify_event: Option<inotify::Event>,
+impl Error {
+    /// Gives the error of failing to watch the directory.
+    pub(crate) fn add_watch(source: io::Error, path: &Path) -> Self {
+        let path = path.to_owned();
+        match source.raw_os_error() {
+            Some(errno @ libc::EACCES) => {
+                Self::PermissionDenied { errno, path }
+            }
+            Some(errno @ libc::ENOSPC) => Self::WatchLimit { errno, path },
+            Some(errno @ libc::ENOTDIR) => Self::NotADirectory { errno, path },
+            Some(errno @ libc::ENOENT) => Self::NotFound { errno, path },
+            _ => Self::AddWatch { source, path },
+        }
+    }
+
+    /// Tells whether the error is of the limit of inotify watches, for one
+    /// directory or for a scan.
+    pub fn is_watch_limit(&self) -> bool {
+        matches!(
+            self,
+            Self::WatchLimit { .. } | Self::WatchLimitReached { .. }
+        )
+    }
+
+    /// Gives the directory, the kind and the errno of a failure to watch
+    /// it.
+    pub(crate) fn failed_watch(
+        &self,
+    ) -> Option<(&Path, io::ErrorKind, Option<i32>)> {
+        match self {
+            Self::AddWatch { source, path } => {
+                Some((path, source.kind(), source.raw_os_error()))
+            }
+            Self::PermissionDenied { errno, path }
+            | Self::WatchLimit { errno, path }
+            | Self::NotADirectory { errno, path }
+            | Self::NotFound { errno, path } => Some((
+                path,
+                io::Error::from_raw_os_error(*errno).kind(),
+                Some(*errno),
+            )),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct WatcherOpts {
     sub_dotdir: Dotdir,
     event_types: u32,
+    hash_algo: Option<HashAlgo>,
+    hash_size_limit: u64,
+    dedupe_modify: Option<NonZeroUsize>,
+    reattach_top: bool,
+    watch_parent: bool,
+    include_regex: Vec<Regex>,
+    exclude_regex: Vec<Regex>,
+    mask_overrides: Vec<(Regex, u32)>,
+    file_types: Option<Vec<FileType>>,
+    filter_fn: Option<Arc<FilterFn>>,
+    hidden_prefixes: Vec<OsString>,
+    exclude_hidden_files: bool,
+    retry_interval: Option<Duration>,
+    lazy_depth: Option<usize>,
+    max_depth: Option<usize>,
+    max_watches: Option<NonZeroUsize>,
+    rewatch_interval: Option<Duration>,
+    alias_events: bool,
+    dedupe_dirs: bool,
+    one_file_system: bool,
+    rescan_on_resume: bool,
+    suspend_threshold: Option<Duration>,
+    symlink: Symlink,
 }
 
+type FilterFn = dyn Fn(&Path, FileType) -> bool + Send + Sync;
+
 impl WatcherOpts {
     pub fn new(sub_dotdir: Dotdir, extra_events: Vec<ExtraEvent>) -> Self {
-        let mut event_types = libc::IN_CREATE
+        let event_types = libc::IN_CREATE
             | libc::IN_MOVE
             | libc::IN_MOVE_SELF
             | libc::IN_DELETE
             | libc::IN_DELETE_SELF
-            | libc::IN_ONLYDIR;
-        event_types = extra_events.iter().fold(event_types, |v, e| match e {
-            ExtraEvent::Modify => v | libc::IN_MODIFY,
-            ExtraEvent::Access => v | libc::IN_ACCESS,
-            ExtraEvent::Attrib => v | libc::IN_ATTRIB,
-            ExtraEvent::Open => v | libc::IN_OPEN,
-            ExtraEvent::Close => v | libc::IN_CLOSE,
-        });
+            | libc::IN_ONLYDIR
+            | mask(&extra_events);
+
+        Self {
+            sub_dotdir,
+            event_types,
+            hash_algo: None,
+            hash_size_limit: DEFAULT_HASH_SIZE_LIMIT,
+            dedupe_modify: None,
+            reattach_top: false,
+            watch_parent: false,
+            include_regex: Vec::new(),
+            exclude_regex: Vec::new(),
+            mask_overrides: Vec::new(),
+            file_types: None,
+            filter_fn: None,
+            hidden_prefixes: vec![OsString::from(".")],
+            exclude_hidden_files: false,
+            retry_interval: None,
+            lazy_depth: None,
+            max_depth: None,
+            max_watches: None,
+            rewatch_interval: None,
+            alias_events: false,
+            dedupe_dirs: true,
+            one_file_system: false,
+            rescan_on_resume: false,
+            suspend_threshold: None,
+            symlink: Symlink::FollowTop,
+        }
+    }
+
+    /// Chooses how symbolic links are treated. Defaults to
+    /// `Symlink::FollowTop`.
+    pub fn symlink(mut self, symlink: Symlink) -> Self {
+        self.symlink = symlink;
+        if symlink == Symlink::NoFollow {
+            self.event_types |= libc::IN_DONT_FOLLOW;
+        } else {
+            self.event_types &= !libc::IN_DONT_FOLLOW;
+        }
+        self
+    }
+
+    /// Stops events of files after they are unlinked, e.g. deleted files
+    /// which are still open.
+    pub fn excl_unlink(mut self, excl_unlink: bool) -> Self {
+        if excl_unlink {
+            self.event_types |= libc::IN_EXCL_UNLINK;
+        } else {
+            self.event_types &= !libc::IN_EXCL_UNLINK;
+        }
+        self
+    }
+
+    /// Tries again every `interval` to watch the directories which could
+    /// not be watched for lack of permission or watches, and at once when
+    /// their permissions change. `Event::Watching` is yielded for each one
+    /// which succeeds. Needs the feature `tokio-reactor`.
+    pub fn retry_failed(mut self, interval: Duration) -> Self {
+        self.retry_interval = Some(interval);
+        self
+    }
+
+    /// Checks every `interval` whether a file system is mounted again on
+    /// directories unmounted with `Event::Unmount`, as long as their
+    /// parents are watched. Each one is watched again and yields
+    /// `Event::Remounted`. Needs the feature `tokio-reactor`.
+    pub fn rewatch_unmounted(mut self, interval: Duration) -> Self {
+        self.rewatch_interval = Some(interval);
+        self
+    }
+
+    /// Watches directories only up to `depth` levels below each directory
+    /// being watched, e.g. the top one. The subdirectories of a directory
+    /// at the last level are watched once it has an event, and so on,
+    /// so changes deeper in quiet parts of the tree are missed.
+    pub fn lazy_depth(mut self, depth: usize) -> Self {
+        self.lazy_depth = Some(depth);
+        self
+    }
+
+    /// Never watches directories more than `depth` levels below the top
+    /// directory, unlike `lazy_depth`. With 0, only the top directory is
+    /// watched.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Keeps at most `max` directories watched. Beyond that, the least
+    /// recently active ones are unwatched with `Event::Unwatched`, like
+    /// with `lazy_depth`, and directories are left unwatched while
+    /// scanning.
+    pub fn max_watches(mut self, max: usize) -> Self {
+        self.max_watches = NonZeroUsize::new(max);
+        self
+    }
+
+    /// Repeats the events in a directory reached by several paths, e.g.
+    /// through a bind mount, under each path. Otherwise the paths share one
+    /// watch and its events come under the path scanned first.
+    pub fn alias_events(mut self, alias: bool) -> Self {
+        self.alias_events = alias;
+        self
+    }
+
+    /// Scans the watched directories when paused and again when resumed,
+    /// and yields `Create`, `Delete` and `Modify` events for the changes
+    /// in between, which were dropped.
+    pub fn rescan_on_resume(mut self, rescan: bool) -> Self {
+        self.rescan_on_resume = rescan;
+        self
+    }
+
+    /// Checks every second whether the system was suspended, e.g. a laptop
+    /// asleep, for at least `min`. The files in watched directories are
+    /// listed at the checks after events, and if so, directories are
+    /// scanned again: new ones are watched, and the changes from the list
+    /// yield `Create`, `Delete`, and `Modify` if it is watched. Needs the
+    /// feature `tokio-reactor`.
+    pub fn rescan_after_suspend(mut self, min: Duration) -> Self {
+        self.suspend_threshold = Some(min);
+        self
+    }
+
+    /// Scans each directory once, by its device and inode. A directory
+    /// reached again through another path, e.g. a bind mount, shares the
+    /// watch of the first path without its subdirectories scanned again.
+    /// Defaults to true.
+    pub fn dedupe_dirs(mut self, dedupe: bool) -> Self {
+        self.dedupe_dirs = dedupe;
+        self
+    }
+
+    /// Does not watch directories on other file systems than the top
+    /// directory, e.g. `/proc` or network mounts under it.
+    pub fn one_file_system(mut self, one: bool) -> Self {
+        self.one_file_system = one;
+        self
+    }
+
+    /// Names with any of these prefixes are hidden. Defaults to `.`.
+    pub fn hidden_prefixes(mut self, prefixes: Vec<OsString>) -> Self {
+        self.hidden_prefixes = prefixes;
+        self
+    }
+
+    /// Drops events of hidden files, like hidden subdirectories are not
+    /// watched by `Dotdir::Exclude`.
+    pub fn exclude_hidden_files(mut self, exclude: bool) -> Self {
+        self.exclude_hidden_files = exclude;
+        self
+    }
 
-        Self { sub_dotdir, event_types }
+    /// Skips paths for which the function gives false. It is consulted for
+    /// directories before watching them and for events before yielding
+    /// them. The top directory is always watched.
+    pub fn filter_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Path, FileType) -> bool + Send + Sync + 'static,
+    {
+        self.filter_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Only yields events of files of these types.
+    pub fn file_types(mut self, file_types: Vec<FileType>) -> Self {
+        self.file_types = Some(file_types);
+        self
+    }
+
+    /// Only yields events with a path matching any of such regexes. Full
+    /// paths are matched as bytes.
+    pub fn include_regex(mut self, regex: Regex) -> Self {
+        self.include_regex.push(regex);
+        self
+    }
+
+    /// Drops events with a path matching the regex. It takes precedence
+    /// over `include_regex`.
+    pub fn exclude_regex(mut self, regex: Regex) -> Self {
+        self.exclude_regex.push(regex);
+        self
+    }
+
+    /// Receives the extra events also in directories with a path matching
+    /// the regex, e.g. modify events only under `src`. Full paths are
+    /// matched as bytes, when the directories are watched. Their masks are
+    /// added with `IN_MASK_ADD`, so the others are kept.
+    pub fn mask_override(
+        mut self,
+        regex: Regex,
+        extra_events: Vec<ExtraEvent>,
+    ) -> Self {
+        self.mask_overrides.push((regex, mask(&extra_events)));
+        self
+    }
+
+    /// Gives the first option set which needs the runtime of tokio.
+    #[cfg(not(feature = "tokio-reactor"))]
+    pub(crate) fn tokio_option(&self) -> Option<&'static str> {
+        [
+            ("retry_failed", self.retry_interval.is_some()),
+            ("rewatch_unmounted", self.rewatch_interval.is_some()),
+            ("rescan_after_suspend", self.suspend_threshold.is_some()),
+            ("hash_on_modify", self.hash_algo.is_some()),
+            ("reattach_top", self.reattach_top),
+        ]
+        .iter()
+        .find(|(_, set)| *set)
+        .map(|(option, _)| *option)
+    }
+
+    /// Gives the mask of watches, with the events needed by options.
+    pub(crate) fn mask(&self) -> u32 {
+        let mut mask = self.event_types;
+        if self.hash_algo.is_some() {
+            mask |= libc::IN_CLOSE_WRITE;
+        }
+        // Failed directories are tried again when their permissions change.
+        if self.retry_interval.is_some() {
+            mask |= libc::IN_ATTRIB;
+        }
+        mask
+    }
+
+    /// Gives the extra events of the overrides matching the directory.
+    pub(crate) fn extra_mask(&self, dir: &Path) -> u32 {
+        let dir = dir.as_os_str().as_bytes();
+        self.mask_overrides
+            .iter()
+            .filter(|(regex, _)| regex.is_match(dir))
+            .fold(0, |v, (_, mask)| v | mask)
+    }
+
+    /// Suppresses modify events of files whose size and mtime, or digest
+    /// if hashing, are unchanged since the last modify event. The states
+    /// of at most `capacity` recently modified files are remembered.
+    pub fn dedupe_modify(mut self, capacity: usize) -> Self {
+        self.dedupe_modify = NonZeroUsize::new(capacity);
+        self
+    }
+
+    /// Includes the digest of the file in modify events. The modify events
+    /// of a file are held until it is closed after writing, when a worker
+    /// hashes it on the blocking pool of the runtime, and one is yielded
+    /// after that, so it may come after later events. If the worker falls
+    /// behind, the event is yielded without a digest. Needs the feature
+    /// `tokio-reactor`.
+    pub fn hash_on_modify(mut self, algo: HashAlgo) -> Self {
+        self.hash_algo = Some(algo);
+        self
+    }
+
+    /// Keeps going after the top directory is deleted, moved away or
+    /// unmounted. The watcher waits for a directory to appear at the same
+    /// path, watches it instead and yields `Event::Reattached`. Needs the
+    /// feature `tokio-reactor`.
+    pub fn reattach_top(mut self, reattach: bool) -> Self {
+        self.reattach_top = reattach;
+        self
+    }
+
+    /// Watches renames in the parent of the top directory too, so that
+    /// `Event::MoveTop` has the new path when the top directory is renamed
+    /// within its parent, and `Event::ReplaceTop` is told from
+    /// `Event::DeleteTop`.
+    pub fn watch_parent(mut self, watch_parent: bool) -> Self {
+        self.watch_parent = watch_parent;
+        self
+    }
+
+    /// Skips hashing files larger than `size_limit` bytes.
+    pub fn hash_size_limit(mut self, size_limit: u64) -> Self {
+        self.hash_size_limit = size_limit;
+        self
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ExtraEvent {
     Modify,
     Access,
     Attrib,
     Open,
     Close,
+    /// Close of files opened for writing only, given as `Event::Close`.
+    CloseWrite,
 }
 
-impl Watcher {
-    pub fn new(dir: &Path, opts: WatcherOpts) -> Result<Self> {
-        let fd = unsafe { libc::inotify_init() };
-        if fd < 0 {
-            return Err(Error::InitInotify);
-        }
-
-        let mut watcher = Self {
-            fd,
-            opts,
-            top_wd: 0,
-            top_dir: dir.to_owned(),
-            path_tree: path_tree::Head::new(dir.to_owned()),
-            event_seq: inotify::EventSeq::new(fd),
-            cached_inotify_event: None,
-        };
-        if let (Some(top_wd), walk) = watcher.add_watch_all(dir) {
-            watcher.top_wd = top_wd;
-            for entry in walk {
-                if let Err(e) = watcher.add_watch(entry.path()) {
-                    warn!("{}", e);
-                }
+impl ExtraEvent {
+    /// Tells whether the event is only given with this extra event.
+    pub fn gives(&self, event: &Event) -> bool {
+        match event {
+            Event::Modify(..) => *self == Self::Modify,
+            Event::Access(..) | Event::AccessTop(_) => *self == Self::Access,
+            Event::Attrib(..) | Event::AttribTop(_) => *self == Self::Attrib,
+            Event::Open(..) | Event::OpenTop(_) => *self == Self::Open,
+            Event::Close(..) | Event::CloseTop(_) => {
+                matches!(self, Self::Close | Self::CloseWrite)
             }
+            _ => false,
         }
+    }
 
-        Ok(watcher)
-    }
-
-    pub fn stream(
-        &mut self,
-    ) -> impl Stream<Item = (Event, time::OffsetDateTime)> + '_ {
-        stream! {
-            loop {
-                let (inotify_event, event, wd) = loop {
-                    let inotify_event = match self.cached_inotify_event.take()
-                    {
-                        Some(e) => e,
-                        None => {
-                            let stream = self.event_seq.stream();
-                            pin_mut!(stream);
-                            // FIXME: handle error
-                            stream.next().await.unwrap().unwrap()
-                        }
-                    };
-                    let (event, wd) = self.recognize(&inotify_event).await;
-                    if event != Event::Noise {
-                        break (inotify_event, event, wd);
-                    }
-                };
-
-                match event {
-                    Event::Move(ref from_path, ref to_path, FileType::Dir) => {
-                        if guard(self.opts, from_path, FileType::Dir) {
-                            if guard(self.opts, to_path, FileType::Dir) {
-                                self.update_path(wd.unwrap(), to_path);
-                            } else {
-                                self.rm_watch_all(wd.unwrap());
-                            }
-                        } else {
-                            if guard(self.opts, to_path, FileType::Dir) {
-                                let (_, walk) = self.add_watch_all(to_path);
-                                for entry in walk {
-                                    if let Err(e) = self.add_watch(
-                                        entry.path()) {
-                                        warn!("{}", e);
-                                    }
-                                }
-                            }
-                        }
-                        yield (event, inotify_event.t)
-                    }
-                    Event::MoveAway(_, FileType::Dir)
-                        | Event::Delete(_, FileType::Dir) => {
-                        if let Some(wd) = wd {
-                            self.rm_watch_all(wd);
-                        }
-                        yield (event, inotify_event.t)
-                    }
-                    Event::MoveInto(ref path, FileType::Dir) => {
-                        if let Ok(metadata) = fs::symlink_metadata(path) {
-                            if guard(self.opts, path,
-                                metadata.file_type().into()) {
-                                let (_, walk) = self.add_watch_all(path);
-                                for entry in walk {
-                                    if let Err(e) = self.add_watch(
-                                        entry.path()) {
-                                        warn!("{}", e);
-                                    }
-                                }
-                            }
-                        }
-                        yield (event, inotify_event.t)
-                    }
-                    Event::Create(ref path, FileType::Dir) => {
-                        if let Ok(metadata) = fs::symlink_metadata(path) {
-                            if guard(self.opts, path,
-                                metadata.file_type().into()) {
-                                let next_events: Vec<Event> = self
-                                    .add_watch_all(path)
-                                    .1
-                                    .map(|entry| entry.path().to_owned())
-                                    .map(|path| {
-                                        if let Err(e) = self.add_watch(&path) {
-                                            warn!("{}", e);
-                                        }
-                                        path
-                                    })
-                                    .map(|path| Event::Create(
-                                            path, FileType::Dir))
-                                    .collect();
-
-                                yield (event, inotify_event.t);
-                                for event in next_events {
-                                    yield (event, inotify_event.t)
-                                }
-                            } else {
-                                yield (event, inotify_event.t)
-                            }
-                        } else {
-                            yield (event, inotify_event.t)
-                        }
-                    }
-                    Event::DeleteTop(_) | Event::UnmountTop(_) => {
-                        let top_wd = self.top_wd;
-                        self.rm_watch_all(top_wd);
-                        yield (event, inotify_event.t)
-                    }
-                    Event::Unmount(..) => {
-                        self.rm_watch_all(inotify_event.wd);
-                        yield (event, inotify_event.t)
-                    }
-
-                    _ => {
-                        yield (event, inotify_event.t)
-                    }
-                }
-            }
-        }
+    /// Tells whether the event is one of the default events, or is given
+    /// with one of the extra events.
+    pub fn allows(extra_events: &[Self], event: &Event) -> bool {
+        let all = [
+            Self::Modify,
+            Self::Access,
+            Self::Attrib,
+            Self::Open,
+            Self::Close,
+        ];
+        !all.iter().any(|v| v.gives(event))
+            || extra_events.iter().any(|v| v.gives(event))
     }
+}
 
-    fn add_watch(&mut self, path: &Path) -> Result<i32> {
-        let ffi_path = CString::new(path.as_os_str().as_bytes()).unwrap();
-        let wd = unsafe {
-            libc::inotify_add_watch(
-                self.fd,
-                ffi_path.as_ptr(),
-                self.opts.event_types,
-            )
-        };
-        if wd < 0 {
-            return Err(Error::AddWatch {
-                source: std::io::Error::last_os_error(),
-                path: path.to_owned(),
-            });
-        }
+fn mask(extra_events: &[ExtraEvent]) -> u32 {
+    extra_events.iter().fold(0, |v, e| match e {
+        ExtraEvent::Modify => v | libc::IN_MODIFY,
+        ExtraEvent::Access => v | libc::IN_ACCESS,
+        ExtraEvent::Attrib => v | libc::IN_ATTRIB,
+        ExtraEvent::Open => v | libc::IN_OPEN,
+        ExtraEvent::Close => v | libc::IN_CLOSE,
+        ExtraEvent::CloseWrite => v | libc::IN_CLOSE_WRITE,
+    })
+}
 
-        if self.path_tree.has(wd) {
-            return Err(Error::WatchSame { wd, path: path.to_owned() });
-        }
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Number of yielded events by their names.
+    pub events: BTreeMap<&'static str, u64>,
+    /// Number of watched directories.
+    pub watches: usize,
+    /// Depth of the deepest watched directory below the top one.
+    pub depth: usize,
+    /// Times the kernel event queue overflowed.
+    pub overflows: u64,
+    /// Records from the kernel which are not recognized.
+    pub dropped: u64,
+}
 
-        self.path_tree.insert(path, wd).unwrap();
-        Ok(wd)
+#[derive(Clone)]
+pub struct StatsReader(Arc<Mutex<Stats>>);
+
+impl StatsReader {
+    pub fn read(&self) -> Stats {
+        self.0.lock().unwrap().clone()
     }
+}
 
-    fn add_watch_all(
-        &mut self,
-        path: &Path,
-    ) -> (Option<i32>, impl Iterator<Item = walkdir::DirEntry>) {
-        let top_wd = match self.add_watch(path) {
-            Err(e) => {
-                warn!("{}", e);
-                None
-            }
-            Ok(wd) => Some(wd),
-        };
-        let opts = self.opts;
-        let new_dirs = WalkDir::new(path)
-            .min_depth(1)
-            .into_iter()
-            .filter_entry(move |entry| {
-                guard(opts, entry.path(), entry.file_type().into())
-            })
-            .filter_map(Result::ok);
+/// Requests to the watcher, handled by the stream.
+#[cfg(feature = "async")]
+pub(crate) enum Request {
+    Watches(tokio::sync::oneshot::Sender<Vec<PathBuf>>),
+    Pause(bool),
+    Watch(PathBuf),
+    Unwatch(PathBuf),
+    Regex { include: Vec<Regex>, exclude: Vec<Regex> },
+}
 
-        (top_wd, new_dirs)
+/// Gives the watched directories while the watcher is streaming.
+#[cfg(feature = "async")]
+#[derive(Clone)]
+pub struct WatchesReader(tokio::sync::mpsc::UnboundedSender<Request>);
+
+#[cfg(feature = "async")]
+impl WatchesReader {
+    /// Waits for the stream to answer, so only while it is polled. Gives
+    /// `None` if the watcher is gone.
+    pub async fn read(&self) -> Option<Vec<PathBuf>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.0.send(Request::Watches(tx)).ok()?;
+        rx.await.ok()
     }
+}
 
-    fn path(&self, wd: i32) -> PathBuf {
-        self.path_tree.path(wd)
-    }
+/// Controls the watcher from other tasks while it is streaming. Requests
+/// take effect while the stream is polled. Methods give false if the
+/// watcher is gone.
+#[cfg(feature = "async")]
+#[derive(Clone)]
+pub struct WatcherController {
+    tx: tokio::sync::mpsc::UnboundedSender<Request>,
+    stats: Arc<Mutex<Stats>>,
+}
 
-    fn full_path(&self, wd: i32, path: &Path) -> PathBuf {
-        self.path(wd).join(path)
+#[cfg(feature = "async")]
+impl WatcherController {
+    /// Drops events until `resume`, except those about watching, such as
+    /// `WatchError`. Directories are still watched as they change.
+    pub fn pause(&self) -> bool {
+        self.send(Request::Pause(true))
     }
 
-    fn update_path(&mut self, wd: i32, path: &Path) {
-        self.path_tree.rename(wd, path).unwrap()
+    pub fn resume(&self) -> bool {
+        self.send(Request::Pause(false))
     }
 
-    fn rm_watch_all(&mut self, wd: i32) {
-        let values = self.path_tree.delete(wd).unwrap();
-        for wd in values {
-            unsafe {
-                libc::inotify_rm_watch(self.fd, wd);
-            }
-        }
+    /// Watches the directory in the tree and its subdirectories, e.g. one
+    /// skipped by `filter_fn`.
+    pub fn watch(&self, path: impl Into<PathBuf>) -> bool {
+        self.send(Request::Watch(path.into()))
     }
 
-    async fn next_inotify_event(&mut self) -> Option<inotify::Event> {
-        if self.event_seq.has_next_event() {
-            let stream = self.event_seq.stream();
-            pin_mut!(stream);
-            // FIXME: handle error
-            Some(stream.next().await.unwrap().unwrap())
-        } else {
-            None
-        }
+    /// Stops watching the directory in the tree and its subdirectories,
+    /// until it is created or moved there again. The top directory is
+    /// always watched.
+    pub fn unwatch(&self, path: impl Into<PathBuf>) -> bool {
+        self.send(Request::Unwatch(path.into()))
     }
 
-    pub fn has_next_event(&mut self) -> bool {
-        self.cached_inotify_event.is_some() | self.event_seq.has_next_event()
-    }
-
-    async fn recognize(
-        &mut self,
-        inotify_event: &inotify::Event,
-    ) -> (Event, Option<i32>) {
-        let wd = inotify_event.wd;
-
-        match &inotify_event.kind {
-            inotify::EventKind::Create(path, file_type) => {
-                let full_path = self.full_path(wd, path);
-                let event = match file_type {
-                    inotify::FileType::Dir => {
-                        Event::Create(full_path, FileType::Dir)
-                    }
-                    inotify::FileType::File => {
-                        Event::Create(full_path, FileType::File)
-                    }
-                };
-                (event, None)
-            }
-
-            inotify::EventKind::MoveFrom(from_path, file_type) => {
-                let full_from_path = self.full_path(wd, from_path);
-                if let Some(next_inotify_event) =
-                    self.next_inotify_event().await
-                {
-                    match next_inotify_event.kind {
-                        inotify::EventKind::MoveSelf => {
-                            if next_inotify_event.wd != self.top_wd {
-                                (
-                                    Event::MoveAway(
-                                        full_from_path,
-                                        FileType::Dir,
-                                    ),
-                                    Some(next_inotify_event.wd),
-                                )
-                            } else {
-                                self.cached_inotify_event =
-                                    Some(next_inotify_event);
-                                (
-                                    Event::MoveAway(
-                                        full_from_path,
-                                        FileType::from(file_type),
-                                    ),
-                                    None,
-                                )
-                            }
-                        }
-                        inotify::EventKind::MoveTo(
-                            ref to_path,
-                            ref file_type,
-                        ) => {
-                            if inotify_event.cookie
-                                != next_inotify_event.cookie
-                            {
-                                let file_type = FileType::from(file_type);
-                                self.cached_inotify_event =
-                                    Some(next_inotify_event);
-                                (
-                                    Event::MoveAway(full_from_path, file_type),
-                                    None,
-                                )
-                            } else {
-                                let full_to_path = self
-                                    .full_path(next_inotify_event.wd, to_path);
-                                if let Some(next2_inotify_event) =
-                                    self.next_inotify_event().await
-                                {
-                                    match next2_inotify_event.kind {
-                                        inotify::EventKind::MoveSelf => (
-                                            Event::Move(
-                                                full_from_path,
-                                                full_to_path,
-                                                FileType::Dir,
-                                            ),
-                                            Some(next2_inotify_event.wd),
-                                        ),
-                                        _ => {
-                                            self.cached_inotify_event =
-                                                Some(next2_inotify_event);
-                                            (
-                                                Event::Move(
-                                                    full_from_path,
-                                                    full_to_path,
-                                                    FileType::from(file_type),
-                                                ),
-                                                None,
-                                            )
-                                        }
-                                    }
-                                } else {
-                                    (
-                                        Event::Move(
-                                            full_from_path,
-                                            full_to_path,
-                                            FileType::from(file_type),
-                                        ),
-                                        None,
-                                    )
-                                }
-                            }
-                        }
-                        _ => {
-                            self.cached_inotify_event =
-                                Some(next_inotify_event);
-                            (
-                                Event::MoveAway(
-                                    full_from_path,
-                                    FileType::from(file_type),
-                                ),
-                                None,
-                            )
-                        }
-                    }
-                } else {
-                    (
-                        Event::MoveAway(
-                            full_from_path,
-                            FileType::from(file_type),
-                        ),
-                        None,
-                    )
-                }
-            }
+    /// Replaces the regexes of `include_regex` and `exclude_regex`.
+    pub fn set_regex(&self, include: Vec<Regex>, exclude: Vec<Regex>) -> bool {
+        self.send(Request::Regex { include, exclude })
+    }
 
-            inotify::EventKind::MoveTo(path, file_type) => {
-                let full_path = self.full_path(wd, path);
-                let event = match file_type {
-                    inotify::FileType::Dir => {
-                        Event::MoveInto(full_path, FileType::Dir)
-                    }
-                    inotify::FileType::File => {
-                        Event::MoveInto(full_path, FileType::File)
-                    }
-                };
-                (event, None)
-            }
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
 
-            inotify::EventKind::Delete(path, file_type) => {
-                let full_path = self.full_path(wd, path);
-                if let Some(next_inotify_event) =
-                    self.next_inotify_event().await
-                {
-                    match next_inotify_event.kind {
-                        inotify::EventKind::DeleteSelf => {
-                            if next_inotify_event.wd == self.top_wd {
-                                self.cached_inotify_event =
-                                    Some(next_inotify_event);
-                                (
-                                    Event::Delete(
-                                        full_path,
-                                        FileType::from(file_type),
-                                    ),
-                                    None,
-                                )
-                            } else {
-                                (
-                                    Event::Delete(full_path, FileType::Dir),
-                                    Some(next_inotify_event.wd),
-                                )
-                            }
-                        }
-                        _ => {
-                            self.cached_inotify_event =
-                                Some(next_inotify_event);
-                            (
-                                Event::Delete(
-                                    full_path,
-                                    FileType::from(file_type),
-                                ),
-                                None,
-                            )
-                        }
-                    }
-                } else {
-                    (Event::Delete(full_path, FileType::from(file_type)), None)
-                }
-            }
+    /// Like `WatchesReader::read`.
+    pub async fn watched_paths(&self) -> Option<Vec<PathBuf>> {
+        WatchesReader(self.tx.clone()).read().await
+    }
 
-            inotify::EventKind::MoveSelf => {
-                (Event::MoveTop(self.top_dir.to_owned()), None)
-            }
+    fn send(&self, request: Request) -> bool {
+        self.tx.send(request).is_ok()
+    }
+}
 
-            inotify::EventKind::DeleteSelf => {
-                (Event::DeleteTop(self.top_dir.to_owned()), None)
-            }
+/// Progress of the initial scan, reported by `Watcher::new_async`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    pub scanned: usize,
+    pub watched: usize,
+}
 
-            inotify::EventKind::Modify(path) => {
-                let full_path = self.full_path(wd, path);
-                (Event::Modify(full_path, FileType::File), None)
+/// Tells whether the event passes the file type, path and user filters. A move
+/// passes if either path does. Events which change the top directory always
+/// pass.
+pub(crate) fn allows(opts: &WatcherOpts, event: &Event) -> bool {
+    let matched = |path: &Path| {
+        if let Some(file_type) = event.file_type() {
+            if opts.exclude_hidden_files
+                && *file_type == FileType::File
+                && is_hidden(opts, path)
+            {
+                return false;
             }
-            inotify::EventKind::Access(path, file_type) => match path {
-                Some(path) => {
-                    let full_path = self.full_path(wd, path);
-                    let event = match file_type {
-                        inotify::FileType::Dir => {
-                            Event::Access(full_path, FileType::Dir)
-                        }
-                        inotify::FileType::File => {
-                            Event::Access(full_path, FileType::File)
-                        }
-                    };
-                    (event, None)
-                }
-                None => {
-                    if wd == self.top_wd {
-                        (Event::AccessTop(self.top_dir.to_owned()), None)
-                    } else {
-                        (Event::Noise, None)
-                    }
-                }
-            },
-            inotify::EventKind::Attrib(path, file_type) => match path {
-                Some(path) => {
-                    let full_path = self.full_path(wd, path);
-                    let event = match file_type {
-                        inotify::FileType::Dir => {
-                            Event::Attrib(full_path, FileType::Dir)
-                        }
-                        inotify::FileType::File => {
-                            Event::Attrib(full_path, FileType::File)
-                        }
-                    };
-                    (event, None)
-                }
-                None => {
-                    if wd == self.top_wd {
-                        (Event::AttribTop(self.top_dir.to_owned()), None)
-                    } else {
-                        (Event::Noise, None)
-                    }
-                }
-            },
-            inotify::EventKind::Open(path, file_type) => match path {
-                Some(path) => {
-                    let full_path = self.full_path(wd, path);
-                    let event = match file_type {
-                        inotify::FileType::Dir => {
-                            Event::Open(full_path, FileType::Dir)
-                        }
-                        inotify::FileType::File => {
-                            Event::Open(full_path, FileType::File)
-                        }
-                    };
-                    (event, None)
-                }
-                None => {
-                    if wd == self.top_wd {
-                        (Event::OpenTop(self.top_dir.to_owned()), None)
-                    } else {
-                        (Event::Noise, None)
-                    }
-                }
-            },
-            inotify::EventKind::Close(path, file_type) => match path {
-                Some(path) => {
-                    let full_path = self.full_path(wd, path);
-                    let event = match file_type {
-                        inotify::FileType::Dir => {
-                            Event::Close(full_path, FileType::Dir)
-                        }
-                        inotify::FileType::File => {
-                            Event::Close(full_path, FileType::File)
-                        }
-                    };
-                    (event, None)
-                }
-                None => {
-                    if wd == self.top_wd {
-                        (Event::CloseTop(self.top_dir.to_owned()), None)
-                    } else {
-                        (Event::Noise, None)
-                    }
-                }
-            },
-
-            inotify::EventKind::Unmount => {
-                if inotify_event.wd == self.top_wd {
-                    (Event::UnmountTop(self.top_dir.to_owned()), None)
-                } else {
-                    let full_path = self.path(wd);
-                    (Event::Unmount(full_path, FileType::Dir), None)
+            if let Some(f) = &opts.filter_fn {
+                if !f(path, *file_type) {
+                    return false;
                 }
             }
-
-            inotify::EventKind::Ignored => (Event::Ignored, None),
-            inotify::EventKind::Unknown => (Event::Unknown, None),
         }
+        let path = path.as_os_str().as_bytes();
+        (opts.include_regex.is_empty()
+            || opts.include_regex.iter().any(|v| v.is_match(path)))
+            && !opts.exclude_regex.iter().any(|v| v.is_match(path))
+    };
+    if is_about_watching(event) {
+        return true;
     }
-}
-
-impl Drop for Watcher {
-    fn drop(&mut self) {
-        for wd in self.path_tree.values() {
-            unsafe { libc::inotify_rm_watch(self.fd, *wd) };
+    if let (Some(file_types), Some(file_type)) =
+        (&opts.file_types, event.file_type())
+    {
+        if !file_types.contains(file_type) {
+            return false;
         }
     }
+    match event {
+        Event::Move(from, to, _) => matched(from) || matched(to),
+        _ => event.path().is_none_or(matched),
+    }
 }
 
-fn guard(opts: WatcherOpts, path: &Path, file_type: FileType) -> bool {
+/// Tells whether the event is about the watching itself rather than files
+/// in the tree.
+pub(crate) fn is_about_watching(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::MoveTop(..)
+            | Event::DeleteTop(_)
+            | Event::ReplaceTop(_)
+            | Event::UnmountTop(_)
+            | Event::Reattached(_)
+            | Event::WatchError(..)
+            | Event::Watching(_)
+            | Event::Remounted(_)
+            | Event::Unwatched(_)
+            | Event::WatchRemoved(_)
+    )
+}
+
+fn is_hidden(opts: &WatcherOpts, path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(v) => v.as_bytes(),
+        None => return false,
+    };
+    opts.hidden_prefixes.iter().any(|v| name.starts_with(v.as_bytes()))
+}
+
+fn guard(opts: &WatcherOpts, path: &Path, file_type: FileType) -> bool {
     if file_type != FileType::Dir {
         return false;
     }
-    if path.file_name().unwrap().as_bytes()[0] == b'.' {
-        matches!(opts.sub_dotdir, Dotdir::Include)
-    } else {
-        true
+    if matches!(opts.sub_dotdir, Dotdir::Exclude) && is_hidden(opts, path) {
+        return false;
     }
+    opts.filter_fn.as_ref().is_none_or(|f| f(path, FileType::Dir))
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum FileType {
     Dir,
     File,
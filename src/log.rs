@@ -0,0 +1,18 @@
+//! Logging with tracing, or nothing without the feature `tracing`.
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, warn};
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! log_nothing {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log_nothing as debug;
+#[cfg(all(not(feature = "tracing"), feature = "async"))]
+pub(crate) use log_nothing as warn;
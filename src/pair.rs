@@ -0,0 +1,136 @@
+use std::{collections::VecDeque, time::Duration};
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use time::OffsetDateTime;
+use tokio::time::Instant;
+
+use crate::Event;
+
+type Timed = (Event, OffsetDateTime);
+
+/// Pairs the halves of renames between watchers, e.g. of several roots
+/// whose streams are merged. A `MoveAway` and a `MoveInto` with the same
+/// cookie are given as one `Event::Move`, where the first of them was.
+///
+/// Either half is held until the other one comes or `window` has passed,
+/// since the watchers read their events apart. Events after it are held
+/// too, so that their order is kept.
+pub struct PairMoves {
+    window: Duration,
+}
+
+enum Entry {
+    Ready(Timed),
+    /// Half of a rename waiting for the other half.
+    Pending {
+        event: Timed,
+        deadline: Instant,
+    },
+}
+
+impl PairMoves {
+    pub fn new(window: Duration) -> Self {
+        Self { window }
+    }
+
+    pub fn apply<S>(self, stream: S) -> impl Stream<Item = Timed>
+    where
+        S: Stream<Item = Timed>,
+    {
+        stream! {
+            pin_mut!(stream);
+            let mut queue = VecDeque::new();
+            let mut ended = false;
+            loop {
+                while let Some(entry) = queue.front() {
+                    if let Entry::Pending { deadline, .. } = entry {
+                        if !ended && Instant::now() < *deadline {
+                            break;
+                        }
+                    }
+                    match queue.pop_front() {
+                        Some(Entry::Ready(v) | Entry::Pending { event: v, .. }) => {
+                            yield v
+                        }
+                        None => unreachable!(),
+                    }
+                }
+                if ended {
+                    break;
+                }
+                let next = match queue.front() {
+                    Some(Entry::Pending { deadline, .. }) => {
+                        match tokio::time::timeout_at(*deadline, stream.next())
+                            .await
+                        {
+                            Ok(v) => v,
+                            Err(_) => continue,
+                        }
+                    }
+                    _ => stream.next().await,
+                };
+                match next {
+                    Some((event, t)) => self.push(&mut queue, event, t),
+                    None => ended = true,
+                }
+            }
+        }
+    }
+
+    fn push(
+        &self,
+        queue: &mut VecDeque<Entry>,
+        event: Event,
+        t: OffsetDateTime,
+    ) {
+        let cookie = match event {
+            Event::MoveAway(.., cookie) | Event::MoveInto(.., cookie) => {
+                cookie
+            }
+            _ => {
+                queue.push_back(Entry::Ready((event, t)));
+                return;
+            }
+        };
+        let paired = queue.iter().position(|v| match v {
+            Entry::Pending { event: (other, _), .. } => {
+                is_pair(other, &event, cookie)
+            }
+            Entry::Ready(_) => false,
+        });
+        let i = match paired {
+            Some(v) => v,
+            None => {
+                queue.push_back(Entry::Pending {
+                    event: (event, t),
+                    deadline: Instant::now() + self.window,
+                });
+                return;
+            }
+        };
+        let (other, first_t) = match &queue[i] {
+            Entry::Pending { event, .. } => event.clone(),
+            Entry::Ready(_) => unreachable!(),
+        };
+        let (from, to, file_type) = match (other, event) {
+            (Event::MoveAway(from, file_type, _), Event::MoveInto(to, ..))
+            | (Event::MoveInto(to, ..), Event::MoveAway(from, file_type, _)) => {
+                (from, to, file_type)
+            }
+            _ => unreachable!(),
+        };
+        queue[i] = Entry::Ready((Event::Move(from, to, file_type), first_t));
+    }
+}
+
+/// Tells whether the events are the two halves of the rename with the
+/// cookie.
+fn is_pair(a: &Event, b: &Event, cookie: u32) -> bool {
+    matches!(
+        (a, b),
+        (Event::MoveAway(.., v), Event::MoveInto(..))
+            | (Event::MoveInto(.., v), Event::MoveAway(..))
+            if *v == cookie
+    )
+}
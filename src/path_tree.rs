@@ -2,7 +2,6 @@ use std::{
     collections::HashMap,
     ffi::OsString,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, Weak},
 };
 
 use ahash::AHashMap;
@@ -28,10 +27,23 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Tree of paths with a value for each one. Nodes are kept in an arena and
+/// refer to each other by their indices.
 pub struct Head<T> {
     prefix: PathBuf,
-    table: AHashMap<T, Arc<Mutex<Node<T>>>>,
-    tree: Option<Arc<Mutex<Node<T>>>>,
+    table: AHashMap<T, usize>,
+    nodes: Vec<Option<Node<T>>>,
+    /// Indices of the free slots in `nodes`.
+    free: Vec<usize>,
+    root: Option<usize>,
+}
+
+struct Node<T> {
+    /// Name of the directory, or the prefix for the root.
+    key: OsString,
+    value: T,
+    parent: Option<usize>,
+    children: HashMap<OsString, usize>,
 }
 
 impl<T> Head<T>
@@ -39,195 +51,285 @@ where
     T: std::hash::Hash + std::cmp::Eq + Copy,
 {
     pub fn new(prefix: PathBuf) -> Self {
-        Self { prefix, tree: None, table: AHashMap::new() }
+        Self {
+            prefix,
+            table: AHashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: None,
+        }
     }
 
     pub fn has(&self, value: T) -> bool {
         self.table.contains_key(&value)
     }
 
-    pub fn insert(&mut self, path: &Path, value: T) -> Result<()> {
+    pub fn has_path(&self, path: &Path) -> bool {
+        self.get(path).is_some()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<T> {
+        let path = path.strip_prefix(&self.prefix).ok()?;
+        self.find(path).map(|i| self.node(i).value)
+    }
+
+    /// Inserts the value at the path. Nodes left with the same value or at
+    /// the same path are replaced, and the values of them and of the nodes
+    /// under them are returned.
+    pub fn insert(&mut self, path: &Path, value: T) -> Result<Vec<T>> {
         let path_rest = path
             .strip_prefix(&self.prefix)
             .context(PrefixMismatched { path })?;
-        let new_node = match &self.tree {
-            Some(node) => Node::insert(Arc::clone(node), path_rest, value)?,
+        let mut replaced = Vec::new();
+        if self.has(value) {
+            replaced.extend(self.delete(value)?);
+        }
+        if let Some(v) = self.get(path) {
+            replaced.extend(self.delete(v)?);
+        }
+        let index = match self.root {
+            Some(_) => {
+                let parent = path_rest
+                    .parent()
+                    .and_then(|v| self.find(v))
+                    .context(PathNotFound { path })?;
+                let key =
+                    path_rest.file_name().context(InvalidPath { path })?;
+                let index = self.alloc(Node {
+                    key: key.to_owned(),
+                    value,
+                    parent: Some(parent),
+                    children: HashMap::new(),
+                });
+                self.node_mut(parent).children.insert(key.to_owned(), index);
+                index
+            }
             None => {
-                let node = Arc::new(Mutex::new(Node::new(
-                    path.as_os_str().to_owned(),
+                let index = self.alloc(Node {
+                    key: path.as_os_str().to_owned(),
                     value,
-                    None,
-                )));
-                self.tree = Some(Arc::clone(&node));
-                node
+                    parent: None,
+                    children: HashMap::new(),
+                });
+                self.root = Some(index);
+                index
             }
         };
-        self.table.insert(value, new_node);
-        Ok(())
+        self.table.insert(value, index);
+        Ok(replaced)
     }
 
     pub fn delete(&mut self, value: T) -> Result<Vec<T>> {
-        let node = self.table.get(&value).context(ValueNotFound)?;
-        let path = node.lock().unwrap().path();
-        let path_rest = path
-            .strip_prefix(&self.prefix)
-            .context(PrefixMismatched { path: path.to_owned() })?;
-        let values = {
-            if path_rest.as_os_str().is_empty() {
-                self.tree.take().unwrap().lock().unwrap().values()
-            } else {
-                let tree = self.tree.as_ref().context(EmptyTree)?;
-                Node::pop(Arc::clone(tree), path_rest)?
-                    .lock()
-                    .unwrap()
-                    .values()
-            }
-        };
-        for v in &values {
-            self.table.remove(v);
+        let index = *self.table.get(&value).context(ValueNotFound)?;
+        match self.node(index).parent {
+            Some(parent) => self.detach(parent, index),
+            None if self.root == Some(index) => self.root = None,
+            None => {}
         }
-        Ok(values)
+        Ok(self.free_subtree(index))
     }
 
-    pub fn rename(&self, value: T, new_path: &Path) -> Result<()> {
-        let node = self.table.get(&value).context(ValueNotFound)?;
-        let old_path = node.lock().unwrap().path();
-        let old_path_rest = old_path
-            .strip_prefix(&self.prefix)
-            .context(PrefixMismatched { path: old_path.to_owned() })?;
+    /// Moves the node to the path. A node left at the path is replaced,
+    /// and the values of it and of the nodes under it are returned.
+    pub fn rename(&mut self, value: T, new_path: &Path) -> Result<Vec<T>> {
+        let index = *self.table.get(&value).context(ValueNotFound)?;
+        let old_parent = match self.node(index).parent {
+            Some(v) => v,
+            None => {
+                return Err(Error::InvalidPath { path: self.path(value) });
+            }
+        };
         let new_path_rest = new_path
             .strip_prefix(&self.prefix)
             .context(PrefixMismatched { path: new_path })?;
-        let tree = self.tree.as_ref().context(EmptyTree)?;
-        Node::rename(Arc::clone(tree), old_path_rest, new_path_rest)
+        self.root.context(EmptyTree)?;
+        let new_parent = new_path_rest
+            .parent()
+            .and_then(|v| self.find(v))
+            .context(PathNotFound { path: new_path })?;
+        let new_name = new_path_rest
+            .file_name()
+            .context(InvalidPath { path: new_path })?
+            .to_owned();
+
+        self.detach(old_parent, index);
+        let node = self.node_mut(index);
+        node.key = new_name.to_owned();
+        node.parent = Some(new_parent);
+        let replaced =
+            self.node_mut(new_parent).children.insert(new_name, index);
+        Ok(match replaced {
+            Some(v) if v != index => self.free_subtree(v),
+            _ => Vec::new(),
+        })
+    }
+
+    /// Frees the node and the nodes under it, and gives their values.
+    fn free_subtree(&mut self, index: usize) -> Vec<T> {
+        let mut values = Vec::new();
+        let mut stack = vec![index];
+        while let Some(index) = stack.pop() {
+            let node = self.nodes[index].take().unwrap();
+            self.free.push(index);
+            if self.table.get(&node.value) == Some(&index) {
+                self.table.remove(&node.value);
+            }
+            values.push(node.value);
+            stack.extend(node.children.into_values());
+        }
+        values
+    }
+
+    /// Moves the whole tree under another prefix.
+    pub fn set_prefix(&mut self, prefix: PathBuf) {
+        if let Some(root) = self.root {
+            self.node_mut(root).key = prefix.as_os_str().to_owned();
+        }
+        self.prefix = prefix;
     }
 
     pub fn path(&self, value: T) -> PathBuf {
-        self.table[&value].lock().unwrap().path()
+        let mut keys = Vec::new();
+        let mut cur = Some(self.table[&value]);
+        while let Some(index) = cur {
+            let node = self.node(index);
+            keys.push(&node.key);
+            cur = node.parent;
+        }
+        keys.iter().rev().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// Gives the depth of the deepest node, where the root is 0.
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut stack: Vec<_> = self.root.iter().map(|v| (*v, 0)).collect();
+        while let Some((index, d)) = stack.pop() {
+            depth = depth.max(d);
+            stack.extend(
+                self.node(index).children.values().map(|v| (*v, d + 1)),
+            );
+        }
+        depth
     }
 
     pub fn values(&self) -> impl Iterator<Item = &T> {
         self.table.keys()
     }
-}
 
-pub struct Node<T> {
-    key: OsString,
-    value: T,
-    parent: Weak<Mutex<Node<T>>>,
-    children: HashMap<OsString, Arc<Mutex<Node<T>>>>,
-}
+    /// Gives the index of the node at the path relative to the prefix.
+    fn find(&self, path: &Path) -> Option<usize> {
+        path.components().try_fold(self.root?, |index, c| {
+            self.node(index).children.get(c.as_os_str()).copied()
+        })
+    }
 
-impl<T> Node<T>
-where
-    T: std::hash::Hash + std::cmp::Eq + Copy,
-{
-    fn new(
-        key: OsString,
-        value: T,
-        parent: Option<&Arc<Mutex<Node<T>>>>,
-    ) -> Self {
-        Self {
-            key,
-            value,
-            parent: match parent {
-                Some(node) => Arc::downgrade(node),
-                None => Weak::new(),
-            },
-            children: HashMap::new(),
+    /// Removes the node from the children of its parent, unless another
+    /// node has replaced it there.
+    fn detach(&mut self, parent: usize, index: usize) {
+        let key = self.node(index).key.to_owned();
+        let children = &mut self.node_mut(parent).children;
+        if children.get(&key) == Some(&index) {
+            children.remove(&key);
         }
     }
 
-    fn get(self_: Arc<Mutex<Self>>, path: &Path) -> Option<Arc<Mutex<Self>>> {
-        let mut path = path.components();
-        path.try_fold(self_, |acc, i| {
-            let acc = acc.lock().unwrap();
-            acc.children.get(i.as_os_str()).map(Arc::clone)
-        })
+    fn alloc(&mut self, node: Node<T>) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
     }
 
-    fn insert(
-        self_: Arc<Mutex<Self>>,
-        path: &Path,
-        value: T,
-    ) -> Result<Arc<Mutex<Node<T>>>> {
-        let parent = {
-            let p = path.parent().context(InvalidPath { path })?;
-            Self::get(self_, p).context(PathNotFound { path })?
-        };
-        let key = path.file_name().context(InvalidPath { path })?;
-        let node = Arc::new(Mutex::new(Self::new(
-            key.to_owned(),
-            value,
-            Some(&parent),
-        )));
-
-        parent
-            .lock()
-            .unwrap()
-            .children
-            .insert(key.to_owned(), Arc::clone(&node));
-
-        Ok(node)
-    }
-
-    fn pop(self_: Arc<Mutex<Self>>, path: &Path) -> Result<Arc<Mutex<Self>>> {
-        let name = path.file_name().context(InvalidPath { path })?;
-        let parent = {
-            let p = path.parent().context(InvalidPath { path })?;
-            Self::get(self_, p).context(PathNotFound { path })?
-        };
-        let mut p = parent.lock().unwrap();
-        p.children.remove(name).context(PathNotFound { path })
-    }
-
-    fn rename(
-        self_: Arc<Mutex<Self>>,
-        old_path: &Path,
-        new_path: &Path,
-    ) -> Result<()> {
-        let node = Self::pop(Arc::clone(&self_), old_path)?;
-        let parent = {
-            let p =
-                new_path.parent().context(InvalidPath { path: new_path })?;
-            Self::get(self_, p).context(PathNotFound { path: new_path })?
-        };
+    fn node(&self, index: usize) -> &Node<T> {
+        self.nodes[index].as_ref().unwrap()
+    }
 
-        let new_name =
-            new_path.file_name().context(InvalidPath { path: new_path })?;
-        node.lock().unwrap().key = new_name.to_owned();
-        node.lock().unwrap().parent = Arc::downgrade(&parent);
-        parent.lock().unwrap().children.insert(new_name.to_owned(), node);
-        Ok(())
+    fn node_mut(&mut self, index: usize) -> &mut Node<T> {
+        self.nodes[index].as_mut().unwrap()
     }
+}
 
-    fn values(&self) -> Vec<T> {
-        let mut values = vec![self.value];
-        let mut stack: Vec<Arc<Mutex<Node<T>>>> =
-            self.children.values().map(Arc::clone).collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        while let Some(node) = stack.pop() {
-            let node = node.lock().unwrap();
-            values.push(node.value);
-            for c in node.children.values() {
-                stack.push(Arc::clone(c));
-            }
+    fn tree() -> Head<i32> {
+        let mut tree = Head::new(PathBuf::from("/top"));
+        let paths = ["/top", "/top/a", "/top/a/b", "/top/c"];
+        for (value, path) in (1..).zip(paths) {
+            tree.insert(Path::new(path), value).unwrap();
         }
-        values
+        tree
     }
 
-    fn path(&self) -> PathBuf {
-        let mut path = PathBuf::new();
-        let mut temp = vec![self.key.to_owned()];
+    #[test]
+    fn test_reuse_value() {
+        let mut tree = tree();
+        let replaced = tree.insert(Path::new("/top/c/d"), 2).unwrap();
+        assert_eq!(sorted(replaced), [2, 3]);
+        assert_eq!(tree.path(2), Path::new("/top/c/d"));
+        assert!(!tree.has(3));
+        assert!(!tree.has_path(Path::new("/top/a")));
+        assert_eq!(tree.len(), 3);
+    }
 
-        let mut cur = self.parent.upgrade();
-        while let Some(node) = cur {
-            temp.push(node.lock().unwrap().key.to_owned());
-            cur = node.lock().unwrap().parent.upgrade();
-        }
-        for i in temp.iter().rev() {
-            path.push(i);
-        }
-        path
+    #[test]
+    fn test_rename() {
+        let mut tree = tree();
+        assert!(tree.rename(3, Path::new("/top/b")).unwrap().is_empty());
+        assert_eq!(tree.path(3), Path::new("/top/b"));
+        assert_eq!(tree.get(Path::new("/top/b")), Some(3));
+        assert!(!tree.has_path(Path::new("/top/a/b")));
+        assert_eq!(tree.len(), 4);
+    }
+
+    #[test]
+    fn test_rename_over_existing() {
+        let mut tree = tree();
+        let replaced = tree.rename(4, Path::new("/top/a")).unwrap();
+        assert_eq!(sorted(replaced), [2, 3]);
+        assert_eq!(tree.get(Path::new("/top/a")), Some(4));
+        assert!(!tree.has(2) && !tree.has(3));
+        assert!(tree.delete(2).is_err());
+        assert_eq!(tree.len(), 2);
+
+        // Slots of the replaced nodes are reused without stale links.
+        tree.insert(Path::new("/top/a/e"), 5).unwrap();
+        tree.insert(Path::new("/top/f"), 6).unwrap();
+        assert_eq!(tree.path(5), Path::new("/top/a/e"));
+        assert_eq!(tree.path(6), Path::new("/top/f"));
+        assert_eq!(tree.depth(), 2);
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut tree = tree();
+        assert_eq!(sorted(tree.delete(2).unwrap()), [2, 3]);
+        assert!(!tree.has_path(Path::new("/top/a")));
+        assert!(tree.rename(3, Path::new("/top/b")).is_err());
+        assert_eq!(tree.len(), 2);
+        assert_eq!(sorted(tree.delete(1).unwrap()), [1, 4]);
+        assert!(tree.is_empty());
+        tree.insert(Path::new("/top"), 1).unwrap();
+        assert_eq!(tree.path(1), Path::new("/top"));
+    }
+
+    fn sorted(mut values: Vec<i32>) -> Vec<i32> {
+        values.sort_unstable();
+        values
     }
 }
@@ -0,0 +1,110 @@
+//! Dry run of the walk which sets up the watches.
+
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    is_hidden,
+    walk::{inode, walk_with_errors},
+    Dotdir, FileType, Symlink, WatcherOpts,
+};
+
+/// Why a directory would not be watched.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Skip {
+    /// Hidden subdirectories are excluded.
+    Hidden,
+    /// Rejected by the filter function.
+    Filtered,
+    /// A link to a directory, which is not followed.
+    Link,
+    /// Already reached through another link.
+    Loop,
+    /// Already reached through another path, e.g. a bind mount. It shares
+    /// the watch of that path.
+    Seen,
+    /// On another file system, with `one_file_system`.
+    OtherFs,
+    /// Could not be read, so it can not be watched, and its subdirectories
+    /// are unknown.
+    Unreadable(io::ErrorKind),
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Hidden => "hidden",
+            Self::Filtered => "filtered",
+            Self::Link => "link not followed",
+            Self::Loop => "already reached by a link",
+            Self::Seen => "already reached by another path",
+            Self::OtherFs => "on another file system",
+            Self::Unreadable(kind) => {
+                return write!(f, "could not be read: {}", kind);
+            }
+        })
+    }
+}
+
+/// Walks `dir` as the watcher would with the options, without adding any
+/// watch. Gives the directories which would be watched, and those skipped
+/// with the reason, sorted by path. Subdirectories of skipped ones are not
+/// walked.
+pub fn plan(dir: &Path, opts: &WatcherOpts) -> Vec<(PathBuf, Option<Skip>)> {
+    let follow = opts.symlink == Symlink::Follow;
+    let mut seen: HashSet<_> = fs::metadata(dir).iter().map(inode).collect();
+    let dev = fs::metadata(dir).ok().map(|v| v.dev());
+    let mut planned = vec![(dir.to_owned(), None)];
+    let mut unreadable = Vec::new();
+    let filter = |path: &Path, _| {
+        let metadata = match fs::metadata(path) {
+            Ok(v) if v.is_dir() => v,
+            _ => return false,
+        };
+        let is_link = fs::symlink_metadata(path)
+            .is_ok_and(|v| v.file_type().is_symlink());
+        let skip = if is_link && !follow {
+            Some(Skip::Link)
+        } else if opts.one_file_system && Some(metadata.dev()) != dev {
+            Some(Skip::OtherFs)
+        } else if matches!(opts.sub_dotdir, Dotdir::Exclude)
+            && is_hidden(opts, path)
+        {
+            Some(Skip::Hidden)
+        } else if opts
+            .filter_fn
+            .as_ref()
+            .is_some_and(|f| !f(path, FileType::Dir))
+        {
+            Some(Skip::Filtered)
+        } else if follow && !seen.insert(inode(&metadata)) {
+            Some(Skip::Loop)
+        } else if !follow && opts.dedupe_dirs && !seen.insert(inode(&metadata))
+        {
+            Some(Skip::Seen)
+        } else {
+            None
+        };
+        planned.push((path.to_owned(), skip));
+        skip.is_none()
+    };
+    // Files which are gone or broken links are not directories to watch.
+    let on_error = |path: &Path, e: io::Error| {
+        if fs::metadata(path).is_ok_and(|v| v.is_dir()) {
+            unreadable.push((path.to_owned(), e.kind()));
+        }
+    };
+    walk_with_errors(dir, follow, None, filter, on_error).for_each(drop);
+
+    for (path, kind) in unreadable {
+        if let Some(v) = planned.iter_mut().find(|v| v.0 == path) {
+            v.1 = Some(Skip::Unreadable(kind));
+        }
+    }
+    planned.sort_by(|a, b| a.0.cmp(&b.0));
+    planned
+}
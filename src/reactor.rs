@@ -0,0 +1,122 @@
+//! Small reactor which waits on a thread of its own for file descriptors
+//! to be readable, so reads can be awaited on any executor.
+
+use std::{
+    future::Future,
+    io,
+    os::unix::io::RawFd,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref REACTOR: Reactor = Reactor::start();
+}
+
+struct Reactor {
+    waiters: Mutex<Vec<(RawFd, Waker)>>,
+    /// Interrupts polling when a waiter is added.
+    event_fd: RawFd,
+}
+
+impl Reactor {
+    fn start() -> Self {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(event_fd >= 0, "{}", io::Error::last_os_error());
+        thread::Builder::new()
+            .name("watchdir-reactor".to_owned())
+            .spawn(|| REACTOR.run())
+            .unwrap();
+        Self { waiters: Mutex::new(Vec::new()), event_fd }
+    }
+
+    fn register(&self, fd: RawFd, waker: &Waker) {
+        let mut waiters = self.waiters.lock().unwrap();
+        match waiters.iter_mut().find(|(v, _)| *v == fd) {
+            Some((_, v)) => *v = waker.clone(),
+            None => waiters.push((fd, waker.clone())),
+        }
+        let one: u64 = 1;
+        unsafe {
+            libc::write(self.event_fd, &one as *const u64 as *const _, 8)
+        };
+    }
+
+    fn run(&self) {
+        loop {
+            let mut pollfds: Vec<_> = std::iter::once(self.event_fd)
+                .chain(self.waiters.lock().unwrap().iter().map(|(fd, _)| *fd))
+                .map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            let n = unsafe {
+                libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, -1)
+            };
+            if n <= 0 {
+                continue;
+            }
+            if pollfds[0].revents != 0 {
+                let mut count: u64 = 0;
+                unsafe {
+                    libc::read(
+                        self.event_fd,
+                        &mut count as *mut u64 as *mut _,
+                        8,
+                    )
+                };
+            }
+
+            // Closed descriptors are ready too, so their waiters are gone.
+            let ready: Vec<_> = pollfds[1..]
+                .iter()
+                .filter(|v| v.revents != 0)
+                .map(|v| v.fd)
+                .collect();
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.retain(|(fd, waker)| {
+                if ready.contains(fd) {
+                    waker.wake_by_ref();
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+}
+
+/// Reads from the non-blocking file descriptor, waiting until it is
+/// readable.
+pub fn read<'a>(fd: RawFd, buffer: &'a mut [u8]) -> Read<'a> {
+    Read { fd, buffer }
+}
+
+pub struct Read<'a> {
+    fd: RawFd,
+    buffer: &'a mut [u8],
+}
+
+impl Future for Read<'_> {
+    type Output = io::Result<usize>;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        let fd = self.fd;
+        match crate::inotify::read(fd, self.buffer) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                REACTOR.register(fd, cx.waker());
+                Poll::Pending
+            }
+            res => Poll::Ready(res),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{Digest, Event, FileType};
+
+/// Representation of an event for machine-readable outputs, which
+/// `replay` reads back. Paths which are not UTF-8 are written lossily.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Record {
+    pub kind: String,
+    pub time: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cookie: Option<u32>,
+    /// Id of the group of related events, if grouped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u64>,
+    /// Processes which had the file open, if audited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processes: Option<Vec<Process>>,
+}
+
+/// Process which has a file open.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Process {
+    pub pid: u32,
+    /// Command name, from `/proc/PID/comm`.
+    pub comm: String,
+}
+
+impl Record {
+    /// Gives `None` for events which are never printed.
+    pub fn new(event: &Event, t: OffsetDateTime) -> Option<Self> {
+        let (path, from, to, file_type) = match event {
+            Event::Create(path, file_type)
+            | Event::Delete(path, file_type)
+            | Event::MoveAway(path, file_type, _)
+            | Event::MoveInto(path, file_type, _)
+            | Event::Modify(path, file_type, _)
+            | Event::Open(path, file_type)
+            | Event::Close(path, file_type)
+            | Event::Access(path, file_type)
+            | Event::Attrib(path, file_type)
+            | Event::Unmount(path, file_type) => {
+                (Some(path), None, None, Some(file_type))
+            }
+            Event::Move(from, to, file_type) => {
+                (None, Some(from), Some(to), Some(file_type))
+            }
+            Event::MoveTop(from, Some(to)) => {
+                (None, Some(from), Some(to), None)
+            }
+            Event::MoveTop(path, None)
+            | Event::DeleteTop(path)
+            | Event::ReplaceTop(path)
+            | Event::UnmountTop(path)
+            | Event::AccessTop(path)
+            | Event::AttribTop(path)
+            | Event::OpenTop(path)
+            | Event::CloseTop(path)
+            | Event::Reattached(path) => (Some(path), None, None, None),
+            Event::Noise
+            | Event::Ignored
+            | Event::Unknown
+            | Event::WatchError(..)
+            | Event::Watching(_)
+            | Event::Remounted(_)
+            | Event::Unwatched(_)
+            | Event::WatchRemoved(_) => return None,
+        };
+
+        Some(Self {
+            kind: kind(event),
+            time: t.format(&Rfc3339).unwrap(),
+            path: path.map(|v| v.to_string_lossy().into_owned()),
+            from: from.map(|v| v.to_string_lossy().into_owned()),
+            to: to.map(|v| v.to_string_lossy().into_owned()),
+            file_type: file_type.map(|v| {
+                match v {
+                    FileType::Dir => "dir",
+                    FileType::File => "file",
+                }
+                .to_owned()
+            }),
+            digest: match event {
+                Event::Modify(_, _, Some(digest)) => Some(digest.to_string()),
+                _ => None,
+            },
+            cookie: match event {
+                Event::MoveAway(.., cookie) | Event::MoveInto(.., cookie) => {
+                    Some(*cookie)
+                }
+                _ => None,
+            },
+            group: None,
+            processes: None,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Gives the event of the record back, or why it can not.
+    pub fn event(&self) -> Result<(Event, OffsetDateTime), String> {
+        let t = OffsetDateTime::parse(&self.time, &Rfc3339)
+            .map_err(|e| format!("invalid time: {}", e))?;
+        let file_type = match self.file_type.as_deref() {
+            Some("dir") => Some(FileType::Dir),
+            Some("file") => Some(FileType::File),
+            Some(v) => return Err(format!("unknown file type: {}", v)),
+            None => None,
+        };
+        let digest = match &self.digest {
+            Some(v) => Some(
+                Digest::parse(v)
+                    .ok_or_else(|| format!("invalid digest: {}", v))?,
+            ),
+            None => None,
+        };
+        let path = self.path.as_ref().map(PathBuf::from);
+        let from = self.from.as_ref().map(PathBuf::from);
+        let to = self.to.as_ref().map(PathBuf::from);
+        let cookie = self.cookie;
+
+        let event = (|| {
+            Some(match self.kind.as_str() {
+                "create" => Event::Create(path?, file_type?),
+                "move" => Event::Move(from?, to?, file_type?),
+                "move_away" => Event::MoveAway(path?, file_type?, cookie?),
+                "move_into" => Event::MoveInto(path?, file_type?, cookie?),
+                "move_top" => Event::MoveTop(path.or(from)?, to),
+                "delete" => Event::Delete(path?, file_type?),
+                "delete_top" => Event::DeleteTop(path?),
+                "replace_top" => Event::ReplaceTop(path?),
+                "modify" => Event::Modify(path?, file_type?, digest),
+                "access" => Event::Access(path?, file_type?),
+                "access_top" => Event::AccessTop(path?),
+                "attrib" => Event::Attrib(path?, file_type?),
+                "attrib_top" => Event::AttribTop(path?),
+                "open" => Event::Open(path?, file_type?),
+                "open_top" => Event::OpenTop(path?),
+                "close" => Event::Close(path?, file_type?),
+                "close_top" => Event::CloseTop(path?),
+                "unmount" => Event::Unmount(path?, file_type?),
+                "unmount_top" => Event::UnmountTop(path?),
+                "reattached" => Event::Reattached(path?),
+                _ => return None,
+            })
+        })()
+        .ok_or_else(|| {
+            format!("unknown or incomplete event: {}", self.kind)
+        })?;
+
+        Ok((event, t))
+    }
+}
+
+/// Converts the name of the event to snake case, e.g. `move_away`.
+fn kind(event: &Event) -> String {
+    let mut kind = String::new();
+    for (i, c) in event.name().chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                kind.push('_');
+            }
+            kind.push(c.to_ascii_lowercase());
+        } else {
+            kind.push(c);
+        }
+    }
+    kind
+}
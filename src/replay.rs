@@ -0,0 +1,102 @@
+use std::{
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_stream::stream;
+use futures::Stream;
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+use crate::{record::Record, Error, Event, Result};
+
+type Item = Result<(Event, OffsetDateTime)>;
+
+#[derive(Copy, Clone)]
+pub enum Timing {
+    /// Yield all events as fast as they are read.
+    Immediate,
+    /// Wait between events as long as they were apart when recorded.
+    Original,
+}
+
+/// Reads an event log of JSON records, one per line, as written by the
+/// machine-readable outputs of watchdir, and yields the events again.
+///
+/// A malformed line yields an error and replaying goes on with the next
+/// line. A read error ends the stream.
+pub struct ReplayStream {
+    inner: Pin<Box<dyn Stream<Item = Item> + Send>>,
+}
+
+impl ReplayStream {
+    pub fn new<R>(reader: R, timing: Timing) -> Self
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        let mut lines = reader.lines();
+        let inner = stream! {
+            let mut last: Option<OffsetDateTime> = None;
+            let mut line_number = 0;
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(v)) => v,
+                    Ok(None) => break,
+                    Err(source) => {
+                        yield Err(Error::ReadLog { source });
+                        break;
+                    }
+                };
+                line_number += 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let (event, t) = match parse(&line) {
+                    Ok(v) => v,
+                    Err(reason) => {
+                        yield Err(Error::ParseLog {
+                            line: line_number,
+                            reason,
+                        });
+                        continue;
+                    }
+                };
+                if let (Timing::Original, Some(last)) = (timing, last) {
+                    let gap = t - last;
+                    if gap.is_positive() {
+                        tokio::time::sleep(gap.unsigned_abs()).await;
+                    }
+                }
+                last = Some(t);
+                yield Ok((event, t));
+            }
+        };
+        Self { inner: Box::pin(inner) }
+    }
+
+    pub async fn open(path: &Path, timing: Timing) -> Result<Self> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|source| Error::ReadLog { source })?;
+        Ok(Self::new(BufReader::new(file), timing))
+    }
+}
+
+impl Stream for ReplayStream {
+    type Item = Item;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// Parses a JSON record of an event, as written by the machine-readable
+/// outputs of watchdir.
+pub fn parse(line: &str) -> Result<(Event, OffsetDateTime), String> {
+    serde_json::from_str::<Record>(line).map_err(|e| e.to_string())?.event()
+}
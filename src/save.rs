@@ -0,0 +1,146 @@
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use time::OffsetDateTime;
+use tokio::time::Instant;
+
+use crate::{Event, FileType};
+
+type Timed = (Event, OffsetDateTime);
+
+/// Item of the stream given by `AtomicSaves`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Item {
+    Event(Event),
+    /// The file was replaced by another one renamed over it.
+    Replaced(PathBuf, FileType),
+}
+
+/// Recognizes files saved atomically, as editors do: written to a new
+/// temporary file which is then renamed over the target. The events of a
+/// save, from `Create` of the temporary file to `Move` onto the target,
+/// are given as one `Item::Replaced` of the target.
+///
+/// Events are held from the `Create` of a file until it is moved, deleted
+/// or `window` has passed, so that their order is kept.
+pub struct AtomicSaves {
+    window: Duration,
+}
+
+enum Entry {
+    Ready(Vec<(Item, OffsetDateTime)>),
+    /// Events of a file which may be a temporary one.
+    Pending {
+        temp: PathBuf,
+        events: Vec<Timed>,
+        deadline: Instant,
+    },
+}
+
+impl AtomicSaves {
+    pub fn new(window: Duration) -> Self {
+        Self { window }
+    }
+
+    pub fn apply<S>(
+        self,
+        stream: S,
+    ) -> impl Stream<Item = (Item, OffsetDateTime)>
+    where
+        S: Stream<Item = Timed>,
+    {
+        stream! {
+            pin_mut!(stream);
+            let mut queue = VecDeque::new();
+            let mut ended = false;
+            loop {
+                while let Some(entry) = queue.front() {
+                    if let Entry::Pending { deadline, .. } = entry {
+                        if !ended && Instant::now() < *deadline {
+                            break;
+                        }
+                    }
+                    let events = match queue.pop_front() {
+                        Some(Entry::Ready(v)) => v,
+                        Some(Entry::Pending { events, .. }) => ready(events),
+                        None => unreachable!(),
+                    };
+                    for event in events {
+                        yield event;
+                    }
+                }
+                if ended {
+                    break;
+                }
+                let next = match queue.front() {
+                    Some(Entry::Pending { deadline, .. }) => {
+                        match tokio::time::timeout_at(*deadline, stream.next())
+                            .await
+                        {
+                            Ok(v) => v,
+                            Err(_) => continue,
+                        }
+                    }
+                    _ => stream.next().await,
+                };
+                match next {
+                    Some((event, t)) => self.push(&mut queue, event, t),
+                    None => ended = true,
+                }
+            }
+        }
+    }
+
+    fn push(
+        &self,
+        queue: &mut VecDeque<Entry>,
+        event: Event,
+        t: OffsetDateTime,
+    ) {
+        let path = event.file_type().and(event.path());
+        let pending = queue.iter().rposition(|v| {
+            matches!(v, Entry::Pending { temp, .. } if Some(temp.as_path()) == path)
+        });
+        let i = match pending {
+            Some(v) => v,
+            None => {
+                queue.push_back(match event {
+                    Event::Create(ref path, FileType::File) => {
+                        Entry::Pending {
+                            temp: path.to_owned(),
+                            events: vec![(event, t)],
+                            deadline: Instant::now() + self.window,
+                        }
+                    }
+                    _ => Entry::Ready(vec![(Item::Event(event), t)]),
+                });
+                return;
+            }
+        };
+        let events = match &mut queue[i] {
+            Entry::Pending { events, .. } => events,
+            Entry::Ready(_) => unreachable!(),
+        };
+        match event {
+            Event::Move(_, to, file_type) => {
+                queue[i] =
+                    Entry::Ready(vec![(Item::Replaced(to, file_type), t)]);
+            }
+            Event::Modify(..)
+            | Event::Access(..)
+            | Event::Attrib(..)
+            | Event::Open(..)
+            | Event::Close(..) => events.push((event, t)),
+            _ => {
+                let events = std::mem::take(events);
+                queue[i] = Entry::Ready(ready(events));
+                queue.push_back(Entry::Ready(vec![(Item::Event(event), t)]));
+            }
+        }
+    }
+}
+
+fn ready(events: Vec<Timed>) -> Vec<(Item, OffsetDateTime)> {
+    events.into_iter().map(|(event, t)| (Item::Event(event), t)).collect()
+}
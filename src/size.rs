@@ -0,0 +1,155 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use time::OffsetDateTime;
+
+use crate::{walk, Event, FileType};
+
+type Timed = (Event, OffsetDateTime);
+
+/// Item of the stream given by `SizeMonitor`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Item {
+    Event(Event),
+    /// The total size of the files in the tree crossed a threshold, and is
+    /// this many bytes now.
+    SizeThreshold(u64),
+}
+
+/// Tracks the total size of the files in the tree, and gives an
+/// `Item::SizeThreshold` with the total after an event which makes it
+/// cross one of the thresholds, upwards or downwards, e.g. to catch a log
+/// or cache directory growing out of bounds.
+///
+/// The sizes are updated from the events of files by their metadata, so
+/// writes are only seen with `ExtraEvent::Modify` or `ExtraEvent::Close`.
+pub struct SizeMonitor {
+    top_dir: PathBuf,
+    thresholds: Vec<u64>,
+    sizes: BTreeMap<PathBuf, u64>,
+    total: u64,
+}
+
+impl SizeMonitor {
+    /// Scans the files in the top directory for their sizes.
+    pub fn new(top_dir: &Path, mut thresholds: Vec<u64>) -> Self {
+        thresholds.sort_unstable();
+        let mut monitor = Self {
+            top_dir: top_dir.to_owned(),
+            thresholds,
+            sizes: BTreeMap::new(),
+            total: 0,
+        };
+        monitor.scan(top_dir);
+        monitor
+    }
+
+    /// Total size in bytes.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn apply<S>(
+        mut self,
+        stream: S,
+    ) -> impl Stream<Item = (Item, OffsetDateTime)>
+    where
+        S: Stream<Item = Timed>,
+    {
+        stream! {
+            pin_mut!(stream);
+            while let Some((event, t)) = stream.next().await {
+                let level = self.level();
+                self.update(&event);
+                yield (Item::Event(event), t);
+                if self.level() != level {
+                    yield (Item::SizeThreshold(self.total), t);
+                }
+            }
+        }
+    }
+
+    /// Number of thresholds reached.
+    fn level(&self) -> usize {
+        self.thresholds.partition_point(|v| *v <= self.total)
+    }
+
+    fn update(&mut self, event: &Event) {
+        match event {
+            Event::Create(path, FileType::Dir)
+            | Event::MoveInto(path, FileType::Dir, _) => self.scan(path),
+            Event::Create(path, _)
+            | Event::MoveInto(path, ..)
+            | Event::Modify(path, ..)
+            | Event::Close(path, _) => self.stat(path),
+            Event::Delete(path, _) | Event::MoveAway(path, ..) => {
+                self.remove(path);
+            }
+            Event::Move(from, to, _) => {
+                for (path, len) in self.remove(from) {
+                    let path = to.join(path.strip_prefix(from).unwrap());
+                    self.set(path, Some(len));
+                }
+            }
+            Event::MoveTop(_, Some(to)) => {
+                let top_dir = std::mem::replace(&mut self.top_dir, to.clone());
+                self.sizes = std::mem::take(&mut self.sizes)
+                    .into_iter()
+                    .map(|(path, len)| {
+                        (to.join(path.strip_prefix(&top_dir).unwrap()), len)
+                    })
+                    .collect();
+            }
+            Event::DeleteTop(_) => {
+                self.sizes.clear();
+                self.total = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn scan(&mut self, dir: &Path) {
+        for path in walk::walk(dir, false, None, |_, _| true) {
+            self.stat(&path);
+        }
+    }
+
+    fn stat(&mut self, path: &Path) {
+        let len = fs::symlink_metadata(path)
+            .ok()
+            .filter(|v| v.is_file())
+            .map(|v| v.len());
+        self.set(path.to_owned(), len);
+    }
+
+    fn set(&mut self, path: PathBuf, len: Option<u64>) {
+        let old = match len {
+            Some(len) => {
+                self.total += len;
+                self.sizes.insert(path, len)
+            }
+            None => self.sizes.remove(&path),
+        };
+        self.total -= old.unwrap_or(0);
+    }
+
+    /// Removes the path and those under it, giving their sizes.
+    fn remove(&mut self, path: &Path) -> Vec<(PathBuf, u64)> {
+        let removed: Vec<_> = self
+            .sizes
+            .range(path.to_owned()..)
+            .take_while(|(v, _)| v.starts_with(path))
+            .map(|(v, len)| (v.to_owned(), *len))
+            .collect();
+        for (v, len) in &removed {
+            self.sizes.remove(v);
+            self.total -= len;
+        }
+        removed
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    watcher::{FileState, Snapshot},
+    Error, FileType, Result,
+};
+
+/// A file in the saved state, as one JSON line.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: RawPath,
+    dir: bool,
+    len: u64,
+    /// Seconds and nanoseconds since the Unix epoch.
+    mtime: Option<(u64, u32)>,
+}
+
+/// Path as a string, or as bytes if it is not UTF-8.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawPath {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&Path> for RawPath {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(v) => Self::Utf8(v.to_owned()),
+            None => Self::Bytes(path.as_os_str().as_bytes().to_vec()),
+        }
+    }
+}
+
+impl From<RawPath> for PathBuf {
+    fn from(path: RawPath) -> Self {
+        match path {
+            RawPath::Utf8(v) => v.into(),
+            RawPath::Bytes(v) => OsStr::from_bytes(&v).into(),
+        }
+    }
+}
+
+/// Writes the files to a temporary file first, which replaces the old
+/// state once complete.
+pub(crate) fn save(path: &Path, files: &Snapshot) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let write = || {
+        let mut file = fs::File::create(&tmp_path)?;
+        for (path, (file_type, state)) in files {
+            let (len, mtime) = match state {
+                FileState::Metadata { len, mtime } => (*len, *mtime),
+                FileState::Digest(_) => continue,
+            };
+            let entry = Entry {
+                path: path.as_path().into(),
+                dir: *file_type == FileType::Dir,
+                len,
+                mtime: mtime
+                    .and_then(|v| {
+                        v.duration_since(SystemTime::UNIX_EPOCH).ok()
+                    })
+                    .map(|v| (v.as_secs(), v.subsec_nanos())),
+            };
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+    write()
+        .map_err(|source| Error::SaveState { source, path: path.to_owned() })
+}
+
+pub(crate) fn load(path: &Path) -> Result<Snapshot> {
+    let file = fs::File::open(path).map_err(|source| Error::LoadState {
+        source,
+        path: path.to_owned(),
+    })?;
+    let mut files = Snapshot::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|source| Error::LoadState {
+            source,
+            path: path.to_owned(),
+        })?;
+        let entry: Entry = serde_json::from_str(&line).map_err(|e| {
+            Error::ParseState { line: i + 1, reason: e.to_string() }
+        })?;
+        let file_type = if entry.dir { FileType::Dir } else { FileType::File };
+        let state = FileState::Metadata {
+            len: entry.len,
+            mtime: entry.mtime.map(|(secs, nanos)| {
+                SystemTime::UNIX_EPOCH + Duration::new(secs, nanos)
+            }),
+        };
+        files.insert(entry.path.into(), (file_type, state));
+    }
+    Ok(files)
+}
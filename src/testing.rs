@@ -0,0 +1,12 @@
+//! Test doubles and utilities for applications built on watchdir, and
+//! for the tests of watchdir itself.
+
+#[cfg(feature = "async")]
+pub use self::mock::{MockSender, MockWatcher};
+#[cfg(feature = "testing")]
+pub use self::tree::{random_name, TempTree};
+
+#[cfg(feature = "async")]
+mod mock;
+#[cfg(feature = "testing")]
+mod tree;
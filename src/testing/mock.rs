@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex};
+
+use async_stream::stream;
+use futures::{future, Stream, StreamExt};
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+
+use crate::{allows, Event, Stats, StatsReader, WatcherOpts};
+
+/// Stands in for `Watcher`, yielding the events which are pushed to it
+/// instead of those on the filesystem.
+///
+/// Events are filtered by the options and counted in the statistics as
+/// the watcher does. The stream waits for events like the one of the
+/// watcher, while `close` gives back the pending ones.
+pub struct MockWatcher {
+    opts: WatcherOpts,
+    sender: MockSender,
+    receiver: mpsc::UnboundedReceiver<(Event, OffsetDateTime)>,
+    stats: Arc<Mutex<Stats>>,
+}
+
+/// Pushes events to a `MockWatcher` while it is streaming.
+#[derive(Clone)]
+pub struct MockSender(mpsc::UnboundedSender<(Event, OffsetDateTime)>);
+
+impl MockWatcher {
+    pub fn new(opts: WatcherOpts) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            opts,
+            sender: MockSender(sender),
+            receiver,
+            stats: Arc::new(Mutex::new(Stats::default())),
+        }
+    }
+
+    /// Pushes the event, happening now.
+    pub fn push(&self, event: Event) {
+        self.sender.push(event)
+    }
+
+    pub fn push_at(&self, event: Event, t: OffsetDateTime) {
+        self.sender.push_at(event, t)
+    }
+
+    pub fn sender(&self) -> MockSender {
+        self.sender.clone()
+    }
+
+    pub fn has_next_event(&self) -> bool {
+        !self.receiver.is_empty()
+    }
+
+    pub fn stream(
+        &mut self,
+    ) -> impl Stream<Item = (Event, OffsetDateTime)> + '_ {
+        let receiver = &mut self.receiver;
+        let events = stream! {
+            while let Some(event) = receiver.recv().await {
+                yield event
+            }
+        };
+        filter(&self.opts, &self.stats, events)
+    }
+
+    /// Gives back the pending events.
+    pub async fn close(mut self) -> Vec<(Event, OffsetDateTime)> {
+        let receiver = &mut self.receiver;
+        let events: Vec<_> =
+            std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        filter(&self.opts, &self.stats, futures::stream::iter(events))
+            .collect()
+            .await
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn stats_reader(&self) -> StatsReader {
+        StatsReader(Arc::clone(&self.stats))
+    }
+}
+
+impl MockSender {
+    /// Pushes the event, happening now.
+    pub fn push(&self, event: Event) {
+        self.push_at(event, OffsetDateTime::now_utc())
+    }
+
+    pub fn push_at(&self, event: Event, t: OffsetDateTime) {
+        // The receiver is only gone with the mock, when nobody listens.
+        let _ = self.0.send((event, t));
+    }
+}
+
+/// Keeps the events allowed by the options, and counts them.
+fn filter<S>(
+    opts: &WatcherOpts,
+    stats: &Arc<Mutex<Stats>>,
+    events: S,
+) -> impl Stream<Item = (Event, OffsetDateTime)>
+where
+    S: Stream<Item = (Event, OffsetDateTime)>,
+{
+    let stats = Arc::clone(stats);
+    let opts = opts.clone();
+    events
+        .filter(move |(event, _)| future::ready(allows(&opts, event)))
+        .inspect(move |(event, _)| {
+            *stats.lock().unwrap().events.entry(event.name()).or_default() += 1
+        })
+}
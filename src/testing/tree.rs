@@ -0,0 +1,115 @@
+use std::{
+    collections::hash_map::RandomState,
+    env, fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Gives a random name of ASCII letters and digits.
+pub fn random_name(len: usize) -> String {
+    const CHARS: &[u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut hasher = RandomState::new().build_hasher();
+    (0..len)
+        .map(|i| {
+            hasher.write_usize(i);
+            CHARS[hasher.finish() as usize % CHARS.len()] as char
+        })
+        .collect()
+}
+
+/// Temporary directory for scenarios of tests, removed with everything in
+/// it when dropped. Names of created files are random. Paths given to it
+/// are relative to the directory, unless they are absolute.
+pub struct TempTree {
+    root: PathBuf,
+}
+
+impl TempTree {
+    pub fn new() -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let root = env::temp_dir().join(format!(
+            "watchdir-{}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            random_name(6),
+        ));
+        fs::create_dir(&root)?;
+        Ok(Self { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Creates directories nested `depth` levels in `dir`, giving them
+    /// from the outermost.
+    pub fn create_nested(
+        &self,
+        dir: &Path,
+        depth: usize,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut path = self.root.join(dir);
+        let dirs: Vec<_> = (0..depth)
+            .map(|_| {
+                path.push(random_name(5));
+                path.to_owned()
+            })
+            .collect();
+        fs::create_dir_all(&path)?;
+        Ok(dirs)
+    }
+
+    /// Creates a tree in `dir` which has `width` directories in each
+    /// directory down to `depth` levels, giving them from the top down.
+    pub fn create_tree(
+        &self,
+        dir: &Path,
+        depth: usize,
+        width: usize,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        let mut level = vec![self.root.join(dir)];
+        for _ in 0..depth {
+            let mut next = Vec::new();
+            for parent in &level {
+                for _ in 0..width {
+                    let path = parent.join(random_name(5));
+                    fs::create_dir(&path)?;
+                    next.push(path);
+                }
+            }
+            dirs.extend(next.iter().cloned());
+            level = next;
+        }
+        Ok(dirs)
+    }
+
+    /// Creates an empty file in `dir`.
+    pub fn create_file(&self, dir: &Path) -> io::Result<PathBuf> {
+        let path = self.root.join(dir).join(random_name(5));
+        fs::File::create(&path)?;
+        Ok(path)
+    }
+
+    /// Renames the file or subtree to a new name in `to_dir`.
+    pub fn rename(&self, path: &Path, to_dir: &Path) -> io::Result<PathBuf> {
+        let to = self.root.join(to_dir).join(random_name(5));
+        fs::rename(self.root.join(path), &to)?;
+        Ok(to)
+    }
+}
+
+impl AsRef<Path> for TempTree {
+    fn as_ref(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
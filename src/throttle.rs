@@ -0,0 +1,85 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+#[cfg(feature = "async")]
+use futures::{future, Stream, StreamExt};
+use time::OffsetDateTime;
+
+use crate::Event;
+
+/// Expired entries are pruned when there are more than this.
+const PRUNE_THRESHOLD: usize = 1024;
+
+type Window = Box<dyn Fn(&Event) -> Option<Duration> + Send>;
+
+/// Kind of an event with its path, and the destination of a move.
+type Key = (&'static str, PathBuf, Option<PathBuf>);
+
+/// Limits events per path. Once an event passes, later events of the same
+/// kind for the same paths are dropped until its time window is over.
+///
+/// Windows are measured by the time of events, so no timer is involved.
+pub struct Throttle {
+    window: Window,
+    expiries: HashMap<Key, OffsetDateTime>,
+    prune_at: usize,
+}
+
+impl Throttle {
+    /// Uses the same window for all events.
+    pub fn new(window: Duration) -> Self {
+        Self::with(move |_| Some(window))
+    }
+
+    /// Gives the window of each event. Events without a window always pass.
+    pub fn with<F>(window: F) -> Self
+    where
+        F: Fn(&Event) -> Option<Duration> + Send + 'static,
+    {
+        Self {
+            window: Box::new(window),
+            expiries: HashMap::new(),
+            prune_at: PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Tells whether the event passes, and starts its window if so.
+    pub fn allow(&mut self, event: &Event, t: OffsetDateTime) -> bool {
+        let path = match event.path() {
+            Some(v) => v,
+            None => return true,
+        };
+        let window = match (self.window)(event) {
+            Some(v) if !v.is_zero() => v,
+            _ => return true,
+        };
+        let to = match event {
+            Event::Move(_, to, _) | Event::MoveTop(_, Some(to)) => {
+                Some(to.to_owned())
+            }
+            _ => None,
+        };
+        let key = (event.name(), path.to_owned(), to);
+        if matches!(self.expiries.get(&key), Some(expiry) if t < *expiry) {
+            return false;
+        }
+        self.expiries.insert(key, t + window);
+
+        if self.expiries.len() > self.prune_at {
+            self.expiries.retain(|_, expiry| t < *expiry);
+            self.prune_at = PRUNE_THRESHOLD.max(self.expiries.len() * 2);
+        }
+        true
+    }
+
+    /// Drops the events of the stream which do not pass.
+    #[cfg(feature = "async")]
+    pub fn apply<S>(
+        mut self,
+        stream: S,
+    ) -> impl Stream<Item = (Event, OffsetDateTime)>
+    where
+        S: Stream<Item = (Event, OffsetDateTime)>,
+    {
+        stream.filter(move |(event, t)| future::ready(self.allow(event, *t)))
+    }
+}
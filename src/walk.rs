@@ -0,0 +1,144 @@
+//! Scan of the subdirectories of a directory, with walkdir or, without
+//! the feature `scan`, with the standard library only.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::FileType;
+
+/// Device and inode of the file.
+pub fn inode(metadata: &fs::Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Walks the files in `path` from the top down, giving those accepted by
+/// `filter` and going into them. Links are followed if `follow`.
+///
+/// If `seen` is given, it has the inodes of directories not to be walked
+/// again. Those reached again are skipped if following links, or else
+/// given without going into them, e.g. bind mounts.
+pub fn walk<'a, F>(
+    path: &Path,
+    follow: bool,
+    seen: Option<HashSet<(u64, u64)>>,
+    filter: F,
+) -> impl Iterator<Item = PathBuf> + 'a
+where
+    F: FnMut(&Path, FileType) -> bool + 'a,
+{
+    walk_with_errors(path, follow, seen, filter, |_, _| {})
+}
+
+/// Like `walk`, but gives the errors of reading directories and files to
+/// `on_error` with their paths.
+#[cfg(feature = "scan")]
+pub fn walk_with_errors<'a, F, E>(
+    path: &Path,
+    follow: bool,
+    seen: Option<HashSet<(u64, u64)>>,
+    mut filter: F,
+    mut on_error: E,
+) -> impl Iterator<Item = PathBuf> + 'a
+where
+    F: FnMut(&Path, FileType) -> bool + 'a,
+    E: FnMut(&Path, io::Error) + 'a,
+{
+    // Links to seen directories are skipped as they are reached, and
+    // other seen directories once given.
+    let (mut linked, mut bound) = match follow {
+        true => (seen, None),
+        false => (None, seen),
+    };
+    let mut entries = walkdir::WalkDir::new(path)
+        .follow_links(follow)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(move |entry| {
+            filter(entry.path(), entry.file_type().into())
+                && linked.as_mut().is_none_or(|seen| {
+                    entry.metadata().is_ok_and(|v| seen.insert(inode(&v)))
+                })
+        });
+    std::iter::from_fn(move || loop {
+        let entry = match entries.next()? {
+            Ok(v) => v,
+            Err(e) => {
+                let path = e.path().map(Path::to_owned);
+                if let (Some(path), Some(e)) = (path, e.into_io_error()) {
+                    on_error(&path, e);
+                }
+                continue;
+            }
+        };
+        if let Some(seen) =
+            bound.as_mut().filter(|_| entry.file_type().is_dir())
+        {
+            if entry.metadata().is_ok_and(|v| !seen.insert(inode(&v))) {
+                entries.skip_current_dir();
+            }
+        }
+        return Some(entry.into_path());
+    })
+}
+
+#[cfg(not(feature = "scan"))]
+pub fn walk_with_errors<'a, F, E>(
+    path: &Path,
+    follow: bool,
+    mut seen: Option<HashSet<(u64, u64)>>,
+    mut filter: F,
+    mut on_error: E,
+) -> impl Iterator<Item = PathBuf> + 'a
+where
+    F: FnMut(&Path, FileType) -> bool + 'a,
+    E: FnMut(&Path, io::Error) + 'a,
+{
+    // Each directory being read, with its path for errors.
+    let mut stack = Vec::new();
+    match fs::read_dir(path) {
+        Ok(v) => stack.push((path.to_owned(), v)),
+        Err(e) => on_error(path, e),
+    }
+    std::iter::from_fn(move || loop {
+        let (dir, entries) = stack.last_mut()?;
+        let entry = match entries.next() {
+            Some(Ok(v)) => v,
+            Some(Err(e)) => {
+                on_error(dir, e);
+                continue;
+            }
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        let path = entry.path();
+        let metadata = match follow {
+            true => fs::metadata(&path),
+            false => entry.metadata(),
+        };
+        // Gone since read, or a broken link.
+        let metadata = match metadata {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        if !filter(&path, metadata.file_type().into()) {
+            continue;
+        }
+        let is_new = seen.as_mut().is_none_or(|v| v.insert(inode(&metadata)));
+        if follow && !is_new {
+            continue;
+        }
+        if is_new && metadata.is_dir() {
+            match fs::read_dir(&path) {
+                Ok(v) => stack.push((path.to_owned(), v)),
+                Err(e) => on_error(&path, e),
+            }
+        }
+        return Some(path);
+    })
+}
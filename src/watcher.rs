@@ -0,0 +1,1936 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::CString,
+    fs, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_stream::stream;
+use futures::{future, pin_mut, Stream, StreamExt};
+use lru::LruCache;
+use tokio::sync::mpsc;
+
+use crate::{
+    allows, digest, guard, inotify, is_about_watching,
+    log::{debug, warn},
+    path_tree, state,
+    walk::{inode, walk},
+    Digest, Error, Event, FileType, Progress, Request, Result, Stats,
+    StatsReader, Symlink, WatcherController, WatcherOpts, WatchesReader,
+};
+
+/// How often to check whether the top directory has appeared again.
+const REATTACH_INTERVAL: Duration = Duration::from_millis(200);
+/// Files waiting to be hashed, beyond which they are not.
+const HASH_QUEUE: usize = 256;
+/// How often to check whether the system was suspended.
+const SUSPEND_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Watcher {
+    opts: WatcherOpts,
+    top_wd: i32,
+    top_dir: PathBuf,
+    parent_wd: Option<i32>,
+    top_cookie: Option<u32>,
+    top_moved_to: Option<PathBuf>,
+    top_replaced: bool,
+    /// The top directory was moved away from its path, so a rename onto
+    /// the path no longer replaces it.
+    top_left: bool,
+    path_tree: path_tree::Head<i32>,
+    event_seq: inotify::EventSeq,
+    cached_inotify_event: Option<inotify::Event>,
+    deleted_dirs: HashSet<PathBuf>,
+    failed_watches: usize,
+    watch_errors: Vec<(PathBuf, io::ErrorKind, time::OffsetDateTime)>,
+    failed_dirs: BTreeSet<PathBuf>,
+    /// Watched directories whose subdirectories are not, by `lazy_depth`
+    /// or `max_watches`.
+    lazy_wds: HashSet<i32>,
+    /// Watched directories by their last events, with `max_watches`.
+    active_wds: Option<LruCache<i32, ()>>,
+    /// Directories unwatched by `max_watches`, whose events are dropped
+    /// until the kernel confirms the removal.
+    evicted_wds: HashSet<i32>,
+    /// Watches removed by watchdir, until the kernel confirms it. Others
+    /// removed by the kernel leave stale entries to be purged.
+    removed_wds: HashSet<i32>,
+    /// Directories found gone with their watches stale, e.g. replaced by a
+    /// rename or with their descriptors reused, to report as removed.
+    stale_dirs: Vec<(PathBuf, time::OffsetDateTime)>,
+    unwatched: Vec<(PathBuf, time::OffsetDateTime)>,
+    /// Other paths to watched directories, sharing their watches.
+    aliases: Vec<Alias>,
+    /// Events repeated under aliases, yielded after the event.
+    alias_events: Vec<(Event, time::OffsetDateTime)>,
+    /// Device and inode of watched directories, when links are followed or
+    /// directories are deduplicated.
+    dir_inodes: HashMap<i32, (u64, u64)>,
+    restored_dirs: Vec<(PathBuf, time::OffsetDateTime)>,
+    /// Unmounted directories, with the device under the mount, until a
+    /// file system is mounted again, with `rewatch_unmounted`.
+    unmounted: Vec<(PathBuf, u64)>,
+    remounted: Vec<(PathBuf, time::OffsetDateTime)>,
+    stats: Arc<Mutex<Stats>>,
+    requests_tx: mpsc::UnboundedSender<Request>,
+    /// Options for the filter of yielded events, as changed by requests.
+    event_opts: Arc<Mutex<WatcherOpts>>,
+    paused: Arc<AtomicBool>,
+    /// Pausing or resuming, done once the queued events are read, so that
+    /// they are not dropped or rescanned.
+    pause_request: Option<bool>,
+    /// Files in watched directories when paused, with `rescan_on_resume`.
+    paused_files: Option<Snapshot>,
+    /// Changes found by rescans, yielded as events.
+    rescanned: Vec<(Event, time::OffsetDateTime)>,
+    /// Clocks at the last check for a suspend, with
+    /// `rescan_after_suspend`.
+    clocks: Option<Clocks>,
+    /// Files in watched directories at the last check for a suspend which
+    /// followed events, with `rescan_after_suspend`.
+    suspend_files: Option<Snapshot>,
+    /// Whether events were read since `suspend_files` was taken.
+    files_changed: bool,
+    /// Taken while waiting for events.
+    requests_rx: Option<mpsc::UnboundedReceiver<Request>>,
+    /// Receives errors, from the stream of the last call of `errors`.
+    errors_tx: Option<mpsc::UnboundedSender<Error>>,
+    modified_files: Option<LruCache<PathBuf, FileState>>,
+    /// Files modified since they were last closed after writing, with
+    /// `hash_on_modify`.
+    dirty_files: HashSet<PathBuf>,
+    /// Queue of the hash worker, which is started by the first file.
+    hash_jobs: Option<mpsc::Sender<(PathBuf, time::OffsetDateTime)>>,
+    hashed_tx: mpsc::UnboundedSender<Hashed>,
+    /// Taken while waiting for events.
+    hashed_rx: Option<mpsc::UnboundedReceiver<Hashed>>,
+    /// Hashed files to yield modify events for.
+    hashed: Vec<Hashed>,
+    closing: bool,
+}
+
+type Hashed = (PathBuf, Option<Digest>, time::OffsetDateTime);
+
+/// Path to a watched directory other than its own, e.g. a bind mount.
+struct Alias {
+    wd: i32,
+    path: PathBuf,
+    /// Device and inode of the directory, which the path must still lead to.
+    inode: (u64, u64),
+}
+
+/// What tells whether a modified file has really changed.
+#[derive(PartialEq)]
+pub(crate) enum FileState {
+    Digest(Digest),
+    Metadata { len: u64, mtime: Option<std::time::SystemTime> },
+}
+
+/// Time since boot with and without suspends.
+#[derive(Copy, Clone)]
+struct Clocks {
+    boot: Duration,
+    monotonic: Duration,
+}
+
+impl Clocks {
+    fn now() -> Self {
+        let clock = |id| {
+            let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            unsafe { libc::clock_gettime(id, &mut ts) };
+            Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+        };
+        Self {
+            boot: clock(libc::CLOCK_BOOTTIME),
+            monotonic: clock(libc::CLOCK_MONOTONIC),
+        }
+    }
+}
+
+/// Files in watched directories by their paths.
+pub(crate) type Snapshot = BTreeMap<PathBuf, (FileType, FileState)>;
+
+impl Watcher {
+    pub fn new(dir: &Path, opts: WatcherOpts) -> Result<Self> {
+        Self::with_progress(dir, opts, &mut |_| {})
+    }
+
+    /// Like `new`, but scans the directory on a blocking task so that the
+    /// runtime is not stalled by large trees. `progress` is called for every
+    /// scanned directory.
+    pub async fn new_async<F>(
+        dir: &Path,
+        opts: WatcherOpts,
+        mut progress: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(Progress) + Send + 'static,
+    {
+        let dir = dir.to_owned();
+        tokio::task::spawn_blocking(move || {
+            Self::with_progress(&dir, opts, &mut progress)
+        })
+        .await
+        .unwrap()
+    }
+
+    fn with_progress(
+        dir: &Path,
+        opts: WatcherOpts,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<Self> {
+        #[cfg(not(feature = "tokio-reactor"))]
+        if let Some(option) = opts.tokio_option() {
+            return Err(Error::NeedsTokio { option });
+        }
+
+        let fd = unsafe {
+            libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(Error::InitInotify);
+        }
+
+        let modified_files = opts.dedupe_modify.map(LruCache::new);
+        let active_wds = opts.max_watches.map(|_| LruCache::unbounded());
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let (hashed_tx, hashed_rx) = mpsc::unbounded_channel();
+        let event_opts = Arc::new(Mutex::new(opts.clone()));
+        let mut watcher = Self {
+            opts,
+            top_wd: 0,
+            top_dir: dir.to_owned(),
+            parent_wd: None,
+            top_cookie: None,
+            top_moved_to: None,
+            top_replaced: false,
+            top_left: false,
+            path_tree: path_tree::Head::new(dir.to_owned()),
+            event_seq: inotify::EventSeq::new(fd),
+            cached_inotify_event: None,
+            deleted_dirs: HashSet::new(),
+            failed_watches: 0,
+            watch_errors: Vec::new(),
+            failed_dirs: BTreeSet::new(),
+            lazy_wds: HashSet::new(),
+            active_wds,
+            evicted_wds: HashSet::new(),
+            removed_wds: HashSet::new(),
+            stale_dirs: Vec::new(),
+            unwatched: Vec::new(),
+            aliases: Vec::new(),
+            alias_events: Vec::new(),
+            dir_inodes: HashMap::new(),
+            restored_dirs: Vec::new(),
+            unmounted: Vec::new(),
+            remounted: Vec::new(),
+            stats: Arc::new(Mutex::new(Stats::default())),
+            requests_tx,
+            event_opts,
+            paused: Arc::new(AtomicBool::new(false)),
+            pause_request: None,
+            paused_files: None,
+            rescanned: Vec::new(),
+            clocks: None,
+            suspend_files: None,
+            files_changed: false,
+            requests_rx: Some(requests_rx),
+            errors_tx: None,
+            modified_files,
+            dirty_files: HashSet::new(),
+            hash_jobs: None,
+            hashed_tx,
+            hashed_rx: Some(hashed_rx),
+            hashed: Vec::new(),
+            closing: false,
+        };
+        if let (Some(top_wd), _) = watcher.add_watch_all_with(dir, progress) {
+            watcher.top_wd = top_wd;
+        }
+        if watcher.opts.watch_parent {
+            watcher.parent_wd = watcher.watch_parent();
+        }
+        watcher.clocks = watcher.opts.suspend_threshold.map(|_| Clocks::now());
+        if watcher.clocks.is_some() {
+            watcher.suspend_files = Some(watcher.scan_files());
+        }
+        watcher.check_watch_limit()?;
+
+        Ok(watcher)
+    }
+
+    pub fn stream(
+        &mut self,
+    ) -> impl Stream<Item = (Event, time::OffsetDateTime)> + '_ {
+        let stats = Arc::clone(&self.stats);
+        let opts = Arc::clone(&self.event_opts);
+        let paused = Arc::clone(&self.paused);
+        self.event_stream()
+            .filter(move |(event, _)| {
+                future::ready(
+                    allows(&opts.lock().unwrap(), event)
+                        && (!paused.load(Ordering::Relaxed)
+                            || is_about_watching(event)),
+                )
+            })
+            .inspect(move |(event, _)| {
+                *stats
+                    .lock()
+                    .unwrap()
+                    .events
+                    .entry(event.name())
+                    .or_default() += 1
+            })
+    }
+
+    /// Like `stream`, but yields events in batches. A batch is yielded
+    /// when it has `max_batch` events, or `max_delay` after its first
+    /// event.
+    pub fn stream_batched(
+        &mut self,
+        max_batch: usize,
+        max_delay: std::time::Duration,
+    ) -> impl Stream<Item = Vec<(Event, time::OffsetDateTime)>> + '_ {
+        let events = self.stream();
+        stream! {
+            pin_mut!(events);
+            while let Some(event) = events.next().await {
+                let deadline = tokio::time::Instant::now() + max_delay;
+                let mut batch = vec![event];
+                while batch.len() < max_batch {
+                    match tokio::time::timeout_at(deadline, events.next())
+                        .await
+                    {
+                        Ok(Some(event)) => batch.push(event),
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+                yield batch;
+            }
+        }
+    }
+
+    /// Stops watching. Events already queued in the kernel are read and
+    /// given back, then all watches are removed and the inotify instance
+    /// is closed.
+    pub async fn close(mut self) -> Vec<(Event, time::OffsetDateTime)> {
+        self.closing = true;
+        self.stream().collect().await
+    }
+
+    /// Takes a snapshot of the statistics.
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Gives a reader of the statistics which stays usable while the
+    /// watcher is streaming.
+    pub fn stats_reader(&self) -> StatsReader {
+        StatsReader(Arc::clone(&self.stats))
+    }
+
+    /// Gives the watched directories, sorted.
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> =
+            self.path_tree.values().map(|wd| self.path(*wd)).collect();
+        paths.sort();
+        paths
+    }
+
+    /// Gives a reader of the watched directories which stays usable while
+    /// the watcher is streaming.
+    pub fn watches_reader(&self) -> WatchesReader {
+        WatchesReader(self.requests_tx.clone())
+    }
+
+    /// Like `new`, but first yields `Create`, `Delete` and `Modify` events
+    /// for the differences from the state saved by `save_state`, e.g. by
+    /// the last run.
+    pub fn resume_from(
+        dir: &Path,
+        state: &Path,
+        opts: WatcherOpts,
+    ) -> Result<Self> {
+        let old = state::load(state)?;
+        let mut watcher = Self::new(dir, opts)?;
+        watcher.diff_files(&old);
+        Ok(watcher)
+    }
+
+    /// Saves the paths, sizes and modification times of the files in
+    /// watched directories, for `resume_from`.
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        state::save(path, &self.scan_files())
+    }
+
+    /// Drops events until `resume`, like `WatcherController::pause`. It
+    /// takes effect when the stream has read the events already queued.
+    pub fn pause(&mut self) {
+        self.pause_request = Some(true);
+    }
+
+    pub fn resume(&mut self) {
+        self.pause_request = Some(false);
+    }
+
+    /// Gives a handle to control the watcher while it is streaming.
+    pub fn controller(&self) -> WatcherController {
+        WatcherController {
+            tx: self.requests_tx.clone(),
+            stats: Arc::clone(&self.stats),
+        }
+    }
+
+    /// Gives errors which degrade watching, also logged as warnings:
+    /// directories failed to watch, the limit of watches, overflows of
+    /// the event queue and malformed events. Only the stream of the last
+    /// call gets errors.
+    pub fn errors(&mut self) -> impl Stream<Item = Error> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.errors_tx = Some(tx);
+        stream! {
+            while let Some(e) = rx.recv().await {
+                yield e
+            }
+        }
+    }
+
+    fn event_stream(
+        &mut self,
+    ) -> impl Stream<Item = (Event, time::OffsetDateTime)> + '_ {
+        stream! {
+            'events: loop {
+                for (path, digest, t) in std::mem::take(&mut self.hashed) {
+                    if !self.is_changed(&path, &digest) {
+                        continue;
+                    }
+                    let event = Event::Modify(path, FileType::File, digest);
+                    if self.opts.alias_events && !self.aliases.is_empty() {
+                        self.copy_to_aliases(&event, t);
+                    }
+                    yield (event, t)
+                }
+                for (event, t) in std::mem::take(&mut self.alias_events) {
+                    yield (event, t)
+                }
+                for (event, t) in std::mem::take(&mut self.rescanned) {
+                    yield (event, t)
+                }
+                for (path, t) in std::mem::take(&mut self.restored_dirs) {
+                    yield (Event::Watching(path), t)
+                }
+                for (path, t) in std::mem::take(&mut self.remounted) {
+                    yield (Event::Remounted(path), t)
+                }
+                for (path, t) in std::mem::take(&mut self.stale_dirs) {
+                    yield (Event::WatchRemoved(path), t)
+                }
+                for (path, t) in std::mem::take(&mut self.unwatched) {
+                    yield (Event::Unwatched(path), t)
+                }
+                for (path, kind, t) in std::mem::take(&mut self.watch_errors) {
+                    yield (Event::WatchError(path, kind), t)
+                }
+                let (inotify_event, event, wd) = loop {
+                    let inotify_event = match self.cached_inotify_event.take()
+                    {
+                        Some(e) => e,
+                        None if self.pause_request.is_some()
+                            && !self.event_seq.has_next_event() => {
+                            let paused = self.pause_request.take().unwrap();
+                            self.set_paused(paused);
+                            continue 'events;
+                        }
+                        None if self.closing
+                            && !self.event_seq.has_next_event() => return,
+                        None => {
+                            let interval = self.retry_interval();
+                            let read = self.read_or_answer();
+                            let read = match interval {
+                                Some(v) => tokio::time::timeout(v, read).await,
+                                None => Ok(read.await),
+                            };
+                            match read {
+                                Ok(Some(e)) => {
+                                    self.files_changed = true;
+                                    e
+                                }
+                                // Requests may give events to yield.
+                                Ok(None) => continue 'events,
+                                Err(_) => {
+                                    self.retry_failed_dirs();
+                                    self.rewatch_unmounted();
+                                    self.check_suspend();
+                                    continue 'events;
+                                }
+                            }
+                        }
+                    };
+                    let ignored =
+                        inotify_event.kind == inotify::EventKind::Ignored;
+                    if self.evicted_wds.contains(&inotify_event.wd) {
+                        if ignored {
+                            self.evicted_wds.remove(&inotify_event.wd);
+                            self.removed_wds.remove(&inotify_event.wd);
+                        }
+                        continue;
+                    }
+                    if let Some(path) =
+                        ignored.then(|| self.purge(inotify_event.wd)).flatten()
+                    {
+                        break (inotify_event, Event::WatchRemoved(path), None);
+                    }
+                    let (event, wd) = self.recognize(&inotify_event).await;
+                    if self.opts.alias_events && !self.aliases.is_empty() {
+                        self.copy_to_aliases(&event, inotify_event.t);
+                    }
+                    if event != Event::Noise && event != Event::Ignored {
+                        self.touch(inotify_event.wd);
+                    }
+                    if event != Event::Noise
+                        && event != Event::Ignored
+                        && self.lazy_wds.remove(&inotify_event.wd)
+                    {
+                        // The path of the event is watched by itself.
+                        let skip = match &event {
+                            Event::Move(_, to, _) => Some(to.to_owned()),
+                            _ => event.path().map(Path::to_owned),
+                        };
+                        self.expand(inotify_event.wd, skip.as_deref());
+                    }
+                    if event != Event::Noise {
+                        break (inotify_event, event, wd);
+                    }
+                };
+
+                if self.opts.hash_algo.is_some() {
+                    self.follow_dirty(&event);
+                }
+                match event {
+                    Event::Move(_, ref to_path, FileType::Dir)
+                        if wd.is_none() => {
+                        // Not watched, e.g. by lazy_depth.
+                        if guard(&self.opts, to_path, FileType::Dir) {
+                            self.add_watch_all(to_path);
+                            if let Err(e) = self.check_watch_limit() {
+                                self.report(e);
+                            }
+                        }
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Move(ref from_path, ref to_path, FileType::Dir) => {
+                        if guard(&self.opts, from_path, FileType::Dir) {
+                            if guard(&self.opts, to_path, FileType::Dir) {
+                                self.update_path(wd.unwrap(), to_path);
+                            } else {
+                                self.rm_watch_all(wd.unwrap());
+                            }
+                        } else {
+                            if guard(&self.opts, to_path, FileType::Dir) {
+                                self.add_watch_all(to_path);
+                                if let Err(e) = self.check_watch_limit() {
+                                    self.report(e);
+                                }
+                            }
+                        }
+                        yield (event, inotify_event.t)
+                    }
+                    Event::MoveAway(ref path, FileType::Dir, _)
+                        | Event::Delete(ref path, FileType::Dir) => {
+                        // The descriptor may be given to another directory
+                        // already.
+                        if let Some(wd) = wd.filter(|v| {
+                            self.path_tree.has(*v) && self.path(*v) == *path
+                        }) {
+                            self.rm_watch_all(wd);
+                        }
+                        yield (event, inotify_event.t)
+                    }
+                    Event::MoveInto(ref path, FileType::Dir, _) => {
+                        if let Ok(metadata) = fs::symlink_metadata(path) {
+                            if guard(&self.opts, path,
+                                metadata.file_type().into()) {
+                                self.add_watch_all(path);
+                                if let Err(e) = self.check_watch_limit() {
+                                    self.report(e);
+                                }
+                            }
+                        }
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Create(ref path, FileType::Dir) => {
+                        if let Ok(metadata) = fs::symlink_metadata(path) {
+                            if guard(&self.opts, path,
+                                metadata.file_type().into()) {
+                                let (_, subdirs) = self.add_watch_all(path);
+                                if let Err(e) = self.check_watch_limit() {
+                                    self.report(e);
+                                }
+
+                                yield (event, inotify_event.t);
+                                for path in subdirs {
+                                    yield (Event::Create(path, FileType::Dir),
+                                        inotify_event.t)
+                                }
+                            } else {
+                                yield (event, inotify_event.t)
+                            }
+                        } else {
+                            yield (event, inotify_event.t)
+                        }
+                    }
+                    Event::Create(ref path, FileType::File)
+                        if self.is_dir_link(path) => {
+                        let (_, subdirs) = self.add_watch_all(path);
+                        if let Err(e) = self.check_watch_limit() {
+                            self.report(e);
+                        }
+                        yield (event, inotify_event.t);
+                        for path in subdirs {
+                            yield (Event::Create(path, FileType::Dir),
+                                inotify_event.t)
+                        }
+                    }
+                    Event::MoveInto(ref path, FileType::File, _)
+                        if self.is_dir_link(path) => {
+                        self.add_watch_all(path);
+                        if let Err(e) = self.check_watch_limit() {
+                            self.report(e);
+                        }
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Move(ref from_path, ref to_path, FileType::File)
+                        if self.path_tree.has_path(from_path) => {
+                        let wd = self.path_tree.get(from_path).unwrap();
+                        self.update_path(wd, to_path);
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Delete(ref path, FileType::File)
+                        | Event::MoveAway(ref path, FileType::File, _)
+                        if self.path_tree.has_path(path) => {
+                        let wd = self.path_tree.get(path).unwrap();
+                        self.rm_watch_all(wd);
+                        yield (event, inotify_event.t)
+                    }
+                    Event::MoveTop(..)
+                        | Event::DeleteTop(_)
+                        | Event::ReplaceTop(_)
+                        | Event::UnmountTop(_)
+                        if self.opts.reattach_top
+                            && inotify_event.wd == self.top_wd => {
+                        let top_wd = self.top_wd;
+                        self.rm_watch_all(top_wd);
+                        yield (event, inotify_event.t);
+
+                        self.discard_queue().await;
+                        while !self.top_dir.is_dir() {
+                            tokio::time::sleep(REATTACH_INTERVAL).await;
+                        }
+                        // Renames in the parent up to now, e.g. the one
+                        // which brought the directory, are of the past.
+                        self.discard_queue().await;
+                        self.top_replaced = false;
+                        self.top_left = false;
+                        let top_dir = self.top_dir.to_owned();
+                        if let (Some(top_wd), _) = self.add_watch_all(&top_dir)
+                        {
+                            self.top_wd = top_wd;
+                        }
+                        if let Err(e) = self.check_watch_limit() {
+                            self.report(e);
+                        }
+                        yield (Event::Reattached(top_dir),
+                            time::OffsetDateTime::now_utc())
+                    }
+                    Event::MoveTop(_, Some(ref to)) => {
+                        self.top_dir = to.to_owned();
+                        self.path_tree.set_prefix(to.to_owned());
+                        yield (event, inotify_event.t)
+                    }
+                    Event::DeleteTop(_)
+                    | Event::ReplaceTop(_)
+                    | Event::UnmountTop(_) => {
+                        let top_wd = self.top_wd;
+                        self.rm_watch_all(top_wd);
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Unmount(ref path, _) => {
+                        self.rm_watch_all(inotify_event.wd);
+                        self.note_unmounted(path);
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Modify(path, FileType::File, None)
+                        if self.opts.hash_algo.is_some() => {
+                        // Yielded once hashed after the file is closed.
+                        self.dirty_files.insert(path);
+                        self.alias_events.clear();
+                    }
+                    Event::Modify(path, file_type, None) => {
+                        if !self.is_changed(&path, &None) {
+                            self.alias_events.clear();
+                            continue;
+                        }
+                        yield (Event::Modify(path, file_type, None),
+                            inotify_event.t)
+                    }
+                    Event::Attrib(ref path, FileType::Dir)
+                        if self.failed_dirs.contains(path) => {
+                        // Its permissions changed, so it may be readable.
+                        self.retry_failed_dir(path.to_owned());
+                        if let Err(e) = self.check_watch_limit() {
+                            self.report(e);
+                        }
+                        // Watched only for retrying.
+                        if self.opts.event_types & libc::IN_ATTRIB == 0 {
+                            self.alias_events.clear();
+                            continue;
+                        }
+                        yield (event, inotify_event.t)
+                    }
+                    Event::Attrib(..) | Event::AttribTop(_)
+                        if self.opts.event_types & libc::IN_ATTRIB == 0 => {
+                        self.alias_events.clear();
+                    }
+                    Event::Close(ref path, FileType::File)
+                        if self.opts.hash_algo.is_some()
+                            && matches!(inotify_event.kind,
+                                inotify::EventKind::CloseWrite(..)) => {
+                        if self.dirty_files.remove(path) {
+                            self.hash(path.to_owned(), inotify_event.t);
+                        }
+                        // Watched only for hashing.
+                        if self.opts.event_types & libc::IN_CLOSE_WRITE == 0 {
+                            self.alias_events.clear();
+                            continue;
+                        }
+                        yield (event, inotify_event.t)
+                    }
+
+                    _ => {
+                        yield (event, inotify_event.t)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gives the file to the hash worker, or yields its modify event
+    /// without a digest if the worker is behind.
+    fn hash(&mut self, path: PathBuf, t: time::OffsetDateTime) {
+        let (algo, size_limit) =
+            (self.opts.hash_algo.unwrap(), self.opts.hash_size_limit);
+        let hashed_tx = self.hashed_tx.clone();
+        let jobs = self.hash_jobs.get_or_insert_with(|| {
+            let (tx, mut rx) =
+                mpsc::channel::<(PathBuf, time::OffsetDateTime)>(HASH_QUEUE);
+            tokio::spawn(async move {
+                while let Some((path, t)) = rx.recv().await {
+                    let digest =
+                        digest::hash_file(path.to_owned(), algo, size_limit)
+                            .await;
+                    if hashed_tx.send((path, digest, t)).is_err() {
+                        break;
+                    }
+                }
+            });
+            tx
+        });
+        if let Err(e) = jobs.try_send((path, t)) {
+            let (path, t) = e.into_inner();
+            self.hashed.push((path, None, t));
+        }
+    }
+
+    /// Moves the marks of modified files along with the event.
+    fn follow_dirty(&mut self, event: &Event) {
+        let (path, to) = match event {
+            Event::Move(from, to, FileType::File) => (from, Some(to)),
+            Event::Delete(path, FileType::File)
+            | Event::MoveAway(path, FileType::File, _) => (path, None),
+            _ => return,
+        };
+        if self.dirty_files.remove(path) {
+            self.dirty_files.extend(to.cloned());
+        }
+    }
+
+    /// Remembers the state of the modified file and tells whether it
+    /// differs from the last one.
+    fn is_changed(&mut self, path: &Path, digest: &Option<Digest>) -> bool {
+        let modified_files = match &mut self.modified_files {
+            Some(v) => v,
+            None => return true,
+        };
+        let state = match digest {
+            Some(digest) => FileState::Digest(digest.to_owned()),
+            None => match fs::metadata(path) {
+                Ok(metadata) => FileState::Metadata {
+                    len: metadata.len(),
+                    mtime: metadata.modified().ok(),
+                },
+                Err(_) => return true,
+            },
+        };
+        if modified_files.get(path) == Some(&state) {
+            return false;
+        }
+        modified_files.put(path.to_owned(), state);
+        true
+    }
+
+    fn add_watch(&mut self, path: &Path) -> Result<i32> {
+        let ffi_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                self.event_seq.fd(),
+                ffi_path.as_ptr(),
+                self.opts.mask(),
+            )
+        };
+        if wd < 0 {
+            return Err(Error::add_watch(io::Error::last_os_error(), path));
+        }
+
+        if self.path_tree.has(wd) {
+            let old_path = self.path(wd);
+            if is_same_dir(&old_path, path) {
+                return Err(Error::WatchSame { wd, path: path.to_owned() });
+            }
+            // The old directory is gone without the removal of its watch
+            // processed yet, and the kernel gives its descriptor again.
+            warn!(
+                "Watch descriptor {} of {} is reused for {}",
+                wd,
+                old_path.display(),
+                path.display()
+            );
+            let stale = self.path_tree.delete(wd).unwrap();
+            self.lazy_wds.remove(&wd);
+            self.drop_watches(&stale[1..]);
+            self.stale_dirs.push((old_path, time::OffsetDateTime::now_utc()));
+        }
+
+        let replaced = self.path_tree.insert(path, wd).unwrap();
+        self.drop_watches(&replaced);
+        if let Some(active_wds) = &mut self.active_wds {
+            active_wds.put(wd, ());
+        }
+        let extra_mask = self.opts.extra_mask(path);
+        if extra_mask != 0 {
+            let mask = libc::IN_MASK_ADD
+                | extra_mask
+                | self.opts.event_types
+                    & (libc::IN_ONLYDIR | libc::IN_DONT_FOLLOW);
+            if unsafe {
+                libc::inotify_add_watch(
+                    self.event_seq.fd(),
+                    ffi_path.as_ptr(),
+                    mask,
+                )
+            } < 0
+            {
+                warn!(
+                    "Failed to add events to the watch of {}: {}",
+                    path.display(),
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        if self.opts.symlink == Symlink::Follow || self.opts.dedupe_dirs {
+            if let Ok(metadata) = fs::metadata(path) {
+                self.dir_inodes.insert(wd, inode(&metadata));
+            }
+        }
+        Ok(wd)
+    }
+
+    /// Watches `path` and its subdirectories, which are returned.
+    fn add_watch_all(&mut self, path: &Path) -> (Option<i32>, Vec<PathBuf>) {
+        self.make_room(1);
+        self.add_watch_all_with(path, &mut |_| {})
+    }
+
+    fn add_watch_all_with(
+        &mut self,
+        path: &Path,
+        progress: &mut dyn FnMut(Progress),
+    ) -> (Option<i32>, Vec<PathBuf>) {
+        let follow = self.opts.symlink == Symlink::Follow;
+        let dedupe = follow || self.opts.dedupe_dirs;
+        // Following links, the same directory can be reached again.
+        let mut seen: HashSet<_> = self.dir_inodes.values().copied().collect();
+        if follow
+            && fs::metadata(path).is_ok_and(|v| seen.contains(&inode(&v)))
+        {
+            return (None, Vec::new());
+        }
+        let dev = self.top_dev();
+        if !on_dev(path, dev) {
+            return (None, Vec::new());
+        }
+
+        let top_wd = self.try_add_watch(path);
+        let mut scanned = 1;
+        progress(Progress { scanned, watched: self.path_tree.len() });
+        if top_wd.is_none() {
+            return (None, Vec::new());
+        }
+        let opts = self.opts.clone();
+        let depth = self.opts.lazy_depth;
+        if depth == Some(0) {
+            self.lazy_wds.extend(top_wd);
+        }
+        seen.extend(top_wd.and_then(|wd| self.dir_inodes.get(&wd)));
+        let seen = Some(seen).filter(|_| dedupe);
+        let (base, top_dir) = (path.to_owned(), self.top_dir.clone());
+        let subdirs = walk(path, follow, seen, move |path, file_type| {
+            guard(&opts, path, file_type)
+                && depth.is_none_or(|v| level(&base, path) <= v)
+                && opts.max_depth.is_none_or(|v| level(&top_dir, path) <= v)
+                && on_dev(path, dev)
+        })
+        .inspect(|subdir| {
+            let wd = self.try_add_watch(subdir);
+            if depth.is_some_and(|v| level(path, subdir) == v) {
+                self.lazy_wds.extend(wd);
+            }
+            scanned += 1;
+            progress(Progress { scanned, watched: self.path_tree.len() });
+        })
+        .collect();
+        self.update_tree_stats();
+
+        (top_wd, subdirs)
+    }
+
+    /// Watches the subdirectories of a directory left by `lazy_depth`,
+    /// except `skip`. They are left the same way in turn.
+    fn expand(&mut self, wd: i32, skip: Option<&Path>) {
+        let dir = self.path(wd);
+        let opts = self.opts.clone();
+        let follow = self.opts.symlink == Symlink::Follow;
+        let seen = Some(self.dir_inodes.values().copied().collect())
+            .filter(|_| follow || self.opts.dedupe_dirs);
+        let base = dir.to_owned();
+        let dev = self.top_dev();
+        let subdirs: Vec<_> =
+            walk(&dir, follow, seen, move |path, file_type| {
+                guard(&opts, path, file_type)
+                    && level(&base, path) <= 1
+                    && on_dev(path, dev)
+            })
+            .filter(|v| {
+                Some(v.as_path()) != skip && !self.path_tree.has_path(v)
+            })
+            .collect();
+        self.make_room(subdirs.len());
+        if !self.path_tree.has(wd) {
+            return;
+        }
+        for subdir in subdirs {
+            if let Some(wd) = self.try_add_watch(&subdir) {
+                self.lazy_wds.insert(wd);
+            }
+        }
+        self.update_tree_stats();
+        if let Err(e) = self.check_watch_limit() {
+            self.report(e);
+        }
+    }
+
+    /// Like `add_watch`, but failures are kept to be yielded as events.
+    /// Running out of inotify watches is also counted, to be reported by
+    /// `check_watch_limit`.
+    fn try_add_watch(&mut self, path: &Path) -> Option<i32> {
+        if self.opts.max_depth.is_some_and(|v| level(&self.top_dir, path) > v)
+        {
+            return None;
+        }
+        if self.is_full() {
+            let parent = path.parent().and_then(|v| self.path_tree.get(v));
+            self.lazy_wds.extend(parent);
+            return None;
+        }
+        match self.add_watch(path) {
+            Ok(wd) => Some(wd),
+            Err(Error::WatchSame { wd, path }) => {
+                self.add_alias(wd, path);
+                None
+            }
+            Err(e) => {
+                let (path, kind, errno) = match e.failed_watch() {
+                    Some((path, kind, errno)) => {
+                        (path.to_owned(), kind, errno)
+                    }
+                    None => {
+                        self.report(e);
+                        return None;
+                    }
+                };
+                if self.opts.retry_interval.is_some()
+                    && matches!(errno, Some(libc::EACCES) | Some(libc::ENOSPC))
+                    && !self.failed_dirs.insert(path.to_owned())
+                {
+                    // Failed again when retrying, which is already known.
+                    return None;
+                }
+                if errno == Some(libc::ENOSPC) {
+                    self.failed_watches += 1;
+                }
+                self.watch_errors.push((
+                    path,
+                    kind,
+                    time::OffsetDateTime::now_utc(),
+                ));
+                // Logged as the event.
+                self.send_error(e);
+                None
+            }
+        }
+    }
+
+    /// Shares the watch with another path to the same directory. Paths
+    /// below known aliases are covered by them.
+    fn add_alias(&mut self, wd: i32, path: PathBuf) {
+        let dir = self.path(wd);
+        if path == dir
+            || self.aliases.iter().any(|v| path.starts_with(&v.path))
+        {
+            return;
+        }
+        let inode = match fs::metadata(&path) {
+            Ok(v) => inode(&v),
+            Err(_) => return,
+        };
+        debug!("{} shares the watch of {}", path.display(), dir.display());
+        self.aliases.push(Alias { wd, path, inode });
+    }
+
+    /// Keeps copies of the event under the aliases of its directories, to
+    /// be yielded after it. The aliases are checked first if the event may
+    /// have moved or removed them.
+    fn copy_to_aliases(&mut self, event: &Event, t: time::OffsetDateTime) {
+        if self.moves_alias(event) {
+            self.check_aliases();
+        }
+        let copies: Vec<_> = self
+            .aliases
+            .iter()
+            .filter_map(|v| event.rebase(&self.path(v.wd), &v.path))
+            .collect();
+        self.alias_events.extend(copies.into_iter().map(|v| (v, t)));
+    }
+
+    /// Tells whether the event moves or removes the path of an alias, or a
+    /// directory above it.
+    fn moves_alias(&self, event: &Event) -> bool {
+        let path = match event {
+            Event::MoveTop(..)
+            | Event::DeleteTop(_)
+            | Event::ReplaceTop(_)
+            | Event::UnmountTop(_) => return true,
+            Event::Delete(path, _)
+            | Event::MoveAway(path, ..)
+            | Event::Move(path, ..)
+            | Event::Unmount(path, _) => path,
+            _ => return false,
+        };
+        self.aliases.iter().any(|v| v.path.starts_with(path))
+    }
+
+    /// Forgets the aliases which no longer lead to their directories.
+    fn check_aliases(&mut self) {
+        self.aliases.retain(|alias| {
+            let kept = fs::metadata(&alias.path)
+                .is_ok_and(|v| inode(&v) == alias.inode);
+            if !kept {
+                debug!("{} no longer shares a watch", alias.path.display());
+            }
+            kept
+        });
+    }
+
+    /// Gives the device of the top directory, with `one_file_system`.
+    fn top_dev(&self) -> Option<u64> {
+        if !self.opts.one_file_system {
+            return None;
+        }
+        fs::metadata(&self.top_dir).ok().map(|v| v.dev())
+    }
+
+    /// Tells whether the file is a link to a directory to be followed.
+    fn is_dir_link(&self, path: &Path) -> bool {
+        self.opts.symlink == Symlink::Follow
+            && guard(&self.opts, path, FileType::Dir)
+            && fs::metadata(path).is_ok_and(|v| v.is_dir())
+    }
+
+    fn retry_interval(&self) -> Option<Duration> {
+        let failed =
+            self.opts.retry_interval.filter(|_| !self.failed_dirs.is_empty());
+        let unmounted =
+            self.opts.rewatch_interval.filter(|_| !self.unmounted.is_empty());
+        let suspend = self.clocks.map(|_| SUSPEND_CHECK_INTERVAL);
+        failed.into_iter().chain(unmounted).chain(suspend).min()
+    }
+
+    /// Tries again to watch the directories which failed. Directories which
+    /// are gone, or are watched with their parents, are forgotten.
+    fn retry_failed_dirs(&mut self) {
+        let paths: Vec<_> = self.failed_dirs.iter().cloned().collect();
+        for path in paths {
+            self.retry_failed_dir(path);
+        }
+        if let Err(e) = self.check_watch_limit() {
+            self.report(e);
+        }
+    }
+
+    fn retry_failed_dir(&mut self, path: PathBuf) {
+        let parent_watched =
+            path.parent().is_some_and(|v| self.path_tree.has_path(v));
+        if !parent_watched || !path.is_dir() || self.path_tree.has_path(&path)
+        {
+            self.failed_dirs.remove(&path);
+            return;
+        }
+        if let (Some(_), _) = self.add_watch_all(&path) {
+            self.failed_dirs.remove(&path);
+            self.restored_dirs.push((path, time::OffsetDateTime::now_utc()));
+        }
+    }
+
+    /// Remembers the unmounted directory, if it is still there as the
+    /// directory under the mount.
+    fn note_unmounted(&mut self, path: &Path) {
+        if self.opts.rewatch_interval.is_none() {
+            return;
+        }
+        if let Ok(metadata) = fs::metadata(path) {
+            self.unmounted.push((path.to_owned(), metadata.dev()));
+        }
+    }
+
+    /// Watches again the unmounted directories where a file system is
+    /// mounted now. Directories whose parents are no longer watched are
+    /// forgotten.
+    fn rewatch_unmounted(&mut self) {
+        for (path, dev) in std::mem::take(&mut self.unmounted) {
+            if !path.parent().is_some_and(|v| self.path_tree.has_path(v)) {
+                continue;
+            }
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.dev() != dev => {}
+                _ => {
+                    self.unmounted.push((path, dev));
+                    continue;
+                }
+            }
+            // The directory under the mount may be watched, e.g. when it
+            // was created again.
+            if let Some(wd) = self.path_tree.get(&path) {
+                self.rm_watch_all(wd);
+            }
+            if let (Some(_), _) = self.add_watch_all(&path) {
+                self.remounted.push((path, time::OffsetDateTime::now_utc()));
+            }
+        }
+        if let Err(e) = self.check_watch_limit() {
+            self.report(e);
+        }
+    }
+
+    /// Rescans the directories if the system was suspended long enough
+    /// since the last check. The files are listed again if there were
+    /// events since the last list.
+    fn check_suspend(&mut self) {
+        let (last, min) = match (self.clocks, self.opts.suspend_threshold) {
+            (Some(last), Some(min)) => (last, min),
+            _ => return,
+        };
+        let now = Clocks::now();
+        self.clocks = Some(now);
+        let suspended = (now.boot - last.boot)
+            .saturating_sub(now.monotonic - last.monotonic);
+        if suspended >= min {
+            debug!("Suspended for {:?}, rescanning", suspended);
+            if let Some(old) = self.suspend_files.take() {
+                self.rescan(&old);
+            }
+        } else if !self.files_changed {
+            return;
+        }
+        self.files_changed = false;
+        self.suspend_files = Some(self.scan_files());
+    }
+
+    /// Unwatches directories which are gone and watches new ones, then
+    /// yields events for the differences of the files from the old ones.
+    fn rescan(&mut self, old: &Snapshot) {
+        let mut dirs: Vec<_> =
+            self.path_tree.values().map(|wd| (self.path(*wd), *wd)).collect();
+        dirs.sort();
+        for (path, wd) in &dirs {
+            if self.path_tree.has(*wd) && !path.is_dir() && *wd != self.top_wd
+            {
+                self.rm_watch_all(*wd);
+            }
+        }
+        for (dir, wd) in dirs {
+            if !self.path_tree.has(wd) || self.lazy_wds.contains(&wd) {
+                continue;
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.metadata().is_ok_and(|v| v.is_dir())
+                    && !self.path_tree.has_path(&path)
+                    && guard(&self.opts, &path, FileType::Dir)
+                {
+                    self.add_watch_all(&path);
+                }
+            }
+        }
+        if let Err(e) = self.check_watch_limit() {
+            self.report(e);
+        }
+        self.diff_files(old);
+    }
+
+    fn report(&self, e: Error) {
+        warn!("{}", e);
+        self.send_error(e);
+    }
+
+    fn send_error(&self, e: Error) {
+        if let Some(tx) = &self.errors_tx {
+            let _ = tx.send(e);
+        }
+    }
+
+    fn check_watch_limit(&mut self) -> Result<()> {
+        if self.failed_watches == 0 {
+            return Ok(());
+        }
+        let failed = std::mem::take(&mut self.failed_watches);
+        Err(Error::WatchLimitReached {
+            watched: self.path_tree.len(),
+            failed,
+            limit: inotify::max_user_watches(),
+        })
+    }
+
+    fn path(&self, wd: i32) -> PathBuf {
+        self.path_tree.path(wd)
+    }
+
+    fn full_path(&self, wd: i32, path: &Path) -> PathBuf {
+        self.path(wd).join(path)
+    }
+
+    fn update_path(&mut self, wd: i32, path: &Path) {
+        let replaced = self.path_tree.rename(wd, path).unwrap();
+        if !replaced.is_empty() {
+            self.drop_watches(&replaced);
+            let t = time::OffsetDateTime::now_utc();
+            self.stale_dirs.push((path.to_owned(), t));
+        }
+        self.update_tree_stats();
+    }
+
+    /// Removes the watches of the directory and its subdirectories, which
+    /// are returned.
+    fn rm_watch_all(&mut self, wd: i32) -> Vec<i32> {
+        let values = self.path_tree.delete(wd).unwrap();
+        self.drop_watches(&values);
+        self.update_tree_stats();
+        values
+    }
+
+    /// Forgets the watches, and removes them from the kernel.
+    fn drop_watches(&mut self, wds: &[i32]) {
+        self.aliases.retain(|v| !wds.contains(&v.wd));
+        for wd in wds {
+            self.dir_inodes.remove(wd);
+            self.lazy_wds.remove(wd);
+            if let Some(active_wds) = &mut self.active_wds {
+                active_wds.pop(wd);
+            }
+            if unsafe { libc::inotify_rm_watch(self.event_seq.fd(), *wd) } == 0
+            {
+                self.removed_wds.insert(*wd);
+            }
+        }
+    }
+
+    /// Handles a watch removed by the kernel. Unless watchdir removed it,
+    /// the directory is gone without an event processed to tell, and the
+    /// entries of it and its subdirectories are stale. Gives the path of
+    /// the directory if so.
+    fn purge(&mut self, wd: i32) -> Option<PathBuf> {
+        if self.removed_wds.remove(&wd)
+            || wd == self.top_wd
+            || !self.path_tree.has(wd)
+        {
+            return None;
+        }
+        let path = self.path(wd);
+        self.rm_watch_all(wd);
+        Some(path)
+    }
+
+    fn is_full(&self) -> bool {
+        self.opts.max_watches.is_some_and(|v| self.path_tree.len() >= v.get())
+    }
+
+    /// Marks the directory and the directories above it as active.
+    fn touch(&mut self, wd: i32) {
+        let active_wds = match &mut self.active_wds {
+            Some(v) => v,
+            None => return,
+        };
+        if !self.path_tree.has(wd) {
+            return;
+        }
+        for dir in self.path_tree.path(wd).ancestors() {
+            match self.path_tree.get(dir) {
+                Some(wd) => active_wds.promote(&wd),
+                None => break,
+            }
+        }
+    }
+
+    /// Unwatches the least recently active directories until `n` more
+    /// fit in `max_watches`. Their parents watch them again on demand.
+    fn make_room(&mut self, n: usize) {
+        let max = match self.opts.max_watches {
+            Some(v) => v.get(),
+            None => return,
+        };
+        let mut top_popped = false;
+        while self.path_tree.len() + n > max {
+            let wd = match self.active_wds.as_mut().and_then(|v| v.pop_lru()) {
+                Some((wd, _)) => wd,
+                None => break,
+            };
+            if wd == self.top_wd {
+                top_popped = true;
+                continue;
+            }
+            if !self.path_tree.has(wd) {
+                continue;
+            }
+            let path = self.path(wd);
+            let parent = path.parent().and_then(|v| self.path_tree.get(v));
+            self.lazy_wds.extend(parent);
+            let removed = self.rm_watch_all(wd);
+            self.evicted_wds.extend(removed);
+            self.unwatched.push((path, time::OffsetDateTime::now_utc()));
+        }
+        if let (true, Some(active_wds)) = (top_popped, &mut self.active_wds) {
+            active_wds.put(self.top_wd, ());
+        }
+    }
+
+    fn update_tree_stats(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.watches = self.path_tree.len();
+        stats.depth = self.path_tree.depth();
+    }
+
+    /// Watches renames in the parent of the top directory, to know the new
+    /// name of the top directory when it is renamed, or whether it is
+    /// replaced by a rename.
+    fn watch_parent(&self) -> Option<i32> {
+        let parent = self.parent_dir()?;
+        let ffi_path = CString::new(parent.as_os_str().as_bytes()).unwrap();
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                self.event_seq.fd(),
+                ffi_path.as_ptr(),
+                libc::IN_MOVE | libc::IN_ONLYDIR,
+            )
+        };
+        if wd < 0 {
+            warn!("Failed to watch the parent of the top directory");
+            return None;
+        }
+        Some(wd)
+    }
+
+    fn parent_dir(&self) -> Option<PathBuf> {
+        self.top_dir.file_name()?;
+        match self.top_dir.parent()? {
+            v if v.as_os_str().is_empty() => Some(PathBuf::from(".")),
+            v => Some(v.to_owned()),
+        }
+    }
+
+    /// Notes the new name of the top directory from the events of its
+    /// parent. Moves to other directories are not tracked.
+    fn track_top(&mut self, event: &inotify::Event) {
+        match &event.kind {
+            inotify::EventKind::MoveFrom(name, _)
+                if Some(name.as_os_str()) == self.top_dir.file_name() =>
+            {
+                self.top_cookie = Some(event.cookie);
+                self.top_replaced = false;
+                self.top_left = true;
+            }
+            inotify::EventKind::MoveTo(name, _)
+                if self.top_cookie == Some(event.cookie) =>
+            {
+                self.top_moved_to = self.parent_dir().map(|v| v.join(name));
+            }
+            inotify::EventKind::MoveTo(name, _)
+                if !self.top_left
+                    && Some(name.as_os_str()) == self.top_dir.file_name() =>
+            {
+                self.top_replaced = true;
+            }
+            inotify::EventKind::Ignored => self.parent_wd = None,
+            _ => {}
+        }
+    }
+
+    /// Drops the events left by the removed watches, whose paths are no
+    /// longer known.
+    async fn discard_queue(&mut self) {
+        self.cached_inotify_event = None;
+        while self.event_seq.has_next_event() {
+            self.read_inotify_event().await;
+        }
+        self.removed_wds.clear();
+    }
+
+    async fn next_inotify_event(&mut self) -> Option<inotify::Event> {
+        while self.event_seq.has_next_event() {
+            if let Some(event) = self.read_inotify_event().await {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    /// Like `read_inotify_event`, but answers a request or takes a hashed
+    /// file if one comes while waiting, and then gives `None`.
+    async fn read_or_answer(&mut self) -> Option<inotify::Event> {
+        loop {
+            let mut requests_rx = self.requests_rx.take();
+            let mut hashed_rx = self.hashed_rx.take();
+            let (request, hashed) = {
+                let read = self.read_inotify_event();
+                let request = async {
+                    match &mut requests_rx {
+                        Some(v) => v.recv().await,
+                        None => future::pending().await,
+                    }
+                };
+                let hashed = async {
+                    match &mut hashed_rx {
+                        Some(v) => v.recv().await,
+                        None => future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    Some(v) = request => (Some(v), None),
+                    Some(v) = hashed => (None, Some(v)),
+                    v = read => {
+                        self.requests_rx = requests_rx;
+                        self.hashed_rx = hashed_rx;
+                        return v;
+                    }
+                }
+            };
+            self.requests_rx = requests_rx;
+            self.hashed_rx = hashed_rx;
+            if let Some(request) = request {
+                self.answer(request);
+                return None;
+            }
+            if let Some(hashed) = hashed {
+                self.hashed.push(hashed);
+                return None;
+            }
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if self.paused.swap(paused, Ordering::Relaxed) == paused
+            || !self.opts.rescan_on_resume
+        {
+            return;
+        }
+        if paused {
+            self.paused_files = Some(self.scan_files());
+            return;
+        }
+        if let Some(old) = self.paused_files.take() {
+            self.diff_files(&old);
+        }
+    }
+
+    /// Yields events for the differences of the files in watched
+    /// directories from the old ones.
+    fn diff_files(&mut self, old: &Snapshot) {
+        let new = self.scan_files();
+        let t = time::OffsetDateTime::now_utc();
+        let modify = self.opts.event_types & libc::IN_MODIFY != 0;
+        for (path, (file_type, _)) in old {
+            if new.get(path).is_none_or(|(v, _)| v != file_type) {
+                self.rescanned
+                    .push((Event::Delete(path.to_owned(), *file_type), t));
+            }
+        }
+        for (path, (file_type, state)) in new {
+            match old.get(&path) {
+                Some((v, old_state)) if *v == file_type => {
+                    if modify
+                        && file_type == FileType::File
+                        && *old_state != state
+                    {
+                        self.rescanned
+                            .push((Event::Modify(path, file_type, None), t));
+                    }
+                }
+                _ => self.rescanned.push((Event::Create(path, file_type), t)),
+            }
+        }
+    }
+
+    /// Lists the files in watched directories, without following links.
+    fn scan_files(&self) -> Snapshot {
+        let mut files = BTreeMap::new();
+        for wd in self.path_tree.values() {
+            let entries = match fs::read_dir(self.path(*wd)) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                if let Ok(metadata) = entry.metadata() {
+                    let state = FileState::Metadata {
+                        len: metadata.len(),
+                        mtime: metadata.modified().ok(),
+                    };
+                    files.insert(
+                        entry.path(),
+                        (metadata.file_type().into(), state),
+                    );
+                }
+            }
+        }
+        files
+    }
+
+    fn answer(&mut self, request: Request) {
+        match request {
+            Request::Watches(tx) => {
+                // The reader may be gone.
+                let _ = tx.send(self.watched_paths());
+            }
+            Request::Pause(paused) => self.pause_request = Some(paused),
+            Request::Watch(path) => {
+                if path.starts_with(&self.top_dir)
+                    && !self.path_tree.has_path(&path)
+                    && path.is_dir()
+                {
+                    self.add_watch_all(&path);
+                    if let Err(e) = self.check_watch_limit() {
+                        self.report(e);
+                    }
+                }
+            }
+            Request::Unwatch(path) => {
+                if let Some(wd) = self.path_tree.get(&path) {
+                    if wd != self.top_wd {
+                        self.rm_watch_all(wd);
+                    }
+                }
+            }
+            Request::Regex { include, exclude } => {
+                self.opts.include_regex = include;
+                self.opts.exclude_regex = exclude;
+                *self.event_opts.lock().unwrap() = self.opts.clone();
+            }
+        }
+    }
+
+    /// Reads the next record from the kernel. Records which are not events
+    /// are counted in the statistics and give `None`. The watcher is closed
+    /// if the instance can not be read.
+    async fn read_inotify_event(&mut self) -> Option<inotify::Event> {
+        let record = {
+            let stream = self.event_seq.stream();
+            pin_mut!(stream);
+            stream.next().await?
+        };
+        match record {
+            Ok(event) if Some(event.wd) == self.parent_wd => {
+                self.track_top(&event);
+                None
+            }
+            Ok(event) => Some(event),
+            Err(inotify::Error::Overflow) => {
+                self.report(Error::Overflow);
+                self.stats.lock().unwrap().overflows += 1;
+                None
+            }
+            Err(inotify::Error::UnknownEvent) => {
+                self.stats.lock().unwrap().dropped += 1;
+                None
+            }
+            Err(e @ inotify::Error::Malformed { .. }) => {
+                self.report(Error::ParseEvent { reason: e.to_string() });
+                self.stats.lock().unwrap().dropped += 1;
+                None
+            }
+            Err(inotify::Error::Read { source }) => {
+                self.report(Error::ReadEvents { source });
+                self.closing = true;
+                None
+            }
+        }
+    }
+
+    pub fn has_next_event(&mut self) -> bool {
+        self.cached_inotify_event.is_some() | self.event_seq.has_next_event()
+    }
+
+    async fn recognize(
+        &mut self,
+        inotify_event: &inotify::Event,
+    ) -> (Event, Option<i32>) {
+        let wd = inotify_event.wd;
+
+        match &inotify_event.kind {
+            inotify::EventKind::Create(path, file_type) => {
+                let full_path = self.full_path(wd, path);
+                let event = match file_type {
+                    inotify::FileType::Dir => {
+                        Event::Create(full_path, FileType::Dir)
+                    }
+                    inotify::FileType::File => {
+                        Event::Create(full_path, FileType::File)
+                    }
+                };
+                (event, None)
+            }
+
+            inotify::EventKind::MoveFrom(from_path, file_type) => {
+                let full_from_path = self.full_path(wd, from_path);
+                let from_type = FileType::from(file_type);
+                let cookie = inotify_event.cookie;
+                if let Some(next_inotify_event) =
+                    self.next_inotify_event().await
+                {
+                    match next_inotify_event.kind {
+                        inotify::EventKind::MoveSelf
+                            if next_inotify_event.wd != self.top_wd =>
+                        {
+                            (
+                                Event::MoveAway(
+                                    full_from_path,
+                                    FileType::Dir,
+                                    cookie,
+                                ),
+                                Some(next_inotify_event.wd),
+                            )
+                        }
+                        inotify::EventKind::MoveTo(
+                            ref to_path,
+                            ref file_type,
+                        ) => {
+                            if cookie != next_inotify_event.cookie {
+                                self.cached_inotify_event =
+                                    Some(next_inotify_event);
+                                (
+                                    Event::MoveAway(
+                                        full_from_path,
+                                        from_type,
+                                        cookie,
+                                    ),
+                                    None,
+                                )
+                            } else {
+                                let full_to_path = self
+                                    .full_path(next_inotify_event.wd, to_path);
+                                if let Some(next2_inotify_event) =
+                                    self.next_inotify_event().await
+                                {
+                                    match next2_inotify_event.kind {
+                                        inotify::EventKind::MoveSelf => (
+                                            Event::Move(
+                                                full_from_path,
+                                                full_to_path,
+                                                FileType::Dir,
+                                            ),
+                                            Some(next2_inotify_event.wd),
+                                        ),
+                                        _ => {
+                                            self.cached_inotify_event =
+                                                Some(next2_inotify_event);
+                                            (
+                                                Event::Move(
+                                                    full_from_path,
+                                                    full_to_path,
+                                                    FileType::from(file_type),
+                                                ),
+                                                None,
+                                            )
+                                        }
+                                    }
+                                } else {
+                                    (
+                                        Event::Move(
+                                            full_from_path,
+                                            full_to_path,
+                                            FileType::from(file_type),
+                                        ),
+                                        None,
+                                    )
+                                }
+                            }
+                        }
+                        _ => {
+                            self.cached_inotify_event =
+                                Some(next_inotify_event);
+                            (
+                                Event::MoveAway(
+                                    full_from_path,
+                                    from_type,
+                                    cookie,
+                                ),
+                                None,
+                            )
+                        }
+                    }
+                } else {
+                    (Event::MoveAway(full_from_path, from_type, cookie), None)
+                }
+            }
+
+            inotify::EventKind::MoveTo(path, file_type) => {
+                let full_path = self.full_path(wd, path);
+                (
+                    Event::MoveInto(
+                        full_path,
+                        FileType::from(file_type),
+                        inotify_event.cookie,
+                    ),
+                    None,
+                )
+            }
+
+            inotify::EventKind::Delete(path, file_type) => {
+                let full_path = self.full_path(wd, path);
+                // Newer kernels report the deletion of a watched directory
+                // to itself before its parent, in which case it has been
+                // yielded already.
+                if self.deleted_dirs.remove(&full_path) {
+                    return (Event::Noise, None);
+                }
+                if let Some(next_inotify_event) =
+                    self.next_inotify_event().await
+                {
+                    match next_inotify_event.kind {
+                        inotify::EventKind::DeleteSelf
+                            if self.path_tree.has(next_inotify_event.wd)
+                                && self.path(next_inotify_event.wd)
+                                    == full_path =>
+                        {
+                            (
+                                Event::Delete(full_path, FileType::Dir),
+                                Some(next_inotify_event.wd),
+                            )
+                        }
+                        _ => {
+                            self.cached_inotify_event =
+                                Some(next_inotify_event);
+                            (
+                                Event::Delete(
+                                    full_path,
+                                    FileType::from(file_type),
+                                ),
+                                None,
+                            )
+                        }
+                    }
+                } else {
+                    (Event::Delete(full_path, FileType::from(file_type)), None)
+                }
+            }
+
+            inotify::EventKind::MoveSelf => {
+                let to = if wd == self.top_wd {
+                    self.top_cookie = None;
+                    self.top_replaced = false;
+                    let to = self.top_moved_to.take();
+                    // Renamed within the parent, so it is at its path.
+                    self.top_left = to.is_none();
+                    to
+                } else {
+                    None
+                };
+                (Event::MoveTop(self.top_dir.to_owned(), to), None)
+            }
+
+            inotify::EventKind::DeleteSelf => {
+                if wd == self.top_wd {
+                    if std::mem::take(&mut self.top_replaced) {
+                        (Event::ReplaceTop(self.top_dir.to_owned()), None)
+                    } else {
+                        (Event::DeleteTop(self.top_dir.to_owned()), None)
+                    }
+                } else if self.path_tree.has(wd) {
+                    let full_path = self.path(wd);
+                    self.deleted_dirs.insert(full_path.to_owned());
+                    (Event::Delete(full_path, FileType::Dir), Some(wd))
+                } else {
+                    (Event::Noise, None)
+                }
+            }
+
+            inotify::EventKind::Modify(path) => {
+                let full_path = self.full_path(wd, path);
+                (Event::Modify(full_path, FileType::File, None), None)
+            }
+            inotify::EventKind::Access(path, file_type) => match path {
+                Some(path) => {
+                    let full_path = self.full_path(wd, path);
+                    let event = match file_type {
+                        inotify::FileType::Dir => {
+                            Event::Access(full_path, FileType::Dir)
+                        }
+                        inotify::FileType::File => {
+                            Event::Access(full_path, FileType::File)
+                        }
+                    };
+                    (event, None)
+                }
+                None => {
+                    if wd == self.top_wd {
+                        (Event::AccessTop(self.top_dir.to_owned()), None)
+                    } else {
+                        (Event::Noise, None)
+                    }
+                }
+            },
+            inotify::EventKind::Attrib(path, file_type) => match path {
+                Some(path) => {
+                    let full_path = self.full_path(wd, path);
+                    let event = match file_type {
+                        inotify::FileType::Dir => {
+                            Event::Attrib(full_path, FileType::Dir)
+                        }
+                        inotify::FileType::File => {
+                            Event::Attrib(full_path, FileType::File)
+                        }
+                    };
+                    (event, None)
+                }
+                None => {
+                    if wd == self.top_wd {
+                        (Event::AttribTop(self.top_dir.to_owned()), None)
+                    } else {
+                        (Event::Noise, None)
+                    }
+                }
+            },
+            inotify::EventKind::Open(path, file_type) => match path {
+                Some(path) => {
+                    let full_path = self.full_path(wd, path);
+                    let event = match file_type {
+                        inotify::FileType::Dir => {
+                            Event::Open(full_path, FileType::Dir)
+                        }
+                        inotify::FileType::File => {
+                            Event::Open(full_path, FileType::File)
+                        }
+                    };
+                    (event, None)
+                }
+                None => {
+                    if wd == self.top_wd {
+                        (Event::OpenTop(self.top_dir.to_owned()), None)
+                    } else {
+                        (Event::Noise, None)
+                    }
+                }
+            },
+            inotify::EventKind::Close(path, file_type)
+            | inotify::EventKind::CloseWrite(path, file_type) => match path {
+                Some(path) => {
+                    let full_path = self.full_path(wd, path);
+                    let event = match file_type {
+                        inotify::FileType::Dir => {
+                            Event::Close(full_path, FileType::Dir)
+                        }
+                        inotify::FileType::File => {
+                            Event::Close(full_path, FileType::File)
+                        }
+                    };
+                    (event, None)
+                }
+                None => {
+                    if wd == self.top_wd {
+                        (Event::CloseTop(self.top_dir.to_owned()), None)
+                    } else {
+                        (Event::Noise, None)
+                    }
+                }
+            },
+
+            inotify::EventKind::Unmount => {
+                if inotify_event.wd == self.top_wd {
+                    (Event::UnmountTop(self.top_dir.to_owned()), None)
+                } else if !self.path_tree.has(wd) {
+                    // Unwatched with a directory above on the same mount.
+                    (Event::Noise, None)
+                } else {
+                    let full_path = self.path(wd);
+                    (Event::Unmount(full_path, FileType::Dir), None)
+                }
+            }
+
+            inotify::EventKind::Ignored => (Event::Ignored, None),
+            inotify::EventKind::Unknown => (Event::Unknown, None),
+        }
+    }
+}
+
+/// Tells whether the directory is on the device, if any is given.
+fn on_dev(path: &Path, dev: Option<u64>) -> bool {
+    dev.is_none_or(|dev| fs::metadata(path).is_ok_and(|v| v.dev() == dev))
+}
+
+/// Tells whether both paths are the same existing directory.
+fn is_same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => inode(&a) == inode(&b),
+        _ => false,
+    }
+}
+
+/// Gives how many levels the path is below the directory.
+fn level(dir: &Path, path: &Path) -> usize {
+    path.strip_prefix(dir).map_or(0, |v| v.components().count())
+}
+
+impl Drop for Watcher {
+    /// Removes all watches. The inotify instance is closed afterwards when
+    /// `event_seq` is dropped.
+    fn drop(&mut self) {
+        for wd in self.path_tree.values().chain(&self.parent_wd) {
+            unsafe { libc::inotify_rm_watch(self.event_seq.fd(), *wd) };
+        }
+    }
+}
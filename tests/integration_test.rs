@@ -1,16 +1,30 @@
 use std::{
+    ffi::{CString, OsStr},
     fs::{self, File},
-    path::PathBuf,
+    io::Write,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 use futures::{pin_mut, StreamExt};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use watchdir::*;
+use watchdir::{testing::TempTree, *};
 
 fn random_string(len: usize) -> String {
     thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
 }
 
+fn cookie(event: &Event) -> u32 {
+    match event {
+        Event::MoveAway(.., cookie) | Event::MoveInto(.., cookie) => *cookie,
+        _ => panic!("Not a move: {:?}", event),
+    }
+}
+
 #[tokio::test]
 async fn test_create_file() {
     let top_dir = tempfile::tempdir().unwrap();
@@ -105,7 +119,7 @@ async fn test_move_dir() {
     pin_mut!(stream);
 
     let new_dir = top_dir.path().join(random_string(5));
-    fs::rename(old_dir.to_owned(), new_dir.to_owned()).unwrap();
+    fs::rename(&old_dir, &new_dir).unwrap();
 
     assert_eq!(
         stream.next().await.unwrap().0,
@@ -128,7 +142,7 @@ async fn test_move_long_name_dir() {
     pin_mut!(stream);
 
     let new_dir = top_dir.path().join(random_string(180));
-    fs::rename(old_dir.to_owned(), new_dir.to_owned()).unwrap();
+    fs::rename(&old_dir, &new_dir).unwrap();
 
     assert_eq!(
         stream.next().await.unwrap().0,
@@ -153,7 +167,36 @@ async fn test_move_top_dir() {
 
     fs::rename(&top_dir, new_top_dir).unwrap();
 
-    assert_eq!(stream.next().await.unwrap().0, Event::MoveTop(top_dir))
+    assert_eq!(stream.next().await.unwrap().0, Event::MoveTop(top_dir, None))
+}
+
+#[tokio::test]
+async fn test_rename_top_dir() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let top_dir = temp_dir.path().join(random_string(5));
+    let new_top_dir = temp_dir.path().join(random_string(5));
+    fs::create_dir(&top_dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).watch_parent(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::rename(&top_dir, &new_top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::MoveTop(top_dir, Some(new_top_dir.to_owned()))
+    );
+
+    let path = new_top_dir.join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
 }
 
 #[tokio::test]
@@ -166,7 +209,7 @@ async fn test_create_in_moved_subdir() {
     for _ in 0..3 {
         sub_dirs.push(PathBuf::from(random_string(5)));
     }
-    fs::create_dir_all(&old_dir.join(sub_dirs.to_owned())).unwrap();
+    fs::create_dir_all(old_dir.join(&sub_dirs)).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
@@ -178,7 +221,7 @@ async fn test_create_in_moved_subdir() {
 
     let new_dir = top_dir.path().join(random_string(5));
 
-    fs::rename(old_dir.to_owned(), new_dir.to_owned()).unwrap();
+    fs::rename(&old_dir, &new_dir).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
         Event::Move(old_dir, new_dir.to_owned(), FileType::Dir)
@@ -203,7 +246,7 @@ async fn test_create_in_moved_dir_in_subdir() {
     for _ in 0..3 {
         sub_dirs.push(PathBuf::from(random_string(5)));
     }
-    fs::create_dir_all(&top_dir.path().join(sub_dirs.to_owned())).unwrap();
+    fs::create_dir_all(top_dir.path().join(&sub_dirs)).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
@@ -214,7 +257,7 @@ async fn test_create_in_moved_dir_in_subdir() {
     pin_mut!(stream);
 
     let new_dir = sub_dirs.to_owned().join(random_string(5));
-    fs::rename(old_dir.to_owned(), new_dir.to_owned()).unwrap();
+    fs::rename(&old_dir, &new_dir).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
         Event::Move(old_dir, new_dir.to_owned(), FileType::Dir)
@@ -243,7 +286,7 @@ async fn test_move_file() {
     pin_mut!(stream);
 
     let new_file = top_dir.path().join(random_string(5));
-    fs::rename(old_file.to_owned(), new_file.to_owned()).unwrap();
+    fs::rename(&old_file, &new_file).unwrap();
 
     assert_eq!(
         stream.next().await.unwrap().0,
@@ -267,12 +310,10 @@ async fn test_dir_move_away() {
     pin_mut!(stream);
 
     let new_dir = unwatched_dir.path().join(random_string(5));
-    fs::rename(old_dir.to_owned(), new_dir.to_owned()).unwrap();
+    fs::rename(&old_dir, &new_dir).unwrap();
 
-    assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::MoveAway(old_dir, FileType::Dir)
-    );
+    let (event, _) = stream.next().await.unwrap();
+    assert_eq!(event, Event::MoveAway(old_dir, FileType::Dir, cookie(&event)));
 
     let unwatched_file = new_dir.join(random_string(5));
     File::create(&unwatched_file).unwrap();
@@ -295,11 +336,12 @@ async fn test_file_move_away() {
     pin_mut!(stream);
 
     let new_file = unwatched_dir.path().join(random_string(5));
-    fs::rename(old_file.to_owned(), new_file).unwrap();
+    fs::rename(&old_file, new_file).unwrap();
 
+    let (event, _) = stream.next().await.unwrap();
     assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::MoveAway(old_file, FileType::File)
+        event,
+        Event::MoveAway(old_file, FileType::File, cookie(&event))
     );
 }
 
@@ -319,11 +361,12 @@ async fn test_dir_move_into() {
     pin_mut!(stream);
 
     let new_dir = top_dir.path().join(random_string(5));
-    fs::rename(old_dir, new_dir.to_owned()).unwrap();
+    fs::rename(old_dir, &new_dir).unwrap();
 
+    let (event, _) = stream.next().await.unwrap();
     assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::MoveInto(new_dir.to_owned(), FileType::Dir)
+        event,
+        Event::MoveInto(new_dir.to_owned(), FileType::Dir, cookie(&event))
     );
 
     let new_file = new_dir.join(random_string(5));
@@ -350,11 +393,12 @@ async fn test_file_move_into() {
     pin_mut!(stream);
 
     let new_file = top_dir.path().join(random_string(5));
-    fs::rename(old_file, new_file.to_owned()).unwrap();
+    fs::rename(old_file, &new_file).unwrap();
 
+    let (event, _) = stream.next().await.unwrap();
     assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::MoveInto(new_file, FileType::File)
+        event,
+        Event::MoveInto(new_file, FileType::File, cookie(&event))
     );
 }
 
@@ -367,7 +411,7 @@ async fn test_file_move_away_and_move_into() {
     File::create(&old_file).unwrap();
 
     let next_file_name = random_string(5);
-    let next_old_file = unwatched_dir.path().join(next_file_name.to_owned());
+    let next_old_file = unwatched_dir.path().join(&next_file_name);
     File::create(&next_old_file).unwrap();
 
     let mut watcher = Watcher::new(
@@ -379,18 +423,56 @@ async fn test_file_move_away_and_move_into() {
     pin_mut!(stream);
 
     let new_file = unwatched_dir.path().join(random_string(5));
-    fs::rename(old_file.to_owned(), new_file).unwrap();
+    fs::rename(&old_file, new_file).unwrap();
     let next_new_file = top_dir.path().join(next_file_name);
-    fs::rename(next_old_file, next_new_file.to_owned()).unwrap();
+    fs::rename(next_old_file, &next_new_file).unwrap();
 
+    let (event, _) = stream.next().await.unwrap();
     assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::MoveAway(old_file, FileType::File)
+        event,
+        Event::MoveAway(old_file, FileType::File, cookie(&event))
     );
+    let (event, _) = stream.next().await.unwrap();
     assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::MoveInto(next_new_file, FileType::File)
+        event,
+        Event::MoveInto(next_new_file, FileType::File, cookie(&event))
+    )
+}
+
+#[tokio::test]
+async fn test_move_between_watchers() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let other_top_dir = tempfile::tempdir().unwrap();
+    let old_file = top_dir.path().join(random_string(5));
+    File::create(&old_file).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let mut other_watcher = Watcher::new(
+        other_top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
     )
+    .unwrap();
+    let stream = watcher.stream();
+    let other_stream = other_watcher.stream();
+    pin_mut!(stream, other_stream);
+
+    let new_file = other_top_dir.path().join(random_string(5));
+    fs::rename(&old_file, &new_file).unwrap();
+
+    let (event, _) = stream.next().await.unwrap();
+    let (other_event, _) = other_stream.next().await.unwrap();
+    assert_eq!(
+        event,
+        Event::MoveAway(old_file, FileType::File, cookie(&event))
+    );
+    assert_eq!(
+        other_event,
+        Event::MoveInto(new_file, FileType::File, cookie(&event))
+    );
 }
 
 #[tokio::test]
@@ -437,6 +519,37 @@ async fn test_remove_dir() {
     )
 }
 
+#[tokio::test]
+async fn test_remove_dir_once() {
+    let top_dir = tempfile::tempdir().unwrap();
+
+    let dir = top_dir.path().join("dir");
+    fs::create_dir(&dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    // The deletion is reported to the directory and to its parent, in
+    // either order depending on the kernel.
+    fs::remove_dir(&dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Delete(dir, FileType::Dir)
+    );
+    assert_eq!(stream.next().await.unwrap().0, Event::Ignored);
+    let path = top_dir.path().join("file");
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    )
+}
+
 #[tokio::test]
 async fn test_remove_top_dir() {
     let top_dir = tempfile::tempdir().unwrap();
@@ -454,6 +567,139 @@ async fn test_remove_top_dir() {
     assert_eq!(stream.next().await.unwrap().0, Event::DeleteTop(top_dir))
 }
 
+#[tokio::test]
+async fn test_reattach_top_dir() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let top_dir = top_dir.path().to_owned();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).reattach_top(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::remove_dir(&top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::DeleteTop(top_dir.to_owned())
+    );
+
+    fs::create_dir(&top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Reattached(top_dir.to_owned())
+    );
+
+    let path = top_dir.join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_replace_top_dir() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let top_dir = temp_dir.path().join(random_string(5));
+    let other_dir = temp_dir.path().join(random_string(5));
+    fs::create_dir(&top_dir).unwrap();
+    fs::create_dir(&other_dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .reattach_top(true)
+            .watch_parent(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::rename(&other_dir, &top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::ReplaceTop(top_dir.to_owned())
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Reattached(top_dir.to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_replace_top_dir_after_move() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let top_dir = temp_dir.path().join(random_string(5));
+    let other_dir = temp_dir.path().join(random_string(5));
+    fs::create_dir(&top_dir).unwrap();
+    fs::create_dir(&other_dir).unwrap();
+    let away_dir = tempfile::tempdir().unwrap();
+    let moved_top_dir = away_dir.path().join(random_string(5));
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .reattach_top(true)
+            .watch_parent(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    // Moved onto the path after the top directory left, so it replaces
+    // nothing.
+    fs::rename(&top_dir, &moved_top_dir).unwrap();
+    fs::rename(&other_dir, &top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::MoveTop(top_dir.to_owned(), None)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Reattached(top_dir.to_owned())
+    );
+
+    fs::remove_dir(&top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::DeleteTop(top_dir.to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_delete_top_dir_after_move() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let top_dir = temp_dir.path().join(random_string(5));
+    let other_dir = temp_dir.path().join(random_string(5));
+    fs::create_dir(&top_dir).unwrap();
+    fs::create_dir(&other_dir).unwrap();
+    let away_dir = tempfile::tempdir().unwrap();
+    let moved_top_dir = away_dir.path().join(random_string(5));
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).watch_parent(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::rename(&top_dir, &moved_top_dir).unwrap();
+    fs::rename(&other_dir, &top_dir).unwrap();
+    fs::remove_dir(&moved_top_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::MoveTop(top_dir.to_owned(), None)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::DeleteTop(top_dir.to_owned())
+    );
+}
+
 #[tokio::test]
 async fn test_remove_dir_recursively() {
     let top_dir = tempfile::tempdir().unwrap();
@@ -508,360 +754,2193 @@ async fn test_modify_file() {
     fs::write(&file, "test").unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Modify(file, FileType::File)
+        Event::Modify(file, FileType::File, None)
     );
 }
 
 #[tokio::test]
-async fn test_open_file() {
+async fn test_mask_override() {
     let top_dir = tempfile::tempdir().unwrap();
+    let src_dir = top_dir.path().join("src");
+    fs::create_dir(&src_dir).unwrap();
     let file = top_dir.path().join(random_string(5));
+    let src_file = src_dir.join(random_string(5));
     File::create(&file).unwrap();
+    File::create(&src_file).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Open])),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).mask_override(
+            Regex::new(r"/src$").unwrap(),
+            Vec::from([ExtraEvent::Modify]),
+        ),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    fs::File::open(&file).unwrap();
-    assert_eq!(
-        stream.next().await.unwrap().0,
-        Event::OpenTop(top_dir.path().to_owned())
-    );
+    fs::write(&file, "test").unwrap();
+    fs::write(&src_file, "test").unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Open(file, FileType::File)
+        Event::Modify(src_file, FileType::File, None)
     );
 }
 
 #[tokio::test]
-async fn test_open_dir() {
+async fn test_lazy_depth() {
     let top_dir = tempfile::tempdir().unwrap();
-    let sub_dir = top_dir.path().join(random_string(5));
-    fs::create_dir(&sub_dir).unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    let sub_dir = dir.join(random_string(5));
+    fs::create_dir_all(&sub_dir).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Open])),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).lazy_depth(1),
     )
     .unwrap();
+    assert_eq!(watcher.stats().watches, 2);
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    fs::File::open(&sub_dir).unwrap();
+    File::create(sub_dir.join(random_string(5))).unwrap();
+    let path = dir.join(random_string(5));
+    File::create(&path).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::OpenTop(top_dir.path().to_owned())
+        Event::Create(path, FileType::File)
     );
+    let path = sub_dir.join(random_string(5));
+    File::create(&path).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Open(sub_dir, FileType::Dir)
+        Event::Create(path, FileType::File)
     );
 }
 
 #[tokio::test]
-async fn test_close_file() {
+async fn test_max_depth() {
     let top_dir = tempfile::tempdir().unwrap();
-    let file = top_dir.path().join(random_string(5));
-    File::create(&file).unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&dir).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Close])),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).max_depth(0),
     )
     .unwrap();
+    let stats = watcher.stats_reader();
+    assert_eq!(stats.read().watches, 1);
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    fs::File::open(&file).unwrap();
-
+    // Events in the subdirectory do not make it watched.
+    File::create(dir.join(random_string(5))).unwrap();
+    let new_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&new_dir).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::CloseTop(top_dir.path().to_owned())
+        Event::Create(new_dir.to_owned(), FileType::Dir)
     );
+    File::create(new_dir.join(random_string(5))).unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Close(file, FileType::File)
+        Event::Create(path, FileType::File)
     );
+    assert_eq!(stats.read().watches, 1);
 }
 
 #[tokio::test]
-async fn test_close_dir() {
+async fn test_max_watches() {
     let top_dir = tempfile::tempdir().unwrap();
-    let sub_dir = top_dir.path().join(random_string(5));
-    fs::create_dir(&sub_dir).unwrap();
+    let old_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&old_dir).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Close])),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).max_watches(2),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
+    let new_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&new_dir).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Close(sub_dir, FileType::Dir)
+        Event::Create(new_dir.to_owned(), FileType::Dir)
     );
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::CloseTop(top_dir.path().to_owned())
+        Event::Unwatched(old_dir.to_owned())
+    );
+
+    File::create(old_dir.join(random_string(5))).unwrap();
+    let path = new_dir.join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
     );
 }
 
 #[tokio::test]
-async fn test_access_file() {
+async fn test_excl_unlink() {
     let top_dir = tempfile::tempdir().unwrap();
-    let file = top_dir.path().join(random_string(5));
-    fs::write(&file, "test").unwrap();
+    let path = top_dir.path().join(random_string(5));
+    let mut file = File::create(&path).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Access])),
+        WatcherOpts::new(Dotdir::Exclude, vec![ExtraEvent::Modify])
+            .excl_unlink(true),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    fs::read(&file).unwrap();
-
+    fs::remove_file(&path).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::AccessTop(top_dir.path().to_owned())
+        Event::Delete(path, FileType::File)
     );
+    file.write_all(b"foo").unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Access(file, FileType::File)
+        Event::Create(path, FileType::File)
     );
 }
 
 #[tokio::test]
-async fn test_access_dir() {
+async fn test_hash_on_modify() {
     let top_dir = tempfile::tempdir().unwrap();
-    let sub_dir = top_dir.path().join(random_string(5));
-    fs::create_dir(&sub_dir).unwrap();
+    let file = top_dir.path().join(random_string(5));
+    let big_file = top_dir.path().join(random_string(5));
+    File::create(&file).unwrap();
+    File::create(&big_file).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Access])),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Modify]))
+            .hash_on_modify(HashAlgo::Sha256)
+            .hash_size_limit(4),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
+    fs::write(&file, "test").unwrap();
+    let digest = Digest::parse(
+        "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+    );
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::AccessTop(top_dir.path().to_owned())
+        Event::Modify(file, FileType::File, digest)
     );
+
+    fs::write(&big_file, "tests").unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Access(sub_dir, FileType::Dir)
+        Event::Modify(big_file, FileType::File, None)
     );
 }
 
 #[tokio::test]
-async fn test_attrib_file() {
+async fn test_hash_on_close_write() {
     let top_dir = tempfile::tempdir().unwrap();
     let file = top_dir.path().join(random_string(5));
-    File::create(&file).unwrap();
+    let other = top_dir.path().join(random_string(5));
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Attrib])),
+        WatcherOpts::new(Dotdir::Exclude, vec![ExtraEvent::Modify])
+            .hash_on_modify(HashAlgo::Sha256),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    let mut perms = fs::metadata(&file).unwrap().permissions();
-    perms.set_readonly(true);
-    fs::set_permissions(&file, perms).unwrap();
-
+    let mut f = File::create(&file).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Attrib(file, FileType::File)
+        Event::Create(file.to_owned(), FileType::File)
+    );
+
+    // Held until the file is closed.
+    f.write_all(b"test").unwrap();
+    File::create(&other).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(other, FileType::File)
+    );
+
+    // Closes of files are not yielded without being asked for.
+    drop(f);
+    let digest = Digest::parse(
+        "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Modify(file, FileType::File, digest)
     );
 }
 
 #[tokio::test]
-async fn test_attrib_dir() {
+async fn test_dedupe_modify() {
     let top_dir = tempfile::tempdir().unwrap();
-    let sub_dir = top_dir.path().join(random_string(5));
-    fs::create_dir(&sub_dir).unwrap();
+    let file = top_dir.path().join(random_string(5));
+    File::create(&file).unwrap();
+    let overwrite = |content: &[u8]| {
+        let mut f = fs::OpenOptions::new().write(true).open(&file).unwrap();
+        f.write_all(content).unwrap();
+    };
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Attrib])),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Modify]))
+            .hash_on_modify(HashAlgo::Sha256)
+            .dedupe_modify(16),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    let mut perms = fs::metadata(&sub_dir).unwrap().permissions();
-    perms.set_readonly(true);
-    fs::set_permissions(&sub_dir, perms).unwrap();
-
+    overwrite(b"test");
+    let digest = Digest::parse(
+        "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+    );
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Attrib(sub_dir, FileType::Dir)
+        Event::Modify(file.to_owned(), FileType::File, digest)
     );
 
-    let mut perms = fs::metadata(&top_dir).unwrap().permissions();
-    perms.set_readonly(true);
-    fs::set_permissions(&top_dir, perms).unwrap();
+    // The suppressed event can not be awaited, so the create after it tells
+    // that it has been handled. Inotify also merges an event into the same
+    // last unread one, so the next modify is kept apart from it.
+    overwrite(b"test");
+    let other = top_dir.path().join(random_string(5));
+    File::create(&other).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(other, FileType::File)
+    );
 
+    overwrite(b"best");
+    let digest = Digest::parse(
+        "sha256:8f4375ec65c2c4092a1cc21e76712472eae0de999384e8327c495957121db9c2",
+    );
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::AttribTop(top_dir.path().to_owned())
+        Event::Modify(file.to_owned(), FileType::File, digest)
     );
 }
 
 #[tokio::test]
-async fn test_include_hidden_dir() {
-    let tempdir = tempfile::tempdir().unwrap();
-    let dotdir = tempdir.as_ref().join(".dotdir");
-    fs::create_dir(&dotdir).unwrap();
+async fn test_dedupe_modify_metadata() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let file = top_dir.path().join(random_string(5));
+    File::create(&file).unwrap();
+    // The mtime is set back, so only the size tells changes apart.
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1);
+    let overwrite = |content: &[u8]| {
+        let mut f = fs::OpenOptions::new().write(true).open(&file).unwrap();
+        f.write_all(content).unwrap();
+        f.set_modified(mtime).unwrap();
+    };
 
     let mut watcher = Watcher::new(
-        tempdir.as_ref(),
-        WatcherOpts::new(Dotdir::Include, Vec::new()),
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Modify]))
+            .dedupe_modify(16),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    let file = dotdir.join(random_string(5));
-    File::create(&file).unwrap();
+    // The metadata is read when the event is handled, so after the mtime is
+    // set back.
+    overwrite(b"test");
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Create(file, FileType::File)
+        Event::Modify(file.to_owned(), FileType::File, None)
+    );
+
+    // Same size and mtime, though the content differs.
+    overwrite(b"best");
+    let other = top_dir.path().join(random_string(5));
+    File::create(&other).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(other, FileType::File)
+    );
+
+    overwrite(b"tests");
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Modify(file.to_owned(), FileType::File, None)
     );
 }
 
 #[tokio::test]
-async fn test_exclude_hidden_dir() {
-    let tempdir = tempfile::tempdir().unwrap();
-    let dotdir = tempdir.as_ref().join(".dotdir");
-    fs::create_dir(&dotdir).unwrap();
+async fn test_open_file() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let file = top_dir.path().join(random_string(5));
+    File::create(&file).unwrap();
 
     let mut watcher = Watcher::new(
-        tempdir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Open])),
     )
     .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
 
-    let file = dotdir.join(random_string(5));
-    File::create(&file).unwrap();
-    assert!(!watcher.has_next_event());
+    fs::File::open(&file).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::OpenTop(top_dir.path().to_owned())
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Open(file, FileType::File)
+    );
 }
 
 #[tokio::test]
-async fn test_exclude_new_hidden_dir() {
-    let tempdir = tempfile::tempdir().unwrap();
+async fn test_open_dir() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let sub_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&sub_dir).unwrap();
 
     let mut watcher = Watcher::new(
-        tempdir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Open])),
     )
     .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
 
-    let dotdir = tempdir.as_ref().join(".dotdir");
-    fs::create_dir(&dotdir).unwrap();
-    {
-        let stream = watcher.stream();
-        pin_mut!(stream);
-        assert_eq!(
-            stream.next().await.unwrap().0,
-            Event::Create(dotdir.to_owned(), FileType::Dir)
-        );
-    }
+    fs::File::open(&sub_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::OpenTop(top_dir.path().to_owned())
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Open(sub_dir, FileType::Dir)
+    );
+}
 
-    let file = dotdir.join(random_string(5));
+#[tokio::test]
+async fn test_close_file() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let file = top_dir.path().join(random_string(5));
     File::create(&file).unwrap();
-    assert!(!watcher.has_next_event());
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Close])),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::File::open(&file).unwrap();
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::CloseTop(top_dir.path().to_owned())
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Close(file, FileType::File)
+    );
 }
 
 #[tokio::test]
-async fn test_unwatch_moved_hidden_dir() {
-    let tempdir = tempfile::tempdir().unwrap();
+async fn test_close_dir() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let sub_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&sub_dir).unwrap();
 
-    let dir = tempdir.as_ref().join("dir");
-    fs::create_dir(&dir).unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Close])),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Close(sub_dir, FileType::Dir)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::CloseTop(top_dir.path().to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_close_write() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let file = top_dir.path().join(random_string(5));
+    File::create(&file).unwrap();
 
     let mut watcher = Watcher::new(
-        tempdir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, vec![ExtraEvent::CloseWrite]),
     )
     .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
 
-    let dotdir = tempdir.as_ref().join(".dotdir");
-    fs::rename(&dir, &dotdir).unwrap();
-    {
-        let stream = watcher.stream();
-        pin_mut!(stream);
+    // Closed without writing, which is not given.
+    fs::File::open(&file).unwrap();
+    fs::write(&file, "test").unwrap();
 
-        assert_eq!(
-            stream.next().await.unwrap().0,
-            Event::Move(dir.to_owned(), dotdir.to_owned(), FileType::Dir)
-        );
-        let file = dotdir.join(random_string(5));
-        File::create(&file).unwrap();
-        assert_eq!(stream.next().await.unwrap().0, Event::Ignored);
-    }
-    assert!(!watcher.has_next_event());
+    let event = stream.next().await.unwrap().0;
+    assert_eq!(event, Event::Close(file, FileType::File));
+    assert!(ExtraEvent::allows(&[ExtraEvent::Close], &event));
+    assert!(!ExtraEvent::allows(&[ExtraEvent::Modify], &event));
+    assert!(ExtraEvent::allows(
+        &[],
+        &Event::Create(top_dir.path().to_owned(), FileType::File)
+    ));
 }
 
 #[tokio::test]
-async fn test_rewatch_moved_hidden_dir() {
-    let tempdir = tempfile::tempdir().unwrap();
-
-    let dotdir = tempdir.as_ref().join(".dotdir");
-    fs::create_dir(&dotdir).unwrap();
+async fn test_access_file() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let file = top_dir.path().join(random_string(5));
+    fs::write(&file, "test").unwrap();
 
     let mut watcher = Watcher::new(
-        tempdir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Access])),
     )
     .unwrap();
-
-    let dir = tempdir.as_ref().join("dir");
-    fs::rename(&dotdir, &dir).unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
+    fs::read(&file).unwrap();
+
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Move(dotdir.to_owned(), dir.to_owned(), FileType::Dir)
+        Event::AccessTop(top_dir.path().to_owned())
     );
-    let file = dir.join(random_string(5));
-    File::create(&file).unwrap();
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Create(file, FileType::File)
+        Event::Access(file, FileType::File)
     );
 }
 
 #[tokio::test]
-async fn test_must_include_hidden_top_dir() {
-    let tempdir = tempfile::tempdir().unwrap();
-    let top_dir = tempdir.as_ref().join(".dotdir");
-    fs::create_dir(&top_dir).unwrap();
+async fn test_access_dir() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let sub_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&sub_dir).unwrap();
 
     let mut watcher = Watcher::new(
         top_dir.as_ref(),
-        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Access])),
     )
     .unwrap();
     let stream = watcher.stream();
     pin_mut!(stream);
 
-    let file = top_dir.join(random_string(5));
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::AccessTop(top_dir.path().to_owned())
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Access(sub_dir, FileType::Dir)
+    );
+}
+
+#[tokio::test]
+async fn test_attrib_file() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let file = top_dir.path().join(random_string(5));
     File::create(&file).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Attrib])),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let mut perms = fs::metadata(&file).unwrap().permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(&file, perms).unwrap();
+
     assert_eq!(
         stream.next().await.unwrap().0,
-        Event::Create(file, FileType::File)
+        Event::Attrib(file, FileType::File)
     );
 }
+
+#[tokio::test]
+async fn test_attrib_dir() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let sub_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&sub_dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::from([ExtraEvent::Attrib])),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let mut perms = fs::metadata(&sub_dir).unwrap().permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(&sub_dir, perms).unwrap();
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Attrib(sub_dir, FileType::Dir)
+    );
+
+    let mut perms = fs::metadata(&top_dir).unwrap().permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(&top_dir, perms).unwrap();
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::AttribTop(top_dir.path().to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_regex_filter() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .include_regex(Regex::new(r"\.log$").unwrap())
+            .exclude_regex(Regex::new(r"/skip[^/]*$").unwrap()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    File::create(top_dir.path().join("a.txt")).unwrap();
+    File::create(top_dir.path().join("skip.log")).unwrap();
+    let path = top_dir.path().join("a.log");
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_file_type_filter() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .file_types(vec![FileType::File]),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::create_dir(top_dir.path().join(random_string(5))).unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_filter_fn() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let skipped_dir = top_dir.path().join("skipped");
+    fs::create_dir(&skipped_dir).unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .filter_fn(|path, _| !path.ends_with("skipped")),
+    )
+    .unwrap();
+    assert_eq!(watcher.stats().watches, 1);
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    File::create(skipped_dir.join(random_string(5))).unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_follow_symlink() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let target_dir = tempfile::tempdir().unwrap();
+    let link = top_dir.path().join(random_string(5));
+    std::os::unix::fs::symlink(target_dir.path(), &link).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).symlink(Symlink::Follow),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let name = random_string(5);
+    File::create(target_dir.path().join(&name)).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(link.join(name), FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_follow_new_symlink() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let target_dir = tempfile::tempdir().unwrap();
+    // A loop of links is watched only once.
+    std::os::unix::fs::symlink(top_dir.path(), top_dir.path().join("loop"))
+        .unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).symlink(Symlink::Follow),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let link = top_dir.path().join(random_string(5));
+    std::os::unix::fs::symlink(target_dir.path(), &link).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(link.to_owned(), FileType::File)
+    );
+
+    let name = random_string(5);
+    File::create(target_dir.path().join(&name)).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(link.join(name), FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_watch_removed_by_rename() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    let other_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&dir).unwrap();
+    fs::create_dir(&other_dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    // The empty directory is replaced without an event of its own.
+    fs::rename(&other_dir, &dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Move(other_dir, dir.to_owned(), FileType::Dir)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::WatchRemoved(dir.to_owned())
+    );
+
+    let name = random_string(5);
+    File::create(dir.join(&name)).unwrap();
+    let path = dir.join(name);
+    loop {
+        match stream.next().await.unwrap().0 {
+            Event::Ignored => continue,
+            event => {
+                assert_eq!(event, Event::Create(path, FileType::File));
+                break;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_watch_removed_by_reused_wd() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    // The new parent is watched with the directory in it before the move
+    // is read, so the kernel gives the descriptor of the directory again.
+    let parent = top_dir.path().join(random_string(5));
+    fs::create_dir(&parent).unwrap();
+    let new_dir = parent.join(random_string(5));
+    fs::rename(&dir, &new_dir).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(parent, FileType::Dir)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(new_dir.to_owned(), FileType::Dir)
+    );
+    assert_eq!(stream.next().await.unwrap().0, Event::WatchRemoved(dir));
+
+    let name = random_string(5);
+    File::create(new_dir.join(&name)).unwrap();
+    let path = new_dir.join(name);
+    loop {
+        match stream.next().await.unwrap().0 {
+            Event::MoveAway(..) => continue,
+            event => {
+                assert_eq!(event, Event::Create(path, FileType::File));
+                break;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_include_hidden_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dotdir = tempdir.as_ref().join(".dotdir");
+    fs::create_dir(&dotdir).unwrap();
+
+    let mut watcher = Watcher::new(
+        tempdir.as_ref(),
+        WatcherOpts::new(Dotdir::Include, Vec::new()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let file = dotdir.join(random_string(5));
+    File::create(&file).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(file, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_exclude_hidden_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dotdir = tempdir.as_ref().join(".dotdir");
+    fs::create_dir(&dotdir).unwrap();
+
+    let mut watcher = Watcher::new(
+        tempdir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+
+    let file = dotdir.join(random_string(5));
+    File::create(&file).unwrap();
+    assert!(!watcher.has_next_event());
+}
+
+#[tokio::test]
+async fn test_exclude_new_hidden_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+
+    let mut watcher = Watcher::new(
+        tempdir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+
+    let dotdir = tempdir.as_ref().join(".dotdir");
+    fs::create_dir(&dotdir).unwrap();
+    {
+        let stream = watcher.stream();
+        pin_mut!(stream);
+        assert_eq!(
+            stream.next().await.unwrap().0,
+            Event::Create(dotdir.to_owned(), FileType::Dir)
+        );
+    }
+
+    let file = dotdir.join(random_string(5));
+    File::create(&file).unwrap();
+    assert!(!watcher.has_next_event());
+}
+
+#[tokio::test]
+async fn test_unwatch_moved_hidden_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+
+    let dir = tempdir.as_ref().join("dir");
+    fs::create_dir(&dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        tempdir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+
+    let dotdir = tempdir.as_ref().join(".dotdir");
+    fs::rename(&dir, &dotdir).unwrap();
+    {
+        let stream = watcher.stream();
+        pin_mut!(stream);
+
+        assert_eq!(
+            stream.next().await.unwrap().0,
+            Event::Move(dir.to_owned(), dotdir.to_owned(), FileType::Dir)
+        );
+        let file = dotdir.join(random_string(5));
+        File::create(&file).unwrap();
+        assert_eq!(stream.next().await.unwrap().0, Event::Ignored);
+    }
+    assert!(!watcher.has_next_event());
+}
+
+#[tokio::test]
+async fn test_rewatch_moved_hidden_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+
+    let dotdir = tempdir.as_ref().join(".dotdir");
+    fs::create_dir(&dotdir).unwrap();
+
+    let mut watcher = Watcher::new(
+        tempdir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+
+    let dir = tempdir.as_ref().join("dir");
+    fs::rename(&dotdir, &dir).unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Move(dotdir.to_owned(), dir.to_owned(), FileType::Dir)
+    );
+    let file = dir.join(random_string(5));
+    File::create(&file).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(file, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_exclude_hidden_files() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .hidden_prefixes(vec![".".into(), "_".into()])
+            .exclude_hidden_files(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    File::create(top_dir.path().join(".hidden")).unwrap();
+    File::create(top_dir.path().join("_hidden")).unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_watch_error() {
+    let top_dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(&top_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let locked_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&locked_dir).unwrap();
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o000))
+        .unwrap();
+
+    let user = OtherUser::new();
+    let watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    );
+    drop(user);
+    let mut watcher = watcher.unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::WatchError(
+            locked_dir.to_owned(),
+            std::io::ErrorKind::PermissionDenied
+        )
+    );
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o755))
+        .unwrap();
+}
+
+/// Makes this thread access files as another user while it lives, since
+/// root reads any directory otherwise.
+struct OtherUser {
+    is_root: bool,
+}
+
+impl OtherUser {
+    fn new() -> Self {
+        let is_root = unsafe { libc::geteuid() } == 0;
+        if is_root {
+            unsafe { libc::setfsuid(65534) };
+        }
+        Self { is_root }
+    }
+}
+
+impl Drop for OtherUser {
+    fn drop(&mut self) {
+        if self.is_root {
+            unsafe { libc::setfsuid(0) };
+        }
+    }
+}
+
+/// Runs the test again in a child process, in a new user namespace where
+/// at most `limit` inotify watches can be added. Tells whether this is the
+/// child, which goes on with the test.
+fn with_watch_limit(test: &str, limit: usize) -> bool {
+    if std::env::var_os("WATCHDIR_TEST_NAMESPACE").is_some() {
+        fs::write("/proc/sys/user/max_inotify_watches", limit.to_string())
+            .unwrap();
+        return true;
+    }
+    in_namespace(test, 0)
+}
+
+/// Runs the test again in a new user namespace, unshared with `flags` as
+/// well, and tells whether this is that run.
+fn in_namespace(test: &str, flags: libc::c_int) -> bool {
+    use std::os::unix::process::CommandExt;
+
+    if std::env::var_os("WATCHDIR_TEST_NAMESPACE").is_some() {
+        return true;
+    }
+    let mut command =
+        std::process::Command::new(std::env::current_exe().unwrap());
+    command
+        .args([test, "--exact", "--test-threads=1"])
+        .env("WATCHDIR_TEST_NAMESPACE", "1");
+    unsafe {
+        command.pre_exec(move || {
+            let (uid, gid) = (libc::geteuid(), libc::getegid());
+            if libc::unshare(libc::CLONE_NEWUSER | flags) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+            fs::write("/proc/self/setgroups", "deny")?;
+            fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+            Ok(())
+        })
+    };
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    false
+}
+
+#[tokio::test]
+async fn test_alias_events() {
+    if !in_namespace("test_alias_events", libc::CLONE_NEWNS) {
+        return;
+    }
+    let top_dir = tempfile::tempdir().unwrap();
+    let other_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&dir).unwrap();
+    // The bind mount is moved in later, to be found after the directory.
+    let name = random_string(5);
+    let mount_point = other_dir.path().join(&name).join(random_string(5));
+    fs::create_dir_all(&mount_point).unwrap();
+    let (source, target) = (
+        CString::new(dir.as_os_str().as_bytes()).unwrap(),
+        CString::new(mount_point.as_os_str().as_bytes()).unwrap(),
+    );
+    let res = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND,
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .dedupe_dirs(false)
+            .alias_events(true),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let parent = top_dir.path().join(&name);
+    fs::rename(other_dir.path().join(&name), &parent).unwrap();
+    let event = stream.next().await.unwrap().0;
+    assert_eq!(
+        event,
+        Event::MoveInto(parent.to_owned(), FileType::Dir, cookie(&event))
+    );
+    let alias = parent.join(mount_point.file_name().unwrap());
+    let file_name = random_string(5);
+    File::create(dir.join(&file_name)).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(dir.join(&file_name), FileType::File)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(alias.join(&file_name), FileType::File)
+    );
+
+    // The alias is forgotten once it is moved.
+    let new_parent = top_dir.path().join(random_string(5));
+    fs::rename(&parent, &new_parent).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Move(parent, new_parent, FileType::Dir)
+    );
+    let file_name = random_string(5);
+    File::create(dir.join(&file_name)).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(dir.join(&file_name), FileType::File)
+    );
+    let timeout = std::time::Duration::from_millis(100);
+    assert!(tokio::time::timeout(timeout, stream.next()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_watch_limit_reached() {
+    if !with_watch_limit("test_watch_limit_reached", 3) {
+        return;
+    }
+    let top_dir = tempfile::tempdir().unwrap();
+    for _ in 0..5 {
+        fs::create_dir(top_dir.path().join(random_string(5))).unwrap();
+    }
+
+    match Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    ) {
+        Err(Error::WatchLimitReached { watched, failed, limit }) => {
+            assert_eq!((watched, failed, limit), (3, 3, Some(3)));
+        }
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Watched beyond the limit"),
+    }
+}
+
+#[tokio::test]
+async fn test_watch_limit() {
+    if !with_watch_limit("test_watch_limit", 1) {
+        return;
+    }
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let errors = watcher.errors();
+    let stream = watcher.stream();
+    pin_mut!(errors, stream);
+
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&dir).unwrap();
+    stream.next().await.unwrap();
+
+    // Failures of directories come before the sum of them.
+    let e = errors.next().await.unwrap();
+    assert!(e.is_watch_limit());
+    match e {
+        Error::WatchLimit { errno, path } => {
+            assert_eq!((errno, path), (libc::ENOSPC, dir));
+        }
+        e => panic!("Unexpected error: {}", e),
+    }
+    let e = errors.next().await.unwrap();
+    assert!(e.is_watch_limit());
+    match e {
+        Error::WatchLimitReached { watched, failed, limit } => {
+            assert_eq!((watched, failed, limit), (1, 1, Some(1)));
+        }
+        e => panic!("Unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_errors() {
+    let top_dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(&top_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let errors = watcher.errors();
+    let stream = watcher.stream();
+    pin_mut!(errors, stream);
+
+    let locked_dir = top_dir.path().join(random_string(5));
+    fs::DirBuilder::new().mode(0o000).create(&locked_dir).unwrap();
+    // The watcher runs on this thread.
+    let user = OtherUser::new();
+    stream.next().await.unwrap();
+    drop(user);
+
+    match errors.next().await.unwrap() {
+        Error::PermissionDenied { errno, path } => {
+            assert_eq!(path, locked_dir);
+            assert_eq!(errno, libc::EACCES);
+        }
+        e => panic!("Unexpected error: {}", e),
+    }
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o755))
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_retry_failed() {
+    let top_dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(&top_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let locked_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&locked_dir).unwrap();
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o000))
+        .unwrap();
+
+    let user = OtherUser::new();
+    let watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .retry_failed(std::time::Duration::from_millis(50)),
+    );
+    drop(user);
+    let mut watcher = watcher.unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::WatchError(
+            locked_dir.to_owned(),
+            std::io::ErrorKind::PermissionDenied
+        )
+    );
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o755))
+        .unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Watching(locked_dir.to_owned())
+    );
+
+    let path = locked_dir.join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_retry_on_attrib() {
+    let top_dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(&top_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let locked_dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&locked_dir).unwrap();
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o000))
+        .unwrap();
+
+    let user = OtherUser::new();
+    let watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            // Longer than the test, so only the change of permissions
+            // retries.
+            .retry_failed(std::time::Duration::from_secs(60)),
+    );
+    drop(user);
+    let mut watcher = watcher.unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::WatchError(
+            locked_dir.to_owned(),
+            std::io::ErrorKind::PermissionDenied
+        )
+    );
+    fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o755))
+        .unwrap();
+    // The attrib event itself is not asked for.
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Watching(locked_dir.to_owned())
+    );
+
+    let path = locked_dir.join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_must_include_hidden_top_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let top_dir = tempdir.as_ref().join(".dotdir");
+    fs::create_dir(&top_dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let file = top_dir.join(random_string(5));
+    File::create(&file).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(file, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_init_async_with_progress() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir_all(dir.join(random_string(5))).unwrap();
+
+    let last_progress = Arc::new(Mutex::new(Progress::default()));
+    let mut watcher = Watcher::new_async(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        {
+            let last_progress = Arc::clone(&last_progress);
+            move |progress| *last_progress.lock().unwrap() = progress
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(*last_progress.lock().unwrap(), Progress {
+        scanned: 3,
+        watched: 3
+    });
+
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let path = dir.join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    )
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir_all(dir.join(random_string(5))).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stats = watcher.stats_reader();
+    assert_eq!(watcher.stats().watches, 3);
+    assert_eq!(watcher.stats().depth, 2);
+
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    for _ in 0..2 {
+        stream.next().await.unwrap();
+    }
+    assert_eq!(stats.read().events["Create"], 1);
+    assert_eq!(stats.read().events["Delete"], 1);
+
+    while stats.read().watches > 1 {
+        stream.next().await.unwrap();
+    }
+    assert_eq!(stats.read().depth, 0);
+}
+
+#[tokio::test]
+async fn test_identify() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let identify =
+        identity::Identify::new(std::num::NonZeroUsize::new(16).unwrap());
+    let stream = identify.apply(watcher.stream());
+    pin_mut!(stream);
+
+    let path = top_dir.path().join(random_string(5));
+    let new_path = top_dir.path().join(random_string(5));
+    let tmp_path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    let (_, _, created) = stream.next().await.unwrap();
+    assert!(created.is_some());
+
+    // Renaming keeps the inode.
+    fs::rename(&path, &new_path).unwrap();
+    let (_, _, moved) = stream.next().await.unwrap();
+    assert_eq!(moved, created);
+
+    // Replacing by a rename does not.
+    File::create(&tmp_path).unwrap();
+    stream.next().await.unwrap();
+    fs::rename(&tmp_path, &new_path).unwrap();
+    let (_, _, replaced) = stream.next().await.unwrap();
+    assert!(replaced.is_some());
+    assert_ne!(replaced, created);
+
+    fs::remove_file(&new_path).unwrap();
+    let (event, _, deleted) = stream.next().await.unwrap();
+    assert_eq!(event, Event::Delete(new_path, FileType::File));
+    assert_eq!(deleted, replaced);
+}
+
+#[tokio::test]
+async fn test_pair_moves() {
+    let (dir_a, dir_b) =
+        (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let opts = WatcherOpts::new(Dotdir::Exclude, Vec::new());
+    let mut watcher_a = Watcher::new(dir_a.as_ref(), opts.clone()).unwrap();
+    let mut watcher_b = Watcher::new(dir_b.as_ref(), opts).unwrap();
+    let pairs = pair::PairMoves::new(std::time::Duration::from_millis(100));
+    let stream = pairs.apply(futures::stream::select(
+        watcher_a.stream(),
+        watcher_b.stream(),
+    ));
+    pin_mut!(stream);
+
+    let path_a = dir_a.path().join(random_string(5));
+    let path_b = dir_b.path().join(random_string(5));
+    // Events of different watchers come in no certain order, so the create
+    // is read first.
+    File::create(&path_a).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path_a.clone(), FileType::File)
+    );
+    fs::rename(&path_a, &path_b).unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fs::rename(&path_b, outside.path().join(random_string(5))).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Move(path_a, path_b.clone(), FileType::File)
+    );
+    // Given alone after the window.
+    let event = stream.next().await.unwrap().0;
+    assert_eq!(event, Event::MoveAway(path_b, FileType::File, cookie(&event)));
+}
+
+#[tokio::test]
+async fn test_atomic_saves() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, vec![ExtraEvent::Modify]),
+    )
+    .unwrap();
+    let saves = save::AtomicSaves::new(std::time::Duration::from_secs(1));
+    let stream = saves.apply(watcher.stream());
+    pin_mut!(stream);
+
+    let path = top_dir.path().join(random_string(5));
+    let tmp_path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    let mut file = File::create(&tmp_path).unwrap();
+    file.write_all(b"saved").unwrap();
+    drop(file);
+    fs::rename(&tmp_path, &path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // The file created first is not saved, so its events are kept.
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        save::Item::Event(Event::Create(path.clone(), FileType::File))
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        save::Item::Replaced(path.clone(), FileType::File)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        save::Item::Event(Event::Delete(path, FileType::File))
+    );
+}
+
+#[tokio::test]
+async fn test_size_threshold() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let old_path = top_dir.path().join(random_string(5));
+    fs::write(&old_path, [0; 100]).unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, vec![ExtraEvent::Modify]),
+    )
+    .unwrap();
+    let monitor = size::SizeMonitor::new(top_dir.as_ref(), vec![150]);
+    assert_eq!(monitor.total(), 100);
+    let stream = monitor.apply(watcher.stream());
+    pin_mut!(stream);
+
+    // Seen by the create or the modify, whichever comes after the write.
+    fs::write(top_dir.path().join(random_string(5)), [0; 100]).unwrap();
+    loop {
+        match stream.next().await.unwrap().0 {
+            size::Item::SizeThreshold(total) => break assert_eq!(total, 200),
+            size::Item::Event(Event::Create(..) | Event::Modify(..)) => {}
+            item => panic!("Unexpected item: {:?}", item),
+        }
+    }
+
+    fs::remove_file(&old_path).unwrap();
+    let mut item = stream.next().await.unwrap().0;
+    while let size::Item::Event(Event::Modify(..)) = item {
+        item = stream.next().await.unwrap().0;
+    }
+    assert_eq!(
+        item,
+        size::Item::Event(Event::Delete(old_path, FileType::File))
+    );
+    assert_eq!(stream.next().await.unwrap().0, size::Item::SizeThreshold(100));
+}
+
+#[tokio::test]
+async fn test_group_events() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let grouping = group::Grouping::new(std::time::Duration::from_millis(500));
+    let stream = grouping.apply(watcher.stream());
+    pin_mut!(stream);
+
+    let path = top_dir.path().join(random_string(5));
+    let new_path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    fs::rename(&path, &new_path).unwrap();
+    let group = stream.next().await.unwrap();
+    assert_eq!(group.events.len(), 2);
+    assert_eq!(group.events[1].0, Event::Move(path, new_path, FileType::File));
+
+    // A later event is not related.
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    File::create(top_dir.path().join(random_string(5))).unwrap();
+    let next = stream.next().await.unwrap();
+    assert_eq!(next.id, group.id + 1);
+    assert_eq!(next.events.len(), 1);
+}
+
+#[tokio::test]
+async fn test_controller() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    fs::create_dir(&dir).unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let controller = watcher.controller();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+    // Polls the stream for a while to handle requests.
+    let timeout = std::time::Duration::from_millis(100);
+
+    assert!(controller.pause());
+    assert!(controller.unwatch(&dir));
+    assert!(tokio::time::timeout(timeout, stream.next()).await.is_err());
+    File::create(top_dir.path().join(random_string(5))).unwrap();
+    assert!(tokio::time::timeout(timeout, stream.next()).await.is_err());
+
+    assert!(controller.resume());
+    assert!(tokio::time::timeout(timeout, stream.next()).await.is_err());
+    File::create(dir.join(random_string(5))).unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+    assert_eq!(controller.stats().watches, 1);
+}
+
+#[tokio::test]
+async fn test_rescan_on_resume() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()).rescan_on_resume(true),
+    )
+    .unwrap();
+    let controller = watcher.controller();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    controller.pause();
+    let timeout = std::time::Duration::from_millis(100);
+    assert!(tokio::time::timeout(timeout, stream.next()).await.is_err());
+    // The move is dropped, and found by the rescan.
+    let new_path = top_dir.path().join(random_string(5));
+    fs::rename(&path, &new_path).unwrap();
+    controller.resume();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Delete(path, FileType::File)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(new_path, FileType::File)
+    );
+}
+
+#[tokio::test]
+async fn test_rescan_after_suspend() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    // Without a threshold, every check is taken as a suspend, so the
+    // changes since the files were last listed are found again.
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .rescan_after_suspend(std::time::Duration::ZERO),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let new_path = top_dir.path().join(random_string(5));
+    fs::rename(&path, &new_path).unwrap();
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Move(path.to_owned(), new_path.to_owned(), FileType::File)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Delete(path, FileType::File)
+    );
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        Event::Create(new_path, FileType::File)
+    );
+    let timeout = std::time::Duration::from_millis(1500);
+    assert!(tokio::time::timeout(timeout, stream.next()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_resume_from() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let state_dir = tempfile::tempdir().unwrap();
+    let state = state_dir.path().join("state");
+    let path = top_dir.path().join(random_string(5));
+    File::create(&path).unwrap();
+    // Paths which are not UTF-8 are kept too, so no event is yielded.
+    File::create(top_dir.path().join(OsStr::from_bytes(b"\xff"))).unwrap();
+    let watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    watcher.save_state(&state).unwrap();
+    drop(watcher);
+
+    let new_path = top_dir.path().join(random_string(5));
+    fs::rename(&path, &new_path).unwrap();
+    let watcher = Watcher::resume_from(
+        top_dir.as_ref(),
+        &state,
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let events: Vec<_> =
+        watcher.close().await.into_iter().map(|v| v.0).collect();
+    assert_eq!(events, vec![
+        Event::Delete(path, FileType::File),
+        Event::Create(new_path, FileType::File)
+    ]);
+}
+
+#[cfg(feature = "diff")]
+#[test]
+fn test_diffs() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let path = top_dir.path().join(random_string(5));
+    fs::write(&path, "a\nb\nc\n").unwrap();
+    let mut diffs =
+        diff::Diffs::new(std::num::NonZeroUsize::new(16).unwrap(), 1024);
+    assert_eq!(diffs.diff(&Event::Create(path.clone(), FileType::File)), None);
+
+    fs::write(&path, "a\nB\nc\n").unwrap();
+    let diff = diffs
+        .diff(&Event::Modify(path.clone(), FileType::File, None))
+        .unwrap();
+    assert!(diff.contains("\n-b\n+B\n"));
+
+    // Binary files are not kept.
+    fs::write(&path, b"\0").unwrap();
+    assert_eq!(diffs.diff(&Event::Modify(path, FileType::File, None)), None);
+}
+
+#[tokio::test]
+async fn test_watched_paths() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let dir = top_dir.path().join(random_string(5));
+    let sub_dir = dir.join(random_string(5));
+    fs::create_dir_all(&sub_dir).unwrap();
+
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let watches = watcher.watches_reader();
+    assert_eq!(watcher.watched_paths(), vec![
+        top_dir.path().to_owned(),
+        dir.to_owned(),
+        sub_dir.to_owned()
+    ]);
+
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    fs::remove_dir(&sub_dir).unwrap();
+    while stream.next().await.unwrap().0 != Event::Ignored {}
+    let paths = tokio::select! {
+        v = watches.read() => v.unwrap(),
+        v = stream.next() => panic!("Unexpected event: {:?}", v),
+    };
+    assert_eq!(paths, vec![top_dir.path().to_owned(), dir]);
+}
+
+#[tokio::test]
+async fn test_replay() {
+    let log: &[u8] = br#"{"kind":"create","time":"2022-01-01T00:00:00Z","path":"/a","file_type":"dir"}
+{"kind":"create","time":"2022-01-01T00:00:00Z"}
+
+{"kind":"move","time":"2022-01-01T00:00:00.2Z","from":"/a","to":"/b","file_type":"dir"}
+{"kind":"delete_top","time":"2022-01-01T00:00:00.2Z","path":"/"}
+"#;
+    let stream = replay::ReplayStream::new(log, replay::Timing::Original);
+    pin_mut!(stream);
+
+    let now = std::time::Instant::now();
+    let (event, t) = stream.next().await.unwrap().unwrap();
+    assert_eq!(event, Event::Create(PathBuf::from("/a"), FileType::Dir));
+    assert_eq!(t, time::macros::datetime!(2022-01-01 0:00 UTC));
+    assert!(matches!(
+        stream.next().await.unwrap(),
+        Err(Error::ParseLog { line: 2, .. })
+    ));
+    assert_eq!(
+        stream.next().await.unwrap().unwrap().0,
+        Event::Move(PathBuf::from("/a"), PathBuf::from("/b"), FileType::Dir)
+    );
+    assert!(now.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(
+        stream.next().await.unwrap().unwrap().0,
+        Event::DeleteTop(PathBuf::from("/"))
+    );
+    assert!(stream.next().await.is_none());
+}
+
+#[cfg(not(feature = "tokio-reactor"))]
+#[test]
+fn test_needs_tokio() {
+    let tree = TempTree::new().unwrap();
+    let opts = WatcherOpts::new(Dotdir::Include, vec![])
+        .retry_failed(std::time::Duration::from_secs(1));
+    assert!(matches!(
+        Watcher::new(tree.as_ref(), opts),
+        Err(Error::NeedsTokio { option: "retry_failed" })
+    ));
+}
+
+#[tokio::test]
+async fn test_record_replay() {
+    let t = time::macros::datetime!(2022-01-01 0:00:00.5 UTC);
+    let a = PathBuf::from("/a");
+    let b = PathBuf::from("/b");
+    let digest = Digest { algo: HashAlgo::Blake3, bytes: [7; 32] };
+    let events = vec![
+        Event::Create(a.to_owned(), FileType::Dir),
+        Event::Move(a.to_owned(), b.to_owned(), FileType::File),
+        Event::MoveAway(a.to_owned(), FileType::File, 3),
+        Event::MoveInto(b.to_owned(), FileType::Dir, 3),
+        Event::Modify(a.to_owned(), FileType::File, Some(digest)),
+        Event::Modify(a.to_owned(), FileType::File, None),
+        Event::Attrib(a.to_owned(), FileType::File),
+        Event::MoveTop(a.to_owned(), Some(b.to_owned())),
+        Event::MoveTop(a.to_owned(), None),
+        Event::DeleteTop(b.to_owned()),
+    ];
+    let log: String = events
+        .iter()
+        .map(|v| record::Record::new(v, t).unwrap().to_json() + "\n")
+        .collect();
+
+    let stream = replay::ReplayStream::new(
+        std::io::Cursor::new(log.into_bytes()),
+        replay::Timing::Immediate,
+    );
+    let replayed: Vec<_> = stream.map(|v| v.unwrap()).collect().await;
+    let expected: Vec<_> = events.into_iter().map(|v| (v, t)).collect();
+    assert_eq!(replayed, expected);
+}
+
+#[tokio::test]
+async fn test_throttle() {
+    let t = time::OffsetDateTime::now_utc();
+    let ms = time::Duration::milliseconds;
+    let file = PathBuf::from("/file");
+    let other = PathBuf::from("/other");
+    let events = vec![
+        (Event::Modify(file.to_owned(), FileType::File, None), t),
+        (Event::Modify(file.to_owned(), FileType::File, None), t + ms(50)),
+        (Event::Modify(other.to_owned(), FileType::File, None), t + ms(60)),
+        (Event::Delete(file.to_owned(), FileType::File), t + ms(70)),
+        (Event::Modify(file.to_owned(), FileType::File, None), t + ms(100)),
+    ];
+    let throttle = throttle::Throttle::with(|event| match event {
+        Event::Modify(..) => Some(std::time::Duration::from_millis(100)),
+        _ => None,
+    });
+    let stream = throttle.apply(futures::stream::iter(events));
+    let times: Vec<_> = stream.map(|(_, v)| v - t).collect().await;
+    assert_eq!(times, [ms(0), ms(60), ms(70), ms(100)]);
+}
+
+#[tokio::test]
+async fn test_throttle_per_path() {
+    let t = time::OffsetDateTime::now_utc();
+    let ms = time::Duration::milliseconds;
+    let file = PathBuf::from("/file");
+    let dir = PathBuf::from("/dir");
+    let events = vec![
+        (Event::Create(file.to_owned(), FileType::File), t),
+        (Event::Create(dir.to_owned(), FileType::Dir), t + ms(10)),
+        (
+            Event::Move(file.to_owned(), dir.join("a"), FileType::File),
+            t + ms(20),
+        ),
+        (
+            Event::Move(file.to_owned(), dir.join("b"), FileType::File),
+            t + ms(30),
+        ),
+        (
+            Event::Move(file.to_owned(), dir.join("b"), FileType::File),
+            t + ms(40),
+        ),
+        (Event::Create(file.to_owned(), FileType::File), t + ms(50)),
+    ];
+    let throttle = throttle::Throttle::new(std::time::Duration::from_secs(1));
+    let stream = throttle.apply(futures::stream::iter(events));
+    let times: Vec<_> = stream.map(|(_, v)| v - t).collect().await;
+    assert_eq!(times, [ms(0), ms(10), ms(20), ms(30)]);
+}
+
+#[tokio::test]
+async fn test_burst() {
+    let t = time::OffsetDateTime::from_unix_timestamp(1_000_000).unwrap();
+    let secs = time::Duration::seconds;
+    let modify = |dir: &str, i: u32| {
+        Event::Modify(Path::new(dir).join(i.to_string()), FileType::File, None)
+    };
+    let mut events = Vec::new();
+    events.extend((0..10).map(|i| (modify("/a", i), t)));
+    events.extend((0..5).map(|i| (modify("/a", i), t + secs(1))));
+    events.extend((0..5).map(|i| (modify("/b", i), t + secs(1))));
+    let detector = burst::BurstDetector::new(5.0, 10.0);
+    let stream = detector.apply(futures::stream::iter(events));
+    let bursts: Vec<_> = stream
+        .filter_map(|(item, v)| async move {
+            matches!(item, burst::Item::Burst(..)).then_some((item, v - t))
+        })
+        .collect()
+        .await;
+    // The rate of /a in the second second is not far above its average.
+    assert_eq!(bursts, [
+        (burst::Item::Burst(PathBuf::from("/a"), 5.0), secs(0)),
+        (burst::Item::Burst(PathBuf::from("/b"), 5.0), secs(1)),
+    ]);
+}
+
+#[tokio::test]
+async fn test_stream_batched() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stream =
+        watcher.stream_batched(2, std::time::Duration::from_millis(100));
+    pin_mut!(stream);
+
+    let paths: Vec<_> =
+        (0..3).map(|_| top_dir.path().join(random_string(5))).collect();
+    for path in &paths {
+        File::create(path).unwrap();
+    }
+    let batch = stream.next().await.unwrap();
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch[1].0, Event::Create(paths[1].to_owned(), FileType::File));
+
+    let now = std::time::Instant::now();
+    let batch = stream.next().await.unwrap();
+    assert!(now.elapsed() >= std::time::Duration::from_millis(100));
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].0, Event::Create(paths[2].to_owned(), FileType::File));
+}
+
+#[tokio::test]
+async fn test_close() {
+    let top_dir = tempfile::tempdir().unwrap();
+    let mut watcher = Watcher::new(
+        top_dir.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    {
+        let stream = watcher.stream();
+        pin_mut!(stream);
+
+        let path = top_dir.path().join(random_string(5));
+        File::create(&path).unwrap();
+        let (event, _) = stream.next().await.unwrap();
+        assert_eq!(event, Event::Create(path, FileType::File));
+    }
+
+    let paths: Vec<_> =
+        (0..3).map(|_| top_dir.path().join(random_string(5))).collect();
+    for path in &paths {
+        File::create(path).unwrap();
+    }
+    let events: Vec<_> =
+        watcher.close().await.into_iter().map(|(event, _)| event).collect();
+    let expected: Vec<_> = paths
+        .into_iter()
+        .map(|path| Event::Create(path, FileType::File))
+        .collect();
+    assert_eq!(events, expected);
+}
+
+#[tokio::test]
+async fn test_drop_many_watchers() {
+    // Far more than the default limit of inotify instances per user.
+    let top_dir = tempfile::tempdir().unwrap();
+    for i in 0..2000 {
+        let mut watcher = Watcher::new(
+            top_dir.as_ref(),
+            WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+        )
+        .unwrap();
+        if i % 100 == 0 {
+            let stream = watcher.stream();
+            pin_mut!(stream);
+            let path = top_dir.path().join(random_string(5));
+            File::create(&path).unwrap();
+            let (event, _) = stream.next().await.unwrap();
+            assert_eq!(event, Event::Create(path, FileType::File));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_mock_watcher() {
+    let mut mock = testing::MockWatcher::new(
+        WatcherOpts::new(Dotdir::Exclude, Vec::new())
+            .file_types(vec![FileType::File]),
+    );
+    let path = PathBuf::from("/mock/file");
+    mock.push(Event::Create(PathBuf::from("/mock/dir"), FileType::Dir));
+    mock.push(Event::Create(path.to_owned(), FileType::File));
+
+    let sender = mock.sender();
+    {
+        let stream = mock.stream();
+        pin_mut!(stream);
+        assert_eq!(
+            stream.next().await.unwrap().0,
+            Event::Create(path.to_owned(), FileType::File)
+        );
+        sender.push(Event::Delete(path.to_owned(), FileType::File));
+        assert_eq!(
+            stream.next().await.unwrap().0,
+            Event::Delete(path, FileType::File)
+        );
+    }
+    assert_eq!(mock.stats().events.get("Create"), Some(&1));
+    assert!(mock.close().await.is_empty());
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn test_blocking_watcher() {
+    let tree = TempTree::new().unwrap();
+    let mut watcher = blocking::Watcher::new(
+        tree.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+
+    let path = tree.create_file(Path::new("")).unwrap();
+    assert_eq!(watcher.next().unwrap().0, Event::Create(path, FileType::File));
+    assert!(watcher.try_next().is_none());
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn test_notify_compat() {
+    use compat::notify::{self, Watcher as _};
+
+    let tree = TempTree::new().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher =
+        notify::RecommendedWatcher::new(tx, notify::Config::default())
+            .unwrap();
+    watcher.watch(tree.as_ref(), notify::RecursiveMode::Recursive).unwrap();
+
+    let path = tree.create_file(Path::new("")).unwrap();
+    let new_path = tree.path().join(random_string(5));
+    fs::rename(&path, &new_path).unwrap();
+    assert_eq!(
+        rx.recv().unwrap().unwrap(),
+        notify::Event::new(notify::EventKind::Create(
+            notify::CreateKind::File
+        ))
+        .add_path(path.clone())
+    );
+    assert_eq!(rx.recv().unwrap().unwrap(), notify::Event {
+        kind: notify::EventKind::Modify(notify::ModifyKind::Name(
+            notify::RenameMode::Both
+        )),
+        paths: vec![path, new_path],
+    });
+
+    watcher.unwatch(tree.as_ref()).unwrap();
+    assert!(matches!(
+        watcher.unwatch(tree.as_ref()),
+        Err(Error::WatchNotFound { .. })
+    ));
+}
+
+#[cfg(not(feature = "tokio-reactor"))]
+#[test]
+fn test_any_executor() {
+    let tree = TempTree::new().unwrap();
+    let mut watcher = Watcher::new(
+        tree.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let stream = watcher.stream();
+    pin_mut!(stream);
+
+    let path = tree.path().join(random_string(5));
+    let creating = {
+        let path = path.to_owned();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            File::create(path).unwrap();
+        })
+    };
+    assert_eq!(
+        futures::executor::block_on(stream.next()).unwrap().0,
+        Event::Create(path, FileType::File)
+    );
+    creating.join().unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_buffered_drop_oldest() {
+    let tree = TempTree::new().unwrap();
+    let watcher = Watcher::new(
+        tree.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let mut stream = watcher.buffered(1, Backpressure::DropOldest);
+
+    let paths: Vec<_> =
+        (0..3).map(|_| tree.create_file(Path::new("")).unwrap()).collect();
+    // The task reads the queued events before the clock is advanced.
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    assert_eq!(stream.next().await.unwrap().0, buffer::Item::Dropped(2));
+    assert_eq!(
+        stream.next().await.unwrap().0,
+        buffer::Item::Event(Event::Create(
+            paths[2].to_owned(),
+            FileType::File
+        ))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_buffered_drop_newest() {
+    let t = time::OffsetDateTime::UNIX_EPOCH;
+    let events = futures::stream::iter(0..4).then(move |i| async move {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        (Event::Create(PathBuf::from(i.to_string()), FileType::File), t)
+    });
+    let stream = BufferedStream::new(events, 2, Backpressure::DropNewest);
+
+    // All are read while the consumer is away, and the stream ends after
+    // the buffer.
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    let items: Vec<_> = stream.map(|(item, _)| item).collect().await;
+    let create = |name: &str| {
+        buffer::Item::Event(Event::Create(PathBuf::from(name), FileType::File))
+    };
+    assert_eq!(items, [buffer::Item::Dropped(2), create("0"), create("1")]);
+}
+
+#[tokio::test]
+async fn test_into_broadcast() {
+    let tree = TempTree::new().unwrap();
+    let watcher = Watcher::new(
+        tree.as_ref(),
+        WatcherOpts::new(Dotdir::Exclude, Vec::new()),
+    )
+    .unwrap();
+    let tx = watcher.into_broadcast(16);
+    let (mut a, mut b) = (tx.subscribe(), tx.subscribe());
+
+    let path = tree.create_file(Path::new("")).unwrap();
+    let event = Event::Create(path, FileType::File);
+    assert_eq!(*a.recv().await.unwrap(), event);
+    assert_eq!(*b.recv().await.unwrap(), event);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_buffered_block() {
+    let t = time::OffsetDateTime::UNIX_EPOCH;
+    let events = futures::stream::iter(0..3).map(move |i| {
+        (Event::Create(PathBuf::from(i.to_string()), FileType::File), t)
+    });
+    let mut stream = BufferedStream::new(events, 1, Backpressure::Block);
+
+    // Nothing is dropped, and the stream ends after the events.
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    for i in 0..3 {
+        assert_eq!(
+            stream.next().await.unwrap().0,
+            buffer::Item::Event(Event::Create(
+                PathBuf::from(i.to_string()),
+                FileType::File
+            ))
+        );
+    }
+    assert!(stream.next().await.is_none());
+}
+
+#[test]
+fn test_plan() {
+    let tree = TempTree::new().unwrap();
+    let top = tree.path();
+    fs::create_dir_all(top.join("a/b")).unwrap();
+    fs::create_dir_all(top.join(".h/c")).unwrap();
+    fs::write(top.join("a/f"), "").unwrap();
+    std::os::unix::fs::symlink(top.join("a"), top.join("l")).unwrap();
+
+    let opts = WatcherOpts::new(Dotdir::Exclude, Vec::new());
+    assert_eq!(plan(top, &opts), vec![
+        (top.to_owned(), None),
+        (top.join(".h"), Some(Skip::Hidden)),
+        (top.join("a"), None),
+        (top.join("a/b"), None),
+        (top.join("l"), Some(Skip::Link)),
+    ]);
+
+    // Either the link or the directory is reached first.
+    let opts = opts.symlink(Symlink::Follow);
+    let loops: Vec<_> = plan(top, &opts)
+        .into_iter()
+        .filter(|(_, skip)| *skip == Some(Skip::Loop))
+        .collect();
+    assert_eq!(loops.len(), 1);
+}
+
+#[test]
+fn test_plan_unreadable() {
+    let tree = TempTree::new().unwrap();
+    let top = tree.path();
+    fs::create_dir_all(top.join("a/b")).unwrap();
+    fs::set_permissions(top.join("a"), fs::Permissions::from_mode(0o000))
+        .unwrap();
+
+    let user = OtherUser::new();
+    let planned = plan(top, &WatcherOpts::new(Dotdir::Exclude, Vec::new()));
+    drop(user);
+    fs::set_permissions(top.join("a"), fs::Permissions::from_mode(0o755))
+        .unwrap();
+    assert_eq!(planned, vec![
+        (top.to_owned(), None),
+        (
+            top.join("a"),
+            Some(Skip::Unreadable(std::io::ErrorKind::PermissionDenied))
+        ),
+    ]);
+}